@@ -7,6 +7,7 @@
 )]
 
 use std::io::Write;
+use std::num::NonZeroU32;
 use std::path::PathBuf;
 use std::time::Duration;
 
@@ -106,7 +107,7 @@ fn main() -> Result<()> {
 
     // initialize the context
     let ctx_params = LlamaContextParams::default()
-        .with_n_threads_batch(std::thread::available_parallelism()?.get() as u32)
+        .with_n_threads_batch(NonZeroU32::try_from(std::thread::available_parallelism()?)?)
         .with_embeddings(true);
 
     let mut ctx = model
@@ -116,9 +117,9 @@ fn main() -> Result<()> {
     // Split the prompt to display the batching functionality
     let prompt_lines = prompt.lines();
 
-    // tokenize the prompt
+    // tokenize the prompt, ending each line with [SEP] for BERT-style models
     let tokens_lines_list = prompt_lines
-        .map(|line| model.str_to_token(line, AddBos::Always))
+        .map(|line| model.str_to_token_with_sep(line, AddBos::Always))
         .collect::<Result<Vec<_>, _>>()
         .with_context(|| format!("failed to tokenize {prompt}"))?;
 
@@ -211,7 +212,7 @@ fn batch_decode(
 
     for i in 0..s_batch {
         let embedding = ctx
-            .embeddings_seq_ith(i)
+            .embeddings_seq(i)
             .with_context(|| "Failed to get embeddings")?;
         let output_embeddings = if normalise {
             normalize(embedding)
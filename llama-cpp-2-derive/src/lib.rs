@@ -0,0 +1,126 @@
+//! Derive macros for [llama-cpp-2](https://crates.io/crates/llama-cpp-2).
+//!
+//! Use these through the `derive` feature of `llama-cpp-2` rather than depending on this crate
+//! directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Fields, LitStr};
+
+/// Implement `llama_cpp_2::grammar::schema::LlamaSchema` for a struct with named fields or an
+/// enum with only unit variants.
+///
+/// Structs are described as JSON objects with every field in declaration order, enums as JSON
+/// strings of the variant names. `#[serde(rename = "...")]` on fields and variants is respected.
+#[proc_macro_derive(LlamaSchema)]
+pub fn derive_llama_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let schema = quote!(::llama_cpp_2::grammar::schema);
+    let builder = quote!(::llama_cpp_2::grammar::builder);
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let Fields::Named(fields) = &data.fields else {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "LlamaSchema can only be derived for structs with named fields",
+                ));
+            };
+            let fields = fields
+                .named
+                .iter()
+                .map(|field| {
+                    let ident = field.ident.as_ref().expect("named fields have idents");
+                    let name = match serde_rename(&field.attrs)? {
+                        Some(name) => name,
+                        None => ident.to_string().trim_start_matches("r#").to_string(),
+                    };
+                    let ty = &field.ty;
+                    Ok(quote!((#name, builder.rule_for::<#ty>())))
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                let fields = [#(#fields),*];
+                builder.object(&fields)
+            }
+        }
+        Data::Enum(data) => {
+            let variants = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    if !matches!(variant.fields, Fields::Unit) {
+                        return Err(syn::Error::new_spanned(
+                            variant,
+                            "LlamaSchema can only be derived for enums with unit variants",
+                        ));
+                    }
+                    let name = match serde_rename(&variant.attrs)? {
+                        Some(name) => name,
+                        None => variant.ident.to_string(),
+                    };
+                    let literal = format!("\"{name}\"");
+                    Ok(quote!(#builder::literal(#literal)))
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                let _ = builder;
+                #builder::choice([#(#variants),*])
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "LlamaSchema can not be derived for unions",
+            ))
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#schema::LlamaSchema));
+    }
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics #schema::LlamaSchema for #ident #ty_generics #where_clause {
+            fn schema(builder: &mut #schema::SchemaBuilder) -> #builder::GrammarExpr {
+                #body
+            }
+        }
+    })
+}
+
+/// The name given by `#[serde(rename = "...")]`, if any.
+fn serde_rename(attrs: &[Attribute]) -> syn::Result<Option<String>> {
+    let mut rename = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") && meta.input.peek(syn::Token![=]) {
+                rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                skip_meta(&meta)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(rename)
+}
+
+/// Skip over the value of a serde option we do not care about.
+fn skip_meta(meta: &syn::meta::ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(syn::Token![=]) {
+        meta.value()?.parse::<syn::Lit>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        meta.parse_nested_meta(|meta| skip_meta(&meta))?;
+    }
+    Ok(())
+}
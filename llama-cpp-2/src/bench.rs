@@ -0,0 +1,318 @@
+//! Throughput benchmarks in the style of llama.cpp's `llama-bench`.
+//!
+//! A benchmark runs prompt processing (decoding `n_prompt` tokens in batches of up to
+//! [`LlamaContext::n_batch`]) and text generation (decoding `n_gen` tokens one at a time) with
+//! synthetic tokens, and reports the throughput in tokens per second. Each test is run once to warm
+//! up and then [`BenchParams::with_repetitions`] times, starting from an empty KV cache.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::bench::{self, BenchParams};
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! for result in bench::run(&mut ctx, &BenchParams::default())? {
+//!     println!(
+//!         "{}: {:.2} ± {:.2} t/s",
+//!         result.test,
+//!         result.avg_tokens_per_second(),
+//!         result.stddev_tokens_per_second()
+//!     );
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// A benchmark workload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BenchTest {
+    /// Decode a prompt of this many tokens.
+    PromptProcessing(u32),
+    /// Generate this many tokens, one decode each.
+    TextGeneration(u32),
+}
+
+impl BenchTest {
+    /// The number of tokens the test decodes.
+    #[must_use]
+    pub fn n_tokens(self) -> u32 {
+        match self {
+            Self::PromptProcessing(n) | Self::TextGeneration(n) => n,
+        }
+    }
+}
+
+/// Formats the test the way `llama-bench` names it.
+///
+/// ```
+/// # use llama_cpp_2::bench::BenchTest;
+/// assert_eq!(BenchTest::PromptProcessing(512).to_string(), "pp512");
+/// assert_eq!(BenchTest::TextGeneration(128).to_string(), "tg128");
+/// ```
+impl Display for BenchTest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PromptProcessing(n) => write!(f, "pp{n}"),
+            Self::TextGeneration(n) => write!(f, "tg{n}"),
+        }
+    }
+}
+
+/// What [`run`] benchmarks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct BenchParams {
+    n_prompt: u32,
+    n_gen: u32,
+    repetitions: u32,
+    warmup: bool,
+}
+
+impl Default for BenchParams {
+    /// The defaults of `llama-bench`: `pp512` and `tg128`, five repetitions each.
+    fn default() -> Self {
+        Self {
+            n_prompt: 512,
+            n_gen: 128,
+            repetitions: 5,
+            warmup: true,
+        }
+    }
+}
+
+impl BenchParams {
+    /// Set the number of prompt tokens to process, 0 to skip the prompt processing test.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::bench::{BenchParams, BenchTest};
+    /// let params = BenchParams::default().with_n_prompt(0).with_n_gen(32);
+    /// assert_eq!(params.tests(), vec![BenchTest::TextGeneration(32)]);
+    /// ```
+    #[must_use]
+    pub fn with_n_prompt(mut self, n_prompt: u32) -> Self {
+        self.n_prompt = n_prompt;
+        self
+    }
+
+    /// Get the number of prompt tokens to process.
+    #[must_use]
+    pub fn n_prompt(&self) -> u32 {
+        self.n_prompt
+    }
+
+    /// Set the number of tokens to generate, 0 to skip the text generation test.
+    #[must_use]
+    pub fn with_n_gen(mut self, n_gen: u32) -> Self {
+        self.n_gen = n_gen;
+        self
+    }
+
+    /// Get the number of tokens to generate.
+    #[must_use]
+    pub fn n_gen(&self) -> u32 {
+        self.n_gen
+    }
+
+    /// Set how often each test is measured.
+    #[must_use]
+    pub fn with_repetitions(mut self, repetitions: u32) -> Self {
+        self.repetitions = repetitions;
+        self
+    }
+
+    /// Get how often each test is measured.
+    #[must_use]
+    pub fn repetitions(&self) -> u32 {
+        self.repetitions
+    }
+
+    /// Set whether each test is run once without measuring it first.
+    #[must_use]
+    pub fn with_warmup(mut self, warmup: bool) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Get whether each test is run once without measuring it first.
+    #[must_use]
+    pub fn warmup(&self) -> bool {
+        self.warmup
+    }
+
+    /// The tests [`run`] performs, in order.
+    #[must_use]
+    pub fn tests(&self) -> Vec<BenchTest> {
+        let mut tests = Vec::new();
+        if self.n_prompt > 0 {
+            tests.push(BenchTest::PromptProcessing(self.n_prompt));
+        }
+        if self.n_gen > 0 {
+            tests.push(BenchTest::TextGeneration(self.n_gen));
+        }
+        tests
+    }
+}
+
+/// The measurements of one [`BenchTest`].
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct BenchResult {
+    /// The test that was run.
+    pub test: BenchTest,
+    /// The duration of each repetition.
+    pub samples: Vec<Duration>,
+}
+
+impl BenchResult {
+    /// The throughput of each repetition in tokens per second.
+    pub fn tokens_per_second(&self) -> impl Iterator<Item = f64> + '_ {
+        let n_tokens = f64::from(self.test.n_tokens());
+        self.samples
+            .iter()
+            .map(move |sample| n_tokens / sample.as_secs_f64())
+    }
+
+    /// The mean throughput in tokens per second, 0 without samples.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use llama_cpp_2::bench::{BenchResult, BenchTest};
+    /// let result = BenchResult {
+    ///     test: BenchTest::TextGeneration(100),
+    ///     samples: vec![Duration::from_secs(1), Duration::from_millis(500)],
+    /// };
+    /// assert_eq!(result.avg_tokens_per_second(), 150.0);
+    /// assert_eq!(result.stddev_tokens_per_second(), 5000.0_f64.sqrt());
+    /// ```
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn avg_tokens_per_second(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.tokens_per_second().sum::<f64>() / self.samples.len() as f64
+    }
+
+    /// The sample standard deviation of the throughput in tokens per second, 0 with less than two
+    /// samples.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn stddev_tokens_per_second(&self) -> f64 {
+        if self.samples.len() < 2 {
+            return 0.0;
+        }
+        let avg = self.avg_tokens_per_second();
+        let squares = self
+            .tokens_per_second()
+            .map(|ts| (ts - avg).powi(2))
+            .sum::<f64>();
+        (squares / (self.samples.len() - 1) as f64).sqrt()
+    }
+}
+
+/// An error while running a benchmark.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum BenchError {
+    /// Decoding failed, e.g. because the test does not fit into the context.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// The batch could not hold the tokens.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+}
+
+/// Run the tests of `params` on `ctx`.
+///
+/// The KV cache of `ctx` is cleared before every run and left in an unspecified state.
+///
+/// # Errors
+///
+/// If decoding fails, e.g. because a test needs more than [`LlamaContext::n_ctx`] tokens.
+pub fn run(ctx: &mut LlamaContext, params: &BenchParams) -> Result<Vec<BenchResult>, BenchError> {
+    params
+        .tests()
+        .into_iter()
+        .map(|test| {
+            if params.warmup {
+                run_once(ctx, test)?;
+            }
+            let samples = (0..params.repetitions)
+                .map(|_| run_once(ctx, test))
+                .collect::<Result<_, _>>()?;
+            Ok(BenchResult { test, samples })
+        })
+        .collect()
+}
+
+/// Run `test` once from an empty KV cache and measure how long it took.
+fn run_once(ctx: &mut LlamaContext, test: BenchTest) -> Result<Duration, BenchError> {
+    ctx.clear_kv_cache();
+    let start = Instant::now();
+    match test {
+        BenchTest::PromptProcessing(n_prompt) => process_prompt(ctx, n_prompt)?,
+        BenchTest::TextGeneration(n_gen) => generate(ctx, n_gen)?,
+    }
+    unsafe { llama_cpp_sys_2::llama_synchronize(ctx.context.as_ptr()) };
+    Ok(start.elapsed())
+}
+
+/// Decode `n_prompt` synthetic tokens in batches of `n_batch`.
+fn process_prompt(ctx: &mut LlamaContext, n_prompt: u32) -> Result<(), BenchError> {
+    let n_batch = ctx.n_batch().max(1);
+    let mut batch = LlamaBatch::new(n_batch as usize, 1);
+    let mut pos = 0;
+    while pos < n_prompt {
+        let n_tokens = n_batch.min(n_prompt - pos);
+        batch.clear();
+        for i in pos..pos + n_tokens {
+            batch.add(synthetic_token(ctx, i), pos_i32(i), &[0], i + 1 == n_prompt)?;
+        }
+        ctx.decode(&mut batch)?;
+        pos += n_tokens;
+    }
+    Ok(())
+}
+
+/// Decode `n_gen` synthetic tokens one at a time.
+fn generate(ctx: &mut LlamaContext, n_gen: u32) -> Result<(), BenchError> {
+    let mut batch = LlamaBatch::new(1, 1);
+    for i in 0..n_gen {
+        batch.clear();
+        batch.add(synthetic_token(ctx, i), pos_i32(i), &[0], true)?;
+        ctx.decode(&mut batch)?;
+    }
+    Ok(())
+}
+
+/// The BOS token followed by tokens spread over the vocabulary. Their values do not affect the
+/// speed, only their count does.
+fn synthetic_token(ctx: &LlamaContext, i: u32) -> LlamaToken {
+    if i == 0 {
+        return ctx.model.token_bos();
+    }
+    let n_vocab = u64::try_from(ctx.model.n_vocab()).unwrap_or(1).max(1);
+    let id = u64::from(i) * 7919 % n_vocab;
+    LlamaToken(i32::try_from(id).expect("token id is below n_vocab"))
+}
+
+fn pos_i32(pos: u32) -> i32 {
+    i32::try_from(pos).expect("position should fit into an i32")
+}
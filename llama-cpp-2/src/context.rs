@@ -5,15 +5,26 @@ use std::num::NonZeroI32;
 use std::ptr::NonNull;
 use std::slice;
 
-use crate::llama_batch::LlamaBatch;
+use crate::context::params::LlamaContextParams;
+use crate::context::profile::GraphProfiler;
+use crate::embedding::{EmbeddingMatrix, QuantizedEmbedding};
+use crate::llama_batch::{LlamaBatch, LlamaBatchOne};
 use crate::model::LlamaModel;
 use crate::timing::LlamaTimings;
 use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
 use crate::token::LlamaToken;
 use crate::{DecodeError, EmbeddingsError};
 
+pub mod compression;
+pub mod control_vector;
 pub mod kv_cache;
+pub mod logits;
+pub mod owned;
+pub mod parallel;
 pub mod params;
+pub mod partial_decode;
+pub mod profile;
 pub mod sample;
 pub mod session;
 
@@ -25,6 +36,17 @@ pub struct LlamaContext<'a> {
     pub model: &'a LlamaModel,
     initialized_logits: Vec<i32>,
     embeddings_enabled: bool,
+    /// The sequences of the last decoded batch, only tracked with embeddings enabled.
+    decoded_seq_ids: Vec<i32>,
+    /// A buffer for [`LlamaContext::token_data_array_ith`], see
+    /// [`LlamaContext::recycle_token_data_array`].
+    spare_candidates: Vec<LlamaTokenData>,
+    /// Set with [`params::LlamaContextParams::with_graph_profiling`].
+    graph_profiler: Option<GraphProfiler>,
+    /// The types of the K and V caches, see [`LlamaContext::estimated_kv_cache_size`].
+    kv_cache_types: (llama_cpp_sys_2::ggml_type, llama_cpp_sys_2::ggml_type),
+    /// Whether attention is causal, see [`LlamaContext::set_causal_attn`].
+    causal_attn: bool,
 }
 
 impl Debug for LlamaContext<'_> {
@@ -39,13 +61,19 @@ impl<'model> LlamaContext<'model> {
     pub(crate) fn new(
         llama_model: &'model LlamaModel,
         llama_context: NonNull<llama_cpp_sys_2::llama_context>,
-        embeddings_enabled: bool,
+        params: &LlamaContextParams,
+        graph_profiler: Option<GraphProfiler>,
     ) -> Self {
         Self {
             context: llama_context,
             model: llama_model,
             initialized_logits: Vec::new(),
-            embeddings_enabled,
+            embeddings_enabled: params.embeddings(),
+            decoded_seq_ids: Vec::new(),
+            spare_candidates: Vec::new(),
+            graph_profiler,
+            kv_cache_types: (params.context_params.type_k, params.context_params.type_v),
+            causal_attn: llama_model.is_causal(),
         }
     }
 
@@ -55,35 +83,191 @@ impl<'model> LlamaContext<'model> {
         unsafe { llama_cpp_sys_2::llama_n_batch(self.context.as_ptr()) }
     }
 
+    /// Gets the max number of tokens evaluated at once. Batches are split into micro-batches of
+    /// this size, except for non-causal attention where the whole batch has to fit into one.
+    #[must_use]
+    pub fn n_ubatch(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_ubatch(self.context.as_ptr()) }
+    }
+
+    /// Gets the max number of sequences, i.e. distinct `seq_id`s in a batch.
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        unsafe { llama_cpp_sys_2::llama_n_seq_max(self.context.as_ptr()) }
+    }
+
     /// Gets the size of the context.
+    ///
+    /// This is the size that was allocated, which differs from
+    /// [`LlamaContextParams::n_ctx`](params::LlamaContextParams::n_ctx) if it was not set (the
+    /// model's training context is used then) or not a multiple of the padding llama.cpp uses.
     #[must_use]
     pub fn n_ctx(&self) -> u32 {
         unsafe { llama_cpp_sys_2::llama_n_ctx(self.context.as_ptr()) }
     }
 
+    /// Set whether to use causal attention.
+    ///
+    /// Encoder models such as BERT use non-causal attention, which llama.cpp picks up from the
+    /// model itself. This can be used to override it, e.g. to compute embeddings with a
+    /// generative model. Note that with non-causal attention the whole batch must fit into a
+    /// single ubatch, so [`LlamaContext::decode`] fails for batches of more than
+    /// [`LlamaContext::n_ubatch`] tokens.
+    pub fn set_causal_attn(&mut self, causal_attn: bool) {
+        unsafe { llama_cpp_sys_2::llama_set_causal_attn(self.context.as_ptr(), causal_attn) }
+        self.causal_attn = causal_attn;
+    }
+
+    /// Whether attention is causal: the default of the model (see [`LlamaModel::is_causal`]) or
+    /// the value set with [`LlamaContext::set_causal_attn`].
+    #[must_use]
+    pub fn causal_attn(&self) -> bool {
+        self.causal_attn
+    }
+
     /// Decodes the batch.
     ///
     /// # Errors
     ///
-    /// - `DecodeError` if the decoding failed.
+    /// - `DecodeError` if the decoding failed, [`DecodeError::BatchTooLarge`] if the batch has
+    ///   more than [`LlamaContext::n_batch`] tokens, or [`DecodeError::UbatchTooLarge`] if it has
+    ///   more than [`LlamaContext::n_ubatch`] tokens without causal attention.
     ///
     /// # Panics
     ///
     /// - the returned [`std::ffi::c_int`] from llama-cpp does not fit into a i32 (this should never happen on most systems)
+    /// - `batch` holds embeddings (see [`LlamaBatch::new_embeddings`]) of another size than the
+    ///   `n_embd` of the model
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(n_tokens = batch.n_tokens(), seq_ids = ?batch.seq_ids())
+        )
+    )]
     pub fn decode(&mut self, batch: &mut LlamaBatch) -> Result<(), DecodeError> {
-        let result =
-            unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch.llama_batch) };
+        if let Some(n_embd) = batch.n_embd() {
+            assert_eq!(
+                i32::try_from(n_embd).ok(),
+                Some(self.model.n_embd()),
+                "batch embeddings do not match the n_embd of the model"
+            );
+        }
+        self.decode_raw(batch.llama_batch)?;
+        self.initialized_logits = batch.initialized_logits.clone();
+        if self.embeddings_enabled {
+            self.decoded_seq_ids = batch.seq_ids();
+        }
+        Ok(())
+    }
+
+    /// Decodes a borrowed batch of a single sequence, see [`LlamaBatch::get_one`].
+    ///
+    /// Only the logits of the last token are computed, and llama.cpp stores them at index 0, so
+    /// read them with `ctx.candidates_ith(0)`. With embeddings enabled only the sequence embeddings
+    /// are available, as llama.cpp does not extract token embeddings for such batches.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::decode`].
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(n_tokens = batch.llama_batch.n_tokens, seq_id = batch.llama_batch.all_seq_id)
+        )
+    )]
+    pub fn decode_one(&mut self, batch: &LlamaBatchOne) -> Result<(), DecodeError> {
+        self.decode_raw(batch.llama_batch)?;
+        if self.embeddings_enabled {
+            self.initialized_logits = Vec::new();
+            self.decoded_seq_ids = vec![batch.llama_batch.all_seq_id];
+        } else {
+            self.initialized_logits = vec![0];
+        }
+        Ok(())
+    }
+
+    /// Check the size of `batch` and decode it.
+    fn decode_raw(&mut self, batch: llama_cpp_sys_2::llama_batch) -> Result<(), DecodeError> {
+        if u32::try_from(batch.n_tokens).is_ok_and(|n_tokens| n_tokens > self.n_batch()) {
+            return Err(DecodeError::BatchTooLarge {
+                n_tokens: batch.n_tokens,
+                n_batch: self.n_batch(),
+            });
+        }
+        if !self.causal_attn
+            && u32::try_from(batch.n_tokens).is_ok_and(|n_tokens| n_tokens > self.n_ubatch())
+        {
+            return Err(DecodeError::UbatchTooLarge {
+                n_tokens: batch.n_tokens,
+                n_ubatch: self.n_ubatch(),
+            });
+        }
+        self.clear_graph_profile();
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = unsafe { llama_cpp_sys_2::llama_decode(self.context.as_ptr(), batch) };
 
         match NonZeroI32::new(result) {
             None => {
-                self.initialized_logits = batch.initialized_logits.clone();
+                #[cfg(feature = "metrics")]
+                crate::metrics::decoded(
+                    batch.n_tokens,
+                    start.elapsed(),
+                    self.get_kv_cache_used_cells(),
+                    self.n_ctx(),
+                );
                 Ok(())
             }
-            Some(error) => Err(DecodeError::from(error)),
+            Some(error) => Err(DecodeError::new(error, batch.n_tokens, self)),
         }
     }
 
-    /// Get the embeddings for the `i`th sequence in the current context.
+    /// Decode the BOS and EOS tokens once and discard the result, like llama.cpp's examples do
+    /// before their first request.
+    ///
+    /// The first decode pages the weights in from disk (with mmap) and uploads them to the GPU,
+    /// which can take seconds. Warming up at startup keeps that latency out of the first real
+    /// request. The KV cache is cleared and the timings are reset afterwards.
+    ///
+    /// # Errors
+    ///
+    /// - `DecodeError` if the decoding failed.
+    ///
+    /// # Panics
+    ///
+    /// - if the tokens do not fit into the batch allocated for them (this should never happen)
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// ctx.warmup()?;
+    /// assert_eq!(ctx.get_kv_cache_used_cells(), 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn warmup(&mut self) -> Result<(), DecodeError> {
+        let tokens = [self.model.token_bos(), self.model.token_eos()];
+        let n_tokens = tokens.len().min(self.n_batch().max(1) as usize);
+        let mut batch = LlamaBatch::new(n_tokens, 1);
+        batch
+            .add_sequence(&tokens[..n_tokens], 0, false)
+            .expect("batch was allocated for the tokens");
+        self.decode(&mut batch)?;
+        self.clear_kv_cache();
+        unsafe { llama_cpp_sys_2::llama_synchronize(self.context.as_ptr()) };
+        self.reset_timings();
+        Ok(())
+    }
+
+    /// Get the pooled embeddings of sequence `seq_id` in the last decoded batch.
     ///
     /// # Returns
     ///
@@ -93,24 +277,52 @@ impl<'model> LlamaContext<'model> {
     /// # Errors
     ///
     /// - When the current context was constructed without enabling embeddings.
-    /// - If the current model had a pooling type of [`llama_cpp_sys_2::LLAMA_POOLING_TYPE_NONE`]
-    /// - If the given sequence index exceeds the max sequence id.
+    /// - If the sequence was not part of the last decoded batch.
+    /// - If the context uses a pooling type of [`llama_cpp_sys_2::LLAMA_POOLING_TYPE_NONE`].
     ///
     /// # Panics
     ///
     /// * `n_embd` does not fit into a usize
-    pub fn embeddings_seq_ith(&self, i: i32) -> Result<&[f32], EmbeddingsError> {
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let params = LlamaContextParams::default()
+    ///     .with_embeddings(true)
+    ///     .with_pooling_type(LlamaPoolingType::Mean)
+    ///     .with_n_seq_max(std::num::NonZeroU32::new(2).unwrap());
+    /// let mut ctx = model.new_context(&backend, params)?;
+    /// let mut batch = LlamaBatch::new(512, 2);
+    /// for (seq_id, text) in (0..).zip(["first document", "second document"]) {
+    ///     batch.add_sequence(&model.str_to_token(text, AddBos::Always)?, seq_id, false)?;
+    /// }
+    /// ctx.decode(&mut batch)?;
+    /// let first = ctx.embeddings_seq(0)?;
+    /// let second = ctx.embeddings_seq(1)?;
+    /// assert!(ctx.embeddings_seq(2).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn embeddings_seq(&self, seq_id: i32) -> Result<&[f32], EmbeddingsError> {
         if !self.embeddings_enabled {
             return Err(EmbeddingsError::NotEnabled);
         }
+        if self.decoded_seq_ids.binary_search(&seq_id).is_err() {
+            return Err(EmbeddingsError::UnknownSequence(seq_id));
+        }
 
         let n_embd =
             usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
 
         unsafe {
-            let embedding = llama_cpp_sys_2::llama_get_embeddings_seq(self.context.as_ptr(), i);
-
-            // Technically also possible whenever `i >= max(batch.n_seq)`, but can't check that here.
+            let embedding =
+                llama_cpp_sys_2::llama_get_embeddings_seq(self.context.as_ptr(), seq_id);
             if embedding.is_null() {
                 Err(EmbeddingsError::NonePoolType)
             } else {
@@ -119,7 +331,19 @@ impl<'model> LlamaContext<'model> {
         }
     }
 
-    /// Get the embeddings for the `i`th token in the current context.
+    /// Get the embeddings for the `i`th sequence in the current context.
+    ///
+    /// This is the same as [`LlamaContext::embeddings_seq`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_seq`].
+    #[deprecated(note = "use `embeddings_seq`, `i` is a sequence id and not an index")]
+    pub fn embeddings_seq_ith(&self, i: i32) -> Result<&[f32], EmbeddingsError> {
+        self.embeddings_seq(i)
+    }
+
+    /// Get the embeddings for the `i`th token of the last decoded batch.
     ///
     /// # Returns
     ///
@@ -129,8 +353,11 @@ impl<'model> LlamaContext<'model> {
     /// # Errors
     ///
     /// - When the current context was constructed without enabling embeddings.
-    /// - When the given token didn't have logits enabled when it was passed.
-    /// - If the given token index exceeds the max token id.
+    /// - When the given token didn't have logits enabled when it was passed, or is not part of
+    ///   the last decoded batch.
+    ///
+    /// Token embeddings are only available with a pooling type of
+    /// [`llama_cpp_sys_2::LLAMA_POOLING_TYPE_NONE`], otherwise the returned values are meaningless.
     ///
     /// # Panics
     ///
@@ -139,13 +366,15 @@ impl<'model> LlamaContext<'model> {
         if !self.embeddings_enabled {
             return Err(EmbeddingsError::NotEnabled);
         }
+        if !self.initialized_logits.contains(&i) {
+            return Err(EmbeddingsError::LogitsNotEnabled);
+        }
 
         let n_embd =
             usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
 
         unsafe {
             let embedding = llama_cpp_sys_2::llama_get_embeddings_ith(self.context.as_ptr(), i);
-            // Technically also possible whenever `i >= batch.n_tokens`, but no good way of checking `n_tokens` here.
             if embedding.is_null() {
                 Err(EmbeddingsError::LogitsNotEnabled)
             } else {
@@ -154,6 +383,144 @@ impl<'model> LlamaContext<'model> {
         }
     }
 
+    /// Get the embeddings of several sequences as the rows of a matrix, in the order of `seq_ids`.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_seq`].
+    ///
+    /// # Panics
+    ///
+    /// * `n_embd` does not fit into a usize
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let params = LlamaContextParams::default()
+    /// #     .with_embeddings(true)
+    /// #     .with_pooling_type(LlamaPoolingType::Mean)
+    /// #     .with_n_seq_max(std::num::NonZeroU32::new(2).unwrap());
+    /// # let mut ctx = model.new_context(&backend, params)?;
+    /// let mut batch = LlamaBatch::new(512, 2);
+    /// for (seq_id, text) in (0..).zip(["first document", "second document"]) {
+    ///     batch.add_sequence(&model.str_to_token(text, AddBos::Always)?, seq_id, false)?;
+    /// }
+    /// ctx.decode(&mut batch)?;
+    /// let matrix = ctx.embeddings_seq_matrix(&[0, 1])?;
+    /// assert_eq!(matrix.shape(), (2, model.n_embd() as usize));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn embeddings_seq_matrix(
+        &self,
+        seq_ids: &[i32],
+    ) -> Result<EmbeddingMatrix, EmbeddingsError> {
+        let rows = seq_ids
+            .iter()
+            .map(|&seq_id| self.embeddings_seq(seq_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(EmbeddingMatrix::from_rows(self.n_embd_usize(), rows))
+    }
+
+    /// Get the embeddings of several tokens of the last decoded batch as the rows of a matrix, in
+    /// the order of `indices`.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_ith`].
+    ///
+    /// # Panics
+    ///
+    /// * `n_embd` does not fit into a usize
+    pub fn embeddings_ith_matrix(
+        &self,
+        indices: &[i32],
+    ) -> Result<EmbeddingMatrix, EmbeddingsError> {
+        let rows = indices
+            .iter()
+            .map(|&i| self.embeddings_ith(i))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(EmbeddingMatrix::from_rows(self.n_embd_usize(), rows))
+    }
+
+    fn n_embd_usize(&self) -> usize {
+        usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize")
+    }
+
+    /// Get the embeddings for the sequence `seq_id` quantized to `i8`. See [`QuantizedEmbedding`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_seq`].
+    pub fn quantized_embeddings_seq(
+        &self,
+        seq_id: i32,
+    ) -> Result<QuantizedEmbedding, EmbeddingsError> {
+        self.embeddings_seq(seq_id)
+            .map(QuantizedEmbedding::quantize)
+    }
+
+    /// Get the embeddings for the `i`th token quantized to `i8`. See [`QuantizedEmbedding`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaContext::embeddings_ith`].
+    pub fn quantized_embeddings_ith(&self, i: i32) -> Result<QuantizedEmbedding, EmbeddingsError> {
+        self.embeddings_ith(i).map(QuantizedEmbedding::quantize)
+    }
+
+    /// Get the candidates for the ith token in the context, ready for sampling.
+    ///
+    /// Unlike collecting [`LlamaContext::candidates_ith`] this reuses the buffer of an array passed
+    /// to [`LlamaContext::recycle_token_data_array`] (or consumed by
+    /// [`LlamaContext::sample_token_greedy`]) before, so sampling does not allocate an array of
+    /// `n_vocab` candidates for every token.
+    ///
+    /// # Panics
+    ///
+    /// - logit `i` is not initialized.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let mut batch = LlamaBatch::new(512, 1);
+    /// # batch.add_sequence(&model.str_to_token("Hello", AddBos::Always)?, 0, false)?;
+    /// ctx.decode(&mut batch)?;
+    /// let mut candidates = ctx.token_data_array_ith(batch.n_tokens() - 1);
+    /// candidates.sample_temp(Some(&mut ctx), 0.8);
+    /// let token = candidates.sample_token(&mut ctx);
+    /// // the next call reuses the buffer
+    /// ctx.recycle_token_data_array(candidates);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn token_data_array_ith(&mut self, i: i32) -> LlamaTokenDataArray {
+        let mut candidates =
+            LlamaTokenDataArray::new(std::mem::take(&mut self.spare_candidates), false);
+        candidates.fill_from_logits(self.get_logits_ith(i));
+        candidates
+    }
+
+    /// Keep the buffer of `candidates` for the next [`LlamaContext::token_data_array_ith`].
+    pub fn recycle_token_data_array(&mut self, candidates: LlamaTokenDataArray) {
+        if candidates.data.capacity() > self.spare_candidates.capacity() {
+            self.spare_candidates = candidates.data;
+        }
+    }
+
     /// Get the logits for the ith token in the context.
     ///
     /// # Panics
@@ -0,0 +1,287 @@
+//! Making room in a full context by replacing the oldest turns of a conversation with a summary.
+//!
+//! A [`TurnCache`] keeps track of which tokens of a sequence belong to which turn. When a new turn
+//! does not fit, it hands the oldest turns to a [`ContextCompressor`], which can return a summary
+//! of them. The turns are then removed from the KV cache and the summary and all later turns are
+//! decoded in their place.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::context::compression::{Turn, TurnCache};
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! // keep the system prompt, and replace old turns with their first line
+//! let mut cache = TurnCache::new(0, |turns: &[Turn]| {
+//!     let lines: Vec<&str> = turns.iter().filter_map(|turn| turn.text.lines().next()).collect();
+//!     Some(format!("(Earlier: {})\n", lines.join(" / ")))
+//! })
+//! .with_keep_first(1)
+//! .with_reserve(256);
+//!
+//! let system = model.str_to_token("You are a helpful assistant.\n", AddBos::Always)?;
+//! cache.push_turn(&mut ctx, &system)?;
+//! let user = model.str_to_token("User: Hi!\nAssistant:", AddBos::Never)?;
+//! let logits = cache.push_turn(&mut ctx, &user)?;
+//! let candidates = ctx.token_data_array_ith(logits);
+//! let token = ctx.sample_token_greedy(candidates);
+//! // add the reply to the turn it answers
+//! cache.extend_turn(&mut ctx, &[token])?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::{DecodeError, StringToTokenError};
+
+/// A turn handed to a [`ContextCompressor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Turn {
+    /// The tokens of the turn.
+    pub tokens: Vec<LlamaToken>,
+    /// The text of the tokens, with invalid UTF-8 replaced.
+    pub text: String,
+}
+
+/// Summarizes the oldest turns when the context of a [`TurnCache`] is full.
+///
+/// Any closure taking the turns and returning the summary is a compressor.
+pub trait ContextCompressor {
+    /// Summarize `turns`, which are removed from the context. The summary is tokenized without a
+    /// BOS token and decoded in their place. `None` drops the turns without a summary.
+    fn compress(&mut self, turns: &[Turn]) -> Option<String>;
+}
+
+impl<F> ContextCompressor for F
+where
+    F: FnMut(&[Turn]) -> Option<String>,
+{
+    fn compress(&mut self, turns: &[Turn]) -> Option<String> {
+        self(turns)
+    }
+}
+
+/// Failed to add tokens to a [`TurnCache`].
+#[derive(Debug, thiserror::Error)]
+pub enum CompressionError {
+    /// The tokens do not fit into the context even after compressing all turns that may be
+    /// compressed.
+    #[error("the context is full ({n_ctx} tokens)")]
+    ContextFull {
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// Failed to tokenize the summary.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a batch.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// The turns of a conversation on one sequence of a context, see the
+/// [module docs](crate::context::compression).
+#[derive(Debug)]
+pub struct TurnCache<C> {
+    seq_id: i32,
+    compressor: C,
+    keep_first: usize,
+    reserve: usize,
+    turns: Vec<Vec<LlamaToken>>,
+}
+
+impl<C: ContextCompressor> TurnCache<C> {
+    /// An empty conversation on sequence `seq_id`, which must be empty as well.
+    #[must_use]
+    pub fn new(seq_id: i32, compressor: C) -> Self {
+        Self {
+            seq_id,
+            compressor,
+            keep_first: 0,
+            reserve: 0,
+            turns: Vec::new(),
+        }
+    }
+
+    /// Never compress the first `keep_first` turns, e.g. the system prompt.
+    #[must_use]
+    pub fn with_keep_first(mut self, keep_first: usize) -> Self {
+        self.keep_first = keep_first;
+        self
+    }
+
+    /// Compress once fewer than `reserve` cells would be left after adding a turn, so there is
+    /// room for the reply. Compression is only required to make room for the turn itself.
+    #[must_use]
+    pub fn with_reserve(mut self, reserve: usize) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// The tokens of every turn in the context, including summaries.
+    #[must_use]
+    pub fn turns(&self) -> &[Vec<LlamaToken>] {
+        &self.turns
+    }
+
+    /// The number of tokens in the context.
+    #[must_use]
+    pub fn n_past(&self) -> usize {
+        self.turns.iter().map(Vec::len).sum()
+    }
+
+    /// Decode `tokens` as a new turn, compressing older turns first if the context is nearly
+    /// full. Returns the batch index of the logits of the last token.
+    ///
+    /// # Errors
+    ///
+    /// See [`CompressionError`].
+    pub fn push_turn(
+        &mut self,
+        ctx: &mut LlamaContext,
+        tokens: &[LlamaToken],
+    ) -> Result<i32, CompressionError> {
+        self.make_room(ctx, tokens.len(), self.turns.len())?;
+        let logits = self.decode(ctx, self.n_past(), tokens)?;
+        self.turns.push(tokens.to_vec());
+        Ok(logits)
+    }
+
+    /// Decode `tokens` as part of the last turn, e.g. the tokens of a reply as they are sampled.
+    /// The last turn is never compressed. Returns the batch index of the logits of the last token.
+    ///
+    /// # Errors
+    ///
+    /// See [`CompressionError`].
+    pub fn extend_turn(
+        &mut self,
+        ctx: &mut LlamaContext,
+        tokens: &[LlamaToken],
+    ) -> Result<i32, CompressionError> {
+        let Some(last) = self.turns.len().checked_sub(1) else {
+            return self.push_turn(ctx, tokens);
+        };
+        self.make_room(ctx, tokens.len(), last)?;
+        let logits = self.decode(ctx, self.n_past(), tokens)?;
+        // compression keeps the last turn, so it is still the last one
+        if let Some(turn) = self.turns.last_mut() {
+            turn.extend_from_slice(tokens);
+        }
+        Ok(logits)
+    }
+
+    /// Compress turns before `end` until `n_new` tokens (and if possible the reserve) fit.
+    fn make_room(
+        &mut self,
+        ctx: &mut LlamaContext,
+        n_new: usize,
+        end: usize,
+    ) -> Result<(), CompressionError> {
+        let n_ctx = ctx.n_ctx() as usize;
+        let n_past = self.n_past();
+        if n_past + n_new + self.reserve <= n_ctx {
+            return Ok(());
+        }
+        let context_full = CompressionError::ContextFull { n_ctx: ctx.n_ctx() };
+        let needed = n_past + n_new + self.reserve - n_ctx;
+        let start = self.keep_first.min(end);
+        let mut stop = start;
+        let mut n_removed = 0;
+        while stop < end && n_removed < needed {
+            n_removed += self.turns[stop].len();
+            stop += 1;
+        }
+        if stop == start {
+            // nothing can be compressed, which is only an error if the new tokens do not fit
+            return if n_past + n_new <= n_ctx {
+                Ok(())
+            } else {
+                Err(context_full)
+            };
+        }
+
+        let turns: Vec<Turn> = self.turns[start..stop]
+            .iter()
+            .map(|tokens| Turn {
+                tokens: tokens.clone(),
+                text: ctx.model.tokens_to_str_lossy(tokens),
+            })
+            .collect();
+        let summary = match self.compressor.compress(&turns) {
+            Some(summary) => ctx.model.str_to_token(&summary, AddBos::Never)?,
+            None => Vec::new(),
+        };
+        if n_past - n_removed + summary.len() + n_new > n_ctx {
+            return Err(context_full);
+        }
+
+        let p0: usize = self.turns[..start].iter().map(Vec::len).sum();
+        let replacement = (!summary.is_empty()).then_some(summary);
+        self.turns.splice(start..stop, replacement);
+        // recurrent models can not remove part of a sequence, decode it from the start instead
+        let p0 = if ctx.clear_kv_cache_seq_from(self.seq_id, p0) {
+            p0
+        } else {
+            ctx.clear_kv_cache_seq(self.seq_id, None, None);
+            0
+        };
+        let mut pos = 0;
+        let mut redecode = Vec::new();
+        for turn in &self.turns {
+            if pos + turn.len() > p0 {
+                redecode.extend_from_slice(&turn[p0.saturating_sub(pos)..]);
+            }
+            pos += turn.len();
+        }
+        if !redecode.is_empty() {
+            self.decode(ctx, p0, &redecode)?;
+        }
+        Ok(())
+    }
+
+    /// Decode `tokens` at `pos`, in chunks of at most `n_batch` tokens, with logits for the last
+    /// token only. Returns the batch index of those logits.
+    fn decode(
+        &self,
+        ctx: &mut LlamaContext,
+        mut pos: usize,
+        tokens: &[LlamaToken],
+    ) -> Result<i32, CompressionError> {
+        if pos + tokens.len() > ctx.n_ctx() as usize {
+            return Err(CompressionError::ContextFull { n_ctx: ctx.n_ctx() });
+        }
+        let n_batch = ctx.n_batch() as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let n_chunks = tokens.chunks(n_batch.max(1)).len();
+        for (i, chunk) in tokens.chunks(n_batch.max(1)).enumerate() {
+            batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let last = i + 1 == n_chunks && j + 1 == chunk.len();
+                let p = i32::try_from(pos).expect("positions fit into an i32");
+                batch.add(token, p, &[self.seq_id], last)?;
+                pos += 1;
+            }
+            ctx.decode(&mut batch)?;
+        }
+        Ok(batch.n_tokens() - 1)
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Remove the positions from `p0` on of sequence `seq_id`, unlike
+    /// [`LlamaContext::clear_kv_cache_seq`] not limited to 16 bit positions.
+    fn clear_kv_cache_seq_from(&mut self, seq_id: i32, p0: usize) -> bool {
+        let p0 = i32::try_from(p0).expect("positions fit into an i32");
+        unsafe { llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), seq_id, p0, -1) }
+    }
+}
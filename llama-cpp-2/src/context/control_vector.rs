@@ -0,0 +1,271 @@
+//! Steering generation with control vectors, like the `--control-vector` options of llama.cpp's
+//! tools.
+//!
+//! A control vector holds a direction per layer which is added to the output of that layer. Each
+//! [`ControlVector`] is applied with its own strength and layer range, so a common recipe is to
+//! only steer the middle layers:
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! use llama_cpp_2::context::control_vector::ControlVector;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let happy = ControlVector::from_file("happy.gguf")?
+//!     .with_strength(0.8)
+//!     .with_layers(10, 20);
+//! let honest = ControlVector::from_file("honest.gguf")?.with_strength(0.5);
+//! ctx.apply_control_vectors(&[happy, honest])?;
+//! // ... generate
+//! ctx.clear_control_vectors();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use crate::context::LlamaContext;
+use crate::model::integrity::GgufHeader;
+
+/// Failed to load or apply a control vector.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ControlVectorError {
+    /// The path is not valid unicode.
+    #[error("failed to convert path {0} to str")]
+    PathToStr(PathBuf),
+    /// The path contains a null byte.
+    #[error("null byte in path {0}")]
+    NulError(#[from] std::ffi::NulError),
+    /// The file is not a GGUF file with `f32` `direction.<layer>` tensors.
+    #[error("{0} is not a control vector file")]
+    InvalidFile(PathBuf),
+    /// The directions do not all have the same length.
+    #[error("direction for layer {layer} has {actual} elements instead of {expected}")]
+    InconsistentDirections {
+        /// The layer of the first direction with a different length.
+        layer: i32,
+        /// The length of the other directions.
+        expected: usize,
+        /// The length of the direction of `layer`.
+        actual: usize,
+    },
+    /// The directions do not match the embedding size of the model.
+    #[error("control vector has {actual} elements per layer but the model has n_embd {expected}")]
+    EmbeddingMismatch {
+        /// The embedding size of the model.
+        expected: usize,
+        /// The length of the directions.
+        actual: usize,
+    },
+    /// llama.cpp failed to apply the control vectors.
+    #[error("failed to apply the control vectors (code {0})")]
+    ApplyFailed(i32),
+}
+
+/// Directions to add to the output of layers, with the strength and layers to apply them with.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ControlVector {
+    n_embd: usize,
+    directions: BTreeMap<i32, Vec<f32>>,
+    strength: f32,
+    layers: Option<(i32, i32)>,
+}
+
+impl ControlVector {
+    /// Create a control vector from a direction per layer. Layers start at 1, as layer 0 is
+    /// never steered.
+    ///
+    /// It is applied with strength 1 to all layers it has a direction for.
+    ///
+    /// # Errors
+    ///
+    /// If the directions do not all have the same length.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::context::control_vector::{ControlVector, ControlVectorError};
+    /// let vector = ControlVector::from_directions([(1, vec![0.5; 4]), (2, vec![-0.5; 4])])?;
+    /// assert_eq!(vector.n_embd(), 4);
+    /// assert_eq!(vector.layers(), (1, 2));
+    ///
+    /// let err = ControlVector::from_directions([(1, vec![0.5; 4]), (2, vec![-0.5; 3])]);
+    /// assert_eq!(
+    ///     err,
+    ///     Err(ControlVectorError::InconsistentDirections { layer: 2, expected: 4, actual: 3 })
+    /// );
+    /// # Ok::<(), ControlVectorError>(())
+    /// ```
+    pub fn from_directions(
+        directions: impl IntoIterator<Item = (i32, Vec<f32>)>,
+    ) -> Result<Self, ControlVectorError> {
+        let directions = directions.into_iter().collect::<BTreeMap<_, _>>();
+        let n_embd = directions.values().next().map_or(0, Vec::len);
+        if let Some((&layer, direction)) = directions.iter().find(|(_, d)| d.len() != n_embd) {
+            return Err(ControlVectorError::InconsistentDirections {
+                layer,
+                expected: n_embd,
+                actual: direction.len(),
+            });
+        }
+        Ok(Self {
+            n_embd,
+            directions,
+            strength: 1.0,
+            layers: None,
+        })
+    }
+
+    /// Load a control vector from a GGUF file with a `direction.<layer>` tensor per layer, as
+    /// written by llama.cpp's `cvector-generator` and repeng.
+    ///
+    /// # Errors
+    ///
+    /// If the file can not be read or has no directions, see [`ControlVectorError`].
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ControlVectorError> {
+        let path = path.as_ref();
+        let c_path = path
+            .to_str()
+            .ok_or_else(|| ControlVectorError::PathToStr(path.to_path_buf()))?;
+        let c_path = CString::new(c_path)?;
+        let invalid = || ControlVectorError::InvalidFile(path.to_path_buf());
+        let header = GgufHeader::read_with_data(&c_path).ok_or_else(invalid)?;
+        let mut directions = Vec::new();
+        for name in header.tensor_names() {
+            let Some(layer) = name.strip_prefix("direction.") else {
+                continue;
+            };
+            let layer = layer.parse().map_err(|_| invalid())?;
+            let data = header
+                .tensor_f32(&CString::new(name)?)
+                .ok_or_else(invalid)?;
+            directions.push((layer, data.to_vec()));
+        }
+        if directions.is_empty() {
+            return Err(invalid());
+        }
+        Self::from_directions(directions)
+    }
+
+    /// Scale the directions by `strength`, negative values steer in the opposite direction.
+    #[must_use]
+    pub fn with_strength(mut self, strength: f32) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    /// Only apply the directions of the layers `start..=end`.
+    #[must_use]
+    pub fn with_layers(mut self, start: i32, end: i32) -> Self {
+        self.layers = Some((start, end));
+        self
+    }
+
+    /// The number of elements of each direction.
+    #[must_use]
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// The strength the directions are applied with.
+    #[must_use]
+    pub fn strength(&self) -> f32 {
+        self.strength
+    }
+
+    /// The first and last layer the directions are applied to, by default all layers with a
+    /// direction.
+    #[must_use]
+    pub fn layers(&self) -> (i32, i32) {
+        self.layers.unwrap_or_else(|| {
+            let first = self.directions.keys().next().copied().unwrap_or(1);
+            let last = self.directions.keys().next_back().copied().unwrap_or(0);
+            (first, last)
+        })
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Steer this context with `vectors`, replacing any control vectors applied before. Each
+    /// vector is scaled by its strength and limited to its layers, then they are summed.
+    ///
+    /// Unlike `LoRA` adapters, control vectors belong to the context and take effect from the next
+    /// decode, so the KV cache is kept.
+    ///
+    /// # Errors
+    ///
+    /// If a vector does not match the embedding size of the model or llama.cpp fails to apply
+    /// them.
+    pub fn apply_control_vectors(
+        &mut self,
+        vectors: &[ControlVector],
+    ) -> Result<(), ControlVectorError> {
+        let n_embd = usize::try_from(self.model.n_embd()).unwrap_or(0);
+        let n_layer = self.model.n_layer();
+        if let Some(vector) = vectors.iter().find(|vector| vector.n_embd != n_embd) {
+            return Err(ControlVectorError::EmbeddingMismatch {
+                expected: n_embd,
+                actual: vector.n_embd,
+            });
+        }
+
+        // llama.cpp takes a single buffer starting at layer 1 with one layer range
+        let mut data = vec![0.0; n_embd * usize::try_from(n_layer - 1).unwrap_or(0)];
+        let mut range: Option<(i32, i32)> = None;
+        for vector in vectors {
+            let (start, end) = vector.layers();
+            let (start, end) = (start.max(1), end.min(n_layer - 1));
+            if start > end {
+                continue;
+            }
+            range = Some(range.map_or((start, end), |(s, e)| (s.min(start), e.max(end))));
+            for (&layer, direction) in vector.directions.range(start..=end) {
+                let offset = usize::try_from(layer - 1).unwrap_or(0) * n_embd;
+                for (value, d) in data[offset..offset + n_embd].iter_mut().zip(direction) {
+                    *value += vector.strength * d;
+                }
+            }
+        }
+
+        let Some((start, end)) = range else {
+            self.clear_control_vectors();
+            return Ok(());
+        };
+        let result = unsafe {
+            llama_cpp_sys_2::llama_control_vector_apply(
+                self.context.as_ptr(),
+                data.as_ptr(),
+                data.len(),
+                self.model.n_embd(),
+                start,
+                end,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(ControlVectorError::ApplyFailed(result))
+        }
+    }
+
+    /// Stop steering this context with control vectors.
+    pub fn clear_control_vectors(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_control_vector_apply(
+                self.context.as_ptr(),
+                std::ptr::null(),
+                0,
+                self.model.n_embd(),
+                -1,
+                -1,
+            );
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! utilities for working with the kv cache
 
 use crate::context::LlamaContext;
-use std::ffi::c_int;
+use std::ffi::{c_int, CStr};
 use std::num::NonZeroU8;
 
 impl LlamaContext<'_> {
@@ -24,6 +24,10 @@ impl LlamaContext<'_> {
     /// * `dest` - The sequence id to copy the cache to.
     /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is copied up to `p1`.
     /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is copied starting from `p0`.
+    ///
+    /// For recurrent models (see [`crate::model::LlamaModel::is_recurrent`]) the whole state of `src`
+    /// is copied regardless of `p0` and `p1`, and both sequence ids must be less than
+    /// [`crate::context::params::LlamaContextParams::n_seq_max`].
     pub fn copy_kv_cache_seq(&mut self, src: i32, dest: i32, p0: Option<u16>, p1: Option<u16>) {
         let p0 = p0.map_or(-1, i32::from);
         let p1 = p1.map_or(-1, i32::from);
@@ -39,12 +43,42 @@ impl LlamaContext<'_> {
     /// * `src` - The sequence id to clear the cache for.
     /// * `p0` - The start position of the cache to clear. If `None`, the entire cache is cleared up to `p1`.
     /// * `p1` - The end position of the cache to clear. If `None`, the entire cache is cleared from `p0`.
-    pub fn clear_kv_cache_seq(&mut self, src: i32, p0: Option<u16>, p1: Option<u16>) {
+    ///
+    /// Returns `false` if the range could not be removed (recurrent models can only clear a whole
+    /// sequence, see [`crate::model::LlamaModel::is_recurrent`]). Removing a whole sequence never
+    /// fails.
+    pub fn clear_kv_cache_seq(&mut self, src: i32, p0: Option<u16>, p1: Option<u16>) -> bool {
         let p0 = p0.map_or(-1, i32::from);
         let p1 = p1.map_or(-1, i32::from);
-        unsafe {
-            llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), src, p0, p1);
-        }
+        unsafe { llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), src, p0, p1) }
+    }
+
+    /// Save the cache of sequence `seq_id` into the otherwise unused sequence `checkpoint`.
+    ///
+    /// The saved state can later be restored with [`Self::restore_kv_cache_seq`], e.g. to branch
+    /// several continuations off a shared prompt. This works for both transformer and recurrent
+    /// models. For the latter it is the only way to go back to an earlier state, as their state
+    /// can not be partially cleared.
+    ///
+    /// # Parameters
+    ///
+    /// * `seq_id` - The sequence id to save.
+    /// * `checkpoint` - The sequence id to save the state into. Anything it held before is discarded.
+    pub fn checkpoint_kv_cache_seq(&mut self, seq_id: i32, checkpoint: i32) {
+        self.clear_kv_cache_seq(checkpoint, None, None);
+        self.copy_kv_cache_seq(seq_id, checkpoint, None, None);
+    }
+
+    /// Restore the cache of sequence `seq_id` from a checkpoint created with
+    /// [`Self::checkpoint_kv_cache_seq`]. The checkpoint is kept and can be restored again.
+    ///
+    /// # Parameters
+    ///
+    /// * `checkpoint` - The sequence id the state was saved into.
+    /// * `seq_id` - The sequence id to restore.
+    pub fn restore_kv_cache_seq(&mut self, checkpoint: i32, seq_id: i32) {
+        self.clear_kv_cache_seq(seq_id, None, None);
+        self.copy_kv_cache_seq(checkpoint, seq_id, None, None);
     }
 
     /// Returns the number of used KV cells (i.e. have at least one sequence assigned to them)
@@ -121,6 +155,54 @@ impl LlamaContext<'_> {
         unsafe { llama_cpp_sys_2::llama_kv_cache_seq_pos_max(self.context.as_ptr(), seq_id) }
     }
 
+    /// Discard the `n_discard` tokens after the first `n_keep` of sequence `seq_id` and move the
+    /// tokens after them back, e.g. to continue generating once the context is full while keeping
+    /// the system prompt (StreamingLLM-style context shifting). Unlike
+    /// [`Self::clear_kv_cache_seq`] and [`Self::kv_cache_seq_add`] this is not limited to 16 bit
+    /// positions.
+    ///
+    /// Returns the new number of positions of the sequence, which the next token is decoded at,
+    /// or `None` if the tokens could not be removed (see [`Self::clear_kv_cache_seq`]). Nothing is
+    /// changed then.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let (mut n_past, n_system_prompt, token) = (0, 32, LlamaToken(0));
+    /// let n_ctx = i32::try_from(ctx.n_ctx())?;
+    /// if n_past == n_ctx {
+    ///     // drop half of the conversation after the system prompt
+    ///     let n_discard = (n_past - n_system_prompt) / 2;
+    ///     n_past = ctx
+    ///         .shift_kv_cache_seq(0, n_system_prompt, n_discard)
+    ///         .expect("not a recurrent model");
+    /// }
+    /// let mut batch = LlamaBatch::new(1, 1);
+    /// batch.add(token, n_past, &[0], true)?;
+    /// ctx.decode(&mut batch)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shift_kv_cache_seq(&mut self, seq_id: i32, n_keep: i32, n_discard: i32) -> Option<i32> {
+        let n_past = self.kv_cache_seq_pos_max(seq_id) + 1;
+        let p0 = n_keep.clamp(0, n_past);
+        let p1 = p0.saturating_add(n_discard.max(0)).min(n_past);
+        let ctx = self.context.as_ptr();
+        if !unsafe { llama_cpp_sys_2::llama_kv_cache_seq_rm(ctx, seq_id, p0, p1) } {
+            return None;
+        }
+        unsafe { llama_cpp_sys_2::llama_kv_cache_seq_add(ctx, seq_id, p1, n_past, p0 - p1) };
+        Some(n_past - (p1 - p0))
+    }
+
     /// Defragment the KV cache
     /// This will be applied:
     ///   - lazily on next [`LlamaContext::decode`]
@@ -141,6 +223,84 @@ impl LlamaContext<'_> {
         unsafe { llama_cpp_sys_2::llama_get_kv_cache_token_count(self.context.as_ptr()) }
     }
 
+    /// An estimate of the memory allocated for the K and V caches, e.g. to monitor the memory used
+    /// per session together with [`Self::get_state_size`].
+    ///
+    /// The vendored llama.cpp does not report its buffer sizes, so this is computed from the
+    /// model's metadata and the cache types the context was created with, like llama.cpp sizes
+    /// the cache. It covers the whole cache of [`Self::n_ctx`] cells (one cell per sequence for
+    /// recurrent models, see [`crate::model::LlamaModel::is_recurrent`]), no matter how many are
+    /// in use, but not the compute buffers, which need memory on top of it.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let kv_cache = ctx.estimated_kv_cache_size();
+    /// println!(
+    ///     "KV cache: {} MiB (K: {}, V: {}), state: {} MiB",
+    ///     kv_cache.total_bytes() >> 20,
+    ///     kv_cache.type_k,
+    ///     kv_cache.type_v,
+    ///     ctx.get_state_size() >> 20,
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn estimated_kv_cache_size(&self) -> KVCacheSize {
+        let (n_embd_k, n_embd_v, n_cells) = self.kv_cache_dims();
+        let n_layer = usize::try_from(self.model.n_layer()).unwrap_or(0);
+        let (type_k, type_v) = self.kv_cache_types;
+        let bytes = |ggml_type, n_embd: i64| {
+            n_layer * unsafe { llama_cpp_sys_2::ggml_row_size(ggml_type, n_embd * n_cells) }
+        };
+        KVCacheSize {
+            k_bytes: bytes(type_k, n_embd_k),
+            v_bytes: bytes(type_v, n_embd_v),
+            type_k: ggml_type_name(type_k),
+            type_v: ggml_type_name(type_v),
+        }
+    }
+
+    /// The number of K and V values per layer and cell and the number of cells of the KV cache,
+    /// from the model's metadata as llama.cpp computes them.
+    pub(crate) fn kv_cache_dims(&self) -> (i64, i64, i64) {
+        let model = self.model;
+        let arch = model
+            .meta_val_str("general.architecture")
+            .unwrap_or_default();
+        let hparam = |key: &str| {
+            model
+                .meta_val_str(&format!("{arch}.{key}"))
+                .and_then(|value| value.parse::<i64>().ok())
+        };
+        let n_embd = i64::from(model.n_embd());
+        if model.is_recurrent() {
+            // the rolling conv states and the ssm states of every sequence
+            let d_inner = hparam("ssm.inner_size").unwrap_or(0);
+            let d_conv = hparam("ssm.conv_kernel").unwrap_or(0);
+            let d_state = hparam("ssm.state_size").unwrap_or(0);
+            let n_seq = i64::from(self.n_seq_max().max(1));
+            ((d_conv - 1).max(0) * d_inner, d_state * d_inner, n_seq)
+        } else {
+            let n_head = hparam("attention.head_count").unwrap_or(1).max(1);
+            let n_head_kv = hparam("attention.head_count_kv").unwrap_or(n_head);
+            let n_embd_head_k = hparam("attention.key_length").unwrap_or(n_embd / n_head);
+            let n_embd_head_v = hparam("attention.value_length").unwrap_or(n_embd / n_head);
+            (
+                n_embd_head_k * n_head_kv,
+                n_embd_head_v * n_head_kv,
+                i64::from(self.n_ctx()),
+            )
+        }
+    }
+
     /// Create an empty KV cache view. (use only for debugging purposes)
     ///
     /// # Parameters
@@ -156,6 +316,38 @@ impl LlamaContext<'_> {
     }
 }
 
+/// The estimated memory allocated for the KV cache of a context, see [`LlamaContext::estimated_kv_cache_size`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KVCacheSize {
+    /// The bytes allocated for the K cache.
+    pub k_bytes: usize,
+    /// The bytes allocated for the V cache.
+    pub v_bytes: usize,
+    /// The ggml type of the K cache, e.g. `f16` or `q8_0`.
+    pub type_k: String,
+    /// The ggml type of the V cache.
+    pub type_v: String,
+}
+
+impl KVCacheSize {
+    /// The bytes allocated for the K and V caches.
+    #[must_use]
+    pub fn total_bytes(&self) -> usize {
+        self.k_bytes + self.v_bytes
+    }
+}
+
+/// The name ggml uses for `ggml_type`.
+fn ggml_type_name(ggml_type: llama_cpp_sys_2::ggml_type) -> String {
+    let name = unsafe { llama_cpp_sys_2::ggml_type_name(ggml_type) };
+    if name.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
 /// Information associated with an individual cell in the KV cache view.
 #[derive(Debug)]
 pub struct KVCacheViewCell {
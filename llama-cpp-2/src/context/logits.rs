@@ -0,0 +1,128 @@
+//! A two dimensional view of the logits of several positions of the last decoded batch.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_batch::LlamaBatch;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let tokens = model.str_to_token("The quick brown fox", AddBos::Always)?;
+//! let mut batch = LlamaBatch::new(512, 1);
+//! for (pos, &token) in (0..).zip(&tokens) {
+//!     batch.add(token, pos, &[0], true)?;
+//! }
+//! ctx.decode(&mut batch)?;
+//!
+//! // the most likely next token at every position
+//! let logits = ctx.logits_view(0..batch.n_tokens());
+//! let predictions: Vec<usize> = logits
+//!     .rows()
+//!     .map(|row| (0..row.len()).max_by(|&a, &b| row[a].total_cmp(&row[b])).unwrap())
+//!     .collect();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::Range;
+use std::slice;
+
+use crate::context::LlamaContext;
+
+/// The logits of consecutive positions of a batch as the rows of a row-major
+/// `positions × n_vocab` matrix, borrowed from the context. Create one with
+/// [`LlamaContext::logits_view`].
+///
+/// The layout is that of a C-contiguous array, so the view converts to the matrix types of
+/// numeric crates without copying, e.g. with
+/// `ndarray::ArrayView2::from_shape(view.shape(), view.as_slice())`.
+#[derive(Debug, Clone, Copy)]
+pub struct LogitsView<'a> {
+    data: &'a [f32],
+    n_vocab: usize,
+    start: i32,
+}
+
+impl<'a> LogitsView<'a> {
+    /// The batch position of the first row.
+    #[must_use]
+    pub fn start(&self) -> i32 {
+        self.start
+    }
+
+    /// The number of positions.
+    #[must_use]
+    pub fn n_rows(&self) -> usize {
+        self.data.len() / self.n_vocab
+    }
+
+    /// The number of logits of every position.
+    #[must_use]
+    pub fn n_vocab(&self) -> usize {
+        self.n_vocab
+    }
+
+    /// The number of rows and columns, `(positions, n_vocab)`.
+    #[must_use]
+    pub fn shape(&self) -> (usize, usize) {
+        (self.n_rows(), self.n_vocab)
+    }
+
+    /// The logits of the `i`th row, i.e. of batch position `start + i`. `None` if out of bounds.
+    #[must_use]
+    pub fn row(&self, i: usize) -> Option<&'a [f32]> {
+        self.data.chunks_exact(self.n_vocab).nth(i)
+    }
+
+    /// The logits of every position in order.
+    pub fn rows(&self) -> slice::ChunksExact<'a, f32> {
+        self.data.chunks_exact(self.n_vocab)
+    }
+
+    /// All logits, position by position.
+    #[must_use]
+    pub fn as_slice(&self) -> &'a [f32] {
+        self.data
+    }
+}
+
+impl LlamaContext<'_> {
+    /// View the logits of the batch positions in `positions` as one matrix, see [`LogitsView`].
+    ///
+    /// # Panics
+    ///
+    /// - the logits of a position in `positions` are not initialized.
+    /// - `n_vocab` does not fit into a usize
+    #[must_use]
+    pub fn logits_view(&self, positions: Range<i32>) -> LogitsView<'_> {
+        let n_vocab =
+            usize::try_from(self.model.n_vocab()).expect("n_vocab does not fit into a usize");
+        if positions.is_empty() {
+            return LogitsView {
+                data: &[],
+                n_vocab,
+                start: positions.start,
+            };
+        }
+        for i in positions.clone() {
+            assert!(
+                self.initialized_logits.contains(&i),
+                "logit {i} is not initialized. only {:?} is",
+                self.initialized_logits
+            );
+        }
+
+        // the logits of batch position i start at i * n_vocab, and every initialized position
+        // lies within the buffer
+        let first = self.get_logits_ith(positions.start).as_ptr();
+        let len = positions.len() * n_vocab;
+        LogitsView {
+            data: unsafe { slice::from_raw_parts(first, len) },
+            n_vocab,
+            start: positions.start,
+        }
+    }
+}
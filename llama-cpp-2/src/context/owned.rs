@@ -0,0 +1,99 @@
+//! A context that owns a reference count of its model instead of borrowing it.
+//!
+//! A [`LlamaContext`] borrows the [`LlamaModel`] it was created from, so it can not outlive the
+//! stack frame that owns the model, nor be stored in a struct next to it. An
+//! [`OwnedLlamaContext`] keeps the model alive through an [`Arc`] instead.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use llama_cpp_2::context::owned::OwnedLlamaContext;
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::llama_backend::LlamaBackend;
+//! use llama_cpp_2::llama_batch::LlamaBatch;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//!
+//! struct Session {
+//!     ctx: OwnedLlamaContext,
+//! }
+//!
+//! fn open(backend: &LlamaBackend) -> Result<Session, Box<dyn std::error::Error>> {
+//!     let model = Arc::new(LlamaModel::load_from_file(backend, "path/to/model", &Default::default())?);
+//!     let ctx = model.new_owned_context(backend, LlamaContextParams::default())?;
+//!     Ok(Session { ctx })
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let mut session = open(&backend)?;
+//! let tokens = session.ctx.model().str_to_token("Hello", AddBos::Always)?;
+//! let mut batch = LlamaBatch::new(512, 1);
+//! batch.add_sequence(&tokens, 0, false)?;
+//! session.ctx.with(|ctx| ctx.decode(&mut batch))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use crate::context::params::LlamaContextParams;
+use crate::context::LlamaContext;
+use crate::llama_backend::LlamaBackend;
+use crate::model::LlamaModel;
+use crate::LlamaContextLoadError;
+
+/// A [`LlamaContext`] together with the [`Arc`] of the model it was created from. Create one with
+/// [`LlamaModel::new_owned_context`].
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub struct OwnedLlamaContext {
+    // declared (and so dropped) before the model it borrows
+    context: LlamaContext<'static>,
+    model: Arc<LlamaModel>,
+}
+
+impl OwnedLlamaContext {
+    /// The model of the context.
+    #[must_use]
+    pub fn model(&self) -> &Arc<LlamaModel> {
+        &self.model
+    }
+
+    /// The context, for methods that do not need it mutably.
+    #[must_use]
+    pub fn context(&self) -> &LlamaContext<'_> {
+        &self.context
+    }
+
+    /// Run `f` with the context.
+    ///
+    /// The context is only lent to a closure, which can not know how long the model lives, as
+    /// handing out `&mut LlamaContext` would allow replacing it with a context of a model that is
+    /// dropped before this one.
+    pub fn with<R>(&mut self, f: impl FnOnce(&mut LlamaContext<'_>) -> R) -> R {
+        f(&mut self.context)
+    }
+}
+
+impl LlamaModel {
+    /// Create a context that keeps the model alive, see [`OwnedLlamaContext`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModel::new_context`].
+    pub fn new_owned_context(
+        self: &Arc<Self>,
+        backend: &LlamaBackend,
+        params: LlamaContextParams,
+    ) -> Result<OwnedLlamaContext, LlamaContextLoadError> {
+        // SAFETY: the model is kept alive by the `Arc` stored next to the context, which is
+        // dropped first, and `OwnedLlamaContext` never hands out the `'static` context.
+        let model: &'static LlamaModel = unsafe { &*Arc::as_ptr(self) };
+        Ok(OwnedLlamaContext {
+            context: model.new_context(backend, params)?,
+            model: Arc::clone(self),
+        })
+    }
+}
@@ -0,0 +1,257 @@
+//! Decoding several sequences in shared batches, e.g. to serve requests in parallel.
+//!
+//! [`ParallelSequences`] assigns a sequence id to every prompt, decodes the pending tokens of all
+//! sequences together and hands the logits of each sequence to a sampling closure, which can use a
+//! different sampler per sequence. Sequences finish on an end of generation token, after
+//! [`ParallelSequences::with_max_tokens`] tokens or once the context is full, and new prompts can be
+//! added between steps.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::context::parallel::ParallelSequences;
+//! # use std::num::NonZeroU32;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let params = LlamaContextParams::default().with_n_seq_max(NonZeroU32::new(4).unwrap());
+//! let mut ctx = model.new_context(&backend, params)?;
+//! let mut sequences = ParallelSequences::new(ctx.n_seq_max()).with_max_tokens(Some(128));
+//! for prompt in ["The capital of France is", "Llamas are", "Rust is"] {
+//!     sequences.add(&model.str_to_token(prompt, AddBos::Always)?);
+//! }
+//!
+//! while !sequences.is_done() {
+//!     let sampled = sequences.step(&mut ctx, |ctx, _seq_id, logits| {
+//!         let candidates = ctx.token_data_array_ith(logits);
+//!         ctx.sample_token_greedy(candidates)
+//!     })?;
+//!     for (seq_id, token) in sampled {
+//!         println!("{seq_id}: {}", model.token_to_str_lossy(token));
+//!     }
+//! }
+//! for seq_id in sequences.seq_ids().collect::<Vec<_>>() {
+//!     let reason = sequences.finish_reason(seq_id);
+//!     let tokens = sequences.remove(&mut ctx, seq_id).unwrap_or_default();
+//!     println!("{seq_id} ({reason:?}): {}", model.tokens_to_str_lossy(&tokens));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::generation::FinishReason;
+use crate::llama_batch::LlamaBatch;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Sequences decoded together, see the [module docs](crate::context::parallel).
+#[derive(Debug)]
+pub struct ParallelSequences {
+    batch: LlamaBatch,
+    max_tokens: Option<usize>,
+    /// The sequences, indexed by their id.
+    slots: Vec<Option<Sequence>>,
+}
+
+/// The state of one sequence.
+#[derive(Debug, Default)]
+struct Sequence {
+    /// The number of decoded tokens.
+    n_past: i32,
+    /// Tokens that still have to be decoded.
+    pending: Vec<LlamaToken>,
+    /// The sampled tokens.
+    generated: Vec<LlamaToken>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl ParallelSequences {
+    /// Manage up to `n_seq_max` sequences with the ids `0..n_seq_max`, usually
+    /// [`LlamaContext::n_seq_max`].
+    #[must_use]
+    pub fn new(n_seq_max: u32) -> Self {
+        Self {
+            batch: LlamaBatch::new(512, 1),
+            max_tokens: None,
+            slots: (0..n_seq_max).map(|_| None).collect(),
+        }
+    }
+
+    /// Finish sequences after `max_tokens` sampled tokens, [`None`] for no limit.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Start a sequence with `prompt`, returning its id. `None` if every id is in use or the
+    /// prompt is empty.
+    ///
+    /// The KV cache of the id must be empty, which it is unless the context was used for other
+    /// things, as [`ParallelSequences::remove`] clears it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::context::parallel::ParallelSequences;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut sequences = ParallelSequences::new(2);
+    /// assert_eq!(sequences.add(&[LlamaToken(1), LlamaToken(2)]), Some(0));
+    /// assert_eq!(sequences.add(&[LlamaToken(1)]), Some(1));
+    /// assert_eq!(sequences.add(&[LlamaToken(1)]), None, "both ids are in use");
+    /// assert!(!sequences.is_done());
+    /// ```
+    pub fn add(&mut self, prompt: &[LlamaToken]) -> Option<i32> {
+        if prompt.is_empty() {
+            return None;
+        }
+        let free = self.slots.iter().position(Option::is_none)?;
+        self.slots[free] = Some(Sequence {
+            pending: prompt.to_vec(),
+            ..Sequence::default()
+        });
+        Some(seq_id(free))
+    }
+
+    /// Remove sequence `seq_id` and clear its KV cache, freeing the id for another prompt.
+    /// Returns its sampled tokens, `None` if there is no such sequence.
+    pub fn remove(&mut self, ctx: &mut LlamaContext, seq_id: i32) -> Option<Vec<LlamaToken>> {
+        let sequence = self.slot_mut(seq_id)?.take()?;
+        ctx.clear_kv_cache_seq(seq_id, None, None);
+        Some(sequence.generated)
+    }
+
+    /// The ids of the sequences, finished or not, in ascending order.
+    pub fn seq_ids(&self) -> impl Iterator<Item = i32> + '_ {
+        (0..self.slots.len())
+            .filter(|&i| self.slots[i].is_some())
+            .map(seq_id)
+    }
+
+    /// The tokens sampled for sequence `seq_id`, `None` if there is no such sequence.
+    #[must_use]
+    pub fn generated(&self, seq_id: i32) -> Option<&[LlamaToken]> {
+        self.slot(seq_id)
+            .map(|sequence| sequence.generated.as_slice())
+    }
+
+    /// Why sequence `seq_id` finished, `None` while it is running or if there is no such sequence.
+    #[must_use]
+    pub fn finish_reason(&self, seq_id: i32) -> Option<FinishReason> {
+        self.slot(seq_id)?.finish_reason
+    }
+
+    /// Whether every sequence finished, i.e. [`ParallelSequences::step`] has nothing to do.
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.slots
+            .iter()
+            .flatten()
+            .all(|sequence| sequence.finish_reason.is_some())
+    }
+
+    /// Decode the pending tokens of the running sequences, at most [`LlamaContext::n_batch`], then
+    /// sample the next token of every sequence that has logits with `sample`. `sample` is called
+    /// with the context, the sequence id and the batch index of the logits (e.g. for
+    /// [`LlamaContext::token_data_array_ith`]). Long prompts take several steps.
+    ///
+    /// Returns the sampled tokens and the sequences they belong to. Sequences whose token ends the
+    /// generation or reaches the limit are finished but keep their KV cache until they are
+    /// removed.
+    ///
+    /// # Errors
+    ///
+    /// - decoding failed, e.g. because the KV cache has no room for the batch. No sequence
+    ///   advances then, and the step can be retried after removing sequences.
+    ///
+    /// # Panics
+    ///
+    /// - a position does not fit into an `i32`.
+    pub fn step(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut sample: impl FnMut(&mut LlamaContext, i32, i32) -> LlamaToken,
+    ) -> Result<Vec<(i32, LlamaToken)>, DecodeError> {
+        let n_ctx = i32::try_from(ctx.n_ctx()).unwrap_or(i32::MAX);
+        for sequence in self.slots.iter_mut().flatten() {
+            if sequence.finish_reason.is_none() && sequence.n_past + pending_len(sequence) > n_ctx {
+                sequence.finish_reason = Some(FinishReason::ContextFull);
+            }
+        }
+
+        // the slots in the batch and how many of their pending tokens it holds
+        let mut in_batch = Vec::new();
+        let mut budget = ctx.n_batch() as usize;
+        self.batch.clear();
+        for (i, sequence) in self.slots.iter().enumerate() {
+            let Some(sequence) = sequence else { continue };
+            if sequence.finish_reason.is_some() || sequence.pending.is_empty() || budget == 0 {
+                continue;
+            }
+            let n = sequence.pending.len().min(budget);
+            budget -= n;
+            for (j, &token) in sequence.pending[..n].iter().enumerate() {
+                let pos = sequence.n_past + i32::try_from(j).expect("positions fit into an i32");
+                let last = j + 1 == sequence.pending.len();
+                self.batch
+                    .add(token, pos, &[seq_id(i)], last)
+                    .expect("the batch grows as needed");
+            }
+            in_batch.push((i, n));
+        }
+        if in_batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        ctx.decode(&mut self.batch)?;
+
+        let mut sampled = Vec::new();
+        let mut logits_index = 0;
+        for (i, n) in in_batch {
+            let max_tokens = self.max_tokens;
+            let sequence = self.slots[i]
+                .as_mut()
+                .expect("only existing sequences are decoded");
+            sequence.pending.drain(..n);
+            sequence.n_past += i32::try_from(n).expect("positions fit into an i32");
+            logits_index += i32::try_from(n).expect("the batch index fits into an i32");
+            if !sequence.pending.is_empty() {
+                continue;
+            }
+            let token = sample(ctx, seq_id(i), logits_index - 1);
+            sequence.generated.push(token);
+            sampled.push((seq_id(i), token));
+            if token == ctx.model.token_eos() {
+                sequence.finish_reason = Some(FinishReason::Eos);
+            } else if ctx.model.is_eog_token(token) {
+                sequence.finish_reason = Some(FinishReason::EogToken);
+            } else if max_tokens.is_some_and(|max| sequence.generated.len() >= max) {
+                sequence.finish_reason = Some(FinishReason::MaxTokens);
+            } else {
+                sequence.pending.push(token);
+            }
+        }
+        Ok(sampled)
+    }
+
+    fn slot(&self, seq_id: i32) -> Option<&Sequence> {
+        self.slots.get(usize::try_from(seq_id).ok()?)?.as_ref()
+    }
+
+    fn slot_mut(&mut self, seq_id: i32) -> Option<&mut Option<Sequence>> {
+        self.slots.get_mut(usize::try_from(seq_id).ok()?)
+    }
+}
+
+/// The number of pending tokens of `sequence` as an `i32`.
+fn pending_len(sequence: &Sequence) -> i32 {
+    i32::try_from(sequence.pending.len()).unwrap_or(i32::MAX)
+}
+
+/// The sequence id of slot `i`, which fits as there are at most `u32::MAX` slots.
+#[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+fn seq_id(i: usize) -> i32 {
+    i as i32
+}
@@ -4,11 +4,16 @@ use std::num::NonZeroU32;
 
 use llama_cpp_sys_2;
 
+use crate::model::LlamaModel;
+
 /// A rusty wrapper around `rope_scaling_type`.
 #[repr(i8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum RopeScalingType {
-    /// The scaling type is unspecified
+    /// The scaling type is unspecified, so the type the model was trained with is used, see
+    /// [`crate::model::LlamaModel::rope_scaling_type_train`].
     Unspecified = -1,
     /// No scaling
     None = 0,
@@ -43,10 +48,149 @@ impl From<RopeScalingType> for i32 {
     }
 }
 
+/// A rusty wrapper around `llama_pooling_type`.
+#[repr(i8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LlamaPoolingType {
+    /// The pooling type is unspecified, the model's default is used.
+    Unspecified = -1,
+    /// No pooling, only per-token embeddings are available.
+    None = 0,
+    /// Mean pooling over all tokens of a sequence.
+    Mean = 1,
+    /// Use the embedding of the first (`[CLS]`) token of a sequence.
+    Cls = 2,
+}
+
+/// Create a `LlamaPoolingType` from a `c_int` - returns `LlamaPoolingType::Unspecified` if
+/// the value is not recognized.
+impl From<i32> for LlamaPoolingType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::Mean,
+            2 => Self::Cls,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+/// Create a `c_int` from a `LlamaPoolingType`.
+impl From<LlamaPoolingType> for i32 {
+    fn from(value: LlamaPoolingType) -> Self {
+        match value {
+            LlamaPoolingType::None => 0,
+            LlamaPoolingType::Mean => 1,
+            LlamaPoolingType::Cls => 2,
+            LlamaPoolingType::Unspecified => -1,
+        }
+    }
+}
+
+/// The number of tokens a context holds, see [`LlamaContextParams::with_n_ctx`].
+///
+/// Converts from [`NonZeroU32`] and from `Option<NonZeroU32>` (where [`None`] uses the size of
+/// the model).
+///
+/// # Examples
+///
+/// ```rust
+/// # use std::num::NonZeroU32;
+/// use llama_cpp_2::context::params::ContextSize;
+/// let n_ctx = NonZeroU32::new(4096).unwrap();
+/// assert_eq!(ContextSize::from(n_ctx), ContextSize::Tokens(n_ctx));
+/// assert_eq!(ContextSize::from(None), ContextSize::FromModel);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ContextSize {
+    /// Use the context size the model was trained with.
+    FromModel,
+    /// A fixed number of tokens.
+    Tokens(NonZeroU32),
+}
+
+impl From<NonZeroU32> for ContextSize {
+    fn from(value: NonZeroU32) -> Self {
+        Self::Tokens(value)
+    }
+}
+
+impl From<Option<NonZeroU32>> for ContextSize {
+    fn from(value: Option<NonZeroU32>) -> Self {
+        value.map_or(Self::FromModel, Self::Tokens)
+    }
+}
+
+impl From<ContextSize> for Option<NonZeroU32> {
+    fn from(value: ContextSize) -> Self {
+        match value {
+            ContextSize::FromModel => None,
+            ContextSize::Tokens(n_ctx) => Some(n_ctx),
+        }
+    }
+}
+
+/// The context of [`LlamaContextParams`] is longer than a model supports, see
+/// [`LlamaContextParams::context_size_warning`]. Beyond the supported size the output of the model
+/// silently degrades, often into repetition or gibberish.
+///
+/// # Examples
+///
+/// ```
+/// use llama_cpp_2::context::params::ContextSizeWarning;
+/// let warning = ContextSizeWarning { n_ctx: 16384, n_ctx_train: 4096, supported_n_ctx: 4096 };
+/// assert_eq!(warning.recommended_rope_freq_scale(), 0.25);
+/// assert_eq!(
+///     warning.to_string(),
+///     "n_ctx 16384 exceeds the 4096 tokens the model supports (trained on 4096), \
+///      use a smaller n_ctx or a rope frequency scale of 0.25"
+/// );
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ContextSizeWarning {
+    /// The requested context size.
+    pub n_ctx: u32,
+    /// The context size the model was trained with.
+    pub n_ctx_train: u32,
+    /// The context size the model supports with the rope configuration of the parameters.
+    pub supported_n_ctx: u32,
+}
+
+impl ContextSizeWarning {
+    /// The rope frequency scale for linear scaling of the model to `n_ctx`, see
+    /// [`LlamaContextParams::with_rope_freq_scale`].
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn recommended_rope_freq_scale(&self) -> f32 {
+        self.n_ctx_train as f32 / self.n_ctx as f32
+    }
+}
+
+impl std::fmt::Display for ContextSizeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n_ctx {} exceeds the {} tokens the model supports (trained on {}), use a smaller n_ctx or a rope frequency scale of {}",
+            self.n_ctx,
+            self.supported_n_ctx,
+            self.n_ctx_train,
+            self.recommended_rope_freq_scale()
+        )
+    }
+}
+
 /// A safe wrapper around `llama_context_params`.
 ///
+/// Values that llama.cpp would reject (such as zero threads) are ruled out by the types of the
+/// `with_*` methods.
+///
 /// Generally this should be created with [`Default::default()`] and then modified with `with_*` methods.
 ///
+/// With the `serde` feature the values that have `with_*` methods can be (de)serialized. Missing
+/// fields keep their default values, and `n_ctx` is `null` to use the size of the model.
+///
 /// # Examples
 ///
 /// ```rust
@@ -60,6 +204,33 @@ impl From<RopeScalingType> for i32 {
 /// assert_eq!(ctx_params.seed(), 1234);
 /// assert_eq!(ctx_params.n_ctx(), NonZeroU32::new(2048));
 /// ```
+///
+/// Loading the parameters from JSON:
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # fn main() -> Result<(), serde_json::Error> {
+/// # use std::num::NonZeroU32;
+/// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+///
+/// let params: LlamaContextParams = serde_json::from_str(
+///     r#"{ "n_ctx": 4096, "embeddings": true, "pooling_type": "mean", "graph_profiling": true }"#,
+/// )?;
+/// assert_eq!(params.n_ctx(), NonZeroU32::new(4096));
+/// assert!(params.graph_profiling());
+/// assert_eq!(params.pooling_type(), LlamaPoolingType::Mean);
+/// assert_eq!(params.n_batch(), LlamaContextParams::default().n_batch());
+///
+/// let json = serde_json::to_string(&params)?;
+/// let round_tripped: LlamaContextParams = serde_json::from_str(&json)?;
+/// assert_eq!(serde_json::to_string(&round_tripped)?, json);
+///
+/// assert!(serde_json::from_str::<LlamaContextParams>(r#"{ "n_threads": 0 }"#).is_err());
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "serde"))]
+/// # fn main() {}
+/// ```
 #[derive(Debug, Clone)]
 #[allow(
     missing_docs,
@@ -68,6 +239,7 @@ impl From<RopeScalingType> for i32 {
 )]
 pub struct LlamaContextParams {
     pub(crate) context_params: llama_cpp_sys_2::llama_context_params,
+    graph_profiling: bool,
 }
 
 /// SAFETY: we do not currently allow setting or reading the pointers that cause this to not be automatically send or sync.
@@ -112,14 +284,17 @@ impl LlamaContextParams {
     ///
     /// ```rust
     /// # use std::num::NonZeroU32;
-    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// use llama_cpp_2::context::params::{ContextSize, LlamaContextParams};
     /// let params = LlamaContextParams::default();
     /// let params = params.with_n_ctx(NonZeroU32::new(2048));
     /// assert_eq!(params.n_ctx(), NonZeroU32::new(2048));
+    ///
+    /// let params = params.with_n_ctx(ContextSize::FromModel);
+    /// assert_eq!(params.n_ctx(), None);
     /// ```
     #[must_use]
-    pub fn with_n_ctx(mut self, n_ctx: Option<NonZeroU32>) -> Self {
-        self.context_params.n_ctx = n_ctx.map_or(0, std::num::NonZeroU32::get);
+    pub fn with_n_ctx(mut self, n_ctx: impl Into<ContextSize>) -> Self {
+        self.context_params.n_ctx = Option::from(n_ctx.into()).map_or(0, NonZeroU32::get);
         self
     }
 
@@ -137,6 +312,20 @@ impl LlamaContextParams {
         NonZeroU32::new(self.context_params.n_ctx)
     }
 
+    /// Get the size of the context as a [`ContextSize`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{ContextSize, LlamaContextParams};
+    /// let params = LlamaContextParams::default().with_n_ctx(ContextSize::FromModel);
+    /// assert_eq!(params.context_size(), ContextSize::FromModel);
+    /// ```
+    #[must_use]
+    pub fn context_size(&self) -> ContextSize {
+        ContextSize::from(self.n_ctx())
+    }
+
     /// Set the `n_batch`
     ///
     /// # Examples
@@ -145,12 +334,12 @@ impl LlamaContextParams {
     /// # use std::num::NonZeroU32;
     /// use llama_cpp_2::context::params::LlamaContextParams;
     /// let params = LlamaContextParams::default()
-    ///     .with_n_batch(2048);
+    ///     .with_n_batch(NonZeroU32::new(2048).unwrap());
     /// assert_eq!(params.n_batch(), 2048);
     /// ```
     #[must_use]
-    pub fn with_n_batch(mut self, n_batch: u32) -> Self {
-        self.context_params.n_batch = n_batch;
+    pub fn with_n_batch(mut self, n_batch: NonZeroU32) -> Self {
+        self.context_params.n_batch = n_batch.get();
         self
     }
 
@@ -168,6 +357,40 @@ impl LlamaContextParams {
         self.context_params.n_batch
     }
 
+    /// Set the maximum number of sequences (i.e. distinct states for recurrent models).
+    ///
+    /// Recurrent models keep one state per sequence, so this must be large enough for every
+    /// sequence id used, including checkpoints.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::num::NonZeroU32;
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_seq_max(NonZeroU32::new(4).unwrap());
+    /// assert_eq!(params.n_seq_max(), 4);
+    /// ```
+    #[must_use]
+    pub fn with_n_seq_max(mut self, n_seq_max: NonZeroU32) -> Self {
+        self.context_params.n_seq_max = n_seq_max.get();
+        self
+    }
+
+    /// Get the maximum number of sequences.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.n_seq_max(), 1);
+    /// ```
+    #[must_use]
+    pub fn n_seq_max(&self) -> u32 {
+        self.context_params.n_seq_max
+    }
+
     /// Set the type of rope scaling.
     ///
     /// # Examples
@@ -197,7 +420,10 @@ impl LlamaContextParams {
         RopeScalingType::from(self.context_params.rope_scaling_type)
     }
 
-    /// Set the rope frequency base.
+    /// Set the rope frequency base, 0 to use the one the model was trained with.
+    ///
+    /// Raising the base stretches the positions the model can tell apart (NTK-aware scaling), which
+    /// extends the context beyond [`crate::model::LlamaModel::n_ctx_train`] without fine-tuning.
     ///
     /// # Examples
     ///
@@ -207,6 +433,17 @@ impl LlamaContextParams {
     ///    .with_rope_freq_base(0.5);
     /// assert_eq!(params.rope_freq_base(), 0.5);
     /// ```
+    ///
+    /// Run a model trained on 4096 tokens with 8192, using a base for roughly twice the context:
+    ///
+    /// ```rust
+    /// use std::num::NonZeroU32;
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///     .with_n_ctx(NonZeroU32::new(8192))
+    ///     .with_rope_freq_base(10_000.0 * 2.0_f32.powf(128.0 / 126.0))
+    ///     .with_rope_freq_scale(1.0);
+    /// ```
     #[must_use]
     pub fn with_rope_freq_base(mut self, rope_freq_base: f32) -> Self {
         self.context_params.rope_freq_base = rope_freq_base;
@@ -226,7 +463,10 @@ impl LlamaContextParams {
         self.context_params.rope_freq_base
     }
 
-    /// Set the rope frequency scale.
+    /// Set the rope frequency scale, 0 to use the one the model was trained with.
+    ///
+    /// Positions are multiplied by the scale, so linear scaling of a model to `n` times its
+    /// training context uses a scale of `1 / n`.
     ///
     /// # Examples
     ///
@@ -255,6 +495,65 @@ impl LlamaContextParams {
         self.context_params.rope_freq_scale
     }
 
+    /// Check the context size against the context size `model` was trained with, taking the rope
+    /// scaling of these parameters into account. Returns a warning if the model is likely to
+    /// degrade, so applications can tell their users before it happens.
+    ///
+    /// The size supported with rope scaling is an estimate: linear and `YaRN` scaling extend the
+    /// training context by `1 / rope_freq_scale`, and raising the rope frequency base (NTK-aware
+    /// scaling) roughly by the ratio to the trained base.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::num::NonZeroU32;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(32768));
+    /// if let Some(warning) = params.context_size_warning(&model) {
+    ///     eprintln!("warning: {warning}");
+    ///     params = params.with_rope_freq_scale(warning.recommended_rope_freq_scale());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn context_size_warning(&self, model: &LlamaModel) -> Option<ContextSizeWarning> {
+        let n_ctx = self.n_ctx()?.get();
+        let n_ctx_train = model.n_ctx_train();
+        let scaling_type = match self.rope_scaling_type() {
+            RopeScalingType::Unspecified => model.rope_scaling_type_train(),
+            scaling_type => scaling_type,
+        };
+        let freq_scale = match (scaling_type, self.rope_freq_scale()) {
+            (RopeScalingType::None, _) => 1.0,
+            (_, freq_scale) if freq_scale > 0.0 => freq_scale,
+            _ => model.rope_freq_scale_train(),
+        };
+        let freq_base_train = model.rope_freq_base_train();
+        let ntk_factor = if self.rope_freq_base() > 0.0 && freq_base_train > 0.0 {
+            (self.rope_freq_base() / freq_base_train).max(1.0)
+        } else {
+            1.0
+        };
+        let supported_n_ctx = (n_ctx_train as f32 * ntk_factor / freq_scale.max(f32::EPSILON))
+            .min(u32::MAX as f32) as u32;
+        (n_ctx > supported_n_ctx).then_some(ContextSizeWarning {
+            n_ctx,
+            n_ctx_train,
+            supported_n_ctx,
+        })
+    }
+
     /// Get the number of threads.
     ///
     /// # Examples
@@ -286,14 +585,15 @@ impl LlamaContextParams {
     /// # Examples
     ///
     /// ```rust
+    /// # use std::num::NonZeroU32;
     /// use llama_cpp_2::context::params::LlamaContextParams;
     /// let params = LlamaContextParams::default()
-    ///    .with_n_threads(8);
+    ///    .with_n_threads(NonZeroU32::new(8).unwrap());
     /// assert_eq!(params.n_threads(), 8);
     /// ```
     #[must_use]
-    pub fn with_n_threads(mut self, n_threads: u32) -> Self {
-        self.context_params.n_threads = n_threads;
+    pub fn with_n_threads(mut self, n_threads: NonZeroU32) -> Self {
+        self.context_params.n_threads = n_threads.get();
         self
     }
 
@@ -302,14 +602,15 @@ impl LlamaContextParams {
     /// # Examples
     ///
     /// ```rust
+    /// # use std::num::NonZeroU32;
     /// use llama_cpp_2::context::params::LlamaContextParams;
     /// let params = LlamaContextParams::default()
-    ///    .with_n_threads_batch(8);
+    ///    .with_n_threads_batch(NonZeroU32::new(8).unwrap());
     /// assert_eq!(params.n_threads_batch(), 8);
     /// ```
     #[must_use]
-    pub fn with_n_threads_batch(mut self, n_threads: u32) -> Self {
-        self.context_params.n_threads_batch = n_threads;
+    pub fn with_n_threads_batch(mut self, n_threads: NonZeroU32) -> Self {
+        self.context_params.n_threads_batch = n_threads.get();
         self
     }
 
@@ -341,6 +642,67 @@ impl LlamaContextParams {
         self.context_params.embeddings = embedding;
         self
     }
+
+    /// Check whether graph profiling is enabled
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let params = llama_cpp_2::context::params::LlamaContextParams::default();
+    /// assert!(!params.graph_profiling());
+    /// ```
+    #[must_use]
+    pub fn graph_profiling(&self) -> bool {
+        self.graph_profiling
+    }
+
+    /// Record how long each operation of the compute graph takes, see
+    /// [`crate::context::LlamaContext::graph_profile`]. This slows down decoding, see
+    /// [`crate::context::profile`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::LlamaContextParams;
+    /// let params = LlamaContextParams::default()
+    ///    .with_graph_profiling(true);
+    /// assert!(params.graph_profiling());
+    /// ```
+    #[must_use]
+    pub fn with_graph_profiling(mut self, graph_profiling: bool) -> Self {
+        self.graph_profiling = graph_profiling;
+        self
+    }
+
+    /// Set the type of pooling used to compute sequence embeddings.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default()
+    ///     .with_pooling_type(LlamaPoolingType::Mean);
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Mean);
+    /// ```
+    #[must_use]
+    pub fn with_pooling_type(mut self, pooling_type: LlamaPoolingType) -> Self {
+        self.context_params.pooling_type = i32::from(pooling_type);
+        self
+    }
+
+    /// Get the type of pooling used to compute sequence embeddings.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// let params = LlamaContextParams::default();
+    /// assert_eq!(params.pooling_type(), LlamaPoolingType::Unspecified);
+    /// ```
+    #[must_use]
+    pub fn pooling_type(&self) -> LlamaPoolingType {
+        LlamaPoolingType::from(self.context_params.pooling_type)
+    }
 }
 
 /// Default parameters for `LlamaContext`. (as defined in llama.cpp by `llama_context_default_params`)
@@ -354,6 +716,91 @@ impl LlamaContextParams {
 impl Default for LlamaContextParams {
     fn default() -> Self {
         let context_params = unsafe { llama_cpp_sys_2::llama_context_default_params() };
-        Self { context_params }
+        Self {
+            context_params,
+            graph_profiling: false,
+        }
+    }
+}
+
+/// The (de)serialized form of [`LlamaContextParams`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)]
+struct SerializedContextParams {
+    seed: u32,
+    n_ctx: Option<NonZeroU32>,
+    n_batch: NonZeroU32,
+    n_seq_max: NonZeroU32,
+    rope_scaling_type: RopeScalingType,
+    rope_freq_base: f32,
+    rope_freq_scale: f32,
+    n_threads: NonZeroU32,
+    n_threads_batch: NonZeroU32,
+    embeddings: bool,
+    graph_profiling: bool,
+    pooling_type: LlamaPoolingType,
+}
+
+#[cfg(feature = "serde")]
+impl Default for SerializedContextParams {
+    fn default() -> Self {
+        Self::from(&LlamaContextParams::default())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<&LlamaContextParams> for SerializedContextParams {
+    fn from(params: &LlamaContextParams) -> Self {
+        // only ever set from `NonZeroU32`s or llama.cpp's (non-zero) defaults
+        let non_zero = |value| NonZeroU32::new(value).unwrap_or(NonZeroU32::MIN);
+        Self {
+            seed: params.seed(),
+            n_ctx: params.n_ctx(),
+            n_batch: non_zero(params.n_batch()),
+            n_seq_max: non_zero(params.n_seq_max()),
+            rope_scaling_type: params.rope_scaling_type(),
+            rope_freq_base: params.rope_freq_base(),
+            rope_freq_scale: params.rope_freq_scale(),
+            n_threads: non_zero(params.n_threads()),
+            n_threads_batch: non_zero(params.n_threads_batch()),
+            embeddings: params.embeddings(),
+            graph_profiling: params.graph_profiling(),
+            pooling_type: params.pooling_type(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<SerializedContextParams> for LlamaContextParams {
+    fn from(params: SerializedContextParams) -> Self {
+        Self::default()
+            .with_seed(params.seed)
+            .with_n_ctx(params.n_ctx)
+            .with_n_batch(params.n_batch)
+            .with_n_seq_max(params.n_seq_max)
+            .with_rope_scaling_type(params.rope_scaling_type)
+            .with_rope_freq_base(params.rope_freq_base)
+            .with_rope_freq_scale(params.rope_freq_scale)
+            .with_n_threads(params.n_threads)
+            .with_n_threads_batch(params.n_threads_batch)
+            .with_embeddings(params.embeddings)
+            .with_graph_profiling(params.graph_profiling)
+            .with_pooling_type(params.pooling_type)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for LlamaContextParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedContextParams::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for LlamaContextParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        SerializedContextParams::deserialize(deserializer).map(Self::from)
     }
 }
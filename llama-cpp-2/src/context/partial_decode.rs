@@ -0,0 +1,246 @@
+//! Decoding as many sequences of a batch as fit, and reporting the others, for continuous batching
+//! servers that evict single requests instead of failing a whole batch.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::llama_batch::LlamaBatch;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! let mut batch = LlamaBatch::new(512, 1);
+//! for (seq_id, prompt) in (0..).zip(["first request", "second request"]) {
+//!     batch.add_sequence(&model.str_to_token(prompt, AddBos::Always)?, seq_id, false)?;
+//! }
+//! let report = ctx.decode_sequences(&mut batch)?;
+//! for failed in &report.failed {
+//!     eprintln!("evicting request {}: {:?}", failed.seq_id, failed.reason);
+//!     ctx.clear_kv_cache_seq(failed.seq_id, None, None);
+//! }
+//! // `batch` now only holds the tokens of `report.succeeded`
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::llama_batch::LlamaBatch;
+use crate::DecodeError;
+
+/// Why a sequence was not decoded by [`LlamaContext::decode_sequences`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceFailureReason {
+    /// A token of the sequence has a position past the end of the context.
+    ExceedsContext {
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// The KV cache has no room for the tokens of the sequence next to those of the sequences
+    /// before it in the batch.
+    NoKvCacheSlot,
+}
+
+/// A sequence that was not decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedSequence {
+    /// The sequence id.
+    pub seq_id: i32,
+    /// Why it was not decoded.
+    pub reason: SequenceFailureReason,
+}
+
+/// The outcome of [`LlamaContext::decode_sequences`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SequenceDecodeReport {
+    /// The sequences that were decoded, in the order they first appear in the batch.
+    pub succeeded: Vec<i32>,
+    /// The sequences that were not decoded, with nothing of them added to the KV cache.
+    pub failed: Vec<FailedSequence>,
+}
+
+impl SequenceDecodeReport {
+    /// Whether every sequence was decoded.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Decode the sequences of `batch` that fit, and report the others instead of failing.
+    ///
+    /// Sequences with a token past the end of the context fail with
+    /// [`SequenceFailureReason::ExceedsContext`]. If the KV cache has no room for the rest, the
+    /// largest number of them that fits is decoded, keeping the sequences that appear first in the
+    /// batch, and the later ones fail with [`SequenceFailureReason::NoKvCacheSlot`]. Finding that
+    /// number takes a few extra decodes, which are rolled back by removing the cache from the
+    /// first position of each sequence in the batch on, so the tokens of each sequence should
+    /// follow its cache. Sequences sharing a token succeed or fail together.
+    ///
+    /// When a sequence fails, `batch` is replaced by the tokens of the decoded sequences, in their
+    /// original order, and the logits are read with the indices of the new batch.
+    ///
+    /// # Errors
+    ///
+    /// - decoding failed for a reason that does not depend on the sequences, such as a batch with
+    ///   more than [`LlamaContext::n_batch`] tokens. The KV cache may contain part of the batch.
+    ///
+    /// # Panics
+    ///
+    /// See [`LlamaContext::decode`].
+    pub fn decode_sequences(
+        &mut self,
+        batch: &mut LlamaBatch,
+    ) -> Result<SequenceDecodeReport, DecodeError> {
+        let n_tokens = usize::try_from(batch.n_tokens()).unwrap_or(0);
+        let n_ctx = self.n_ctx();
+        // groups of sequences sharing tokens, in the order they first appear
+        let mut groups: Vec<Vec<i32>> = Vec::new();
+        let mut exceeding = Vec::new();
+        for i in 0..n_tokens {
+            let (pos, seq_ids) = batch.token_pos_seq_ids(i);
+            let mut merged: Option<usize> = None;
+            for &seq_id in seq_ids {
+                let found = groups.iter().position(|group| group.contains(&seq_id));
+                match (found, merged) {
+                    (None, None) => {
+                        groups.push(vec![seq_id]);
+                        merged = Some(groups.len() - 1);
+                    }
+                    (None, Some(into)) => groups[into].push(seq_id),
+                    (Some(found), None) => merged = Some(found),
+                    (Some(found), Some(into)) if found != into => {
+                        let (keep, other) = (found.min(into), found.max(into));
+                        let other = groups.remove(other);
+                        groups[keep].extend(other);
+                        merged = Some(keep);
+                    }
+                    (Some(_), Some(_)) => {}
+                }
+            }
+            if u32::try_from(pos).map_or(true, |pos| pos >= n_ctx) {
+                exceeding.extend_from_slice(seq_ids);
+            }
+        }
+
+        let mut report = SequenceDecodeReport::default();
+        let mut candidates = Vec::new();
+        for group in groups {
+            if group.iter().any(|seq_id| exceeding.contains(seq_id)) {
+                report
+                    .failed
+                    .extend(group.into_iter().map(|seq_id| FailedSequence {
+                        seq_id,
+                        reason: SequenceFailureReason::ExceedsContext { n_ctx },
+                    }));
+            } else {
+                candidates.push(group);
+            }
+        }
+
+        // the largest number of groups known to fit, and the smallest known not to
+        let (mut fit, mut no_fit) = (0, candidates.len() + 1);
+        if report.failed.is_empty() {
+            match self.decode(batch) {
+                Err(DecodeError::NoKvCacheSlot { .. }) => {
+                    self.roll_back(batch, &candidates);
+                    no_fit = candidates.len();
+                }
+                result => {
+                    result?;
+                    report.succeeded = candidates.concat();
+                    return Ok(report);
+                }
+            }
+        }
+        // the number of groups in the cache
+        let mut decoded = 0;
+        let mut scratch = batch.empty_like();
+        while no_fit - fit > 1 {
+            let n = fit + (no_fit - fit) / 2;
+            if decoded > 0 {
+                self.roll_back(&scratch, &candidates[..decoded]);
+            }
+            if self.decode_groups(batch, &mut scratch, &candidates[..n])? {
+                (fit, decoded) = (n, n);
+            } else {
+                (no_fit, decoded) = (n, 0);
+            }
+        }
+        if decoded != fit {
+            // the last attempt did not fit, decode the largest number that does again
+            decoded = if self.decode_groups(batch, &mut scratch, &candidates[..fit])? {
+                fit
+            } else {
+                0
+            };
+        }
+        if decoded == 0 {
+            scratch.clear();
+        }
+
+        report.succeeded = candidates[..decoded].concat();
+        report
+            .failed
+            .extend(
+                candidates[decoded..]
+                    .iter()
+                    .flatten()
+                    .map(|&seq_id| FailedSequence {
+                        seq_id,
+                        reason: SequenceFailureReason::NoKvCacheSlot,
+                    }),
+            );
+        std::mem::swap(batch, &mut scratch);
+        Ok(report)
+    }
+
+    /// Copy the tokens of `groups` from `batch` to `scratch` and decode them. Returns `false` if
+    /// they do not fit, with the cache rolled back.
+    fn decode_groups(
+        &mut self,
+        batch: &LlamaBatch,
+        scratch: &mut LlamaBatch,
+        groups: &[Vec<i32>],
+    ) -> Result<bool, DecodeError> {
+        if groups.is_empty() {
+            return Ok(true);
+        }
+        let kept = groups.concat();
+        batch
+            .copy_retained(scratch, |seq_ids| {
+                seq_ids.iter().all(|seq_id| kept.contains(seq_id))
+            })
+            .expect("the scratch batch has room for the tokens of the batch");
+        match self.decode(scratch) {
+            Err(DecodeError::NoKvCacheSlot { .. }) => {
+                self.roll_back(scratch, groups);
+                Ok(false)
+            }
+            result => result.map(|()| true),
+        }
+    }
+
+    /// Remove the cache of the sequences in `groups` from their first position in `batch` on.
+    fn roll_back(&mut self, batch: &LlamaBatch, groups: &[Vec<i32>]) {
+        let mut first_pos: Vec<(i32, i32)> = Vec::new();
+        for i in 0..usize::try_from(batch.n_tokens()).unwrap_or(0) {
+            let (pos, seq_ids) = batch.token_pos_seq_ids(i);
+            for &seq_id in seq_ids {
+                match first_pos.iter_mut().find(|(id, _)| *id == seq_id) {
+                    Some((_, first)) => *first = (*first).min(pos),
+                    None => first_pos.push((seq_id, pos)),
+                }
+            }
+        }
+        for (seq_id, pos) in first_pos {
+            if groups.iter().any(|group| group.contains(&seq_id)) {
+                unsafe {
+                    llama_cpp_sys_2::llama_kv_cache_seq_rm(self.context.as_ptr(), seq_id, pos, -1);
+                }
+            }
+        }
+    }
+}
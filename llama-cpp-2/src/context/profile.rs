@@ -0,0 +1,171 @@
+//! Per-operation timings of the compute graph, see
+//! [`crate::context::params::LlamaContextParams::with_graph_profiling`].
+//!
+//! ggml evaluates a decode as a graph of tensor operations. With graph profiling enabled llama.cpp
+//! reports every node of the graph to an eval callback right after computing it, and the time it
+//! took is recorded in a [`GraphProfile`]. This shows which layers and operations dominate the
+//! latency on a given machine.
+//!
+//! Observing every node keeps ggml from handing several nodes to a backend at once, so decoding
+//! is slower (especially on GPUs) while profiling.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::ptr::NonNull;
+use std::time::{Duration, Instant};
+
+use crate::context::LlamaContext;
+
+/// The time it took to compute a node of the graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphNodeTiming {
+    /// The name of the tensor, e.g. `attn_norm-0` for the attention norm of the first layer.
+    pub name: String,
+    /// The operation computing the tensor, e.g. `MUL_MAT` or `SOFT_MAX`.
+    pub op: String,
+    /// How long the node took, including copying its inputs between backends.
+    pub duration: Duration,
+}
+
+/// The timings of the nodes of the graph of the last decode, see [`LlamaContext::graph_profile`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphProfile {
+    nodes: Vec<GraphNodeTiming>,
+    /// When the node that is being computed was announced.
+    started: Option<Instant>,
+}
+
+impl GraphProfile {
+    /// The nodes in the order they were computed. Graphs of batches larger than
+    /// [`LlamaContext::n_ubatch`] are computed once per micro-batch.
+    #[must_use]
+    pub fn nodes(&self) -> &[GraphNodeTiming] {
+        &self.nodes
+    }
+
+    /// The total time spent computing nodes.
+    #[must_use]
+    pub fn total(&self) -> Duration {
+        self.nodes.iter().map(|node| node.duration).sum()
+    }
+
+    /// The time spent in each operation, slowest first.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let params = LlamaContextParams::default().with_graph_profiling(true);
+    /// let mut ctx = model.new_context(&backend, params)?;
+    /// let mut batch = LlamaBatch::new(512, 1);
+    /// batch.add_sequence(&model.str_to_token("Hello, World!", AddBos::Always)?, 0, false)?;
+    /// ctx.decode(&mut batch)?;
+    ///
+    /// let profile = ctx.graph_profile().expect("graph profiling is enabled");
+    /// for (op, duration) in profile.by_op().iter().take(5) {
+    ///     println!("{op:>12} {duration:?}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn by_op(&self) -> Vec<(String, Duration)> {
+        let mut by_op = HashMap::<&str, Duration>::new();
+        for node in &self.nodes {
+            *by_op.entry(&node.op).or_default() += node.duration;
+        }
+        let mut by_op: Vec<_> = by_op
+            .into_iter()
+            .map(|(op, duration)| (op.to_string(), duration))
+            .collect();
+        by_op.sort_by(|(a_op, a), (b_op, b)| b.cmp(a).then_with(|| a_op.cmp(b_op)));
+        by_op
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.started = None;
+    }
+}
+
+/// Owns the [`GraphProfile`] the eval callback of a context writes to.
+#[derive(Debug)]
+pub(crate) struct GraphProfiler(NonNull<GraphProfile>);
+
+impl GraphProfiler {
+    /// Create a profile and point the eval callback of `params` to it.
+    pub(crate) fn install(params: &mut llama_cpp_sys_2::llama_context_params) -> Self {
+        let profile = NonNull::from(Box::leak(Box::<GraphProfile>::default()));
+        params.cb_eval = Some(eval_callback);
+        params.cb_eval_user_data = profile.as_ptr().cast();
+        Self(profile)
+    }
+
+    fn get(&self) -> &GraphProfile {
+        // SAFETY: the callback only writes to the profile during a decode, which takes the
+        // context (and with it this profiler) mutably.
+        unsafe { self.0.as_ref() }
+    }
+
+    fn get_mut(&mut self) -> &mut GraphProfile {
+        // SAFETY: see `get`
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl Drop for GraphProfiler {
+    fn drop(&mut self) {
+        // SAFETY: created from a box in `install`, and the context using it is freed before its
+        // fields are dropped.
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+/// Asked about every node before it is computed (`ask`), and passed every node after.
+unsafe extern "C" fn eval_callback(
+    tensor: *mut llama_cpp_sys_2::ggml_tensor,
+    ask: bool,
+    user_data: *mut std::os::raw::c_void,
+) -> bool {
+    let profile = &mut *user_data.cast::<GraphProfile>();
+    if ask {
+        profile.started = Some(Instant::now());
+        return true;
+    }
+    let Some(started) = profile.started.take() else {
+        return true;
+    };
+    let name = CStr::from_ptr((*tensor).name.as_ptr())
+        .to_string_lossy()
+        .into_owned();
+    let op = CStr::from_ptr(llama_cpp_sys_2::ggml_op_desc(tensor))
+        .to_string_lossy()
+        .into_owned();
+    profile.nodes.push(GraphNodeTiming {
+        name,
+        op,
+        duration: started.elapsed(),
+    });
+    true
+}
+
+impl LlamaContext<'_> {
+    /// The per-operation timings of the last decode, `None` unless the context was created with
+    /// [`crate::context::params::LlamaContextParams::with_graph_profiling`].
+    #[must_use]
+    pub fn graph_profile(&self) -> Option<&GraphProfile> {
+        self.graph_profiler.as_ref().map(GraphProfiler::get)
+    }
+
+    /// Forget the timings of the last decode before starting the next one.
+    pub(crate) fn clear_graph_profile(&mut self) {
+        if let Some(profiler) = &mut self.graph_profiler {
+            profiler.get_mut().clear();
+        }
+    }
+}
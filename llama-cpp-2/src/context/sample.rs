@@ -10,16 +10,41 @@ pub mod sampler;
 
 impl LlamaContext<'_> {
     /// Accept a token into the grammar.
+    ///
+    /// # Panics
+    ///
+    /// - the grammar does not allow `token`. See [`LlamaGrammar::accept_token`] to handle this as
+    ///   an error.
     pub fn grammar_accept_token(&mut self, grammar: &mut LlamaGrammar, token: LlamaToken) {
-        unsafe {
-            llama_cpp_sys_2::llama_grammar_accept_token(
-                self.context.as_ptr(),
-                grammar.grammar.as_ptr(),
-                token.0,
-            );
+        if let Err(error) = grammar.accept_token(self, token) {
+            panic!("{error}");
         }
     }
 
+    /// Reseed the random number generator used by [`LlamaTokenDataArray::sample_token`] and the
+    /// other random samplers. Reseeding between requests makes each of them reproducible, no
+    /// matter what was sampled before.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// for (seed, prompt) in [(1, "Once upon a time"), (2, "In a galaxy far away")] {
+    ///     ctx.set_rng_seed(seed);
+    ///     // ... sample a completion of `prompt`
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_rng_seed(&mut self, seed: u32) {
+        unsafe { llama_cpp_sys_2::llama_set_rng_seed(self.context.as_ptr(), seed) }
+    }
+
     /// Perform grammar sampling.
     pub fn sample_grammar(
         &mut self,
@@ -46,10 +71,16 @@ impl LlamaContext<'_> {
     ///
     /// Most of the time [`LlamaTokenDataArray::sample_softmax`] or [`LlamaTokenDataArray::sample_token`] should be used instead.
     ///
+    /// The buffer of `token_data` is kept for the next [`LlamaContext::token_data_array_ith`].
+    ///
     /// # Panics
     ///
     /// - if `token_data` is empty
     #[must_use]
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(level = "debug", skip_all, fields(n_candidates = token_data.data.len()))
+    )]
     pub fn sample_token_greedy(&mut self, mut token_data: LlamaTokenDataArray) -> LlamaToken {
         assert!(!token_data.data.is_empty(), "no tokens");
         let mut data_arr = llama_cpp_sys_2::llama_token_data_array {
@@ -66,6 +97,49 @@ impl LlamaContext<'_> {
                 std::ptr::addr_of_mut!(data_arr),
             )
         };
+        self.recycle_token_data_array(token_data);
+        LlamaToken(token)
+    }
+
+    /// Sample the token with the highest logit for the `i`th token of the last batch, straight from
+    /// the logits without building an array of candidates.
+    ///
+    /// This is the same token [`LlamaContext::sample_token_greedy`] returns for unmodified
+    /// candidates (the first one on ties), but it is not counted in [`LlamaContext::timings`].
+    ///
+    /// # Panics
+    ///
+    /// - logit `i` is not initialized.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let mut batch = LlamaBatch::new(512, 1);
+    /// # batch.add_sequence(&model.str_to_token("Hello", AddBos::Always)?, 0, false)?;
+    /// ctx.decode(&mut batch)?;
+    /// let token = ctx.sample_token_greedy_ith(batch.n_tokens() - 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn sample_token_greedy_ith(&self, i: i32) -> LlamaToken {
+        let (token, _) = (0_i32..).zip(self.get_logits_ith(i)).fold(
+            (0, f32::NEG_INFINITY),
+            |(best, best_logit), (token, &logit)| {
+                if logit > best_logit {
+                    (token, logit)
+                } else {
+                    (best, best_logit)
+                }
+            },
+        );
         LlamaToken(token)
     }
 
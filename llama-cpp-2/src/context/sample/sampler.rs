@@ -45,6 +45,7 @@
 use crate::token::data::LlamaTokenData;
 use crate::token::data_array::LlamaTokenDataArray;
 use std::fmt::{Debug, Formatter};
+use std::time::{Duration, Instant};
 
 /// A single step to sample tokens from the remaining candidates.
 pub type SampleStep<C> = dyn Fn(&mut LlamaTokenDataArray, &mut C);
@@ -58,6 +59,19 @@ pub type SampleFinalizer<C> = dyn Fn(LlamaTokenDataArray, &mut C) -> Vec<LlamaTo
 /// require state to be maintained across multiple samples, and this context can be used to store
 /// that state. For example, [`LlamaTokenDataArray::sample_token_mirostat_v2`] requires a `mu` to be
 /// shared across multiple samples.
+///
+/// # Thread safety
+///
+/// A sampler is neither [`Send`] nor [`Sync`], as [`SampleStep`] and [`SampleFinalizer`] accept
+/// any closure, including ones capturing thread bound state such as an `Rc`. Build a sampler on
+/// the thread that samples with it. [`crate::generation::sampling::ParamsSampler`] can be moved
+/// between threads instead.
+///
+/// ```compile_fail
+/// # use llama_cpp_2::context::sample::sampler::Sampler;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<Sampler<'static, ()>>();
+/// ```
 pub struct Sampler<'a, C> {
     /// The steps to take when sampling.
     pub steps: Vec<&'a SampleStep<C>>,
@@ -65,6 +79,36 @@ pub struct Sampler<'a, C> {
     pub finalizer: &'a SampleFinalizer<C>,
 }
 
+/// Time spent in each stage of a [`Sampler`], see [`ProfiledSampler`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SamplerProfile {
+    /// The total time spent in each step, in the order they were pushed.
+    pub steps: Vec<Duration>,
+    /// The total time spent in the finalizer.
+    pub finalizer: Duration,
+    /// The number of samples the times were collected over.
+    pub n_samples: u32,
+}
+
+impl SamplerProfile {
+    /// The average time per sample spent in each step, in the order they were pushed.
+    #[must_use]
+    pub fn mean_steps(&self) -> Vec<Duration> {
+        self.steps
+            .iter()
+            .map(|total| total.checked_div(self.n_samples).unwrap_or_default())
+            .collect()
+    }
+
+    /// The average time per sample spent in the finalizer.
+    #[must_use]
+    pub fn mean_finalizer(&self) -> Duration {
+        self.finalizer
+            .checked_div(self.n_samples)
+            .unwrap_or_default()
+    }
+}
+
 impl<T> Debug for Sampler<'_, T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Sampler")
@@ -83,6 +127,34 @@ impl<T> Debug for Sampler<'_, T> {
     }
 }
 
+/// Cloning a sampler shares its steps and finalizer, so a configured sampler can be duplicated for
+/// each slot of a server instead of being rebuilt for every request. The state used by the steps
+/// lives in the `C` passed to [`Sampler::sample`], so give each clone its own.
+///
+/// ```rust
+/// # use llama_cpp_2::context::sample::sampler::Sampler;
+/// # use llama_cpp_2::token::data::LlamaTokenData;
+/// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+/// # use llama_cpp_2::token::LlamaToken;
+/// let finalizer = &|mut candidates: LlamaTokenDataArray, _: &mut ()| {
+///     candidates.sample_softmax(None);
+///     vec![candidates.data[0]]
+/// };
+/// let mut sampler = Sampler::new(finalizer);
+/// sampler.push_step(&|c, _| c.sample_top_k(None, 40, 1));
+///
+/// let slots = vec![sampler; 4];
+/// assert!(slots.iter().all(|slot| slot.steps.len() == 1));
+/// ```
+impl<C> Clone for Sampler<'_, C> {
+    fn clone(&self) -> Self {
+        Self {
+            steps: self.steps.clone(),
+            finalizer: self.finalizer,
+        }
+    }
+}
+
 impl<'a, T> Sampler<'a, T> {
     /// Create a new sampler with a given finalizer.
     pub fn new(finalizer: &'a SampleFinalizer<T>) -> Self {
@@ -110,3 +182,103 @@ impl<'a, T> Sampler<'a, T> {
         (self.finalizer)(candidates, context)
     }
 }
+
+/// A [`Sampler`] that measures the time spent in each step and the finalizer.
+///
+/// ```rust
+/// # use llama_cpp_2::context::sample::sampler::{ProfiledSampler, Sampler};
+/// # use llama_cpp_2::token::data::LlamaTokenData;
+/// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+/// # use llama_cpp_2::token::LlamaToken;
+/// let finalizer = &|mut candidates: LlamaTokenDataArray, _: &mut ()| {
+///     candidates.sample_softmax(None);
+///     vec![candidates.data[0]]
+/// };
+/// let mut sampler = Sampler::new(finalizer);
+/// sampler.push_step(&|c, _| c.sample_top_k(None, 40, 1));
+/// sampler.push_step(&|c, _| c.sample_temp(None, 0.5));
+/// let mut sampler = ProfiledSampler::new(sampler);
+///
+/// let candidates = LlamaTokenDataArray::from_iter((0..4).map(|i| LlamaTokenData::new(LlamaToken::new(i), i as f32, 0.0)), false);
+/// for _ in 0..3 {
+///     let _ = sampler.sample(&mut (), candidates.clone());
+/// }
+///
+/// assert_eq!(sampler.profile().n_samples, 3);
+/// assert_eq!(sampler.profile().steps.len(), 2);
+/// ```
+pub struct ProfiledSampler<'a, C> {
+    /// The sampler being measured.
+    pub sampler: Sampler<'a, C>,
+    profile: SamplerProfile,
+}
+
+impl<T> Debug for ProfiledSampler<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProfiledSampler")
+            .field("sampler", &self.sampler)
+            .field("profile", &self.profile)
+            .finish()
+    }
+}
+
+impl<C> Clone for ProfiledSampler<'_, C> {
+    fn clone(&self) -> Self {
+        Self {
+            sampler: self.sampler.clone(),
+            profile: self.profile.clone(),
+        }
+    }
+}
+
+impl<'a, T> ProfiledSampler<'a, T> {
+    /// Start measuring `sampler`.
+    #[must_use]
+    pub fn new(sampler: Sampler<'a, T>) -> Self {
+        Self {
+            sampler,
+            profile: SamplerProfile::default(),
+        }
+    }
+
+    /// The time spent in each stage so far.
+    #[must_use]
+    pub fn profile(&self) -> &SamplerProfile {
+        &self.profile
+    }
+
+    /// Discard the times collected so far.
+    pub fn reset(&mut self) {
+        self.profile = SamplerProfile::default();
+    }
+
+    /// Stop measuring, returning the sampler.
+    #[must_use]
+    pub fn into_inner(self) -> Sampler<'a, T> {
+        self.sampler
+    }
+
+    /// Sample a token from the given candidates like [`Sampler::sample`], adding the time spent in
+    /// each stage to the profile.
+    #[must_use]
+    pub fn sample(
+        &mut self,
+        context: &mut T,
+        mut candidates: LlamaTokenDataArray,
+    ) -> Vec<LlamaTokenData> {
+        let profile = &mut self.profile;
+        profile
+            .steps
+            .resize(self.sampler.steps.len(), Duration::ZERO);
+        for (step, total) in self.sampler.steps.iter().zip(&mut profile.steps) {
+            let start = Instant::now();
+            step(&mut candidates, context);
+            *total += start.elapsed();
+        }
+        let start = Instant::now();
+        let tokens = (self.sampler.finalizer)(candidates, context);
+        profile.finalizer += start.elapsed();
+        profile.n_samples += 1;
+        tokens
+    }
+}
@@ -2,9 +2,12 @@
 
 use crate::context::LlamaContext;
 use crate::token::LlamaToken;
+use kv_state::{KvLayout, KvState};
 use std::ffi::{CString, NulError};
 use std::path::{Path, PathBuf};
 
+mod kv_state;
+
 /// Failed to save a Session file
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum SaveSessionError {
@@ -46,7 +49,251 @@ pub enum LoadSessionError {
     },
 }
 
+/// Failed to copy state between contexts, see [`LlamaContext::copy_sequence_to`].
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum StateTransferError {
+    /// The contexts were created from different models.
+    #[error("the contexts belong to different models")]
+    DifferentModels,
+    /// The contexts have KV caches of different sizes.
+    #[error("the KV caches hold {from} and {to} tokens")]
+    KvCacheSize {
+        /// The [`LlamaContext::n_ctx`] of the source.
+        from: u32,
+        /// The [`LlamaContext::n_ctx`] of the target.
+        to: u32,
+    },
+    /// The contexts differ in the types of their KV caches, whether they compute embeddings or in
+    /// the number of outputs they hold.
+    #[error("the contexts were created with incompatible parameters")]
+    IncompatibleParams,
+    /// The target has not enough free cells in its KV cache for the sequence.
+    #[error("the sequence needs {n_cells} cells of the KV cache but only {n_free} are free")]
+    KvCacheFull {
+        /// The cells the sequence takes.
+        n_cells: usize,
+        /// The free cells of the target.
+        n_free: usize,
+    },
+    /// A single sequence can not be copied from the KV cache of this model, either because the
+    /// model is recurrent or because the V values are quantized.
+    #[error("sequences can not be copied out of this KV cache")]
+    UnsupportedCache,
+}
+
+/// A snapshot of the state of a [`LlamaContext`], see [`LlamaContext::save_state`].
+#[derive(Debug, Clone)]
+pub struct LlamaState {
+    data: Vec<u8>,
+    layout: StateLayout,
+    initialized_logits: Vec<i32>,
+    decoded_seq_ids: Vec<i32>,
+}
+
+impl LlamaState {
+    /// The size of the snapshot in bytes.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the snapshot holds no data.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// The sizes llama.cpp expects a restored state to have, which it does not check itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StateLayout {
+    n_vocab: i32,
+    n_embd: i32,
+    n_ctx: u32,
+    kv_cache_types: (llama_cpp_sys_2::ggml_type, llama_cpp_sys_2::ggml_type),
+    embeddings_enabled: bool,
+    max_size: usize,
+}
+
 impl LlamaContext<'_> {
+    /// Copy the KV cache of sequence `seq_id` to sequence `dest_seq_id` of `target`, e.g. to move
+    /// a session from a prefill context to the member of a pool of contexts that generates it.
+    ///
+    /// Only `seq_id` is copied. It replaces sequence `dest_seq_id` of `target` and takes its free
+    /// cells, so the contexts may have a different [`LlamaContext::n_ctx`]. The other sequences,
+    /// the logits and the random number generator of `target` are kept, so decode the last token
+    /// of the sequence in `target` to sample from it.
+    ///
+    /// This version of llama.cpp only copies the state as a whole, so both states are copied out
+    /// and the sequence is merged into the state of `target`, which takes time and memory in the
+    /// size of both KV caches.
+    ///
+    /// # Errors
+    ///
+    /// - the contexts belong to different models or were created with different KV cache types.
+    /// - the model is recurrent or the V values of the KV cache are quantized.
+    /// - `target` has not enough free cells for the sequence.
+    ///
+    /// Nothing is copied then.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # use std::num::NonZeroU32;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let prefill_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(512));
+    /// let mut prefill = model.new_context(&backend, prefill_params)?;
+    /// let worker_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(4096));
+    /// let mut worker = model.new_context(&backend, worker_params)?;
+    ///
+    /// let tokens = model.str_to_token("Hello", AddBos::Always)?;
+    /// let (prompt, last) = tokens.split_at(tokens.len() - 1);
+    /// let mut batch = LlamaBatch::new(512, 1);
+    /// batch.add_sequence(prompt, 0, false)?;
+    /// prefill.decode(&mut batch)?;
+    ///
+    /// prefill.copy_sequence_to(0, &mut worker, 1)?;
+    /// batch.clear();
+    /// batch.add(last[0], i32::try_from(prompt.len())?, &[1], true)?;
+    /// worker.decode(&mut batch)?;
+    /// let candidates = worker.token_data_array_ith(0);
+    /// let next = worker.sample_token_greedy(candidates);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn copy_sequence_to(
+        &self,
+        seq_id: i32,
+        target: &mut LlamaContext,
+        dest_seq_id: i32,
+    ) -> Result<(), StateTransferError> {
+        if self.model.model != target.model.model {
+            return Err(StateTransferError::DifferentModels);
+        }
+        if self.kv_cache_types != target.kv_cache_types {
+            return Err(StateTransferError::IncompatibleParams);
+        }
+        let (type_k, type_v) = self.kv_cache_types;
+        // V is transposed, so quantized values of a cell share blocks with other cells
+        if self.model.is_recurrent() || unsafe { llama_cpp_sys_2::ggml_blck_size(type_v) } != 1 {
+            return Err(StateTransferError::UnsupportedCache);
+        }
+        let (n_embd_k, n_embd_v, _) = self.kv_cache_dims();
+        let layout = KvLayout {
+            n_layer: usize::try_from(self.model.n_layer()).unwrap_or(0),
+            k_cell_bytes: unsafe { llama_cpp_sys_2::ggml_row_size(type_k, n_embd_k) },
+            n_embd_v: usize::try_from(n_embd_v).unwrap_or(0),
+            v_value_bytes: unsafe { llama_cpp_sys_2::ggml_type_size(type_v) },
+        };
+        let source = self.save_state();
+        let own = target.save_state();
+        let data = KvState::parse(&own.data, layout)?.with_sequence(
+            layout,
+            &KvState::parse(&source.data, layout)?,
+            seq_id,
+            dest_seq_id,
+        )?;
+        // llama.cpp adds the restored sequences to the cells instead of replacing them
+        target.clear_kv_cache();
+        // SAFETY: the state has the layout of the state of `target`
+        unsafe {
+            target.set_state_data(&data);
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of the state: the KV cache of every sequence, the logits and embeddings of
+    /// the last decode and the random number generator.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let mut batch = LlamaBatch::new(512, 1);
+    /// batch.add_sequence(&model.str_to_token("A long system prompt", AddBos::Always)?, 0, true)?;
+    /// ctx.decode(&mut batch)?;
+    /// let prompt = ctx.save_state();
+    ///
+    /// // ... answer a question, then start over from the prompt
+    /// ctx.restore_state(&prompt)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn save_state(&self) -> LlamaState {
+        let mut data = vec![0; self.get_state_size()];
+        // SAFETY: the buffer holds the maximum size of the state
+        let len = unsafe { self.copy_state_data(data.as_mut_ptr()) };
+        data.truncate(len);
+        LlamaState {
+            data,
+            layout: self.state_layout(),
+            initialized_logits: self.initialized_logits.clone(),
+            decoded_seq_ids: self.decoded_seq_ids.clone(),
+        }
+    }
+
+    /// Restore a snapshot taken with [`LlamaContext::save_state`], replacing the whole state of
+    /// this context.
+    ///
+    /// This version of llama.cpp can only restore the state into a context with the same
+    /// [`LlamaContext::n_ctx`] and parameters as the one it was taken from. To keep a state
+    /// between runs of a program, see [`LlamaContext::save_session_file`].
+    ///
+    /// # Errors
+    ///
+    /// - the snapshot was taken from a context of a model of a different size or with different
+    ///   parameters. Nothing is restored then.
+    pub fn restore_state(&mut self, state: &LlamaState) -> Result<(), StateTransferError> {
+        let layout = self.state_layout();
+        if (state.layout.n_vocab, state.layout.n_embd) != (layout.n_vocab, layout.n_embd) {
+            return Err(StateTransferError::DifferentModels);
+        }
+        if state.layout.n_ctx != layout.n_ctx {
+            return Err(StateTransferError::KvCacheSize {
+                from: state.layout.n_ctx,
+                to: layout.n_ctx,
+            });
+        }
+        // llama.cpp aborts instead of failing if the caches or outputs do not match
+        if state.layout != layout {
+            return Err(StateTransferError::IncompatibleParams);
+        }
+        // llama.cpp adds the restored sequences to the cells instead of replacing them
+        self.clear_kv_cache();
+        // SAFETY: the state was copied from a context with the same model, cache and output sizes
+        unsafe {
+            self.set_state_data(&state.data);
+        }
+        self.initialized_logits
+            .clone_from(&state.initialized_logits);
+        self.decoded_seq_ids.clone_from(&state.decoded_seq_ids);
+        Ok(())
+    }
+
+    /// What the layout of the state of this context depends on.
+    fn state_layout(&self) -> StateLayout {
+        StateLayout {
+            n_vocab: self.model.n_vocab(),
+            n_embd: self.model.n_embd(),
+            n_ctx: self.n_ctx(),
+            kv_cache_types: self.kv_cache_types,
+            embeddings_enabled: self.embeddings_enabled,
+            max_size: self.get_state_size(),
+        }
+    }
+
     /// Save the current session to a file.
     ///
     /// # Parameters
@@ -128,6 +375,8 @@ impl LlamaContext<'_> {
             unsafe {
                 tokens.set_len(n_out);
             }
+            #[cfg(feature = "metrics")]
+            crate::metrics::session_restored(n_out);
             Ok(tokens)
         } else {
             Err(LoadSessionError::FailedToLoad)
@@ -0,0 +1,228 @@
+//! Reading and writing the KV cache part of the state data of `llama_copy_state_data`, to move a
+//! single sequence between contexts. This version of llama.cpp only copies the state as a whole.
+//!
+//! The state is the random number generator, the logits and the embeddings, followed by the KV
+//! cache: its buffer size, the number of cells in use up to the last one (`kv_head`), the number of
+//! cells and the number of used cells, then for each layer the K values cell by cell and the V
+//! values row by row (V is transposed), and finally the position and the sequence ids of each
+//! cell up to `kv_head`.
+
+use super::StateTransferError;
+
+/// The sizes of the values of a KV cache in the state data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct KvLayout {
+    pub(super) n_layer: usize,
+    /// The bytes of the K values of one cell in a layer.
+    pub(super) k_cell_bytes: usize,
+    /// The number of V rows in a layer.
+    pub(super) n_embd_v: usize,
+    /// The bytes of one V value.
+    pub(super) v_value_bytes: usize,
+}
+
+/// A cell of the KV cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cell {
+    pos: i32,
+    seq_ids: Vec<i32>,
+}
+
+impl Cell {
+    const EMPTY: Self = Self {
+        pos: -1,
+        seq_ids: Vec::new(),
+    };
+
+    fn is_empty(&self) -> bool {
+        self.seq_ids.is_empty()
+    }
+}
+
+/// The KV cache of a state.
+#[derive(Debug)]
+pub(super) struct KvState<'a> {
+    /// The state before the KV cache: random number generator, logits and embeddings.
+    prefix: &'a [u8],
+    buf_size: usize,
+    /// The number of cells up to the last one in use.
+    kv_head: usize,
+    n_cells: usize,
+    /// The K and V values of each layer for the cells up to the last one in use.
+    layers: Vec<(&'a [u8], &'a [u8])>,
+    cells: Vec<Cell>,
+}
+
+impl<'a> KvState<'a> {
+    /// Parse the state data of a context whose cache has `layout`.
+    pub(super) fn parse(data: &'a [u8], layout: KvLayout) -> Result<Self, StateTransferError> {
+        let mut reader = Reader { data, pos: 0 };
+        let n_rng = reader.usize()?;
+        reader.take(n_rng)?;
+        for _ in 0..2 {
+            // logits and embeddings
+            let n_floats = reader.usize()?;
+            reader.take(n_floats * std::mem::size_of::<f32>())?;
+        }
+        let prefix = &data[..reader.pos];
+
+        let buf_size = reader.usize()?;
+        let kv_head = reader.u32()? as usize;
+        let n_cells = reader.u32()? as usize;
+        let _used = reader.u32()?;
+        let mut layers = Vec::new();
+        if buf_size > 0 {
+            let cell_bytes = layout.k_cell_bytes + layout.n_embd_v * layout.v_value_bytes;
+            // the buffers may be padded
+            if buf_size < layout.n_layer * n_cells * cell_bytes {
+                return Err(StateTransferError::IncompatibleParams);
+            }
+            for _ in 0..layout.n_layer {
+                let k = reader.take(kv_head * layout.k_cell_bytes)?;
+                let v = reader.take(layout.n_embd_v * kv_head * layout.v_value_bytes)?;
+                layers.push((k, v));
+            }
+        }
+        let mut cells = Vec::with_capacity(n_cells);
+        for _ in 0..kv_head {
+            let pos = reader.i32()?;
+            let n_seq_ids = reader.usize()?;
+            let seq_ids = (0..n_seq_ids)
+                .map(|_| reader.i32())
+                .collect::<Result<_, _>>()?;
+            cells.push(Cell { pos, seq_ids });
+        }
+        if reader.pos != data.len() {
+            return Err(StateTransferError::IncompatibleParams);
+        }
+        cells.resize(n_cells, Cell::EMPTY);
+        Ok(Self {
+            prefix,
+            buf_size,
+            kv_head,
+            n_cells,
+            layers,
+            cells,
+        })
+    }
+
+    /// The state data of `self` with sequence `dest_seq_id` replaced by the cells of `seq_id` in
+    /// `source`, which take the first free cells. The rest of `self` is unchanged.
+    pub(super) fn with_sequence(
+        &self,
+        layout: KvLayout,
+        source: &KvState,
+        seq_id: i32,
+        dest_seq_id: i32,
+    ) -> Result<Vec<u8>, StateTransferError> {
+        let mut cells = self.cells.clone();
+        for cell in &mut cells {
+            cell.seq_ids.retain(|&id| id != dest_seq_id);
+            if cell.is_empty() {
+                *cell = Cell::EMPTY;
+            }
+        }
+        let copied = (0..source.kv_head)
+            .filter(|&i| source.cells[i].seq_ids.contains(&seq_id))
+            .collect::<Vec<_>>();
+        let free = (0..cells.len())
+            .filter(|&i| cells[i].is_empty())
+            .collect::<Vec<_>>();
+        if copied.len() > free.len() {
+            return Err(StateTransferError::KvCacheFull {
+                n_cells: copied.len(),
+                n_free: free.len(),
+            });
+        }
+        let moves = copied.into_iter().zip(free).collect::<Vec<_>>();
+        for &(from, to) in &moves {
+            cells[to] = Cell {
+                pos: source.cells[from].pos,
+                seq_ids: vec![dest_seq_id],
+            };
+        }
+        let kv_head = cells
+            .iter()
+            .rposition(|cell| !cell.is_empty())
+            .map_or(0, |i| i + 1);
+        let kv_used = cells.iter().filter(|cell| !cell.is_empty()).count();
+
+        let mut data = self.prefix.to_vec();
+        data.extend_from_slice(&self.buf_size.to_ne_bytes());
+        data.extend_from_slice(&to_u32(kv_head)?.to_ne_bytes());
+        data.extend_from_slice(&to_u32(self.n_cells)?.to_ne_bytes());
+        data.extend_from_slice(&to_u32(kv_used)?.to_ne_bytes());
+        if self.buf_size > 0 {
+            let kept = self.kv_head.min(kv_head);
+            for ((own_k, own_v), (source_k, source_v)) in self.layers.iter().zip(&source.layers) {
+                // K: the values of a cell are contiguous
+                let n = layout.k_cell_bytes;
+                let mut k = vec![0; kv_head * n];
+                k[..kept * n].copy_from_slice(&own_k[..kept * n]);
+                for &(from, to) in &moves {
+                    k[to * n..][..n].copy_from_slice(&source_k[from * n..][..n]);
+                }
+                data.extend_from_slice(&k);
+
+                // V: transposed, each row holds one value per cell
+                let n = layout.v_value_bytes;
+                for row in 0..layout.n_embd_v {
+                    let own_row = &own_v[row * self.kv_head * n..][..self.kv_head * n];
+                    let source_row = &source_v[row * source.kv_head * n..][..source.kv_head * n];
+                    let mut v = vec![0; kv_head * n];
+                    v[..kept * n].copy_from_slice(&own_row[..kept * n]);
+                    for &(from, to) in &moves {
+                        v[to * n..][..n].copy_from_slice(&source_row[from * n..][..n]);
+                    }
+                    data.extend_from_slice(&v);
+                }
+            }
+        }
+        for cell in &cells[..kv_head] {
+            data.extend_from_slice(&cell.pos.to_ne_bytes());
+            data.extend_from_slice(&cell.seq_ids.len().to_ne_bytes());
+            for seq_id in &cell.seq_ids {
+                data.extend_from_slice(&seq_id.to_ne_bytes());
+            }
+        }
+        Ok(data)
+    }
+}
+
+fn to_u32(n: usize) -> Result<u32, StateTransferError> {
+    u32::try_from(n).map_err(|_| StateTransferError::IncompatibleParams)
+}
+
+/// Reads the state data, failing with [`StateTransferError::IncompatibleParams`] if it ends early.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StateTransferError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or(StateTransferError::IncompatibleParams)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateTransferError> {
+        let bytes = self.take(N)?;
+        Ok(bytes.try_into().expect("took N bytes"))
+    }
+
+    fn usize(&mut self) -> Result<usize, StateTransferError> {
+        self.array().map(usize::from_ne_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, StateTransferError> {
+        self.array().map(u32::from_ne_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, StateTransferError> {
+        self.array().map(i32::from_ne_bytes)
+    }
+}
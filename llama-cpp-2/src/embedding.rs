@@ -0,0 +1,526 @@
+//! Utilities for working with embeddings produced by a [`crate::context::LlamaContext`].
+//!
+//! # Encoder models
+//!
+//! BERT-style encoder models (bge, e5, nomic-embed, ...) use a word piece vocabulary
+//! ([`crate::model::VocabType::WPM`]) and non-causal attention. They can only be used to compute
+//! embeddings, so the context must be created with embeddings enabled. Tokenizing with
+//! [`crate::model::LlamaModel::str_to_token_with_sep`] and [`crate::model::AddBos::Always`] adds
+//! the `[CLS]` and `[SEP]` tokens these models expect, and the pooling type selects how the
+//! per-token embeddings are combined into one per sequence.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+//! use llama_cpp_2::llama_backend::LlamaBackend;
+//! use llama_cpp_2::llama_batch::LlamaBatch;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//!
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "path/to/bge-small.gguf", &Default::default())?;
+//! assert!(!model.is_causal());
+//!
+//! let params = LlamaContextParams::default()
+//!     .with_embeddings(true)
+//!     .with_pooling_type(LlamaPoolingType::Cls);
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let tokens = model.str_to_token_with_sep("Hello, World!", AddBos::Always)?;
+//! let mut batch = LlamaBatch::new(tokens.len(), 1);
+//! batch.add_sequence(&tokens, 0, false)?;
+//! ctx.decode(&mut batch)?;
+//!
+//! let embedding = ctx.embeddings_seq(0)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`LlamaContext::embed_batch`] does all of this for many texts at once.
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::token::LlamaToken;
+use crate::{DecodeError, EmbeddingsError, StringToTokenError};
+
+/// An embedding quantized to `i8` with a single per-vector scale.
+///
+/// Each component is stored as `round(x / scale)` where `scale = max(|x|) / 127`, so the original
+/// value can be approximately recovered as `value * scale`. This takes a quarter of the memory of
+/// the `f32` embedding, which adds up quickly when indexing large numbers of vectors.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct QuantizedEmbedding {
+    values: Vec<i8>,
+    scale: f32,
+}
+
+impl QuantizedEmbedding {
+    /// Quantize an `f32` embedding to `i8`.
+    ///
+    /// An embedding of all zeros (or an empty embedding) has a scale of `0.0`.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::QuantizedEmbedding;
+    /// let quantized = QuantizedEmbedding::quantize(&[1.0, -0.25, 0.0]);
+    /// assert_eq!(quantized.values(), &[127, -32, 0]);
+    /// assert_eq!(quantized.scale(), 1.0 / 127.0);
+    /// ```
+    #[must_use]
+    pub fn quantize(embedding: &[f32]) -> Self {
+        let max = embedding.iter().fold(0_f32, |max, x| max.max(x.abs()));
+        if max == 0.0 {
+            return Self {
+                values: vec![0; embedding.len()],
+                scale: 0.0,
+            };
+        }
+        let scale = max / f32::from(i8::MAX);
+        #[allow(clippy::cast_possible_truncation)]
+        let values = embedding
+            .iter()
+            .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+            .collect();
+        Self { values, scale }
+    }
+
+    /// Create a quantized embedding from previously stored values and scale.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::QuantizedEmbedding;
+    /// let quantized = QuantizedEmbedding::from_parts(vec![1, -2], 0.5);
+    /// assert_eq!(quantized.dequantize(), vec![0.5, -1.0]);
+    /// ```
+    #[must_use]
+    pub fn from_parts(values: Vec<i8>, scale: f32) -> Self {
+        Self { values, scale }
+    }
+
+    /// The quantized components.
+    #[must_use]
+    pub fn values(&self) -> &[i8] {
+        &self.values
+    }
+
+    /// The scale to multiply each component by to recover the original value.
+    #[must_use]
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    /// Split into the quantized components and the scale.
+    #[must_use]
+    pub fn into_parts(self) -> (Vec<i8>, f32) {
+        (self.values, self.scale)
+    }
+
+    /// Recover an approximation of the original `f32` embedding.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::QuantizedEmbedding;
+    /// let embedding = [0.1, -0.7, 0.35, 0.0];
+    /// let restored = QuantizedEmbedding::quantize(&embedding).dequantize();
+    /// for (original, restored) in embedding.iter().zip(restored) {
+    ///     assert!((original - restored).abs() < 0.01);
+    /// }
+    /// ```
+    #[must_use]
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values
+            .iter()
+            .map(|&value| f32::from(value) * self.scale)
+            .collect()
+    }
+
+    /// Approximate dot product with another quantized embedding.
+    ///
+    /// The products are accumulated as integers and scaled once at the end.
+    ///
+    /// # Panics
+    ///
+    /// - the embeddings have different lengths.
+    ///
+    /// ```
+    /// # use llama_cpp_2::embedding::QuantizedEmbedding;
+    /// let a = QuantizedEmbedding::quantize(&[0.6, 0.8]);
+    /// let b = QuantizedEmbedding::quantize(&[0.6, 0.8]);
+    /// assert!((a.dot(&b) - 1.0).abs() < 0.01);
+    /// ```
+    #[must_use]
+    pub fn dot(&self, other: &Self) -> f32 {
+        assert_eq!(
+            self.values.len(),
+            other.values.len(),
+            "embeddings must have the same length"
+        );
+        let sum: i32 = self
+            .values
+            .iter()
+            .zip(&other.values)
+            .map(|(&a, &b)| i32::from(a) * i32::from(b))
+            .sum();
+        #[allow(clippy::cast_precision_loss)]
+        let sum = sum as f32;
+        sum * self.scale * other.scale
+    }
+}
+
+/// Several embeddings stored as the rows of a row-major matrix.
+///
+/// The layout is that of a C-contiguous `n_rows × n_embd` array, so the matrix converts to the
+/// matrix types of numeric crates without copying, e.g. with
+/// `ndarray::Array2::from_shape_vec(matrix.shape(), matrix.into_raw_vec())`.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::embedding::EmbeddingMatrix;
+/// let matrix = EmbeddingMatrix::from_flat(vec![1.0, 0.0, 0.0, 1.0, 0.6, 0.8], 2).unwrap();
+/// assert_eq!(matrix.shape(), (3, 2));
+/// assert_eq!(matrix.row(2), Some(&[0.6, 0.8][..]));
+/// let norms: Vec<f32> = matrix.rows().map(|row| row.iter().map(|x| x * x).sum()).collect();
+/// assert_eq!(norms, [1.0, 1.0, 1.0]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct EmbeddingMatrix {
+    data: Vec<f32>,
+    n_embd: usize,
+}
+
+impl EmbeddingMatrix {
+    /// Interpret `data` as rows of `n_embd` values each.
+    ///
+    /// Returns `None` if `n_embd` is 0 or does not divide the length of `data`.
+    #[must_use]
+    pub fn from_flat(data: Vec<f32>, n_embd: usize) -> Option<Self> {
+        (n_embd > 0 && data.len().is_multiple_of(n_embd)).then_some(Self { data, n_embd })
+    }
+
+    /// Stack embeddings of `n_embd` values each.
+    ///
+    /// # Panics
+    ///
+    /// - `n_embd` is 0 or an embedding does not have `n_embd` values.
+    #[must_use]
+    pub fn from_rows<'a>(n_embd: usize, rows: impl IntoIterator<Item = &'a [f32]>) -> Self {
+        assert!(n_embd > 0, "n_embd must not be 0");
+        let mut data = Vec::new();
+        for row in rows {
+            assert_eq!(row.len(), n_embd, "embeddings must have n_embd values");
+            data.extend_from_slice(row);
+        }
+        Self { data, n_embd }
+    }
+
+    /// The number of embeddings.
+    #[must_use]
+    pub fn n_rows(&self) -> usize {
+        self.data.len() / self.n_embd
+    }
+
+    /// The number of values of every embedding.
+    #[must_use]
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// The number of rows and columns, `(n_rows, n_embd)`.
+    #[must_use]
+    pub fn shape(&self) -> (usize, usize) {
+        (self.n_rows(), self.n_embd)
+    }
+
+    /// The `i`th embedding, `None` if out of bounds.
+    #[must_use]
+    pub fn row(&self, i: usize) -> Option<&[f32]> {
+        self.data.chunks_exact(self.n_embd).nth(i)
+    }
+
+    /// The embeddings in order.
+    pub fn rows(&self) -> std::slice::ChunksExact<'_, f32> {
+        self.data.chunks_exact(self.n_embd)
+    }
+
+    /// All values, row by row.
+    #[must_use]
+    pub fn as_slice(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// All values, row by row.
+    #[must_use]
+    pub fn into_raw_vec(self) -> Vec<f32> {
+        self.data
+    }
+}
+
+/// How [`LlamaContext::embed_batch`] combines the token embeddings of a text into one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmbeddingPooling {
+    /// Use the embeddings llama.cpp pooled with the pooling type of the context. The context must
+    /// not use [`crate::context::params::LlamaPoolingType::None`].
+    #[default]
+    Context,
+    /// The mean of the token embeddings.
+    Mean,
+    /// The embedding of the first token, e.g. `[CLS]`.
+    Cls,
+    /// The embedding of the last token, as used by decoder-only embedding models.
+    Last,
+}
+
+/// Options for [`LlamaContext::embed_batch`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::embedding::{EmbedOptions, EmbeddingPooling};
+/// let options = EmbedOptions::default()
+///     .with_pooling(EmbeddingPooling::Last)
+///     .with_normalize(true);
+/// assert_eq!(options.pooling(), EmbeddingPooling::Last);
+/// assert!(options.normalize());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EmbedOptions {
+    pooling: EmbeddingPooling,
+    normalize: bool,
+}
+
+impl EmbedOptions {
+    /// Set how token embeddings are pooled, [`EmbeddingPooling::Context`] by default.
+    ///
+    /// [`EmbeddingPooling::Mean`], [`EmbeddingPooling::Cls`] and [`EmbeddingPooling::Last`] pool
+    /// the token embeddings themselves, so the context must use
+    /// [`crate::context::params::LlamaPoolingType::None`].
+    #[must_use]
+    pub fn with_pooling(mut self, pooling: EmbeddingPooling) -> Self {
+        self.pooling = pooling;
+        self
+    }
+
+    /// Set whether embeddings are scaled to unit length, so their dot product is the cosine
+    /// similarity. Off by default.
+    #[must_use]
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// How token embeddings are pooled.
+    #[must_use]
+    pub fn pooling(&self) -> EmbeddingPooling {
+        self.pooling
+    }
+
+    /// Whether embeddings are scaled to unit length.
+    #[must_use]
+    pub fn normalize(&self) -> bool {
+        self.normalize
+    }
+}
+
+/// An error while embedding texts with [`LlamaContext::embed_batch`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum EmbedError {
+    /// A text could not be tokenized.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+    /// A text has no tokens, which only happens for an empty text if the model adds no BOS token.
+    #[error("text {index} has no tokens")]
+    Empty {
+        /// The index of the text.
+        index: usize,
+    },
+    /// A text has more tokens than fit into a batch.
+    #[error("text {index} has {n_tokens} tokens, but at most {max} fit into a batch")]
+    TooLong {
+        /// The index of the text.
+        index: usize,
+        /// The number of tokens of the text.
+        n_tokens: usize,
+        /// The smallest of [`LlamaContext::n_batch`], [`LlamaContext::n_ubatch`] and
+        /// [`LlamaContext::n_ctx`].
+        max: usize,
+    },
+    /// Decoding failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// The batch could not hold the tokens.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// The embeddings could not be read, e.g. because embeddings are not enabled or the pooling
+    /// does not match the pooling type of the context.
+    #[error("{0}")]
+    Embeddings(#[from] EmbeddingsError),
+}
+
+impl LlamaContext<'_> {
+    /// Embed `texts`, returning one embedding per text in the order of `texts`.
+    ///
+    /// Texts are tokenized with [`crate::model::LlamaModel::str_to_token_with_sep`] and
+    /// [`AddBos::Always`], so they end with `[SEP]` for BERT-style models, and packed into batches
+    /// of up to [`LlamaContext::n_batch`] tokens (and [`LlamaContext::n_ubatch`], as encoder
+    /// models process a batch at once) on up to [`LlamaContext::n_seq_max`] sequences, so the
+    /// context should be created with embeddings enabled and several sequences. The embeddings
+    /// are pooled and normalized as set in `options`.
+    ///
+    /// The KV cache is cleared before every batch and left in an unspecified state.
+    ///
+    /// # Errors
+    ///
+    /// - a text could not be tokenized, has no tokens or does not fit into a batch. No text is embedded then.
+    /// - decoding failed.
+    /// - the embeddings could not be read, see [`EmbedError::Embeddings`].
+    ///
+    /// # Panics
+    ///
+    /// - `n_embd` does not fit into a usize.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::num::NonZeroU32;
+    /// # use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
+    /// use llama_cpp_2::embedding::{EmbedOptions, EmbeddingPooling};
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let params = LlamaContextParams::default()
+    ///     .with_embeddings(true)
+    ///     .with_pooling_type(LlamaPoolingType::None)
+    ///     .with_n_seq_max(NonZeroU32::new(8).unwrap());
+    /// let mut ctx = model.new_context(&backend, params)?;
+    ///
+    /// let options = EmbedOptions::default()
+    ///     .with_pooling(EmbeddingPooling::Mean)
+    ///     .with_normalize(true);
+    /// let embeddings = ctx.embed_batch(&["first document", "second document"], options)?;
+    /// let similarity: f32 = embeddings
+    ///     .row(0)
+    ///     .unwrap()
+    ///     .iter()
+    ///     .zip(embeddings.row(1).unwrap())
+    ///     .map(|(a, b)| a * b)
+    ///     .sum();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn embed_batch(
+        &mut self,
+        texts: &[&str],
+        options: EmbedOptions,
+    ) -> Result<EmbeddingMatrix, EmbedError> {
+        let max = self.n_batch().min(self.n_ubatch()).min(self.n_ctx()) as usize;
+        let mut tokenized = Vec::with_capacity(texts.len());
+        for (index, text) in texts.iter().enumerate() {
+            let tokens = self.model.str_to_token_with_sep(text, AddBos::Always)?;
+            if tokens.is_empty() {
+                return Err(EmbedError::Empty { index });
+            }
+            if tokens.len() > max {
+                return Err(EmbedError::TooLong {
+                    index,
+                    n_tokens: tokens.len(),
+                    max,
+                });
+            }
+            tokenized.push(tokens);
+        }
+
+        let n_embd =
+            usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
+        let n_seq_max = self.n_seq_max().max(1) as usize;
+        let mut batch = LlamaBatch::new(max, 1);
+        let mut data = Vec::with_capacity(texts.len() * n_embd);
+        let mut start = 0;
+        while start < tokenized.len() {
+            let mut end = start;
+            let mut n_tokens = 0;
+            while end < tokenized.len()
+                && end - start < n_seq_max
+                && n_tokens + tokenized[end].len() <= max
+            {
+                n_tokens += tokenized[end].len();
+                end += 1;
+            }
+            self.embed_chunk(&mut batch, &tokenized[start..end], options, &mut data)?;
+            start = end;
+        }
+        Ok(EmbeddingMatrix { data, n_embd })
+    }
+
+    /// Decode `texts` as one batch, text `i` on sequence `i`, and append their embeddings to
+    /// `data`.
+    fn embed_chunk(
+        &mut self,
+        batch: &mut LlamaBatch,
+        texts: &[Vec<LlamaToken>],
+        options: EmbedOptions,
+        data: &mut Vec<f32>,
+    ) -> Result<(), EmbedError> {
+        let n_embd =
+            usize::try_from(self.model.n_embd()).expect("n_embd does not fit into a usize");
+        self.clear_kv_cache();
+        batch.clear();
+        // the batch index of the first token of each text
+        let mut starts = Vec::with_capacity(texts.len());
+        for (seq_id, tokens) in (0..).zip(texts) {
+            starts.push(batch.n_tokens());
+            let last = tokens.len().saturating_sub(1);
+            for (pos, &token) in tokens.iter().enumerate() {
+                let logits = match options.pooling {
+                    EmbeddingPooling::Context => false,
+                    EmbeddingPooling::Mean => true,
+                    EmbeddingPooling::Cls => pos == 0,
+                    EmbeddingPooling::Last => pos == last,
+                };
+                let pos = i32::try_from(pos).expect("positions fit into an i32");
+                batch.add(token, pos, &[seq_id], logits)?;
+            }
+        }
+        self.decode(batch)?;
+
+        for ((seq_id, tokens), start) in (0..).zip(texts).zip(starts) {
+            let offset = data.len();
+            match options.pooling {
+                EmbeddingPooling::Context => data.extend_from_slice(self.embeddings_seq(seq_id)?),
+                EmbeddingPooling::Cls => data.extend_from_slice(self.embeddings_ith(start)?),
+                EmbeddingPooling::Last => {
+                    let last = start + i32::try_from(tokens.len()).expect("fits into an i32") - 1;
+                    data.extend_from_slice(self.embeddings_ith(last)?);
+                }
+                EmbeddingPooling::Mean => {
+                    data.resize(offset + n_embd, 0.0);
+                    for i in (start..).take(tokens.len()) {
+                        for (sum, x) in data[offset..].iter_mut().zip(self.embeddings_ith(i)?) {
+                            *sum += x;
+                        }
+                    }
+                    #[allow(clippy::cast_precision_loss)]
+                    let n = tokens.len() as f32;
+                    for x in &mut data[offset..] {
+                        *x /= n;
+                    }
+                }
+            }
+            if options.normalize {
+                normalize(&mut data[offset..]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scale `embedding` to unit length, leaving it as is if all values are 0.
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in embedding {
+            *x /= norm;
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Scoring multiple-choice answers by their log probability, as MMLU, ARC or `HellaSwag` style
+//! harnesses do.
+//!
+//! [`score_choices`] computes the log probability of each choice following its prompt. Every
+//! (prompt, choice) pair is decoded as its own sequence, and as many pairs as fit are packed into
+//! one batch, so a question with four choices usually takes a single decode.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::num::NonZeroU32;
+//!
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::eval::{score_choices, ChoicePair};
+//! use llama_cpp_2::model::AddBos;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! // one sequence per choice
+//! let params = LlamaContextParams::default().with_n_seq_max(NonZeroU32::new(4).unwrap());
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let question = "Question: What is the capital of France?\nAnswer:";
+//! let prompt = model.str_to_token(question, AddBos::Always)?;
+//! let choices = [" Berlin", " Paris", " Madrid", " Rome"];
+//! let mut pairs = Vec::new();
+//! for choice in choices {
+//!     let choice = model.str_to_token(choice, AddBos::Never)?;
+//!     pairs.push(ChoicePair::new(prompt.clone(), choice));
+//! }
+//!
+//! let scores = score_choices(&mut ctx, &pairs)?;
+//! let best = scores
+//!     .iter()
+//!     .enumerate()
+//!     .max_by(|(_, a), (_, b)| a.mean_logprob().total_cmp(&b.mean_logprob()))
+//!     .map(|(i, _)| choices[i]);
+//! println!("{best:?}");
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// A choice to score and the prompt it follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChoicePair {
+    /// The prompt, including any BOS token.
+    pub prompt: Vec<LlamaToken>,
+    /// The tokens of the choice.
+    pub choice: Vec<LlamaToken>,
+}
+
+impl ChoicePair {
+    /// Pair `choice` with `prompt`.
+    #[must_use]
+    pub fn new(prompt: Vec<LlamaToken>, choice: Vec<LlamaToken>) -> Self {
+        Self { prompt, choice }
+    }
+
+    fn n_tokens(&self) -> usize {
+        self.prompt.len() + self.choice.len()
+    }
+}
+
+/// The log probability of a [`ChoicePair`]'s choice given its prompt.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChoiceScore {
+    /// The sum of the natural log probabilities of the tokens of the choice.
+    pub logprob: f64,
+    /// The number of tokens of the choice.
+    pub n_tokens: usize,
+}
+
+impl ChoiceScore {
+    /// The log probability per token, which does not favor short choices the way
+    /// [`ChoiceScore::logprob`] does. 0 for an empty choice.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mean_logprob(&self) -> f64 {
+        if self.n_tokens == 0 {
+            0.0
+        } else {
+            self.logprob / self.n_tokens as f64
+        }
+    }
+}
+
+/// An error while scoring choices.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum EvalError {
+    /// A pair has an empty prompt, so there is nothing to predict its first token from.
+    #[error("pair {index} has an empty prompt")]
+    EmptyPrompt {
+        /// The index of the pair.
+        index: usize,
+    },
+    /// A pair has more tokens than fit into a batch (or the context).
+    #[error("pair {index} has {n_tokens} tokens, but at most {max} fit into a batch")]
+    TooLong {
+        /// The index of the pair.
+        index: usize,
+        /// The number of tokens of its prompt and choice.
+        n_tokens: usize,
+        /// The smaller of [`LlamaContext::n_batch`] and [`LlamaContext::n_ctx`].
+        max: usize,
+    },
+    /// Decoding failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// The batch could not hold the tokens.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+}
+
+/// Score the choice of every pair, returning the scores in the order of `pairs`.
+///
+/// Pairs are packed into batches of up to [`LlamaContext::n_batch`] tokens (and
+/// [`LlamaContext::n_ctx`], as the sequences of a batch share the KV cache) on up to
+/// [`LlamaContext::n_seq_max`] sequences. Create the context with one sequence per choice of a
+/// question to decode whole questions at once. Logits are only computed for the positions
+/// predicting a choice token.
+///
+/// The KV cache of `ctx` is cleared before every batch and left in an unspecified state.
+///
+/// # Errors
+///
+/// - a pair has an empty prompt or does not fit into a batch. No pair is scored then.
+/// - decoding failed.
+pub fn score_choices(
+    ctx: &mut LlamaContext,
+    pairs: &[ChoicePair],
+) -> Result<Vec<ChoiceScore>, EvalError> {
+    let max = ctx.n_batch().min(ctx.n_ctx()) as usize;
+    for (index, pair) in pairs.iter().enumerate() {
+        if pair.prompt.is_empty() {
+            return Err(EvalError::EmptyPrompt { index });
+        }
+        if pair.n_tokens() > max {
+            return Err(EvalError::TooLong {
+                index,
+                n_tokens: pair.n_tokens(),
+                max,
+            });
+        }
+    }
+
+    let n_seq_max = ctx.n_seq_max().max(1) as usize;
+    let mut batch = LlamaBatch::new(max, 1);
+    let mut scores = Vec::with_capacity(pairs.len());
+    let mut start = 0;
+    while start < pairs.len() {
+        let mut end = start;
+        let mut n_tokens = 0;
+        while end < pairs.len()
+            && end - start < n_seq_max
+            && n_tokens + pairs[end].n_tokens() <= max
+        {
+            n_tokens += pairs[end].n_tokens();
+            end += 1;
+        }
+        scores.extend(score_batch(ctx, &mut batch, &pairs[start..end])?);
+        start = end;
+    }
+    Ok(scores)
+}
+
+/// Decode `pairs` as one batch, pair `i` on sequence `i`.
+fn score_batch(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    pairs: &[ChoicePair],
+) -> Result<Vec<ChoiceScore>, EvalError> {
+    ctx.clear_kv_cache();
+    batch.clear();
+    // the batch index of the logits predicting the first token of each choice
+    let mut first_logits = Vec::with_capacity(pairs.len());
+    for (seq_id, pair) in (0..).zip(pairs) {
+        first_logits.push(batch.n_tokens() + pos_i32(pair.prompt.len()) - 1);
+        let predicting = pair.prompt.len() - 1..pair.n_tokens() - 1;
+        let tokens = pair.prompt.iter().chain(&pair.choice);
+        for (pos, &token) in tokens.enumerate() {
+            batch.add(token, pos_i32(pos), &[seq_id], predicting.contains(&pos))?;
+        }
+    }
+    ctx.decode(batch)?;
+
+    Ok(pairs
+        .iter()
+        .zip(first_logits)
+        .map(|(pair, first)| {
+            let logprob = (first..)
+                .zip(&pair.choice)
+                .map(|(i, &token)| token_logprob(ctx.get_logits_ith(i), token))
+                .sum();
+            ChoiceScore {
+                logprob,
+                n_tokens: pair.choice.len(),
+            }
+        })
+        .collect())
+}
+
+/// The log softmax of `logits` at `token`.
+fn token_logprob(logits: &[f32], LlamaToken(token): LlamaToken) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f64 = logits
+        .iter()
+        .map(|&logit| f64::from(logit - max).exp())
+        .sum();
+    let logit = usize::try_from(token)
+        .ok()
+        .and_then(|token| logits.get(token))
+        .copied()
+        .unwrap_or(f32::NEG_INFINITY);
+    f64::from(logit - max) - sum.ln()
+}
+
+fn pos_i32(pos: usize) -> i32 {
+    i32::try_from(pos).expect("position should fit into an i32")
+}
@@ -0,0 +1,1289 @@
+//! A high level generation loop over a [`LlamaContext`].
+//!
+//! [`LlamaContext::generate`] decodes a prompt and then yields one generated token at a time,
+//! leaving the choice of the next token to a [`TokenSampler`]. [`LlamaContext::generate_with_config`]
+//! instead samples according to the [`sampling::SamplingParams`] of the [`GenerationConfig`], which
+//! (with the `serde` feature) can be loaded from a settings file.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::{GenerationConfig, Greedy};
+//! use llama_cpp_2::llama_backend::LlamaBackend;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = LlamaBackend::init()?;
+//! let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let prompt = model.str_to_token("User: Name a color.\nAssistant:", AddBos::Always)?;
+//! // prefill the reply so the model continues from there
+//! let config = GenerationConfig::default()
+//!     .with_max_tokens(Some(32))
+//!     .with_forced_prefix(model.str_to_token(" My favorite color is", AddBos::Never)?);
+//!
+//! for token in ctx.generate(&prompt, Greedy, config) {
+//!     print!("{}", model.token_to_str(token?)?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Loading the whole configuration from JSON:
+//!
+//! ```
+//! # #[cfg(feature = "serde")]
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use llama_cpp_2::generation::GenerationConfig;
+//! use llama_cpp_2::token::LlamaToken;
+//!
+//! let config: GenerationConfig = serde_json::from_str(
+//!     r#"{
+//!         "max_tokens": 256,
+//!         "stop": ["\nUser:"],
+//!         "sampling": { "temperature": 0.2, "top_k": 20 },
+//!         "seed": 42,
+//!         "grammar": "root ::= [a-z ]+",
+//!         "logit_bias": { "13": -100.0 }
+//!     }"#,
+//! )?;
+//! assert_eq!(config.max_tokens(), Some(256));
+//! assert_eq!(config.sampling().top_k, 20);
+//! assert_eq!(config.sampling().top_p, 0.95, "unset fields keep their defaults");
+//! assert_eq!(config.logit_bias()[&LlamaToken(13)], -100.0);
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() {}
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::context::LlamaContext;
+use crate::generation::constraint::{Constrained, ConstraintState, TokenConstraint};
+use crate::generation::sampling::{ParamsSampler, SamplingParams};
+use crate::grammar::{GrammarAcceptError, LlamaGrammar, LlamaGrammarFromStrError};
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::model::AddBos;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::mask::TokenMask;
+use crate::token::LlamaToken;
+use crate::{DecodeError, StringToTokenError, TokenToStringError};
+
+pub mod chunk;
+pub mod completion;
+pub mod constraint;
+pub mod penalty;
+pub mod reasoning;
+pub mod retry;
+pub mod sampling;
+pub mod tool_call;
+pub mod watermark;
+#[cfg(feature = "async")]
+pub mod worker;
+
+/// Options for [`LlamaContext::generate`] and [`LlamaContext::generate_with_config`].
+///
+/// With the `serde` feature this can be (de)serialized, with missing fields taking their default
+/// values (see the [module docs](crate::generation) for an example).
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[allow(clippy::module_name_repetitions)]
+pub struct GenerationConfig {
+    max_tokens: Option<usize>,
+    min_tokens: usize,
+    forced_prefix: Vec<LlamaToken>,
+    logprobs: bool,
+    stop: Vec<String>,
+    sampling: SamplingParams,
+    seed: Option<u32>,
+    grammar: Option<String>,
+    logit_bias: BTreeMap<LlamaToken, f32>,
+    context_shift: Option<usize>,
+}
+
+impl GenerationConfig {
+    /// Set the maximum number of tokens to generate (including any forced prefix). `None` generates
+    /// until the end of stream token or until the context is full.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_max_tokens(Some(128));
+    /// assert_eq!(config.max_tokens(), Some(128));
+    /// ```
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// Get the maximum number of tokens to generate.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// assert_eq!(GenerationConfig::default().max_tokens(), None);
+    /// ```
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+
+    /// Set the minimum number of tokens to generate (including any forced prefix). Until it is
+    /// reached the end of generation tokens (see [`crate::model::LlamaModel::is_eog_token`]) are
+    /// banned from sampling, unless the grammar (see [`GenerationConfig::with_grammar`]) or the
+    /// constraint allow nothing else.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_min_tokens(16);
+    /// assert_eq!(config.min_tokens(), 16);
+    /// ```
+    ///
+    /// A complete grammar ends the generation before `min_tokens` is reached:
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::{FinishReason, GenerationConfig};
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let config = GenerationConfig::default()
+    ///     .with_min_tokens(16)
+    ///     .with_grammar(Some(r#"root ::= "yes" | "no""#.to_string()));
+    /// let completion = ctx.complete("Is the sky blue? ", &config)?;
+    /// assert!(["yes", "no"].contains(&completion.text.as_str()));
+    /// assert_eq!(completion.finish_reason, FinishReason::Eos);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_min_tokens(mut self, min_tokens: usize) -> Self {
+        self.min_tokens = min_tokens;
+        self
+    }
+
+    /// Get the minimum number of tokens to generate.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// assert_eq!(GenerationConfig::default().min_tokens(), 0);
+    /// ```
+    #[must_use]
+    pub fn min_tokens(&self) -> usize {
+        self.min_tokens
+    }
+
+    /// Force the first generated tokens to be `forced_prefix` instead of sampling them, e.g. to
+    /// prefill the start of an assistant reply.
+    ///
+    /// The prefix is decoded together with the prompt and every token of it is passed to
+    /// [`TokenSampler::accept`], so stateful samplers (grammars, repetition penalties) see it as if
+    /// it had been sampled. With a grammar (see [`GenerationConfig::with_grammar`]) the generation
+    /// fails with [`GenerationError::GrammarAccept`] before decoding anything if the grammar does
+    /// not allow the prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let config = GenerationConfig::default().with_forced_prefix(vec![LlamaToken(90)]);
+    /// assert_eq!(config.forced_prefix(), &[LlamaToken(90)]);
+    /// ```
+    #[must_use]
+    pub fn with_forced_prefix(mut self, forced_prefix: Vec<LlamaToken>) -> Self {
+        self.forced_prefix = forced_prefix;
+        self
+    }
+
+    /// Get the tokens the generation is forced to start with.
+    #[must_use]
+    pub fn forced_prefix(&self) -> &[LlamaToken] {
+        &self.forced_prefix
+    }
+
+    /// Compute the log probability of every sampled token under the model's distribution (before
+    /// any sampling steps), see [`Generator::logprob`]. This costs an extra pass over the logits
+    /// per token.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_logprobs(true);
+    /// assert!(config.logprobs());
+    /// ```
+    #[must_use]
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// Get whether log probabilities are computed.
+    #[must_use]
+    pub fn logprobs(&self) -> bool {
+        self.logprobs
+    }
+
+    /// Stop generating once the output contains any of `stop`. The token completing a stop string
+    /// is still yielded, [`chunk::GenerationChunks`] removes the stop string and anything after it
+    /// from the text.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_stop(vec!["\nUser:".to_string()]);
+    /// assert_eq!(config.stop(), ["\nUser:"]);
+    /// ```
+    #[must_use]
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Get the stop strings.
+    #[must_use]
+    pub fn stop(&self) -> &[String] {
+        &self.stop
+    }
+
+    /// Set the sampling parameters used by [`LlamaContext::generate_with_config`]. They are
+    /// ignored by [`LlamaContext::generate`], which uses the given sampler instead.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::generation::sampling::SamplingParams;
+    /// let config = GenerationConfig::default().with_sampling(SamplingParams {
+    ///     temperature: 0.0,
+    ///     ..SamplingParams::default()
+    /// });
+    /// assert_eq!(config.sampling().temperature, 0.0);
+    /// ```
+    #[must_use]
+    pub fn with_sampling(mut self, sampling: SamplingParams) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Get the sampling parameters.
+    #[must_use]
+    pub fn sampling(&self) -> &SamplingParams {
+        &self.sampling
+    }
+
+    /// Reseed the context's random number generator with `seed` before generating, to make
+    /// sampling reproducible. `None` picks a random seed, which is reported by
+    /// [`Generator::seed`] and [`CompletionResult::seed`] to reproduce the generation later.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_seed(Some(1234));
+    /// assert_eq!(config.seed(), Some(1234));
+    /// ```
+    #[must_use]
+    pub fn with_seed(mut self, seed: Option<u32>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Get the seed of the random number generator.
+    #[must_use]
+    pub fn seed(&self) -> Option<u32> {
+        self.seed
+    }
+
+    /// Constrain the output (including any forced prefix) to a GBNF grammar. It is parsed when
+    /// the generation starts and applied before the sampler.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_grammar(Some(r#"root ::= "yes" | "no""#.to_string()));
+    /// assert_eq!(config.grammar(), Some(r#"root ::= "yes" | "no""#));
+    /// ```
+    #[must_use]
+    pub fn with_grammar(mut self, grammar: Option<String>) -> Self {
+        self.grammar = grammar;
+        self
+    }
+
+    /// Get the grammar the output is constrained to.
+    #[must_use]
+    pub fn grammar(&self) -> Option<&str> {
+        self.grammar.as_deref()
+    }
+
+    /// Add a bias to the logits of tokens before the sampler sees them, see
+    /// [`LlamaTokenDataArray::sample_logit_bias`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::collections::BTreeMap;
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let config = GenerationConfig::default()
+    ///     .with_logit_bias(BTreeMap::from([(LlamaToken(13), f32::NEG_INFINITY)]));
+    /// assert_eq!(config.logit_bias().len(), 1);
+    /// ```
+    #[must_use]
+    pub fn with_logit_bias(mut self, logit_bias: BTreeMap<LlamaToken, f32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    /// Get the logit biases.
+    #[must_use]
+    pub fn logit_bias(&self) -> &BTreeMap<LlamaToken, f32> {
+        &self.logit_bias
+    }
+
+    /// Make room when the context is full by discarding old tokens, keeping the first `n_keep`
+    /// tokens of the prompt (e.g. the system prompt). `None` ends the generation with
+    /// [`GenerationError::ContextFull`] instead.
+    ///
+    /// Like llama.cpp's `main` example, half of the tokens after the kept ones are removed from the
+    /// KV cache at once and the rest are shifted back, so the generation continues without decoding
+    /// anything again. This does not work with recurrent models, whose state can not be partially
+    /// removed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// let config = GenerationConfig::default().with_context_shift(Some(32));
+    /// assert_eq!(config.context_shift(), Some(32));
+    /// ```
+    #[must_use]
+    pub fn with_context_shift(mut self, n_keep: Option<usize>) -> Self {
+        self.context_shift = n_keep;
+        self
+    }
+
+    /// Get the number of tokens kept when the context is shifted, `None` if it is not.
+    #[must_use]
+    pub fn context_shift(&self) -> Option<usize> {
+        self.context_shift
+    }
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FinishReason {
+    /// The model produced the end of stream token.
+    Eos,
+    /// The model produced another end of generation token, such as end of turn, see
+    /// [`crate::model::LlamaModel::eog_tokens`].
+    EogToken,
+    /// The output contains one of the stop strings.
+    StopString,
+    /// `max_tokens` tokens were generated.
+    MaxTokens,
+    /// The generation was cancelled, see [`Generator::with_cancel`].
+    Cancelled,
+    /// The context is full and [`GenerationConfig::with_context_shift`] is not enabled or could
+    /// not make room.
+    ContextFull,
+}
+
+/// The number of tokens processed by a generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usage {
+    /// The number of prompt tokens.
+    pub prompt_tokens: usize,
+    /// The number of generated tokens, including any forced prefix.
+    pub completion_tokens: usize,
+}
+
+impl Usage {
+    /// The number of prompt and generated tokens.
+    #[must_use]
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// How fast a generation processed the prompt and generated tokens.
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::Duration;
+/// # use llama_cpp_2::generation::{Throughput, Usage};
+/// let usage = Usage { prompt_tokens: 100, completion_tokens: 20 };
+/// let throughput = Throughput::new(usage, Duration::from_millis(250), Duration::from_secs(2));
+/// assert_eq!(throughput.prompt_eval_ms, 250.0);
+/// assert_eq!(throughput.prompt_tokens_per_second, 400.0);
+/// assert_eq!(throughput.tokens_per_second, 10.0);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Throughput {
+    /// The time it took to decode the prompt (and any forced prefix), in milliseconds.
+    pub prompt_eval_ms: f64,
+    /// The time spent generating tokens after the prompt, including sampling, in milliseconds.
+    pub eval_ms: f64,
+    /// The number of prompt tokens decoded per second.
+    pub prompt_tokens_per_second: f64,
+    /// The number of tokens generated per second.
+    pub tokens_per_second: f64,
+}
+
+impl Throughput {
+    /// Compute the throughput of a generation from its [`Usage`] and how long the prompt and
+    /// the generation took. Rates are 0 if no time was measured.
+    #[must_use]
+    pub fn new(usage: Usage, prompt_eval: Duration, eval: Duration) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let per_second = |n: usize, duration: Duration| {
+            if duration.is_zero() {
+                0.0
+            } else {
+                n as f64 / duration.as_secs_f64()
+            }
+        };
+        Self {
+            prompt_eval_ms: prompt_eval.as_secs_f64() * 1000.0,
+            eval_ms: eval.as_secs_f64() * 1000.0,
+            prompt_tokens_per_second: per_second(usage.prompt_tokens, prompt_eval),
+            tokens_per_second: per_second(usage.completion_tokens, eval),
+        }
+    }
+}
+
+/// The output of [`LlamaContext::complete`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompletionResult {
+    /// The generated text, without any stop string.
+    pub text: String,
+    /// Why the generation stopped.
+    pub finish_reason: FinishReason,
+    /// The number of prompt and generated tokens.
+    pub usage: Usage,
+    /// How fast the prompt was processed and the tokens were generated.
+    pub throughput: Throughput,
+    /// The seed sampling used, see [`GenerationConfig::with_seed`].
+    pub seed: u32,
+}
+
+/// Chooses the next token during [`LlamaContext::generate`].
+///
+/// Any closure taking the context and the candidates and returning a token is a sampler.
+///
+/// The candidates are built in a buffer that is reused between tokens. Samplers that do not pass
+/// them on to [`LlamaContext::sample_token_greedy`] should hand them back with
+/// [`LlamaContext::recycle_token_data_array`] to avoid allocating a new one for every token.
+///
+/// # Examples
+///
+/// A sampler constrained by a grammar has to advance the grammar with every token:
+///
+/// ```
+/// # use llama_cpp_2::context::LlamaContext;
+/// # use llama_cpp_2::generation::TokenSampler;
+/// # use llama_cpp_2::grammar::LlamaGrammar;
+/// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+/// # use llama_cpp_2::token::LlamaToken;
+/// struct GrammarGreedy(LlamaGrammar);
+///
+/// impl TokenSampler for GrammarGreedy {
+///     fn sample(&mut self, ctx: &mut LlamaContext, mut candidates: LlamaTokenDataArray) -> LlamaToken {
+///         ctx.sample_grammar(&mut candidates, &self.0);
+///         ctx.sample_token_greedy(candidates)
+///     }
+///
+///     fn accept(&mut self, ctx: &mut LlamaContext, token: LlamaToken) {
+///         // a forced token may not match the grammar, which then stays where it was
+///         let _ = self.0.accept_token(ctx, token);
+///     }
+/// }
+/// ```
+pub trait TokenSampler {
+    /// Choose the next token from `candidates`.
+    fn sample(&mut self, ctx: &mut LlamaContext, candidates: LlamaTokenDataArray) -> LlamaToken;
+
+    /// Update any internal state with the token that was chosen, whether it was returned by
+    /// [`Self::sample`] or forced (see [`GenerationConfig::with_forced_prefix`]).
+    fn accept(&mut self, _ctx: &mut LlamaContext, _token: LlamaToken) {}
+
+    /// Update any internal state with the prompt before the first token is sampled, e.g. to include
+    /// it in a repetition penalty. Unlike [`Self::accept`] this is not meant to advance grammars.
+    fn accept_prompt(&mut self, _ctx: &mut LlamaContext, _prompt: &[LlamaToken]) {}
+
+    /// Forget the state of the previous request, so one sampler (e.g. taken back with
+    /// [`Generator::into_sampler`]) can serve many requests. Sampling randomness comes from the
+    /// context, reseed it with [`LlamaContext::set_rng_seed`] or [`GenerationConfig::with_seed`].
+    fn reset(&mut self) {}
+}
+
+impl<F> TokenSampler for F
+where
+    F: FnMut(&mut LlamaContext, LlamaTokenDataArray) -> LlamaToken,
+{
+    fn sample(&mut self, ctx: &mut LlamaContext, candidates: LlamaTokenDataArray) -> LlamaToken {
+        self(ctx, candidates)
+    }
+}
+
+/// Always choose the token with the highest logit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Greedy;
+
+impl TokenSampler for Greedy {
+    fn sample(&mut self, ctx: &mut LlamaContext, candidates: LlamaTokenDataArray) -> LlamaToken {
+        ctx.sample_token_greedy(candidates)
+    }
+}
+
+/// Failed to generate a token.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GenerationError {
+    /// Neither a prompt nor a forced prefix was given, so there is nothing to continue from.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// The prompt does not fit into the context. Running out of space while generating ends the
+    /// generation with [`FinishReason::ContextFull`] instead.
+    #[error("the context is full ({n_ctx} tokens)")]
+    ContextFull {
+        /// The size of the context.
+        n_ctx: u32,
+    },
+    /// More completions were requested than the context has sequences, see
+    /// [`completion::CompletionOptions::with_n`].
+    #[error("{n} choices were requested, but the context has {n_seq_max} sequences")]
+    TooManyChoices {
+        /// The number of requested choices.
+        n: usize,
+        /// The number of sequences of the context.
+        n_seq_max: usize,
+    },
+    /// Failed to add a token to the batch.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+    /// Failed to decode a batch.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// Failed to convert a generated token to text.
+    #[error("{0}")]
+    TokenToString(#[from] TokenToStringError),
+    /// Failed to parse the grammar of the config.
+    #[error("{0}")]
+    Grammar(#[from] LlamaGrammarFromStrError),
+    /// The grammar of the config does not allow the forced prefix, see
+    /// [`GenerationConfig::with_forced_prefix`]. This is checked before the prompt is decoded.
+    #[error("{0}")]
+    GrammarAccept(#[from] GrammarAcceptError),
+    /// Failed to tokenize the prompt.
+    #[error("{0}")]
+    Tokenize(#[from] StringToTokenError),
+}
+
+/// An iterator over generated tokens created by [`LlamaContext::generate`].
+///
+/// The prompt is only decoded on the first call to [`Iterator::next`]. Iteration ends after an end
+/// of generation token (which is not yielded), after a stop string, after `max_tokens` tokens, when
+/// cancelled, when the context is full or after the first error. [`Generator::finish_reason`] tells
+/// which of these it was.
+pub struct Generator<'a, 'model, S> {
+    ctx: &'a mut LlamaContext<'model>,
+    sampler: S,
+    config: GenerationConfig,
+    prompt: Option<Vec<LlamaToken>>,
+    forced: VecDeque<LlamaToken>,
+    /// The end of generation tokens, banned until `min_tokens` is reached.
+    eog: TokenMask,
+    grammar: Option<LlamaGrammar>,
+    constraint: Option<Box<dyn ConstraintState + 'a>>,
+    /// The end of the output, long enough to contain any stop string.
+    text: Vec<u8>,
+    batch: LlamaBatch,
+    /// The sampled token that has not been decoded yet.
+    pending: Option<LlamaToken>,
+    n_past: i32,
+    n_prompt: usize,
+    n_generated: usize,
+    /// The time spent decoding the prompt.
+    prompt_eval: Duration,
+    /// The time spent in [`Iterator::next`], including decoding the prompt.
+    elapsed: Duration,
+    /// The seed of the context's random number generator for this generation.
+    seed: u32,
+    /// The log probability of the last sampled token, if enabled.
+    logprob: Option<f32>,
+    finish_reason: Option<FinishReason>,
+    finished: bool,
+    cancel: Option<Arc<AtomicBool>>,
+    on_token: Option<Box<OnToken<'a>>>,
+    on_prompt_processed: Option<Box<OnPromptProcessed<'a>>>,
+    on_finish: Option<Box<OnFinish<'a>>>,
+}
+
+/// See [`Generator::with_on_token`].
+type OnToken<'a> = dyn FnMut(LlamaToken) + 'a;
+/// See [`Generator::with_on_prompt_processed`].
+type OnPromptProcessed<'a> = dyn FnMut(usize, Duration) + 'a;
+/// See [`Generator::with_on_finish`].
+type OnFinish<'a> = dyn FnMut(Option<FinishReason>, Usage) + 'a;
+
+impl<S> std::fmt::Debug for Generator<'_, '_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Generator")
+            .field("config", &self.config)
+            .field("n_past", &self.n_past)
+            .field("n_generated", &self.n_generated)
+            .field("seed", &self.seed)
+            .field("finish_reason", &self.finish_reason)
+            .field("finished", &self.finished)
+            .field("constraint", &self.constraint.is_some())
+            .field("on_token", &self.on_token.is_some())
+            .field("on_prompt_processed", &self.on_prompt_processed.is_some())
+            .field("on_finish", &self.on_finish.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, 'model, S: TokenSampler> Generator<'a, 'model, S> {
+    /// Call `on_token` with every token as it is yielded.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::{GenerationConfig, Greedy};
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+    /// let mut n_tokens = 0;
+    /// let tokens = ctx
+    ///     .generate(&prompt, Greedy, GenerationConfig::default())
+    ///     .with_on_prompt_processed(|n_prompt, duration| {
+    ///         eprintln!("processed {n_prompt} prompt tokens in {duration:?}");
+    ///     })
+    ///     .with_on_token(|_| n_tokens += 1)
+    ///     .with_on_finish(|reason, usage| {
+    ///         eprintln!("finished with {reason:?} after {} tokens", usage.total_tokens());
+    ///     })
+    ///     .collect::<Result<Vec<_>, _>>()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_on_token(mut self, on_token: impl FnMut(LlamaToken) + 'a) -> Self {
+        self.on_token = Some(Box::new(on_token));
+        self
+    }
+
+    /// Call `on_prompt_processed` once the prompt (and any forced prefix) is decoded, with the
+    /// number of tokens decoded and how long that took. See [`Self::with_on_token`] for an example.
+    #[must_use]
+    pub fn with_on_prompt_processed(
+        mut self,
+        on_prompt_processed: impl FnMut(usize, Duration) + 'a,
+    ) -> Self {
+        self.on_prompt_processed = Some(Box::new(on_prompt_processed));
+        self
+    }
+
+    /// Call `on_finish` once when the generation ends, with why it stopped (`None` after an
+    /// error) and the final token counts. See [`Self::with_on_token`] for an example.
+    #[must_use]
+    pub fn with_on_finish(
+        mut self,
+        on_finish: impl FnMut(Option<FinishReason>, Usage) + 'a,
+    ) -> Self {
+        self.on_finish = Some(Box::new(on_finish));
+        self
+    }
+
+    /// Stop the generation with [`FinishReason::Cancelled`] once `cancel` is set, e.g. from another
+    /// thread when a client disconnects. It is checked before each token.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::{FinishReason, GenerationConfig, Greedy};
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+    /// let cancel = Arc::new(AtomicBool::new(false));
+    /// let mut generator = ctx
+    ///     .generate(&prompt, Greedy, GenerationConfig::default())
+    ///     .with_cancel(Arc::clone(&cancel));
+    /// for token in generator.by_ref().take(5) {
+    ///     token?;
+    /// }
+    /// cancel.store(true, Ordering::Relaxed);
+    /// assert!(generator.next().is_none());
+    /// assert_eq!(generator.finish_reason(), Some(FinishReason::Cancelled));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Only generate tokens allowed by `constraint`, see [`constraint::TokenConstraint`]. It is
+    /// applied together with the grammar of the config, if any, and advanced with every yielded
+    /// token including the forced prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::constraint::TokenConstraint;
+    /// # use llama_cpp_2::generation::{GenerationConfig, Greedy};
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// /// Allow only tokens of the current DFA state, e.g. compiled from a regular expression.
+    /// struct Dfa {
+    ///     allowed: Vec<TokenMask>,
+    ///     transitions: Vec<std::collections::HashMap<LlamaToken, usize>>,
+    /// }
+    ///
+    /// impl TokenConstraint for Dfa {
+    ///     type State = usize;
+    ///
+    ///     fn initial_state(&self) -> usize {
+    ///         0
+    ///     }
+    ///
+    ///     fn allowed_tokens(&self, &state: &usize) -> TokenMask {
+    ///         self.allowed[state].clone()
+    ///     }
+    ///
+    ///     fn advance(&self, &state: &usize, token: LlamaToken) -> usize {
+    ///         self.transitions[state][&token]
+    ///     }
+    /// }
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+    /// # let dfa = Dfa { allowed: vec![], transitions: vec![] };
+    /// let tokens = ctx
+    ///     .generate(&prompt, Greedy, GenerationConfig::default())
+    ///     .with_constraint(dfa)
+    ///     .collect::<Result<Vec<_>, _>>()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_constraint(mut self, constraint: impl TokenConstraint + 'a) -> Self {
+        self.constraint = Some(Box::new(Constrained::new(constraint)));
+        self
+    }
+
+    /// The number of tokens yielded so far.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// The number of prompt and generated tokens so far.
+    #[must_use]
+    pub fn usage(&self) -> Usage {
+        Usage {
+            prompt_tokens: self.n_prompt,
+            completion_tokens: self.n_generated,
+        }
+    }
+
+    /// How fast the prompt was processed and the tokens were generated so far.
+    #[must_use]
+    pub fn throughput(&self) -> Throughput {
+        Throughput::new(
+            self.usage(),
+            self.prompt_eval,
+            self.elapsed.saturating_sub(self.prompt_eval),
+        )
+    }
+
+    /// The log probability of the last yielded token under the model's distribution, if it was
+    /// sampled and [`GenerationConfig::with_logprobs`] is enabled.
+    #[must_use]
+    pub fn logprob(&self) -> Option<f32> {
+        self.logprob
+    }
+
+    /// Why the generation stopped, `None` while it is still running or if it failed.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// Yield [`chunk::GenerationChunk`]s with the text of each token instead of bare tokens.
+    pub fn into_chunks(self) -> chunk::GenerationChunks<'a, 'model, S> {
+        chunk::GenerationChunks::new(self)
+    }
+
+    /// The seed of the context's random number generator for this generation, either the one of
+    /// [`GenerationConfig::with_seed`] or a random one.
+    #[must_use]
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// Give the sampler back, e.g. to inspect its final state.
+    pub fn into_sampler(self) -> S {
+        self.sampler
+    }
+
+    /// Decode `tokens` on sequence 0 after `n_past`, in chunks of at most `n_batch` tokens, with
+    /// logits for the last token only.
+    fn decode(&mut self, tokens: &[LlamaToken]) -> Result<(), GenerationError> {
+        let n_ctx = self.ctx.n_ctx() as usize;
+        let n_past = usize::try_from(self.n_past).expect("n_past is never negative");
+        if n_past + tokens.len() > n_ctx {
+            self.shift_context(n_past + tokens.len() - n_ctx)?;
+        }
+        let n_batch = self.ctx.n_batch() as usize;
+        let chunks = tokens.chunks(n_batch.max(1));
+        let n_chunks = chunks.len();
+        for (i, chunk) in chunks.enumerate() {
+            self.batch.clear();
+            for (j, &token) in chunk.iter().enumerate() {
+                let last = i + 1 == n_chunks && j + 1 == chunk.len();
+                self.batch.add(token, self.n_past, &[0], last)?;
+                self.n_past += 1;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+        Ok(())
+    }
+
+    /// Discard tokens after the first `n_keep` of [`GenerationConfig::with_context_shift`] to make
+    /// room for at least `n_needed` more.
+    fn shift_context(&mut self, n_needed: usize) -> Result<(), GenerationError> {
+        let context_full = GenerationError::ContextFull {
+            n_ctx: self.ctx.n_ctx(),
+        };
+        let Some(n_keep) = self.config.context_shift else {
+            return Err(context_full);
+        };
+        let n_past = usize::try_from(self.n_past).expect("n_past is never negative");
+        let n_keep = n_keep.min(n_past);
+        let n_discard = ((n_past - n_keep) / 2).max(n_needed);
+        if n_discard > n_past - n_keep {
+            return Err(context_full);
+        }
+        let pos = |n: usize| i32::try_from(n).expect("positions fit into an i32");
+        self.n_past = self
+            .ctx
+            .shift_kv_cache_seq(0, pos(n_keep), pos(n_discard))
+            .ok_or(context_full)?;
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Option<LlamaToken>, GenerationError> {
+        if let Some(mut prompt) = self.prompt.take() {
+            self.sampler.accept_prompt(self.ctx, &prompt);
+            prompt.extend(self.forced.iter().copied());
+            if prompt.is_empty() {
+                return Err(GenerationError::EmptyPrompt);
+            }
+            self.grammar = self.config.grammar.as_deref().map(str::parse).transpose()?;
+            if let Some(grammar) = &self.grammar {
+                check_forced_prefix(self.ctx, grammar, &self.config.forced_prefix)?;
+            }
+            self.ctx.set_rng_seed(self.seed);
+            self.ctx.clear_kv_cache_seq(0, None, None);
+            let start = Instant::now();
+            self.decode(&prompt)?;
+            self.prompt_eval = start.elapsed();
+            #[cfg(feature = "metrics")]
+            crate::metrics::prompt_decoded(prompt.len());
+            if let Some(on_prompt_processed) = &mut self.on_prompt_processed {
+                on_prompt_processed(prompt.len(), self.prompt_eval);
+            }
+        }
+        self.logprob = None;
+        if self.finish_reason.is_some() {
+            return Ok(None);
+        }
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            self.finish_reason = Some(FinishReason::Cancelled);
+            return Ok(None);
+        }
+        if self
+            .config
+            .max_tokens
+            .is_some_and(|max_tokens| self.n_generated >= max_tokens)
+        {
+            self.finish_reason = Some(FinishReason::MaxTokens);
+            return Ok(None);
+        }
+        if let Some(token) = self.forced.pop_front() {
+            self.accept(token)?;
+            return Ok(Some(token));
+        }
+        if let Some(token) = self.pending.take() {
+            match self.decode(&[token]) {
+                Err(GenerationError::ContextFull { .. }) => {
+                    self.finish_reason = Some(FinishReason::ContextFull);
+                    return Ok(None);
+                }
+                result => result?,
+            }
+        }
+
+        let last = self.batch.n_tokens() - 1;
+        let mut candidates = self.ctx.token_data_array_ith(last);
+        let log_sum_exp = self.config.logprobs.then(|| log_sum_exp(&candidates));
+        if !self.config.logit_bias.is_empty() {
+            candidates.sample_logit_bias(&self.config.logit_bias);
+        }
+        if let Some(grammar) = &self.grammar {
+            self.ctx.sample_grammar(&mut candidates, grammar);
+        }
+        if let Some(constraint) = &self.constraint {
+            candidates.sample_allow(&constraint.allowed_tokens());
+        }
+        if self.n_generated < self.config.min_tokens {
+            ban_eog_unless_only_choice(&mut candidates, &self.eog);
+        }
+        let token = {
+            #[cfg(feature = "tracing-spans")]
+            let _span =
+                tracing::debug_span!("sample", n_candidates = candidates.data.len()).entered();
+            self.sampler.sample(self.ctx, candidates)
+        };
+        if self.eog.contains(token) {
+            self.sampler.accept(self.ctx, token);
+            self.finish_reason = Some(if token == self.ctx.model.token_eos() {
+                FinishReason::Eos
+            } else {
+                FinishReason::EogToken
+            });
+            return Ok(None);
+        }
+        if let Some(log_sum_exp) = log_sum_exp {
+            let logits = self.ctx.get_logits_ith(last);
+            let logit = usize::try_from(token.0).ok().and_then(|i| logits.get(i));
+            self.logprob = logit.map(|logit| logit - log_sum_exp);
+        }
+        self.accept(token)?;
+        self.pending = Some(token);
+        Ok(Some(token))
+    }
+
+    /// Pass a token that is not an end of generation token to the grammar, the sampler and the
+    /// constraint, and check the output for stop strings. Forced tokens are not sampled with the
+    /// grammar, so it checks them before anything else sees them.
+    fn accept(&mut self, token: LlamaToken) -> Result<(), GenerationError> {
+        if let Some(grammar) = &mut self.grammar {
+            grammar.accept_token(self.ctx, token)?;
+        }
+        self.sampler.accept(self.ctx, token);
+        if let Some(constraint) = &mut self.constraint {
+            constraint.advance(token);
+        }
+        let max_stop_len = self.config.stop.iter().map(String::len).max().unwrap_or(0);
+        if max_stop_len > 0 {
+            self.ctx.model.token_to_bytes_into(token, &mut self.text)?;
+            if find_stop(&self.text, &self.config.stop).is_some() {
+                self.finish_reason = Some(FinishReason::StopString);
+            }
+            let excess = self.text.len().saturating_sub(max_stop_len - 1);
+            self.text.drain(..excess);
+        }
+        Ok(())
+    }
+}
+
+impl<S: TokenSampler> Iterator for Generator<'_, '_, S> {
+    type Item = Result<LlamaToken, GenerationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let start = Instant::now();
+        let step = self.step();
+        self.elapsed += start.elapsed();
+        if let Ok(Some(token)) = step {
+            self.n_generated += 1;
+            #[cfg(feature = "metrics")]
+            crate::metrics::token_generated();
+            if let Some(on_token) = &mut self.on_token {
+                on_token(token);
+            }
+        } else {
+            self.finished = true;
+            let usage = self.usage();
+            if let Some(on_finish) = &mut self.on_finish {
+                on_finish(self.finish_reason, usage);
+            }
+        }
+        step.transpose()
+    }
+}
+
+impl<'model> LlamaContext<'model> {
+    /// Generate tokens following `prompt`, choosing each one with `sampler`.
+    ///
+    /// The prompt is decoded on sequence 0, which is cleared first. See [`Generator`] for when
+    /// generation ends and the [module docs](crate::generation) for an example.
+    pub fn generate<S: TokenSampler>(
+        &mut self,
+        prompt: &[LlamaToken],
+        sampler: S,
+        config: GenerationConfig,
+    ) -> Generator<'_, 'model, S> {
+        let batch = LlamaBatch::new(self.n_batch() as usize, 1);
+        let eog = self.model.eog_token_mask().clone();
+        Generator {
+            forced: config.forced_prefix.iter().copied().collect(),
+            seed: config.seed.unwrap_or_else(random_seed),
+            ctx: self,
+            sampler,
+            config,
+            prompt: Some(prompt.to_vec()),
+            eog,
+            grammar: None,
+            constraint: None,
+            text: Vec::new(),
+            batch,
+            pending: None,
+            n_past: 0,
+            n_prompt: prompt.len(),
+            n_generated: 0,
+            prompt_eval: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            logprob: None,
+            finish_reason: None,
+            finished: false,
+            cancel: None,
+            on_token: None,
+            on_prompt_processed: None,
+            on_finish: None,
+        }
+    }
+
+    /// Generate tokens following `prompt`, sampling each one with a [`ParamsSampler`] built from
+    /// [`GenerationConfig::sampling`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let prompt = model.str_to_token("User: Tell me a joke.\nAssistant:", AddBos::Always)?;
+    /// let config = GenerationConfig::default()
+    ///     .with_stop(vec!["\nUser:".to_string()])
+    ///     .with_seed(Some(42));
+    /// for chunk in ctx.generate_with_config(&prompt, config).into_chunks() {
+    ///     print!("{}", chunk?.text);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn generate_with_config(
+        &mut self,
+        prompt: &[LlamaToken],
+        config: GenerationConfig,
+    ) -> Generator<'_, 'model, ParamsSampler> {
+        let sampler = ParamsSampler::new(config.sampling);
+        self.generate(prompt, sampler, config)
+    }
+
+    /// Stream a completion of `prompt` (tokenized with a BOS token) according to `config` as
+    /// pieces of text. See [`Self::generate_with_config`] and [`Generator::into_chunks`].
+    ///
+    /// # Errors
+    ///
+    /// - the prompt could not be tokenized.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::io::Write;
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let config = GenerationConfig::default()
+    ///     .with_max_tokens(Some(256))
+    ///     .with_stop(vec!["\nUser:".to_string()]);
+    /// for chunk in ctx.stream("User: Tell me a joke.\nAssistant:", config)? {
+    ///     print!("{}", chunk?.text);
+    ///     std::io::stdout().flush()?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream(
+        &mut self,
+        prompt: &str,
+        config: GenerationConfig,
+    ) -> Result<chunk::GenerationChunks<'_, 'model, ParamsSampler>, StringToTokenError> {
+        let prompt = self.model.str_to_token(prompt, AddBos::Always)?;
+        Ok(self.generate_with_config(&prompt, config).into_chunks())
+    }
+
+    /// Generate a completion of `prompt` (tokenized with a BOS token) according to `config` and
+    /// return it once the generation ends. See [`Self::generate_with_config`].
+    ///
+    /// # Errors
+    ///
+    /// See [`GenerationError`] for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let config = GenerationConfig::default()
+    ///     .with_max_tokens(Some(64))
+    ///     .with_stop(vec!["\n".to_string()]);
+    /// let completion = ctx.complete("The capital of France is", &config)?;
+    /// println!(
+    ///     "{} ({:?}, {} tokens)",
+    ///     completion.text,
+    ///     completion.finish_reason,
+    ///     completion.usage.total_tokens()
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn complete(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+    ) -> Result<CompletionResult, GenerationError> {
+        let prompt = self.model.str_to_token(prompt, AddBos::Always)?;
+        let mut text = String::new();
+        let generator = self.generate_with_config(&prompt, config.clone());
+        let seed = generator.seed();
+        for chunk in generator.into_chunks() {
+            let chunk = chunk?;
+            text.push_str(&chunk.text);
+            if let Some(finish_reason) = chunk.finish_reason {
+                return Ok(CompletionResult {
+                    text,
+                    finish_reason,
+                    usage: chunk.usage,
+                    throughput: chunk.throughput.unwrap_or_default(),
+                    seed,
+                });
+            }
+        }
+        unreachable!("the final chunk has a finish reason")
+    }
+}
+
+/// A seed from the random keys of the standard library's hash maps.
+#[allow(clippy::cast_possible_truncation)]
+fn random_seed() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// Ban the end of generation tokens unless no other candidate is left, e.g. because the grammar
+/// is complete. Sampling a token the grammar does not allow would abort llama.cpp.
+fn ban_eog_unless_only_choice(candidates: &mut LlamaTokenDataArray, eog: &TokenMask) {
+    let other_left = candidates
+        .data
+        .iter()
+        .any(|data| data.logit() != f32::NEG_INFINITY && !eog.contains(data.id()));
+    if other_left {
+        candidates.sample_ban(eog);
+    }
+}
+
+/// Check that `grammar` allows the forced prefix, before decoding the prompt with it.
+fn check_forced_prefix(
+    ctx: &mut LlamaContext,
+    grammar: &LlamaGrammar,
+    forced_prefix: &[LlamaToken],
+) -> Result<(), GrammarAcceptError> {
+    let mut grammar = grammar.clone();
+    forced_prefix
+        .iter()
+        .try_for_each(|&token| grammar.accept_token(ctx, token))
+}
+
+/// The start of the first occurrence of any of the (non-empty) `stop` strings in `text`.
+fn find_stop(text: &[u8], stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| {
+            text.windows(stop.len())
+                .position(|window| window == stop.as_bytes())
+        })
+        .min()
+}
+
+/// `ln(sum(exp(logit)))` over all candidates, computed stably.
+fn log_sum_exp(candidates: &LlamaTokenDataArray) -> f32 {
+    let max = candidates
+        .data
+        .iter()
+        .map(LlamaTokenData::logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = candidates
+        .data
+        .iter()
+        .map(|data| (data.logit() - max).exp())
+        .sum();
+    max + sum.ln()
+}
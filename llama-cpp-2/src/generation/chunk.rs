@@ -0,0 +1,253 @@
+//! Streaming generation output as text chunks, e.g. for server-sent events.
+//!
+//! With the `serde` feature [`GenerationChunk`] can be serialized directly.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::generation::{GenerationConfig, Greedy};
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+//!
+//! let config = GenerationConfig::default().with_logprobs(true);
+//! for chunk in ctx.generate(&prompt, Greedy, config).into_chunks() {
+//!     let chunk = chunk?;
+//!     print!("{}", chunk.text);
+//!     if let Some(finish_reason) = chunk.finish_reason {
+//!         println!("\n[{finish_reason:?} after {} tokens]", chunk.usage.completion_tokens);
+//!     }
+//!     if let Some(throughput) = chunk.throughput {
+//!         println!("[{:.1} tokens/s]", throughput.tokens_per_second);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::generation::{
+    find_stop, FinishReason, GenerationError, Generator, Throughput, TokenSampler, Usage,
+};
+use crate::token::decoder::incomplete_suffix_len;
+use crate::token::LlamaToken;
+
+/// A piece of generated output.
+///
+/// Every generated token produces one chunk. A final chunk without a token carries the
+/// [`FinishReason`] and any text that was held back. Text that could be the start of a stop string
+/// (see [`crate::generation::GenerationConfig::with_stop`]) is held back until it is clear that it
+/// is not, and stop strings themselves never appear in the text.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::generation::chunk::GenerationChunk;
+/// # use llama_cpp_2::generation::{FinishReason, Throughput, Usage};
+/// let chunk = GenerationChunk {
+///     text: String::new(),
+///     token: None,
+///     logprob: None,
+///     finish_reason: Some(FinishReason::Eos),
+///     usage: Usage { prompt_tokens: 5, completion_tokens: 12 },
+///     throughput: Some(Throughput {
+///         prompt_eval_ms: 50.0,
+///         eval_ms: 400.0,
+///         prompt_tokens_per_second: 100.0,
+///         tokens_per_second: 30.0,
+///     }),
+/// };
+/// # #[cfg(feature = "serde")]
+/// assert_eq!(
+///     serde_json::to_string(&chunk).unwrap(),
+///     r#"{"text":"","token":null,"logprob":null,"finish_reason":"eos","usage":{"prompt_tokens":5,"completion_tokens":12},"throughput":{"prompt_eval_ms":50.0,"eval_ms":400.0,"prompt_tokens_per_second":100.0,"tokens_per_second":30.0}}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::module_name_repetitions)]
+pub struct GenerationChunk {
+    /// The text added by this chunk. This can be empty if the token is only part of a multi-byte
+    /// character, the rest of which follows in a later chunk.
+    pub text: String,
+    /// The generated token, `None` for the final chunk.
+    pub token: Option<LlamaToken>,
+    /// The log probability of the token, see [`crate::generation::GenerationConfig::with_logprobs`].
+    pub logprob: Option<f32>,
+    /// Why the generation stopped, only set on the final chunk.
+    pub finish_reason: Option<FinishReason>,
+    /// The number of tokens processed so far.
+    pub usage: Usage,
+    /// How fast the prompt was processed and the tokens were generated, only set on the final
+    /// chunk.
+    pub throughput: Option<Throughput>,
+}
+
+/// An iterator over [`GenerationChunk`]s created by [`Generator::into_chunks`].
+#[allow(clippy::module_name_repetitions)]
+pub struct GenerationChunks<'a, 'model, S> {
+    generator: Generator<'a, 'model, S>,
+    /// Bytes of a character that is not complete yet, or that could be the start of a stop string.
+    partial: Vec<u8>,
+    /// Hold back the last grapheme cluster, see [`GenerationChunks::with_complete_graphemes`].
+    #[cfg(feature = "graphemes")]
+    complete_graphemes: bool,
+    done: bool,
+}
+
+impl<S> std::fmt::Debug for GenerationChunks<'_, '_, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenerationChunks")
+            .field("generator", &self.generator)
+            .field("partial", &self.partial)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<'a, 'model, S: TokenSampler> GenerationChunks<'a, 'model, S> {
+    pub(crate) fn new(generator: Generator<'a, 'model, S>) -> Self {
+        Self {
+            generator,
+            partial: Vec::new(),
+            #[cfg(feature = "graphemes")]
+            complete_graphemes: false,
+            done: false,
+        }
+    }
+
+    /// Only yield whole grapheme clusters, for renderers that break on partial ones.
+    ///
+    /// Chunks are already valid UTF-8, but a user perceived character can consist of several
+    /// characters: an emoji joined with zero width joiners, a letter with combining marks or a
+    /// pair of regional indicators forming a flag. With this enabled the last grapheme cluster of
+    /// the text is held back until the next token shows it is complete, so the text of a chunk
+    /// lags one cluster behind its token. The final chunk carries the rest.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::{GenerationConfig, Greedy};
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+    /// let chunks = ctx
+    ///     .generate(&prompt, Greedy, GenerationConfig::default())
+    ///     .into_chunks()
+    ///     .with_complete_graphemes(true);
+    /// for chunk in chunks {
+    ///     print!("{}", chunk?.text);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "graphemes")]
+    #[must_use]
+    pub fn with_complete_graphemes(mut self, complete_graphemes: bool) -> Self {
+        self.complete_graphemes = complete_graphemes;
+        self
+    }
+
+    /// The underlying generator.
+    #[must_use]
+    pub fn generator(&self) -> &Generator<'a, 'model, S> {
+        &self.generator
+    }
+
+    fn chunk(&mut self, token: LlamaToken) -> Result<GenerationChunk, GenerationError> {
+        self.generator
+            .ctx
+            .model
+            .token_to_bytes_into(token, &mut self.partial)?;
+        let stop = &self.generator.config.stop;
+        let text = if let Some(start) = find_stop(&self.partial, stop) {
+            // the generator stops after this token, drop the stop string and anything after it
+            let text = String::from_utf8_lossy(&self.partial[..start]).into_owned();
+            self.partial.clear();
+            text
+        } else {
+            let end = self.partial.len() - stop_prefix_len(&self.partial, stop);
+            #[allow(unused_mut)]
+            let mut complete = end - incomplete_suffix_len(&self.partial[..end]);
+            #[cfg(feature = "graphemes")]
+            if self.complete_graphemes {
+                complete = last_grapheme_start(&self.partial[..complete]);
+            }
+            let text = String::from_utf8_lossy(&self.partial[..complete]).into_owned();
+            self.partial.drain(..complete);
+            text
+        };
+        Ok(GenerationChunk {
+            text,
+            token: Some(token),
+            logprob: self.generator.logprob(),
+            finish_reason: None,
+            usage: self.generator.usage(),
+            throughput: None,
+        })
+    }
+}
+
+impl<S: TokenSampler> Iterator for GenerationChunks<'_, '_, S> {
+    type Item = Result<GenerationChunk, GenerationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.generator.next() {
+            Some(Ok(token)) => {
+                let chunk = self.chunk(token);
+                self.done = chunk.is_err();
+                Some(chunk)
+            }
+            Some(Err(err)) => {
+                self.done = true;
+                Some(Err(err))
+            }
+            None => {
+                self.done = true;
+                Some(Ok(GenerationChunk {
+                    text: String::from_utf8_lossy(&self.partial).into_owned(),
+                    token: None,
+                    logprob: None,
+                    finish_reason: self.generator.finish_reason(),
+                    usage: self.generator.usage(),
+                    throughput: Some(self.generator.throughput()),
+                }))
+            }
+        }
+    }
+}
+
+/// The length of the longest end of `text` that is the start of one of the `stop` strings.
+fn stop_prefix_len(text: &[u8], stop: &[String]) -> usize {
+    stop.iter()
+        .filter_map(|stop| {
+            (1..stop.len())
+                .rev()
+                .find(|&len| text.ends_with(&stop.as_bytes()[..len]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// The start of the last grapheme cluster of `bytes`, which could still be extended by the text
+/// that follows. All of `bytes` if they are not valid UTF-8.
+#[cfg(feature = "graphemes")]
+fn last_grapheme_start(bytes: &[u8]) -> usize {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    std::str::from_utf8(bytes).map_or(bytes.len(), |text| {
+        text.grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(start, _)| start)
+    })
+}
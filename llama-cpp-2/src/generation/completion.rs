@@ -0,0 +1,443 @@
+//! Completions with the prompt echoed and several choices per prompt, as completion style APIs
+//! return them.
+//!
+//! [`LlamaContext::complete_choices`] decodes the prompt once and then generates every choice on
+//! its own sequence, sampling one token of every unfinished choice per decode.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::num::NonZeroU32;
+//!
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::completion::CompletionOptions;
+//! use llama_cpp_2::generation::GenerationConfig;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! // one sequence per choice
+//! let params = LlamaContextParams::default().with_n_seq_max(NonZeroU32::new(3).unwrap());
+//! let mut ctx = model.new_context(&backend, params)?;
+//!
+//! let config = GenerationConfig::default()
+//!     .with_max_tokens(Some(16))
+//!     .with_logprobs(true);
+//! let options = CompletionOptions::default().with_n(3).with_echo(true);
+//! let completions = ctx.complete_choices("Once upon a time", &config, &options)?;
+//!
+//! let prompt = completions.prompt.as_ref().unwrap();
+//! println!("{} ({:?})", prompt.text, prompt.logprobs);
+//! for choice in &completions.choices {
+//!     println!("{}: {}{} ({:?})", choice.index, prompt.text, choice.text, choice.finish_reason);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::generation::sampling::ParamsSampler;
+use crate::generation::{
+    ban_eog_unless_only_choice, check_forced_prefix, find_stop, log_sum_exp, FinishReason,
+    GenerationConfig, GenerationError, TokenSampler, Usage,
+};
+use crate::grammar::LlamaGrammar;
+use crate::llama_batch::LlamaBatch;
+use crate::model::AddBos;
+use crate::token::mask::TokenMask;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// Options for [`LlamaContext::complete_choices`] on top of the [`GenerationConfig`].
+///
+/// With the `serde` feature this can be (de)serialized, with missing fields taking their default
+/// values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[allow(clippy::module_name_repetitions)]
+pub struct CompletionOptions {
+    echo: bool,
+    n: usize,
+}
+
+impl Default for CompletionOptions {
+    fn default() -> Self {
+        Self { echo: false, n: 1 }
+    }
+}
+
+impl CompletionOptions {
+    /// Return the prompt tokens, and with [`GenerationConfig::with_logprobs`] their log
+    /// probabilities, in [`Completions::prompt`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::completion::CompletionOptions;
+    /// let options = CompletionOptions::default().with_echo(true);
+    /// assert!(options.echo());
+    /// ```
+    #[must_use]
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Whether the prompt is returned.
+    #[must_use]
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Set the number of independent completions to generate, at least 1. Each needs a sequence
+    /// of the context, see [`crate::context::params::LlamaContextParams::with_n_seq_max`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::completion::CompletionOptions;
+    /// assert_eq!(CompletionOptions::default().n(), 1);
+    /// assert_eq!(CompletionOptions::default().with_n(4).n(), 4);
+    /// assert_eq!(CompletionOptions::default().with_n(0).n(), 1);
+    /// ```
+    #[must_use]
+    pub fn with_n(mut self, n: usize) -> Self {
+        self.n = n.max(1);
+        self
+    }
+
+    /// The number of completions to generate.
+    #[must_use]
+    pub fn n(&self) -> usize {
+        self.n
+    }
+}
+
+/// The prompt returned with [`CompletionOptions::with_echo`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EchoedPrompt {
+    /// The text of the prompt tokens.
+    pub text: String,
+    /// The prompt tokens, including the BOS token.
+    pub tokens: Vec<LlamaToken>,
+    /// The log probability of every prompt token given the ones before it, with
+    /// [`GenerationConfig::with_logprobs`]. The first token has none.
+    pub logprobs: Option<Vec<Option<f32>>>,
+}
+
+/// One completion of [`LlamaContext::complete_choices`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompletionChoice {
+    /// The index of the choice.
+    pub index: usize,
+    /// The generated text, without any stop string.
+    pub text: String,
+    /// The generated tokens, including any forced prefix.
+    pub tokens: Vec<LlamaToken>,
+    /// The log probability of every token, with [`GenerationConfig::with_logprobs`]. Forced
+    /// tokens have none.
+    pub logprobs: Option<Vec<Option<f32>>>,
+    /// Why the generation of this choice stopped.
+    pub finish_reason: FinishReason,
+}
+
+/// The output of [`LlamaContext::complete_choices`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Completions {
+    /// The prompt, with [`CompletionOptions::with_echo`].
+    pub prompt: Option<EchoedPrompt>,
+    /// The completions, in the order of their index.
+    pub choices: Vec<CompletionChoice>,
+    /// The number of prompt tokens and generated tokens of all choices.
+    pub usage: Usage,
+    /// The seed sampling used, see [`GenerationConfig::with_seed`].
+    pub seed: u32,
+}
+
+/// The state of a choice while it is generated.
+struct Choice {
+    sampler: ParamsSampler,
+    grammar: Option<LlamaGrammar>,
+    forced: std::vec::IntoIter<LlamaToken>,
+    tokens: Vec<LlamaToken>,
+    logprobs: Vec<Option<f32>>,
+    bytes: Vec<u8>,
+    /// The batch index of the logits to sample the next token from.
+    logits: i32,
+    finish_reason: Option<FinishReason>,
+}
+
+impl LlamaContext<'_> {
+    /// Complete `prompt` (tokenized with a BOS token) [`CompletionOptions::n`] times according to
+    /// `config`, optionally returning the prompt as well.
+    ///
+    /// Choice `i` is generated on sequence `i`, which share the cache cells of the prompt. The
+    /// sequences are cleared first. [`GenerationConfig::with_context_shift`] is not supported:
+    /// choices end with [`FinishReason::ContextFull`] once the context is full.
+    ///
+    /// # Errors
+    ///
+    /// - more choices are requested than the context has sequences.
+    /// - otherwise see [`GenerationError`].
+    ///
+    /// # Panics
+    ///
+    /// - `n_vocab` does not fit into a usize
+    ///
+    /// See the [module docs](crate::generation::completion) for an example.
+    pub fn complete_choices(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        options: &CompletionOptions,
+    ) -> Result<Completions, GenerationError> {
+        let n_seq_max = self.n_seq_max() as usize;
+        if options.n > n_seq_max {
+            return Err(GenerationError::TooManyChoices {
+                n: options.n,
+                n_seq_max,
+            });
+        }
+        let prompt = self.model.str_to_token(prompt, AddBos::Always)?;
+        if prompt.is_empty() {
+            return Err(GenerationError::EmptyPrompt);
+        }
+        if prompt.len() >= self.n_ctx() as usize {
+            return Err(GenerationError::ContextFull {
+                n_ctx: self.n_ctx(),
+            });
+        }
+
+        let grammar: Option<LlamaGrammar> =
+            config.grammar.as_deref().map(str::parse).transpose()?;
+        if let Some(grammar) = &grammar {
+            check_forced_prefix(self, grammar, &config.forced_prefix)?;
+        }
+
+        let seed = config.seed.unwrap_or_else(super::random_seed);
+        self.set_rng_seed(seed);
+        let seq_ids = (0..).take(options.n).collect::<Vec<i32>>();
+        for &seq_id in &seq_ids {
+            self.clear_kv_cache_seq(seq_id, None, None);
+        }
+        let echo_logprobs = options.echo && config.logprobs;
+        let mut batch = LlamaBatch::new(self.n_batch() as usize, 1);
+        let prompt_logprobs = self.decode_prompt(&mut batch, &prompt, echo_logprobs)?;
+        for &seq_id in &seq_ids[1..] {
+            self.copy_kv_cache_seq(0, seq_id, None, None);
+        }
+        let choices =
+            self.generate_choices(&mut batch, &prompt, config, grammar.as_ref(), options.n)?;
+
+        let usage = Usage {
+            prompt_tokens: prompt.len(),
+            completion_tokens: choices.iter().map(|choice| choice.tokens.len()).sum(),
+        };
+        let prompt = options.echo.then(|| EchoedPrompt {
+            text: String::from_utf8_lossy(&self.tokens_to_bytes(&prompt)).into_owned(),
+            tokens: prompt,
+            logprobs: prompt_logprobs,
+        });
+        let choices = choices
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut choice)| {
+                if let Some(start) = find_stop(&choice.bytes, &config.stop) {
+                    choice.bytes.truncate(start);
+                }
+                CompletionChoice {
+                    index,
+                    text: String::from_utf8_lossy(&choice.bytes).into_owned(),
+                    tokens: choice.tokens,
+                    logprobs: config.logprobs.then_some(choice.logprobs),
+                    finish_reason: choice
+                        .finish_reason
+                        .expect("every choice finishes before the loop ends"),
+                }
+            })
+            .collect();
+        Ok(Completions {
+            prompt,
+            choices,
+            usage,
+            seed,
+        })
+    }
+
+    /// Generate `n` choices following the decoded `prompt`, whose logits are the last of `batch`.
+    fn generate_choices(
+        &mut self,
+        batch: &mut LlamaBatch,
+        prompt: &[LlamaToken],
+        config: &GenerationConfig,
+        grammar: Option<&LlamaGrammar>,
+        n: usize,
+    ) -> Result<Vec<Choice>, GenerationError> {
+        let mut choices = (0..n)
+            .map(|_| {
+                let mut sampler = ParamsSampler::new(config.sampling);
+                sampler.accept_prompt(self, prompt);
+                Choice {
+                    sampler,
+                    grammar: grammar.cloned(),
+                    forced: config.forced_prefix.clone().into_iter(),
+                    tokens: Vec::new(),
+                    logprobs: Vec::new(),
+                    bytes: Vec::new(),
+                    logits: batch.n_tokens() - 1,
+                    finish_reason: None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let eog = self.model.eog_token_mask();
+        let n_ctx = self.n_ctx() as usize;
+        loop {
+            batch.clear();
+            for (seq_id, choice) in (0..).zip(&mut choices) {
+                if choice.finish_reason.is_some() {
+                    continue;
+                }
+                if let Some(token) = self.next_choice_token(choice, config, eog)? {
+                    let pos = prompt.len() + choice.tokens.len() - 1;
+                    if pos >= n_ctx {
+                        choice.finish_reason = Some(FinishReason::ContextFull);
+                    } else if choice.finish_reason.is_none() {
+                        choice.logits = batch.n_tokens();
+                        batch.add(token, pos_i32(pos), &[seq_id], true)?;
+                    }
+                }
+            }
+            if batch.n_tokens() == 0 {
+                break;
+            }
+            match self.decode(batch) {
+                Err(DecodeError::NoKvCacheSlot { .. }) => {
+                    for choice in &mut choices {
+                        choice
+                            .finish_reason
+                            .get_or_insert(FinishReason::ContextFull);
+                    }
+                    break;
+                }
+                result => result?,
+            }
+        }
+        Ok(choices)
+    }
+
+    /// Decode `prompt` on sequence 0 in chunks of at most `n_batch` tokens, with logits for the
+    /// last token, and with `logprobs` for all tokens to compute their log probabilities.
+    fn decode_prompt(
+        &mut self,
+        batch: &mut LlamaBatch,
+        prompt: &[LlamaToken],
+        logprobs: bool,
+    ) -> Result<Option<Vec<Option<f32>>>, GenerationError> {
+        let mut prompt_logprobs = logprobs.then(|| vec![None]);
+        let n_batch = self.n_batch() as usize;
+        let mut pos = 0;
+        for chunk in prompt.chunks(n_batch.max(1)) {
+            batch.clear();
+            for &token in chunk {
+                let last = pos + 1 == prompt.len();
+                batch.add(token, pos_i32(pos), &[0], logprobs || last)?;
+                pos += 1;
+            }
+            self.decode(batch)?;
+            if let Some(prompt_logprobs) = &mut prompt_logprobs {
+                let next = &prompt[pos + 1 - chunk.len()..];
+                for (i, &token) in (0..).zip(next.iter().take(chunk.len())) {
+                    prompt_logprobs.push(token_logprob(self.get_logits_ith(i), token));
+                }
+            }
+        }
+        Ok(prompt_logprobs)
+    }
+
+    /// Choose the next token of `choice`, from its forced prefix or by sampling, and check whether
+    /// the choice is finished. Returns the token if it needs to be decoded.
+    fn next_choice_token(
+        &mut self,
+        choice: &mut Choice,
+        config: &GenerationConfig,
+        eog: &TokenMask,
+    ) -> Result<Option<LlamaToken>, GenerationError> {
+        if config
+            .max_tokens
+            .is_some_and(|max_tokens| choice.tokens.len() >= max_tokens)
+        {
+            choice.finish_reason = Some(FinishReason::MaxTokens);
+            return Ok(None);
+        }
+        let (token, logprob) = if let Some(token) = choice.forced.next() {
+            (token, None)
+        } else {
+            let mut candidates = self.token_data_array_ith(choice.logits);
+            let log_sum_exp = config.logprobs.then(|| log_sum_exp(&candidates));
+            if !config.logit_bias.is_empty() {
+                candidates.sample_logit_bias(&config.logit_bias);
+            }
+            if let Some(grammar) = &choice.grammar {
+                self.sample_grammar(&mut candidates, grammar);
+            }
+            if choice.tokens.len() < config.min_tokens {
+                ban_eog_unless_only_choice(&mut candidates, eog);
+            }
+            let token = choice.sampler.sample(self, candidates);
+            if eog.contains(token) {
+                choice.sampler.accept(self, token);
+                choice.finish_reason = Some(if token == self.model.token_eos() {
+                    FinishReason::Eos
+                } else {
+                    FinishReason::EogToken
+                });
+                return Ok(None);
+            }
+            let logprob = log_sum_exp.and_then(|log_sum_exp| {
+                let logits = self.get_logits_ith(choice.logits);
+                let logit = usize::try_from(token.0).ok().and_then(|i| logits.get(i));
+                logit.map(|logit| logit - log_sum_exp)
+            });
+            (token, logprob)
+        };
+
+        // forced tokens are not sampled with the grammar, so check them before the sampler
+        if let Some(grammar) = &mut choice.grammar {
+            grammar.accept_token(self, token)?;
+        }
+        choice.sampler.accept(self, token);
+        choice.tokens.push(token);
+        choice.logprobs.push(logprob);
+        self.model.token_to_bytes_into(token, &mut choice.bytes)?;
+        if find_stop(&choice.bytes, &config.stop).is_some() {
+            choice.finish_reason = Some(FinishReason::StopString);
+        }
+        Ok(Some(token))
+    }
+
+    fn tokens_to_bytes(&self, tokens: &[LlamaToken]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &token in tokens {
+            // tokens without a text representation are left out of the echoed text
+            let _ = self.model.token_to_bytes_into(token, &mut bytes);
+        }
+        bytes
+    }
+}
+
+/// The log softmax of `logits` at `token`, `None` if it is not in the vocabulary.
+fn token_logprob(logits: &[f32], LlamaToken(token): LlamaToken) -> Option<f32> {
+    let logit = *usize::try_from(token).ok().and_then(|i| logits.get(i))?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&logit| (logit - max).exp()).sum();
+    Some(logit - max - sum.ln())
+}
+
+fn pos_i32(pos: usize) -> i32 {
+    i32::try_from(pos).expect("position should fit into an i32")
+}
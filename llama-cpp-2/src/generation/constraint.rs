@@ -0,0 +1,105 @@
+//! Constraining generation with a state machine over tokens.
+//!
+//! [`TokenConstraint`] lets external libraries (such as DFAs compiled from regular expressions or
+//! JSON schemas, outlines-style) or custom validators restrict which tokens may be generated,
+//! without implementing a [`TokenSampler`](crate::generation::TokenSampler). Pass one to
+//! [`Generator::with_constraint`](crate::generation::Generator::with_constraint) and it is applied
+//! before the sampler at every step.
+
+use crate::token::mask::TokenMask;
+use crate::token::LlamaToken;
+
+/// A state machine deciding which tokens may be generated next.
+///
+/// The constraint itself is immutable, the generator keeps the current state and advances it with
+/// every generated or forced token. The end of generation tokens are treated like any other token,
+/// so allow them in the states where the output may end.
+///
+/// # Examples
+///
+/// Allow at most `max_digits` digit tokens, then end the output:
+///
+/// ```
+/// # use llama_cpp_2::generation::constraint::TokenConstraint;
+/// # use llama_cpp_2::token::mask::TokenMask;
+/// # use llama_cpp_2::token::LlamaToken;
+/// struct Digits {
+///     digits: TokenMask,
+///     eos: LlamaToken,
+///     max_digits: usize,
+/// }
+///
+/// impl TokenConstraint for Digits {
+///     /// The number of digit tokens so far.
+///     type State = usize;
+///
+///     fn initial_state(&self) -> usize {
+///         0
+///     }
+///
+///     fn allowed_tokens(&self, &n_digits: &usize) -> TokenMask {
+///         let mut allowed = if n_digits < self.max_digits { self.digits.clone() } else { TokenMask::new() };
+///         if n_digits > 0 {
+///             allowed.insert(self.eos);
+///         }
+///         allowed
+///     }
+///
+///     fn advance(&self, &n_digits: &usize, _token: LlamaToken) -> usize {
+///         n_digits + 1
+///     }
+/// }
+///
+/// let digits = Digits { digits: (10..20).map(LlamaToken).collect(), eos: LlamaToken(2), max_digits: 2 };
+/// let state = digits.initial_state();
+/// assert!(!digits.allowed_tokens(&state).contains(LlamaToken(2)));
+/// let state = digits.advance(&state, LlamaToken(11));
+/// let state = digits.advance(&state, LlamaToken(12));
+/// assert_eq!(digits.allowed_tokens(&state).iter().collect::<Vec<_>>(), [LlamaToken(2)]);
+/// ```
+pub trait TokenConstraint {
+    /// The state of the machine, e.g. a DFA state index.
+    type State;
+
+    /// The state before any token was generated.
+    fn initial_state(&self) -> Self::State;
+
+    /// The tokens that may be generated in `state`. Must not be empty while the generation can
+    /// continue.
+    fn allowed_tokens(&self, state: &Self::State) -> TokenMask;
+
+    /// The state after `token` was generated in `state`.
+    fn advance(&self, state: &Self::State, token: LlamaToken) -> Self::State;
+}
+
+/// A [`TokenConstraint`] with its current state, so generators can hold any constraint.
+pub(crate) trait ConstraintState {
+    /// The tokens that may be generated next.
+    fn allowed_tokens(&self) -> TokenMask;
+
+    /// Advance the state with a generated or forced token.
+    fn advance(&mut self, token: LlamaToken);
+}
+
+/// The [`ConstraintState`] of a [`TokenConstraint`].
+pub(crate) struct Constrained<C: TokenConstraint> {
+    constraint: C,
+    state: C::State,
+}
+
+impl<C: TokenConstraint> Constrained<C> {
+    pub(crate) fn new(constraint: C) -> Self {
+        let state = constraint.initial_state();
+        Self { constraint, state }
+    }
+}
+
+impl<C: TokenConstraint> ConstraintState for Constrained<C> {
+    fn allowed_tokens(&self) -> TokenMask {
+        self.constraint.allowed_tokens(&self.state)
+    }
+
+    fn advance(&mut self, token: LlamaToken) {
+        self.state = self.constraint.advance(&self.state, token);
+    }
+}
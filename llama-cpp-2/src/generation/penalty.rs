@@ -0,0 +1,330 @@
+//! Repetition penalties over a configurable window of previous tokens.
+//!
+//! [`RepetitionPenalty`] wraps another [`TokenSampler`] and keeps the [`TokenHistory`] it needs up
+//! to date from the prompt and every accepted token.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::generation::penalty::{PenaltyWindow, RepetitionPenalty};
+//! use llama_cpp_2::generation::{GenerationConfig, Greedy};
+//! use llama_cpp_2::model::AddBos;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! # let prompt = model.str_to_token("<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n", AddBos::Always)?;
+//!
+//! // only penalize repeating what was said since the user last spoke
+//! let mut sampler = RepetitionPenalty::new(Greedy, PenaltyWindow::SinceLastUserTurn)
+//!     .with_penalty_repeat(1.15);
+//! sampler
+//!     .history_mut()
+//!     .set_user_turn_marker(model.str_to_token("<|im_start|>user", AddBos::Never)?);
+//!
+//! for token in ctx.generate(&prompt, sampler, GenerationConfig::default()) {
+//!     print!("{}", model.token_to_str(token?)?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+
+use llama_cpp_sys_2::llama_token;
+
+use crate::context::LlamaContext;
+use crate::generation::TokenSampler;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// Which previous tokens a repetition penalty considers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PenaltyWindow {
+    /// Every token in the context, including the prompt.
+    Context,
+    /// The last `n` tokens.
+    LastN(usize),
+    /// The tokens since the start of the last user turn (see [`TokenHistory::mark_user_turn`]), or
+    /// the whole context if there has not been one.
+    SinceLastUserTurn,
+}
+
+/// A ring buffer of the most recent tokens that also remembers where the last user turn started.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::generation::penalty::{PenaltyWindow, TokenHistory};
+/// # use llama_cpp_2::token::LlamaToken;
+/// let mut history = TokenHistory::new(4);
+/// history.set_user_turn_marker(vec![LlamaToken(7), LlamaToken(8)]);
+///
+/// history.extend([1, 7, 8, 2, 3].map(LlamaToken));
+/// assert_eq!(history.window(PenaltyWindow::Context), [7, 8, 2, 3].map(LlamaToken));
+/// assert_eq!(history.window(PenaltyWindow::LastN(1)), [LlamaToken(3)]);
+/// assert_eq!(history.window(PenaltyWindow::SinceLastUserTurn), [2, 3].map(LlamaToken));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenHistory {
+    tokens: VecDeque<LlamaToken>,
+    capacity: usize,
+    /// The number of tokens ever pushed.
+    n_pushed: usize,
+    /// The value of `n_pushed` when the last user turn started.
+    user_turn_start: Option<usize>,
+    user_turn_marker: Vec<LlamaToken>,
+}
+
+impl TokenHistory {
+    /// Create an empty history holding at most `capacity` tokens.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tokens: VecDeque::with_capacity(capacity),
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// The maximum number of tokens kept.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the maximum number of tokens kept, dropping the oldest ones if needed.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let excess = self.tokens.len().saturating_sub(capacity);
+        self.tokens.drain(..excess);
+    }
+
+    /// Automatically call [`Self::mark_user_turn`] after every occurrence of `marker`, e.g. the
+    /// tokens of `<|im_start|>user` for `ChatML`.
+    pub fn set_user_turn_marker(&mut self, marker: Vec<LlamaToken>) {
+        self.user_turn_marker = marker;
+    }
+
+    /// Record that a user turn starts with the next token pushed.
+    pub fn mark_user_turn(&mut self) {
+        self.user_turn_start = Some(self.n_pushed);
+    }
+
+    /// Add a token, dropping the oldest one if the history is full.
+    pub fn push(&mut self, token: LlamaToken) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.tokens.len() == self.capacity {
+            self.tokens.pop_front();
+        }
+        self.tokens.push_back(token);
+        self.n_pushed += 1;
+
+        let marker = &self.user_turn_marker;
+        if !marker.is_empty()
+            && self.tokens.len() >= marker.len()
+            && self
+                .tokens
+                .iter()
+                .rev()
+                .zip(marker.iter().rev())
+                .all(|(a, b)| a == b)
+        {
+            self.mark_user_turn();
+        }
+    }
+
+    /// Remove all tokens and forget the last user turn.
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.n_pushed = 0;
+        self.user_turn_start = None;
+    }
+
+    /// The number of tokens in the history.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Is the history empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The most recent tokens that fall within `window`, oldest first.
+    pub fn window(&mut self, window: PenaltyWindow) -> &[LlamaToken] {
+        let len = match window {
+            PenaltyWindow::Context => self.tokens.len(),
+            PenaltyWindow::LastN(n) => n,
+            PenaltyWindow::SinceLastUserTurn => self
+                .user_turn_start
+                .map_or(self.tokens.len(), |start| self.n_pushed - start),
+        };
+        let tokens = self.tokens.make_contiguous();
+        &tokens[tokens.len().saturating_sub(len)..]
+    }
+}
+
+impl Extend<LlamaToken> for TokenHistory {
+    fn extend<T: IntoIterator<Item = LlamaToken>>(&mut self, iter: T) {
+        for token in iter {
+            self.push(token);
+        }
+    }
+}
+
+/// Apply repetition, frequency and presence penalties over a [`PenaltyWindow`] before sampling
+/// with another [`TokenSampler`].
+///
+/// See [`LlamaTokenDataArray::sample_repetition_penalty`] for how the penalties work.
+#[derive(Debug, Clone)]
+pub struct RepetitionPenalty<S> {
+    inner: S,
+    window: PenaltyWindow,
+    history: TokenHistory,
+    penalty_repeat: f32,
+    penalty_freq: f32,
+    penalty_present: f32,
+}
+
+impl<S> RepetitionPenalty<S> {
+    /// Penalize tokens in `window` with a repetition penalty of 1.1 before sampling with `inner`.
+    ///
+    /// With [`PenaltyWindow::Context`] and [`PenaltyWindow::SinceLastUserTurn`] the history is
+    /// sized to the context it is used with.
+    #[must_use]
+    pub fn new(inner: S, window: PenaltyWindow) -> Self {
+        let capacity = match window {
+            PenaltyWindow::LastN(n) => n,
+            PenaltyWindow::Context | PenaltyWindow::SinceLastUserTurn => 0,
+        };
+        Self {
+            inner,
+            window,
+            history: TokenHistory::new(capacity),
+            penalty_repeat: 1.1,
+            penalty_freq: 0.0,
+            penalty_present: 0.0,
+        }
+    }
+
+    /// Set the repetition penalty (1.0 for no penalty).
+    #[must_use]
+    pub fn with_penalty_repeat(mut self, penalty_repeat: f32) -> Self {
+        self.penalty_repeat = penalty_repeat;
+        self
+    }
+
+    /// Set the frequency penalty (0.0 for no penalty).
+    #[must_use]
+    pub fn with_penalty_freq(mut self, penalty_freq: f32) -> Self {
+        self.penalty_freq = penalty_freq;
+        self
+    }
+
+    /// Set the presence penalty (0.0 for no penalty).
+    #[must_use]
+    pub fn with_penalty_present(mut self, penalty_present: f32) -> Self {
+        self.penalty_present = penalty_present;
+        self
+    }
+
+    /// The window of tokens that are penalized.
+    #[must_use]
+    pub fn window(&self) -> PenaltyWindow {
+        self.window
+    }
+
+    /// The tokens seen so far.
+    #[must_use]
+    pub fn history(&self) -> &TokenHistory {
+        &self.history
+    }
+
+    /// The tokens seen so far, e.g. to mark user turns.
+    pub fn history_mut(&mut self) -> &mut TokenHistory {
+        &mut self.history
+    }
+
+    /// Give back the wrapped sampler.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn fit_history(&mut self, ctx: &LlamaContext) {
+        if !matches!(self.window, PenaltyWindow::LastN(_)) {
+            self.history.set_capacity(ctx.n_ctx() as usize);
+        }
+    }
+}
+
+impl<S: TokenSampler> TokenSampler for RepetitionPenalty<S> {
+    fn sample(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut candidates: LlamaTokenDataArray,
+    ) -> LlamaToken {
+        let last_tokens = self.history.window(self.window);
+        if !last_tokens.is_empty() {
+            penalize(
+                ctx,
+                &mut candidates,
+                last_tokens,
+                self.penalty_repeat,
+                self.penalty_freq,
+                self.penalty_present,
+            );
+        }
+        self.inner.sample(ctx, candidates)
+    }
+
+    fn accept(&mut self, ctx: &mut LlamaContext, token: LlamaToken) {
+        self.fit_history(ctx);
+        self.history.push(token);
+        self.inner.accept(ctx, token);
+    }
+
+    fn accept_prompt(&mut self, ctx: &mut LlamaContext, prompt: &[LlamaToken]) {
+        self.fit_history(ctx);
+        self.history.extend(prompt.iter().copied());
+        self.inner.accept_prompt(ctx, prompt);
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.inner.reset();
+    }
+}
+
+/// Apply the penalties to every token in `last_tokens` (unlike
+/// [`LlamaTokenDataArray::sample_repetition_penalty`], which skips the last one).
+pub(crate) fn penalize(
+    ctx: &mut LlamaContext,
+    candidates: &mut LlamaTokenDataArray,
+    last_tokens: &[LlamaToken],
+    penalty_repeat: f32,
+    penalty_freq: f32,
+    penalty_present: f32,
+) {
+    let ctx_ptr = ctx.context.as_ptr();
+    unsafe {
+        candidates.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
+            llama_cpp_sys_2::llama_sample_repetition_penalties(
+                ctx_ptr,
+                c_llama_token_data_array,
+                // safe cast as LlamaToken is repr(transparent)
+                last_tokens.as_ptr().cast::<llama_token>(),
+                last_tokens.len(),
+                penalty_repeat,
+                penalty_freq,
+                penalty_present,
+            );
+        });
+    }
+}
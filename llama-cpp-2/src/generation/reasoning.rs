@@ -0,0 +1,334 @@
+//! Separating the reasoning of reasoning models from their final answer.
+//!
+//! Reasoning models think out loud between tags such as `<think>` and `</think>` before they
+//! answer. A [`ReasoningParser`] splits their output into [`ChunkKind::Reasoning`] and
+//! [`ChunkKind::Content`], either all at once with [`ReasoningParser::parse`] or while streaming
+//! with [`ReasoningParser::push`]. Text that could be the start of a tag is held back until it is
+//! clear whether it is one, and the tags themselves never appear in the output.
+//!
+//! # Examples
+//!
+//! ```
+//! use llama_cpp_2::generation::reasoning::{ChunkKind, ReasoningParser};
+//!
+//! let parsed = ReasoningParser::new().parse("<think>\nIt is 4.\n</think>\n\n2 + 2 = 4");
+//! assert_eq!(parsed.reasoning.as_deref(), Some("It is 4.\n"));
+//! assert_eq!(parsed.content, "2 + 2 = 4");
+//!
+//! // streaming, the tags may be split across chunks
+//! let mut parser = ReasoningParser::new();
+//! let mut chunks = Vec::new();
+//! for text in ["<th", "ink>Hmm", ".</thi", "nk>Yes."] {
+//!     chunks.extend(parser.push(text));
+//! }
+//! chunks.extend(parser.finish());
+//! let chunks: Vec<_> = chunks.iter().map(|chunk| (chunk.kind, chunk.text.as_str())).collect();
+//! assert_eq!(
+//!     chunks,
+//!     [
+//!         (ChunkKind::Reasoning, "Hmm"),
+//!         (ChunkKind::Reasoning, "."),
+//!         (ChunkKind::Content, "Yes.")
+//!     ]
+//! );
+//! ```
+//!
+//! With a [`crate::generation::Generator`]:
+//!
+//! ```no_run
+//! use llama_cpp_2::generation::reasoning::{ChunkKind, ReasoningParser};
+//! use llama_cpp_2::generation::GenerationConfig;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+//!
+//! let mut parser = ReasoningParser::new();
+//! for chunk in ctx.generate_with_config(&prompt, GenerationConfig::default()).into_chunks() {
+//!     let chunk = chunk?;
+//!     let mut parsed = parser.push(&chunk.text);
+//!     if chunk.finish_reason.is_some() {
+//!         parsed.extend(parser.finish());
+//!     }
+//!     for parsed in parsed {
+//!         match parsed.kind {
+//!             ChunkKind::Reasoning => eprint!("{}", parsed.text),
+//!             ChunkKind::Content => print!("{}", parsed.text),
+//!         }
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+/// A pair of tags a model puts around its reasoning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ReasoningFormat {
+    /// `<think>` and `</think>`, used by `DeepSeek` R1, `QwQ` and Qwen 3.
+    Think,
+    /// `<thinking>` and `</thinking>`.
+    Thinking,
+    /// `<|START_THINKING|>` and `<|END_THINKING|>`, used by Command R7B.
+    CommandR,
+    /// `[THINK]` and `[/THINK]`, used by Magistral.
+    Mistral,
+}
+
+impl ReasoningFormat {
+    /// Every known format, in the order [`ReasoningParser::new`] tries them.
+    pub const ALL: [Self; 4] = [Self::Think, Self::Thinking, Self::CommandR, Self::Mistral];
+
+    /// The tag opening the reasoning.
+    #[must_use]
+    pub fn open(self) -> &'static str {
+        match self {
+            Self::Think => "<think>",
+            Self::Thinking => "<thinking>",
+            Self::CommandR => "<|START_THINKING|>",
+            Self::Mistral => "[THINK]",
+        }
+    }
+
+    /// The tag closing the reasoning.
+    #[must_use]
+    pub fn close(self) -> &'static str {
+        match self {
+            Self::Think => "</think>",
+            Self::Thinking => "</thinking>",
+            Self::CommandR => "<|END_THINKING|>",
+            Self::Mistral => "[/THINK]",
+        }
+    }
+}
+
+/// Whether a [`ReasoningChunk`] is part of the reasoning or of the answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChunkKind {
+    /// Text between the reasoning tags.
+    Reasoning,
+    /// Everything else, the answer.
+    Content,
+}
+
+/// A piece of output of a [`ReasoningParser`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReasoningChunk {
+    /// Whether the text is reasoning or answer.
+    pub kind: ChunkKind,
+    /// The text, never empty.
+    pub text: String,
+}
+
+/// The output of [`ReasoningParser::parse`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParsedReasoning {
+    /// All reasoning, `None` if the model did not reason.
+    pub reasoning: Option<String>,
+    /// The answer.
+    pub content: String,
+}
+
+/// Splits (streamed) model output into reasoning and answer.
+///
+/// Whitespace directly after a tag is dropped, as models put new lines around their tags.
+#[derive(Debug, Clone)]
+pub struct ReasoningParser {
+    /// The formats whose opening tag starts reasoning.
+    formats: Vec<ReasoningFormat>,
+    /// The format of the current or last reasoning.
+    active: Option<ReasoningFormat>,
+    in_reasoning: bool,
+    /// Drop whitespace until the next non-whitespace text, as a tag was just passed.
+    trim_start: bool,
+    /// Text that could be the start of a tag.
+    pending: String,
+}
+
+impl Default for ReasoningParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReasoningParser {
+    /// A parser recognizing all of [`ReasoningFormat::ALL`]. The first opening tag in the output
+    /// decides the format.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_formats(ReasoningFormat::ALL.to_vec())
+    }
+
+    /// A parser only recognizing the tags of `format`.
+    #[must_use]
+    pub fn for_format(format: ReasoningFormat) -> Self {
+        Self::with_formats(vec![format])
+    }
+
+    fn with_formats(formats: Vec<ReasoningFormat>) -> Self {
+        Self {
+            formats,
+            active: None,
+            in_reasoning: false,
+            trim_start: false,
+            pending: String::new(),
+        }
+    }
+
+    /// Start inside the reasoning of the first format, for chat templates that end the prompt
+    /// with the opening tag (such as the `DeepSeek` R1 template), so the output only contains the
+    /// closing tag.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::generation::reasoning::{ReasoningFormat, ReasoningParser};
+    /// let parsed = ReasoningParser::for_format(ReasoningFormat::Think)
+    ///     .with_reasoning_started(true)
+    ///     .parse("Easy.</think>Paris");
+    /// assert_eq!(parsed.reasoning.as_deref(), Some("Easy."));
+    /// assert_eq!(parsed.content, "Paris");
+    /// ```
+    #[must_use]
+    pub fn with_reasoning_started(mut self, started: bool) -> Self {
+        self.in_reasoning = started;
+        self.active = started.then(|| self.formats[0]);
+        self
+    }
+
+    /// The format of the current or last reasoning, `None` if there was no reasoning yet.
+    #[must_use]
+    pub fn format(&self) -> Option<ReasoningFormat> {
+        self.active
+    }
+
+    /// Whether the text pushed so far ends inside the reasoning.
+    #[must_use]
+    pub fn in_reasoning(&self) -> bool {
+        self.in_reasoning
+    }
+
+    /// Parse the next piece of streamed output. Text that could be the start of a tag is held back
+    /// until the next call, or until [`Self::finish`].
+    pub fn push(&mut self, text: &str) -> Vec<ReasoningChunk> {
+        self.pending.push_str(text);
+        let mut chunks = Vec::new();
+        while let Some((start, end, format)) = self.next_tag() {
+            let text = self.pending[..start].to_string();
+            self.emit(&mut chunks, &text);
+            self.pending.drain(..end);
+            self.active = Some(format);
+            self.in_reasoning = !self.in_reasoning;
+            self.trim_start = true;
+        }
+        let end = self.pending.len() - self.tag_prefix_len();
+        let text: String = self.pending.drain(..end).collect();
+        self.emit(&mut chunks, &text);
+        chunks
+    }
+
+    /// Flush any text held back at the end of the output.
+    pub fn finish(&mut self) -> Vec<ReasoningChunk> {
+        let text = std::mem::take(&mut self.pending);
+        let mut chunks = Vec::new();
+        self.emit(&mut chunks, &text);
+        chunks
+    }
+
+    /// Split complete output into reasoning and answer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::generation::reasoning::ReasoningParser;
+    /// let parsed = ReasoningParser::new().parse("<|START_THINKING|>Short.<|END_THINKING|>Done");
+    /// assert_eq!(parsed.reasoning.as_deref(), Some("Short."));
+    /// assert_eq!(parsed.content, "Done");
+    ///
+    /// let parsed = ReasoningParser::new().parse("No reasoning here.");
+    /// assert_eq!(parsed.reasoning, None);
+    /// ```
+    #[must_use]
+    pub fn parse(mut self, text: &str) -> ParsedReasoning {
+        let mut chunks = self.push(text);
+        chunks.extend(self.finish());
+        let mut parsed = ParsedReasoning::default();
+        for chunk in chunks {
+            match chunk.kind {
+                ChunkKind::Reasoning => parsed
+                    .reasoning
+                    .get_or_insert_with(String::new)
+                    .push_str(&chunk.text),
+                ChunkKind::Content => parsed.content.push_str(&chunk.text),
+            }
+        }
+        parsed
+    }
+
+    /// The tags that can come next.
+    fn tags(&self) -> Vec<(&'static str, ReasoningFormat)> {
+        match (self.in_reasoning, self.active) {
+            (true, Some(format)) => vec![(format.close(), format)],
+            (false, Some(format)) => vec![(format.open(), format)],
+            (_, None) => self
+                .formats
+                .iter()
+                .map(|&format| (format.open(), format))
+                .collect(),
+        }
+    }
+
+    /// The start and end of the first tag in the pending text.
+    fn next_tag(&self) -> Option<(usize, usize, ReasoningFormat)> {
+        self.tags()
+            .into_iter()
+            .filter_map(|(tag, format)| {
+                let start = self.pending.find(tag)?;
+                Some((start, start + tag.len(), format))
+            })
+            .min_by_key(|&(start, _, _)| start)
+    }
+
+    /// The length of the longest end of the pending text that is the start of a tag.
+    fn tag_prefix_len(&self) -> usize {
+        self.tags()
+            .into_iter()
+            .filter_map(|(tag, _)| {
+                (1..tag.len())
+                    .rev()
+                    .find(|&len| self.pending.ends_with(&tag[..len]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn emit(&mut self, chunks: &mut Vec<ReasoningChunk>, mut text: &str) {
+        if self.trim_start {
+            text = text.trim_start();
+            self.trim_start = text.is_empty();
+        }
+        if text.is_empty() {
+            return;
+        }
+        let kind = if self.in_reasoning {
+            ChunkKind::Reasoning
+        } else {
+            ChunkKind::Content
+        };
+        match chunks.last_mut() {
+            Some(last) if last.kind == kind => last.text.push_str(text),
+            _ => chunks.push(ReasoningChunk {
+                kind,
+                text: text.to_string(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Completions checked by a validation closure and retried on rejection.
+//!
+//! [`LlamaContext::complete_validated`] passes each finished completion to a closure. If the
+//! closure rejects the text the completion is generated again with a different seed and (per
+//! [`RetryPolicy::with_temperature_step`]) a higher temperature, until the closure accepts one or
+//! the retries run out.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::generation::retry::{RetryPolicy, ValidatedCompletionError};
+//! use llama_cpp_2::generation::GenerationConfig;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let config = GenerationConfig::default().with_stop(vec!["\n".to_string()]);
+//! let retry = RetryPolicy::default().with_max_retries(4);
+//! let is_number = |text: &str| text.trim().parse::<u32>().is_ok();
+//! match ctx.complete_validated("2 + 2 =", &config, &retry, is_number) {
+//!     Ok(completion) => println!("{}", completion.text),
+//!     Err(ValidatedCompletionError::Rejected { attempts }) => {
+//!         eprintln!("no valid answer in {} attempts", attempts.len());
+//!     }
+//!     Err(err) => return Err(err.into()),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::generation::{CompletionResult, GenerationConfig, GenerationError};
+
+/// How [`LlamaContext::complete_validated`] retries rejected completions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RetryPolicy {
+    max_retries: usize,
+    temperature_step: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            temperature_step: 0.1,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set how often a rejected completion is generated again, so there are at most
+    /// `max_retries + 1` attempts. Defaults to 2.
+    #[must_use]
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// How often a rejected completion is generated again.
+    #[must_use]
+    pub fn max_retries(&self) -> usize {
+        self.max_retries
+    }
+
+    /// Set how much the temperature is raised with every retry. Defaults to 0.1.
+    ///
+    /// A different seed alone does not change the completion of greedy sampling (a temperature of
+    /// 0.0), so keep this positive when retrying greedy configs.
+    #[must_use]
+    pub fn with_temperature_step(mut self, temperature_step: f32) -> Self {
+        self.temperature_step = temperature_step;
+        self
+    }
+
+    /// How much the temperature is raised with every retry.
+    #[must_use]
+    pub fn temperature_step(&self) -> f32 {
+        self.temperature_step
+    }
+
+    /// The config of attempt `attempt` (starting at 0 for the first attempt) of generating with
+    /// `config`.
+    ///
+    /// Retries raise the temperature by [`Self::temperature_step`] each. A fixed seed is
+    /// incremented with every retry so the attempts stay reproducible, otherwise every attempt gets
+    /// a random seed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::generation::retry::RetryPolicy;
+    /// # use llama_cpp_2::generation::GenerationConfig;
+    /// # use llama_cpp_2::generation::sampling::SamplingParams;
+    /// let sampling = SamplingParams {
+    ///     temperature: 0.0,
+    ///     ..SamplingParams::default()
+    /// };
+    /// let config = GenerationConfig::default()
+    ///     .with_sampling(sampling)
+    ///     .with_seed(Some(7));
+    /// let retry = RetryPolicy::default().with_temperature_step(0.25);
+    ///
+    /// assert_eq!(retry.config_for_attempt(&config, 0), config);
+    /// let second = retry.config_for_attempt(&config, 2);
+    /// assert_eq!(second.seed(), Some(9));
+    /// assert_eq!(second.sampling().temperature, 0.5);
+    /// ```
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn config_for_attempt(
+        &self,
+        config: &GenerationConfig,
+        attempt: usize,
+    ) -> GenerationConfig {
+        if attempt == 0 {
+            return config.clone();
+        }
+        let mut sampling = *config.sampling();
+        sampling.temperature =
+            sampling.temperature.max(0.0) + self.temperature_step * attempt as f32;
+        #[allow(clippy::cast_possible_truncation)]
+        let seed = config.seed().map(|seed| seed.wrapping_add(attempt as u32));
+        config.clone().with_sampling(sampling).with_seed(seed)
+    }
+}
+
+/// Failed to generate a completion accepted by the validation closure of
+/// [`LlamaContext::complete_validated`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ValidatedCompletionError {
+    /// Generating an attempt failed.
+    #[error("{0}")]
+    Generation(#[from] GenerationError),
+    /// Every attempt was rejected.
+    #[error("all {} attempts were rejected", attempts.len())]
+    Rejected {
+        /// The rejected completions, in the order they were generated.
+        attempts: Vec<CompletionResult>,
+    },
+}
+
+impl LlamaContext<'_> {
+    /// Generate a completion of `prompt` like [`Self::complete`] and pass its text to `validate`,
+    /// generating it again according to `retry` until `validate` returns `true`. See the
+    /// [module docs](crate::generation::retry) for an example.
+    ///
+    /// # Errors
+    ///
+    /// - generating an attempt failed.
+    /// - `validate` rejected every attempt, which are returned in
+    ///   [`ValidatedCompletionError::Rejected`].
+    pub fn complete_validated(
+        &mut self,
+        prompt: &str,
+        config: &GenerationConfig,
+        retry: &RetryPolicy,
+        mut validate: impl FnMut(&str) -> bool,
+    ) -> Result<CompletionResult, ValidatedCompletionError> {
+        let mut attempts = Vec::new();
+        for attempt in 0..=retry.max_retries {
+            let completion = self.complete(prompt, &retry.config_for_attempt(config, attempt))?;
+            if validate(&completion.text) {
+                return Ok(completion);
+            }
+            attempts.push(completion);
+        }
+        Err(ValidatedCompletionError::Rejected { attempts })
+    }
+}
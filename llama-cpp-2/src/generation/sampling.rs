@@ -0,0 +1,232 @@
+//! The standard sampling steps, configured by plain values.
+//!
+//! [`SamplingParams`] is part of [`GenerationConfig`] so it can be loaded from a settings file
+//! together with the rest of the generation options. [`LlamaContext::generate_with_config`] samples
+//! with a [`ParamsSampler`] built from it.
+//!
+//! [`GenerationConfig`]: crate::generation::GenerationConfig
+//! [`LlamaContext::generate_with_config`]: crate::context::LlamaContext::generate_with_config
+
+use crate::context::LlamaContext;
+use crate::generation::penalty::{self, PenaltyWindow, TokenHistory};
+use crate::generation::TokenSampler;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// Parameters of the standard sampling steps, applied in the order of the fields.
+///
+/// The defaults match the llama.cpp examples. Logit biases are part of
+/// [`GenerationConfig`](crate::generation::GenerationConfig::with_logit_bias), as they are applied
+/// before any sampler.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::generation::sampling::SamplingParams;
+/// let params = SamplingParams {
+///     temperature: 0.2,
+///     top_k: 0,
+///     ..SamplingParams::default()
+/// };
+/// assert_eq!(params.top_p, 0.95);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[allow(clippy::module_name_repetitions)]
+pub struct SamplingParams {
+    /// The number of previous tokens (including the prompt) the penalties consider, 0 to disable
+    /// them.
+    pub penalty_last_n: usize,
+    /// The repetition penalty, 1.0 to disable it.
+    pub penalty_repeat: f32,
+    /// The frequency penalty, 0.0 to disable it.
+    pub penalty_freq: f32,
+    /// The presence penalty, 0.0 to disable it.
+    pub penalty_present: f32,
+    /// Keep only the tokens with a logit at most `top_n_sigma` standard deviations below the
+    /// highest one, 0.0 or less to disable it. See [`LlamaTokenDataArray::sample_top_n_sigma`].
+    pub top_n_sigma: f32,
+    /// Keep only the `top_k` most likely tokens, 0 or less to keep all of them.
+    pub top_k: i32,
+    /// Tail free sampling with parameter `z`, 1.0 to disable it.
+    pub tfs_z: f32,
+    /// Locally typical sampling, 1.0 to disable it.
+    pub typical_p: f32,
+    /// Keep the most likely tokens up to a cumulative probability of `top_p`, 1.0 to disable it.
+    pub top_p: f32,
+    /// Drop tokens less likely than `min_p` times the most likely token, 0.0 to disable it.
+    pub min_p: f32,
+    /// The temperature to sample at. 0.0 or less always chooses the most likely token.
+    pub temperature: f32,
+    /// Sample with mirostat instead of the truncation steps from `top_n_sigma` to `min_p`.
+    pub mirostat: Mirostat,
+}
+
+/// Mirostat sampling, which adjusts the truncation to keep the surprise of the text close to a
+/// target. See [`LlamaTokenDataArray::sample_token_mirostat_v2`] for the parameters.
+///
+/// With the `serde` feature this is (de)serialized as `"disabled"` or e.g.
+/// `{ "v2": { "tau": 5.0, "eta": 0.1 } }`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Mirostat {
+    /// Use the truncation steps of [`SamplingParams`].
+    #[default]
+    Disabled,
+    /// Mirostat 1.0, estimating the distribution from the 100 most likely tokens.
+    V1 {
+        /// The target surprise.
+        tau: f32,
+        /// The learning rate.
+        eta: f32,
+    },
+    /// Mirostat 2.0.
+    V2 {
+        /// The target surprise.
+        tau: f32,
+        /// The learning rate.
+        eta: f32,
+    },
+}
+
+impl Mirostat {
+    /// The initial maximum surprise, twice the target.
+    fn initial_mu(self) -> f32 {
+        match self {
+            Self::Disabled => 0.0,
+            Self::V1 { tau, .. } | Self::V2 { tau, .. } => 2.0 * tau,
+        }
+    }
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            penalty_last_n: 64,
+            penalty_repeat: 1.0,
+            penalty_freq: 0.0,
+            penalty_present: 0.0,
+            top_n_sigma: -1.0,
+            top_k: 40,
+            tfs_z: 1.0,
+            typical_p: 1.0,
+            top_p: 0.95,
+            min_p: 0.05,
+            temperature: 0.8,
+            mirostat: Mirostat::Disabled,
+        }
+    }
+}
+
+/// A [`TokenSampler`] applying the steps of [`SamplingParams`], then sampling with the context's
+/// random number generator.
+///
+/// Cloning a sampler copies its state, e.g. to serve several sequences from one configured
+/// sampler. Call [`TokenSampler::reset`] on a clone to drop the history it was cloned with.
+///
+/// A sampler only holds plain data, so it is [`Send`] and [`Sync`] and can be handed to the
+/// thread serving a request.
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<llama_cpp_2::generation::sampling::ParamsSampler>();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ParamsSampler {
+    params: SamplingParams,
+    history: TokenHistory,
+    /// The maximum surprise of mirostat.
+    mu: f32,
+}
+
+impl ParamsSampler {
+    /// Create a sampler from `params`.
+    #[must_use]
+    pub fn new(params: SamplingParams) -> Self {
+        Self {
+            history: TokenHistory::new(params.penalty_last_n),
+            mu: params.mirostat.initial_mu(),
+            params,
+        }
+    }
+
+    /// The parameters of the sampler.
+    #[must_use]
+    pub fn params(&self) -> &SamplingParams {
+        &self.params
+    }
+}
+
+impl TokenSampler for ParamsSampler {
+    fn sample(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut candidates: LlamaTokenDataArray,
+    ) -> LlamaToken {
+        let params = self.params;
+        let last_tokens = self.history.window(PenaltyWindow::Context);
+        if !last_tokens.is_empty() {
+            penalty::penalize(
+                ctx,
+                &mut candidates,
+                last_tokens,
+                params.penalty_repeat,
+                params.penalty_freq,
+                params.penalty_present,
+            );
+        }
+        if params.temperature <= 0.0 {
+            return ctx.sample_token_greedy(candidates);
+        }
+        let token = match params.mirostat {
+            Mirostat::Disabled => {
+                Self::truncate(ctx, &mut candidates, &params);
+                candidates.sample_temp(Some(ctx), params.temperature);
+                candidates.sample_token(ctx)
+            }
+            Mirostat::V1 { tau, eta } => {
+                candidates.sample_temp(Some(ctx), params.temperature);
+                candidates.sample_token_mirostat_v1(ctx, tau, eta, 100, &mut self.mu)
+            }
+            Mirostat::V2 { tau, eta } => {
+                candidates.sample_temp(Some(ctx), params.temperature);
+                candidates.sample_token_mirostat_v2(ctx, tau, eta, &mut self.mu)
+            }
+        };
+        ctx.recycle_token_data_array(candidates);
+        token
+    }
+
+    fn accept(&mut self, _ctx: &mut LlamaContext, token: LlamaToken) {
+        self.history.push(token);
+    }
+
+    fn accept_prompt(&mut self, _ctx: &mut LlamaContext, prompt: &[LlamaToken]) {
+        self.history.extend(prompt.iter().copied());
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.mu = self.params.mirostat.initial_mu();
+    }
+}
+
+impl ParamsSampler {
+    /// Apply the truncation steps of `params`, in the order llama.cpp uses.
+    fn truncate(
+        ctx: &mut LlamaContext,
+        candidates: &mut LlamaTokenDataArray,
+        params: &SamplingParams,
+    ) {
+        candidates.sample_top_n_sigma(params.top_n_sigma);
+        if params.top_k > 0 {
+            candidates.sample_top_k(Some(ctx), params.top_k, 1);
+        }
+        candidates.sample_tail_free(Some(ctx), params.tfs_z, 1);
+        candidates.sample_typical(Some(ctx), params.typical_p, 1);
+        candidates.sample_top_p(Some(ctx), params.top_p, 1);
+        candidates.sample_min_p(Some(ctx), params.min_p, 1);
+    }
+}
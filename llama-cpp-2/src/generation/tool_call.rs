@@ -0,0 +1,486 @@
+//! Parsing the tool calls of tool calling models out of their generated text.
+//!
+//! Every model family formats tool calls differently. [`ToolCallFormat`] knows the formats of the
+//! popular ones (the same formats llama.cpp's chat output parser handles), and turns the text of a
+//! response into the [`ToolCall`]s it makes and the text around them. [`parse_tool_calls`] tries
+//! every known format, [`LlamaModel::tool_call_format`] picks the one matching the chat template
+//! of a model.
+//!
+//! Arguments are kept as JSON text, ready to be deserialized into the parameters of the tool.
+//!
+//! # Examples
+//!
+//! ```
+//! use llama_cpp_2::generation::tool_call::{parse_tool_calls, ToolCall};
+//!
+//! let response = r#"Let me check.
+//! <tool_call>
+//! {"name": "get_weather", "arguments": {"city": "Paris"}}
+//! </tool_call>"#;
+//! let parsed = parse_tool_calls(response)?;
+//! assert_eq!(parsed.content, "Let me check.");
+//! assert_eq!(
+//!     parsed.tool_calls,
+//!     [ToolCall {
+//!         name: "get_weather".to_string(),
+//!         arguments: r#"{"city": "Paris"}"#.to_string(),
+//!         id: None,
+//!     }]
+//! );
+//! # Ok::<(), llama_cpp_2::generation::tool_call::ToolCallParseError>(())
+//! ```
+
+use crate::model::LlamaModel;
+
+/// A call of a tool by the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::module_name_repetitions)]
+pub struct ToolCall {
+    /// The name of the tool.
+    pub name: String,
+    /// The arguments as a JSON object, `{}` if the model gave none.
+    pub arguments: String,
+    /// The id the model gave the call, only some formats have one.
+    pub id: Option<String>,
+}
+
+/// A response split into its text and its tool calls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParsedToolCalls {
+    /// The text of the response without the tool calls, trimmed.
+    pub content: String,
+    /// The tool calls, in the order the model made them.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// The tool calls of a response could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ToolCallParseError {
+    /// A tool call is not a JSON object, or its arguments are not valid JSON.
+    #[error("malformed tool call {call:?}")]
+    Malformed {
+        /// The text of the call.
+        call: String,
+    },
+    /// A tool call has no name.
+    #[error("tool call without a name {call:?}")]
+    MissingName {
+        /// The text of the call.
+        call: String,
+    },
+}
+
+/// How a model family formats tool calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[allow(clippy::module_name_repetitions)]
+pub enum ToolCallFormat {
+    /// `<tool_call>{"name": …, "arguments": …}</tool_call>`, used by Hermes 2 Pro, Qwen 2.5 and
+    /// Qwen 3.
+    Hermes,
+    /// `[TOOL_CALLS][{"name": …, "arguments": …, "id": …}]`, used by Mistral Nemo.
+    MistralNemo,
+    /// `<｜tool▁call▁begin｜>function<｜tool▁sep｜>name` followed by the arguments in a JSON code
+    /// block, used by `DeepSeek` R1.
+    DeepSeekR1,
+    /// The whole response is `{"name": …, "parameters": …}`, optionally after `<|python_tag|>`,
+    /// used by Llama 3.1 and later. Several calls are separated by `;`.
+    Llama3,
+}
+
+const HERMES_OPEN: &str = "<tool_call>";
+const HERMES_CLOSE: &str = "</tool_call>";
+const MISTRAL_NEMO_PREFIX: &str = "[TOOL_CALLS]";
+const DEEPSEEK_R1_CALLS_BEGIN: &str = "<｜tool▁calls▁begin｜>";
+const DEEPSEEK_R1_CALL_BEGIN: &str = "<｜tool▁call▁begin｜>";
+const DEEPSEEK_R1_CALL_END: &str = "<｜tool▁call▁end｜>";
+const DEEPSEEK_R1_SEP: &str = "<｜tool▁sep｜>";
+const LLAMA3_PYTHON_TAG: &str = "<|python_tag|>";
+
+impl ToolCallFormat {
+    /// Every known format, in the order [`parse_tool_calls`] tries them.
+    pub const ALL: [Self; 4] = [
+        Self::Hermes,
+        Self::MistralNemo,
+        Self::DeepSeekR1,
+        Self::Llama3,
+    ];
+
+    /// The format a chat template asks the model to use, `None` if it matches none of the known
+    /// formats.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::generation::tool_call::ToolCallFormat;
+    /// let template = "{%- for tool in tools %}...<tool_call>\n{\"name\": <function-name>, ...";
+    /// assert_eq!(ToolCallFormat::from_template(template), Some(ToolCallFormat::Hermes));
+    /// assert_eq!(ToolCallFormat::from_template("{{ messages }}"), None);
+    /// ```
+    #[must_use]
+    pub fn from_template(template: &str) -> Option<Self> {
+        if template.contains(HERMES_OPEN) {
+            Some(Self::Hermes)
+        } else if template.contains(MISTRAL_NEMO_PREFIX) {
+            Some(Self::MistralNemo)
+        } else if template.contains(DEEPSEEK_R1_CALLS_BEGIN) {
+            Some(Self::DeepSeekR1)
+        } else if template.contains("<|start_header_id|>ipython<|end_header_id|>")
+            || template.contains(LLAMA3_PYTHON_TAG)
+        {
+            Some(Self::Llama3)
+        } else {
+            None
+        }
+    }
+
+    /// Split `text` into its tool calls and the text around them. A response without tool calls
+    /// is returned as content.
+    ///
+    /// # Errors
+    ///
+    /// - a tool call (recognized by the markers of the format) is malformed. For
+    ///   [`ToolCallFormat::Llama3`], which has no markers, a response that is not a list of calls
+    ///   is returned as content instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::generation::tool_call::ToolCallFormat;
+    /// let response = r#"[TOOL_CALLS][{"name": "add", "arguments": {"a": 1, "b": 2}, "id": "a1b2c3d4e"}]"#;
+    /// let parsed = ToolCallFormat::MistralNemo.parse(response)?;
+    /// assert_eq!(parsed.tool_calls[0].name, "add");
+    /// assert_eq!(parsed.tool_calls[0].arguments, r#"{"a": 1, "b": 2}"#);
+    /// assert_eq!(parsed.tool_calls[0].id.as_deref(), Some("a1b2c3d4e"));
+    ///
+    /// let response = r#"<|python_tag|>{"name": "search", "parameters": {"query": "rust"}}"#;
+    /// let parsed = ToolCallFormat::Llama3.parse(response)?;
+    /// assert_eq!(parsed.tool_calls[0].arguments, r#"{"query": "rust"}"#);
+    ///
+    /// let response = "<｜tool▁calls▁begin｜><｜tool▁call▁begin｜>function<｜tool▁sep｜>now\n```json\n{}\n```<｜tool▁call▁end｜><｜tool▁calls▁end｜>";
+    /// let parsed = ToolCallFormat::DeepSeekR1.parse(response)?;
+    /// assert_eq!((parsed.tool_calls[0].name.as_str(), parsed.tool_calls[0].arguments.as_str()), ("now", "{}"));
+    ///
+    /// let parsed = ToolCallFormat::Llama3.parse("{ is not a call")?;
+    /// assert_eq!((parsed.content.as_str(), parsed.tool_calls.len()), ("{ is not a call", 0));
+    /// # Ok::<(), llama_cpp_2::generation::tool_call::ToolCallParseError>(())
+    /// ```
+    pub fn parse(self, text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+        match self {
+            Self::Hermes => parse_hermes(text),
+            Self::MistralNemo => parse_mistral_nemo(text),
+            Self::DeepSeekR1 => parse_deepseek_r1(text),
+            Self::Llama3 => Ok(parse_llama3(text)),
+        }
+    }
+}
+
+/// Split `text` into its tool calls and the text around them, with the first of
+/// [`ToolCallFormat::ALL`] that finds any. Prefer [`ToolCallFormat::parse`] if the format of the
+/// model is known.
+///
+/// # Errors
+///
+/// - a tool call is malformed, see [`ToolCallFormat::parse`].
+pub fn parse_tool_calls(text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+    for format in ToolCallFormat::ALL {
+        let parsed = format.parse(text)?;
+        if !parsed.tool_calls.is_empty() {
+            return Ok(parsed);
+        }
+    }
+    Ok(ParsedToolCalls {
+        content: text.trim().to_string(),
+        tool_calls: Vec::new(),
+    })
+}
+
+impl LlamaModel {
+    /// The tool call format of the chat template of the model, `None` if the model has no template
+    /// or it uses none of the known formats.
+    #[must_use]
+    pub fn tool_call_format(&self) -> Option<ToolCallFormat> {
+        self.meta_val_str("tokenizer.chat_template")
+            .as_deref()
+            .and_then(ToolCallFormat::from_template)
+    }
+}
+
+fn parse_hermes(text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+    let mut parsed = ParsedToolCalls::default();
+    let mut rest = text;
+    while let Some(start) = rest.find(HERMES_OPEN) {
+        parsed.content.push_str(&rest[..start]);
+        rest = &rest[start + HERMES_OPEN.len()..];
+        // a generation cut short may be missing the closing tag
+        let (call, after) = rest.split_once(HERMES_CLOSE).unwrap_or((rest, ""));
+        parsed.tool_calls.push(call_from_object(call.trim())?);
+        rest = after;
+    }
+    parsed.content.push_str(rest);
+    parsed.content = parsed.content.trim().to_string();
+    Ok(parsed)
+}
+
+fn parse_mistral_nemo(text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+    let Some((content, calls)) = text.split_once(MISTRAL_NEMO_PREFIX) else {
+        return Ok(content_only(text));
+    };
+    let malformed = || ToolCallParseError::Malformed {
+        call: calls.to_string(),
+    };
+    let array = json::array_items(calls.trim()).ok_or_else(malformed)?;
+    Ok(ParsedToolCalls {
+        content: content.trim().to_string(),
+        tool_calls: array
+            .into_iter()
+            .map(call_from_object)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn parse_deepseek_r1(text: &str) -> Result<ParsedToolCalls, ToolCallParseError> {
+    let Some((content, calls)) = text.split_once(DEEPSEEK_R1_CALLS_BEGIN) else {
+        return Ok(content_only(text));
+    };
+    let mut tool_calls = Vec::new();
+    for call in calls.split(DEEPSEEK_R1_CALL_BEGIN).skip(1) {
+        let call = call.split(DEEPSEEK_R1_CALL_END).next().unwrap_or(call);
+        let malformed = || ToolCallParseError::Malformed {
+            call: call.to_string(),
+        };
+        let (_kind, call) = call.split_once(DEEPSEEK_R1_SEP).ok_or_else(malformed)?;
+        let (name, arguments) = call.split_once('\n').unwrap_or((call, ""));
+        let arguments = arguments.trim();
+        let arguments = arguments
+            .strip_prefix("```json")
+            .and_then(|arguments| arguments.strip_suffix("```"))
+            .unwrap_or(arguments)
+            .trim();
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(ToolCallParseError::MissingName {
+                call: call.to_string(),
+            });
+        }
+        tool_calls.push(ToolCall {
+            name: name.to_string(),
+            arguments: arguments_from_json(arguments).ok_or_else(malformed)?,
+            id: None,
+        });
+    }
+    Ok(ParsedToolCalls {
+        content: content.trim().to_string(),
+        tool_calls,
+    })
+}
+
+fn parse_llama3(text: &str) -> ParsedToolCalls {
+    let trimmed = text.trim();
+    let calls = trimmed.strip_prefix(LLAMA3_PYTHON_TAG).unwrap_or(trimmed);
+    let mut tool_calls = Vec::new();
+    let mut rest = calls.trim_start();
+    while !rest.is_empty() {
+        let Some(len) = json::value_len(rest) else {
+            return content_only(text);
+        };
+        match call_from_object(&rest[..len]) {
+            Ok(call) => tool_calls.push(call),
+            Err(_) => return content_only(text),
+        }
+        rest = rest[len..].trim_start();
+        rest = rest.strip_prefix(';').unwrap_or(rest).trim_start();
+    }
+    if tool_calls.is_empty() {
+        return content_only(text);
+    }
+    ParsedToolCalls {
+        content: String::new(),
+        tool_calls,
+    }
+}
+
+fn content_only(text: &str) -> ParsedToolCalls {
+    ParsedToolCalls {
+        content: text.trim().to_string(),
+        tool_calls: Vec::new(),
+    }
+}
+
+/// A call from a JSON object with a `name` and `arguments` (or `parameters`), and optionally an
+/// `id`.
+fn call_from_object(object: &str) -> Result<ToolCall, ToolCallParseError> {
+    let malformed = || ToolCallParseError::Malformed {
+        call: object.to_string(),
+    };
+    let fields = json::object_fields(object).ok_or_else(malformed)?;
+    let field = |key: &str| {
+        fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|&(_, value)| value)
+    };
+    let name =
+        field("name")
+            .and_then(json::string)
+            .ok_or_else(|| ToolCallParseError::MissingName {
+                call: object.to_string(),
+            })?;
+    let arguments = match field("arguments").or_else(|| field("parameters")) {
+        Some(arguments) => arguments_from_json(arguments).ok_or_else(malformed)?,
+        None => "{}".to_string(),
+    };
+    let id = field("id").and_then(json::string);
+    Ok(ToolCall {
+        name,
+        arguments,
+        id,
+    })
+}
+
+/// The arguments as JSON text. Some models give them as a string containing the JSON.
+fn arguments_from_json(arguments: &str) -> Option<String> {
+    if arguments.is_empty() {
+        return Some("{}".to_string());
+    }
+    if json::value_len(arguments)? != arguments.len() {
+        return None;
+    }
+    match json::string(arguments) {
+        Some(inner) => Some(inner),
+        None => Some(arguments.to_string()),
+    }
+}
+
+/// Just enough JSON to find the extent of values and the fields of objects, keeping the values
+/// as they were written.
+mod json {
+    /// The length of the JSON value at the start of `text`, which must not start with whitespace.
+    pub(super) fn value_len(text: &str) -> Option<usize> {
+        let bytes = text.as_bytes();
+        match bytes.first()? {
+            b'"' => string_len(text),
+            b'{' | b'[' => {
+                let mut depth = 0usize;
+                let mut i = 0;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'"' => {
+                            i += string_len(&text[i..])?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return Some(i + 1);
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                None
+            }
+            _ => {
+                let len = text
+                    .find(|c: char| c.is_whitespace() || matches!(c, ',' | ']' | '}' | ';'))
+                    .unwrap_or(text.len());
+                let scalar = &text[..len];
+                let is_number = scalar
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'));
+                (matches!(scalar, "true" | "false" | "null") || (len > 0 && is_number))
+                    .then_some(len)
+            }
+        }
+    }
+
+    /// The length of the JSON string (including its quotes) at the start of `text`.
+    fn string_len(text: &str) -> Option<usize> {
+        let mut escaped = false;
+        for (i, byte) in text.bytes().enumerate().skip(1) {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => return Some(i + 1),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// The value of a JSON string, `None` if `text` is not exactly one string.
+    pub(super) fn string(text: &str) -> Option<String> {
+        if string_len(text)? != text.len() {
+            return None;
+        }
+        let mut value = String::new();
+        let mut chars = text[1..text.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            value.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16).ok()?;
+                    char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                }
+                c => c,
+            });
+        }
+        Some(value)
+    }
+
+    /// The keys and (unparsed) values of a JSON object, `None` if `text` is not exactly one object.
+    pub(super) fn object_fields(text: &str) -> Option<Vec<(String, &str)>> {
+        let mut rest = text.strip_prefix('{')?.trim_start();
+        let mut fields = Vec::new();
+        if let Some(after) = rest.strip_prefix('}') {
+            return after.is_empty().then_some(fields);
+        }
+        loop {
+            let key_len = string_len(rest)?;
+            let key = string(&rest[..key_len])?;
+            rest = rest[key_len..].trim_start().strip_prefix(':')?.trim_start();
+            let value_len = value_len(rest)?;
+            fields.push((key, &rest[..value_len]));
+            rest = rest[value_len..].trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after.trim_start();
+            } else {
+                return rest.strip_prefix('}')?.is_empty().then_some(fields);
+            }
+        }
+    }
+
+    /// The (unparsed) items of a JSON array, `None` if `text` is not exactly one array.
+    pub(super) fn array_items(text: &str) -> Option<Vec<&str>> {
+        let mut rest = text.strip_prefix('[')?.trim_start();
+        let mut items = Vec::new();
+        if let Some(after) = rest.strip_prefix(']') {
+            return after.is_empty().then_some(items);
+        }
+        loop {
+            let len = value_len(rest)?;
+            items.push(&rest[..len]);
+            rest = rest[len..].trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after.trim_start();
+            } else {
+                return rest.strip_prefix(']')?.is_empty().then_some(items);
+            }
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! Marking generated text as machine generated, and detecting the mark.
+//!
+//! This implements the green list watermark of Kirchenbauer et al. ("A Watermark for Large
+//! Language Models", 2023). A secret key and the previous token split the vocabulary into a green
+//! list of [`Watermark::with_gamma`] of the tokens and a red list of the rest. While generating,
+//! [`WatermarkSampler`] adds [`Watermark::with_delta`] to the logits of the green tokens, so the
+//! text contains more green tokens than chance would. [`Watermark::detect`] counts them and tests
+//! that with a z-score, which only needs the key and the tokens, not the model.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::generation::sampling::ParamsSampler;
+//! use llama_cpp_2::generation::watermark::{Watermark, WatermarkSampler};
+//! use llama_cpp_2::generation::GenerationConfig;
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+//! # let prompt = model.str_to_token("Hello", AddBos::Always)?;
+//! let watermark = Watermark::new(0x5eed);
+//! let config = GenerationConfig::default().with_max_tokens(Some(200));
+//! let sampler = WatermarkSampler::new(watermark, ParamsSampler::new(*config.sampling()));
+//! let tokens = ctx.generate(&prompt, sampler, config).collect::<Result<Vec<_>, _>>()?;
+//!
+//! // later, given only the text
+//! let text = model.tokens_to_str_lossy(&tokens);
+//! let tokens = model.str_to_token(&text, AddBos::Never)?;
+//! let score = watermark.detect(&tokens);
+//! println!("{} of {} tokens are green", score.n_green, score.n_scored);
+//! if score.is_watermarked(4.0) {
+//!     println!("the text is watermarked (z = {:.1})", score.z_score);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::context::LlamaContext;
+use crate::generation::TokenSampler;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+/// The key and strength of a watermark.
+///
+/// With the `serde` feature this can be (de)serialized, with missing fields taking their default
+/// values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Watermark {
+    key: u64,
+    gamma: f32,
+    delta: f32,
+}
+
+impl Default for Watermark {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Watermark {
+    /// A watermark with the secret `key`, a green list of a quarter of the vocabulary and a bias
+    /// of 2.0.
+    #[must_use]
+    pub fn new(key: u64) -> Self {
+        Self {
+            key,
+            gamma: 0.25,
+            delta: 2.0,
+        }
+    }
+
+    /// Set the fraction of the vocabulary in the green list, between 0 and 1. Smaller lists make
+    /// the watermark easier to detect but restrict the text more.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::watermark::Watermark;
+    /// let watermark = Watermark::new(1).with_gamma(0.5);
+    /// assert_eq!(watermark.gamma(), 0.5);
+    /// ```
+    #[must_use]
+    pub fn with_gamma(mut self, gamma: f32) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// The fraction of the vocabulary in the green list.
+    #[must_use]
+    pub fn gamma(&self) -> f32 {
+        self.gamma
+    }
+
+    /// Set the bias added to the logits of green tokens. Larger biases make the watermark easier to
+    /// detect but change the text more.
+    #[must_use]
+    pub fn with_delta(mut self, delta: f32) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    /// The bias added to the logits of green tokens.
+    #[must_use]
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    /// The secret key.
+    #[must_use]
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    /// Whether `token` is on the green list following `previous`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::watermark::Watermark;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let watermark = Watermark::new(42);
+    /// let n_green = (0..10_000)
+    ///     .filter(|&i| watermark.is_green(LlamaToken(7), LlamaToken(i)))
+    ///     .count();
+    /// assert!((2_300..2_700).contains(&n_green), "about a quarter is green");
+    /// ```
+    #[must_use]
+    pub fn is_green(&self, previous: LlamaToken, token: LlamaToken) -> bool {
+        let hash = splitmix64(splitmix64(self.key ^ token_bits(previous)) ^ token_bits(token));
+        #[allow(clippy::cast_precision_loss)]
+        let hash = hash as f64 / u64::MAX as f64;
+        hash < f64::from(self.gamma)
+    }
+
+    /// Add the bias to the green tokens among `candidates` following `previous`.
+    pub fn apply(&self, candidates: &mut LlamaTokenDataArray, previous: LlamaToken) {
+        for data in &mut candidates.data {
+            if self.is_green(previous, data.id()) {
+                data.set_logit(data.logit() + self.delta);
+            }
+        }
+        candidates.sorted = false;
+    }
+
+    /// Count the green tokens of `tokens`, each scored against the one before it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::generation::watermark::Watermark;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let watermark = Watermark::new(42);
+    /// // always pick a green token
+    /// let mut tokens = vec![LlamaToken(1)];
+    /// for _ in 0..50 {
+    ///     let previous = *tokens.last().unwrap();
+    ///     let next = (0..).map(LlamaToken).find(|&t| watermark.is_green(previous, t)).unwrap();
+    ///     tokens.push(next);
+    /// }
+    /// let score = watermark.detect(&tokens);
+    /// assert_eq!((score.n_green, score.n_scored), (50, 50));
+    /// assert!(score.is_watermarked(4.0));
+    ///
+    /// let unmarked: Vec<_> = (0..51).map(|i| LlamaToken(i * 7919 % 32_000)).collect();
+    /// assert!(!Watermark::new(42).detect(&unmarked).is_watermarked(4.0));
+    /// ```
+    #[must_use]
+    pub fn detect(&self, tokens: &[LlamaToken]) -> WatermarkScore {
+        let n_scored = tokens.len().saturating_sub(1);
+        let n_green = tokens
+            .windows(2)
+            .filter(|pair| self.is_green(pair[0], pair[1]))
+            .count();
+        #[allow(clippy::cast_precision_loss)]
+        let (n, green) = (n_scored as f64, n_green as f64);
+        let gamma = f64::from(self.gamma);
+        let variance = n * gamma * (1.0 - gamma);
+        let z_score = if variance > 0.0 {
+            (green - gamma * n) / variance.sqrt()
+        } else {
+            0.0
+        };
+        WatermarkScore {
+            n_scored,
+            n_green,
+            z_score,
+        }
+    }
+}
+
+/// The result of [`Watermark::detect`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatermarkScore {
+    /// The number of tokens that were scored, all but the first.
+    pub n_scored: usize,
+    /// The number of green tokens.
+    pub n_green: usize,
+    /// How many standard deviations more green tokens there are than expected in unmarked text.
+    pub z_score: f64,
+}
+
+impl WatermarkScore {
+    /// Whether the z-score exceeds `threshold`. A threshold of 4 has a false positive rate of
+    /// about 3 in 100 000 for unmarked text.
+    #[must_use]
+    pub fn is_watermarked(&self, threshold: f64) -> bool {
+        self.z_score > threshold
+    }
+}
+
+/// A [`TokenSampler`] that watermarks the candidates before passing them to another sampler.
+///
+/// The first token is not watermarked if there is no prompt to follow.
+#[derive(Debug, Clone)]
+pub struct WatermarkSampler<S> {
+    watermark: Watermark,
+    sampler: S,
+    previous: Option<LlamaToken>,
+}
+
+impl<S> WatermarkSampler<S> {
+    /// Watermark the candidates of `sampler`.
+    #[must_use]
+    pub fn new(watermark: Watermark, sampler: S) -> Self {
+        Self {
+            watermark,
+            sampler,
+            previous: None,
+        }
+    }
+
+    /// The watermark.
+    #[must_use]
+    pub fn watermark(&self) -> &Watermark {
+        &self.watermark
+    }
+
+    /// Give the wrapped sampler back.
+    pub fn into_inner(self) -> S {
+        self.sampler
+    }
+}
+
+impl<S: TokenSampler> TokenSampler for WatermarkSampler<S> {
+    fn sample(
+        &mut self,
+        ctx: &mut LlamaContext,
+        mut candidates: LlamaTokenDataArray,
+    ) -> LlamaToken {
+        if let Some(previous) = self.previous {
+            self.watermark.apply(&mut candidates, previous);
+        }
+        self.sampler.sample(ctx, candidates)
+    }
+
+    fn accept(&mut self, ctx: &mut LlamaContext, token: LlamaToken) {
+        self.previous = Some(token);
+        self.sampler.accept(ctx, token);
+    }
+
+    fn accept_prompt(&mut self, ctx: &mut LlamaContext, prompt: &[LlamaToken]) {
+        if let Some(&last) = prompt.last() {
+            self.previous = Some(last);
+        }
+        self.sampler.accept_prompt(ctx, prompt);
+    }
+
+    fn reset(&mut self) {
+        self.previous = None;
+        self.sampler.reset();
+    }
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn token_bits(LlamaToken(token): LlamaToken) -> u64 {
+    u64::from(token as u32)
+}
+
+/// A well mixing 64 bit hash, the finalizer of `SplitMix64`.
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
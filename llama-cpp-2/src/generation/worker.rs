@@ -0,0 +1,339 @@
+//! Generating on a dedicated thread for async code.
+//!
+//! [`LlamaContext::decode`](crate::context::LlamaContext::decode) blocks for as long as the model
+//! takes to evaluate a batch, which stalls an async executor when called from a task. A
+//! [`GenerationWorker`] moves a context to its own thread and runs generations there, one at a
+//! time, while the [`TokenStream`]s it returns yield the tokens as a [`futures_core::Stream`].
+//! Dropping a stream cancels its generation, e.g. when the client of a server disconnects.
+//!
+//! A stream buffers a limited number of tokens, see [`GenerationWorker::with_stream_capacity`].
+//! When its consumer falls behind, the worker pauses decoding until tokens are taken out again, so
+//! a stream that is kept but never polled holds up the generations queued after it.
+//!
+//! The worker is `Send` and `Sync`, so it can be shared between the tasks of a server, e.g. in an
+//! `Arc`. Create a worker per context to serve several requests at once.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use llama_cpp_2::context::params::LlamaContextParams;
+//! use llama_cpp_2::generation::worker::GenerationWorker;
+//! use llama_cpp_2::generation::GenerationConfig;
+//! use llama_cpp_2::model::{AddBos, LlamaModel};
+//! use llama_cpp_2::token::decoder::TokenDecoder;
+//!
+//! async fn complete(worker: &GenerationWorker, model: &LlamaModel, prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+//!     let prompt = model.str_to_token(prompt, AddBos::Always)?;
+//!     let mut stream = worker.generate(prompt, GenerationConfig::default().with_max_tokens(Some(128)));
+//!     let mut decoder = TokenDecoder::new();
+//!     let mut text = String::new();
+//!     while let Some(token) = stream.next_token().await {
+//!         text += &decoder.decode(model, token?)?;
+//!     }
+//!     Ok(text + &decoder.finish())
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! let model = Arc::new(LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?);
+//! let ctx = model.new_owned_context(&backend, LlamaContextParams::default())?;
+//! let worker = GenerationWorker::spawn(ctx);
+//! // from an async task: complete(&worker, &model, "The capital of France is").await?
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+use crate::context::owned::OwnedLlamaContext;
+use crate::generation::{FinishReason, GenerationConfig, GenerationError};
+use crate::token::LlamaToken;
+
+/// Runs generations on a context on its own thread, see the
+/// [module docs](crate::generation::worker).
+#[derive(Debug)]
+pub struct GenerationWorker {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    stream_capacity: usize,
+}
+
+/// A context that is moved to the worker thread.
+struct SendContext(OwnedLlamaContext);
+
+// SAFETY: a llama.cpp context is not tied to the thread that created it, and the worker is the only
+// thread using it once it is moved there.
+unsafe impl Send for SendContext {}
+
+impl SendContext {
+    /// Unwrap the context, taking the whole wrapper into a closure instead of just its field.
+    fn into_inner(self) -> OwnedLlamaContext {
+        self.0
+    }
+}
+
+impl GenerationWorker {
+    /// Move `ctx` to a new thread that runs the generations requested with
+    /// [`GenerationWorker::generate`].
+    ///
+    /// # Panics
+    ///
+    /// - the thread could not be spawned.
+    #[must_use]
+    pub fn spawn(ctx: OwnedLlamaContext) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let ctx = SendContext(ctx);
+        let thread = std::thread::Builder::new()
+            .name("llama-generation".to_string())
+            .spawn(move || {
+                let mut ctx = ctx.into_inner();
+                for mut job in queue {
+                    job.run(&mut ctx);
+                }
+            })
+            .expect("failed to spawn the generation thread");
+        Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            stream_capacity: DEFAULT_STREAM_CAPACITY,
+        }
+    }
+
+    /// Set the number of tokens a [`TokenStream`] buffers before the worker pauses decoding until
+    /// the consumer takes them, 32 by default. A capacity of 0 is treated as 1.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::generation::worker::GenerationWorker;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = Arc::new(LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?);
+    /// let ctx = model.new_owned_context(&backend, LlamaContextParams::default())?;
+    /// // stay at most a few tokens ahead of a client reading slowly over the network
+    /// let worker = GenerationWorker::spawn(ctx).with_stream_capacity(4);
+    /// assert_eq!(worker.stream_capacity(), 4);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn with_stream_capacity(mut self, capacity: usize) -> Self {
+        self.stream_capacity = capacity.max(1);
+        self
+    }
+
+    /// The number of tokens a [`TokenStream`] buffers, see
+    /// [`GenerationWorker::with_stream_capacity`].
+    #[must_use]
+    pub fn stream_capacity(&self) -> usize {
+        self.stream_capacity
+    }
+
+    /// Generate a continuation of `prompt` according to `config` once the generations requested
+    /// before it are done. The prompt is decoded on sequence 0, which is cleared first, as with
+    /// [`LlamaContext::generate_with_config`](crate::context::LlamaContext::generate_with_config).
+    ///
+    /// The returned stream yields the tokens as they are generated. Dropping it (or
+    /// [`TokenStream::cancel`]) stops the generation after the current token.
+    #[must_use]
+    pub fn generate(&self, prompt: Vec<LlamaToken>, config: GenerationConfig) -> TokenStream {
+        let shared = Arc::new(Shared::new(self.stream_capacity));
+        let job = Job {
+            prompt,
+            config,
+            shared: Arc::clone(&shared),
+        };
+        if let Some(jobs) = &self.jobs {
+            // if the thread is gone the job is dropped, which ends the stream
+            let _ = jobs.send(job);
+        }
+        TokenStream { shared }
+    }
+}
+
+impl Drop for GenerationWorker {
+    /// Finish the requested generations and join the thread. Drop the streams first to cancel
+    /// them instead.
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The tokens of a generation requested with [`GenerationWorker::generate`].
+///
+/// The stream ends with the generation, [`TokenStream::finish_reason`] tells why. An error is the
+/// last item.
+#[derive(Debug)]
+pub struct TokenStream {
+    shared: Arc<Shared>,
+}
+
+impl TokenStream {
+    /// The next token, `None` once the generation ended. This is
+    /// [`futures_core::Stream::poll_next`] as a future, for use without a stream combinator
+    /// library.
+    pub fn next_token(
+        &mut self,
+    ) -> impl Future<Output = Option<Result<LlamaToken, GenerationError>>> + '_ {
+        std::future::poll_fn(|cx| self.shared.poll_next(cx))
+    }
+
+    /// Stop the generation after the current token. The tokens generated so far are still
+    /// yielded, and the stream ends with [`FinishReason::Cancelled`].
+    pub fn cancel(&self) {
+        self.shared.cancel();
+    }
+
+    /// Why the generation ended, `None` while it is running, after an error or if the worker
+    /// thread panicked.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.shared.state().finish_reason
+    }
+}
+
+impl futures_core::Stream for TokenStream {
+    type Item = Result<LlamaToken, GenerationError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.shared.poll_next(cx)
+    }
+}
+
+impl futures_core::FusedStream for TokenStream {
+    fn is_terminated(&self) -> bool {
+        let state = self.shared.state();
+        state.done && state.items.is_empty()
+    }
+}
+
+impl Drop for TokenStream {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// A requested generation.
+struct Job {
+    prompt: Vec<LlamaToken>,
+    config: GenerationConfig,
+    shared: Arc<Shared>,
+}
+
+impl Job {
+    fn run(&mut self, ctx: &mut OwnedLlamaContext) {
+        let cancel = Arc::clone(&self.shared.cancel);
+        if cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        ctx.with(|ctx| {
+            let config = std::mem::take(&mut self.config);
+            let mut generator = ctx
+                .generate_with_config(&self.prompt, config)
+                .with_cancel(cancel);
+            for item in &mut generator {
+                self.shared.push(item);
+            }
+            self.shared.state().finish_reason = generator.finish_reason();
+        });
+    }
+}
+
+impl Drop for Job {
+    /// End the stream, also if the job never ran or the generation panicked.
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.done = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The default of [`GenerationWorker::stream_capacity`].
+const DEFAULT_STREAM_CAPACITY: usize = 32;
+
+/// The state shared between a [`TokenStream`] and its [`Job`].
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when an item is taken out of a full buffer or the generation is cancelled.
+    space: Condvar,
+    capacity: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    items: VecDeque<Result<LlamaToken, GenerationError>>,
+    done: bool,
+    finish_reason: Option<FinishReason>,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::default(),
+            space: Condvar::new(),
+            capacity,
+            cancel: Arc::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // the state stays consistent even if a thread panicked while holding the lock
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queue `item` for the stream, waiting while the buffer is full so decoding pauses until the
+    /// consumer catches up. Once cancelled the item is queued right away, the generation stops
+    /// after it.
+    fn push(&self, item: Result<LlamaToken, GenerationError>) {
+        let mut state = self.state();
+        while state.items.len() >= self.capacity && !self.cancel.load(Ordering::Relaxed) {
+            state = self
+                .space
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        // a push that checked the flag before it was set is waiting once the lock is free again
+        drop(self.state());
+        self.space.notify_all();
+    }
+
+    fn poll_next(&self, cx: &mut Context<'_>) -> Poll<Option<Result<LlamaToken, GenerationError>>> {
+        let mut state = self.state();
+        if let Some(item) = state.items.pop_front() {
+            self.space.notify_one();
+            Poll::Ready(Some(item))
+        } else if state.done {
+            Poll::Ready(None)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
@@ -4,13 +4,23 @@
 //! `common` to rust
 
 use std::collections::BTreeMap;
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
 
 use llama_cpp_sys_2::{llama_grammar, llama_grammar_element, llama_gretype};
 use std::ptr::NonNull;
 use std::str::FromStr;
 use tracing::error;
 
+use crate::context::LlamaContext;
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+
+pub mod builder;
+pub mod schema;
+
 /// Details of extraneous characters after a rule error.
 #[derive(thiserror::Error, Debug)]
 #[error("Extraneous chars after rule {name:?}: {chars:?}")]
@@ -92,9 +102,55 @@ pub enum GrammarParseError {
     /// There was unexpected characters after the rule.
     #[error("{0}")]
     ExtraneousCharsAfterRule(ExtraneousCharsAfterRule),
+    /// A rule was referenced but never defined.
+    #[error("Undefined rule {name:?}")]
+    UndefinedRule {
+        /// the name of the undefined rule
+        name: String,
+    },
+    /// The grammar has no "root" rule to start from.
+    #[error("Missing root rule")]
+    MissingRoot,
+}
+
+/// A [`GrammarParseError`] together with where in the grammar it occurred.
+#[derive(thiserror::Error, Debug)]
+#[error("{line}:{column}: {error}")]
+#[allow(clippy::module_name_repetitions)]
+pub struct GrammarDiagnostic {
+    /// The line the error occurred on, starting at 1.
+    pub line: usize,
+    /// The column (in chars) the error occurred at, starting at 1.
+    pub column: usize,
+    /// The error.
+    #[source]
+    pub error: GrammarParseError,
+}
+
+impl GrammarDiagnostic {
+    fn new(grammar: &str, offset: usize, error: GrammarParseError) -> Self {
+        let before = &grammar[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            error,
+        }
+    }
 }
 
 /// A grammar for llama-cpp.
+///
+/// # Thread safety
+///
+/// A grammar is [`Send`] and [`Sync`], so a parsed grammar can be shared between the threads of a
+/// server and cloned for each request. Accepting a token advances the grammar and takes it
+/// mutably, so each generation needs its own clone.
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<llama_cpp_2::grammar::LlamaGrammar>();
+/// ```
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaGrammar {
     parse: ParseState,
@@ -111,8 +167,12 @@ impl Clone for LlamaGrammar {
     }
 }
 
+// SAFETY: the `llama_grammar` is owned by this struct and freed in `Drop`, and llama.cpp keeps no
+// thread local state for it, so it can be moved to and freed on another thread.
 unsafe impl Send for LlamaGrammar {}
 
+// SAFETY: through a shared reference llama.cpp only reads the grammar (`llama_grammar_copy` and
+// `llama_sample_grammar` take it as `const`), advancing it requires `&mut LlamaGrammar`.
 unsafe impl Sync for LlamaGrammar {}
 
 #[allow(clippy::module_name_repetitions)]
@@ -125,10 +185,21 @@ impl Debug for LlamaGrammar {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 struct ParseState {
     symbol_ids: BTreeMap<String, u32>,
     rules: Vec<Vec<llama_grammar_element>>,
+    /// bytes left to parse at the last checkpoint, used to locate errors.
+    remaining: usize,
+    /// bytes left to parse at the first reference of each rule, used to locate undefined rules.
+    first_references: BTreeMap<u32, usize>,
+}
+
+/// Only the parsed grammar is compared, not the bookkeeping used to locate errors.
+impl PartialEq for ParseState {
+    fn eq(&self, other: &Self) -> bool {
+        self.symbol_ids == other.symbol_ids && self.rules == other.rules
+    }
 }
 
 impl ParseState {
@@ -136,7 +207,33 @@ impl ParseState {
         Self {
             symbol_ids: BTreeMap::new(),
             rules: Vec::new(),
+            remaining: 0,
+            first_references: BTreeMap::new(),
+        }
+    }
+
+    /// Parse a grammar, returning the byte offset of the error on failure.
+    fn parse(s: &str) -> Result<Self, (GrammarParseError, usize)> {
+        let mut parse_state = ParseState::new();
+        let mut remaining = Some(s);
+        while let Some(str) = remaining {
+            parse_state.remaining = str.len();
+            remaining = parse_state
+                .parse_rule(str)
+                .map_err(|error| (error, s.len() - parse_state.remaining))?;
         }
+
+        if !parse_state.symbol_ids.contains_key("root") {
+            return Err((GrammarParseError::MissingRoot, 0));
+        }
+        for (name, &id) in &parse_state.symbol_ids {
+            if parse_state.rules.get(id as usize).map_or(0, Vec::len) == 0 {
+                let remaining = parse_state.first_references.get(&id).copied().unwrap_or(0);
+                let error = GrammarParseError::UndefinedRule { name: name.clone() };
+                return Err((error, s.len() - remaining));
+            }
+        }
+        Ok(parse_state)
     }
 
     fn get_symbol_id(&mut self, name: &str) -> u32 {
@@ -161,8 +258,10 @@ impl ParseState {
         if rest.is_empty() {
             return Ok(None);
         }
+        self.remaining = rest.len();
         let (name, rest) = Self::parse_name(rest)?;
         let rest = rest.trim_start();
+        self.remaining = rest.len();
         let rule_id = self.get_symbol_id(name);
 
         let (after_name, rest) =
@@ -179,6 +278,7 @@ impl ParseState {
         }
 
         let rest = self.parse_alternatives(name, rule_id, rest, false)?;
+        self.remaining = rest.len();
 
         let Some((after_rule, rest)) = rest.split_once('\n') else {
             return Ok(None);
@@ -255,6 +355,7 @@ impl ParseState {
     ) -> Result<&'a str, GrammarParseError> {
         let mut last_sym_start = rule.len();
         while !rest.is_empty() {
+            self.remaining = rest.len();
             let first_char =
                 rest.chars()
                     .next()
@@ -265,6 +366,7 @@ impl ParseState {
                 rest = &rest[1..];
                 last_sym_start = rule.len();
                 while !rest.starts_with('"') {
+                    self.remaining = rest.len();
                     let (c, r) = Self::parse_char(rest)?;
                     rest = r;
                     rule.push(llama_grammar_element {
@@ -283,6 +385,7 @@ impl ParseState {
                 };
                 last_sym_start = rule.len();
                 while !rest.starts_with(']') {
+                    self.remaining = rest.len();
                     let (c, r) = Self::parse_char(rest)?;
                     rest = r;
                     let gre_type = if last_sym_start < rule.len() {
@@ -294,8 +397,8 @@ impl ParseState {
                         type_: gre_type,
                         value: c as _,
                     });
-                    if rest.starts_with("-]") {
-                        let (c, r) = Self::parse_char(rest)?;
+                    if let Some(r) = rest.strip_prefix('-').filter(|r| !r.starts_with(']')) {
+                        let (c, r) = Self::parse_char(r)?;
                         rest = r;
                         rule.push(llama_grammar_element {
                             type_: llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER,
@@ -305,9 +408,13 @@ impl ParseState {
                 }
                 rest = Self::consume_whitespace_and_comments(&rest[1..], nested);
             } else if first_char.is_alphabetic() {
+                let reference_start = rest.len();
                 let (name, r) = Self::parse_name(rest)?;
                 rest = Self::consume_whitespace_and_comments(r, nested);
                 let ref_rule_id = self.get_symbol_id(name);
+                self.first_references
+                    .entry(ref_rule_id)
+                    .or_insert(reference_start);
                 last_sym_start = rule.len();
                 rule.push(llama_grammar_element {
                     type_: llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
@@ -317,6 +424,7 @@ impl ParseState {
                 rest = rest[1..].trim_start();
                 let sub_rule_id = self.generate_symbol_id(name);
                 rest = self.parse_alternatives(name, sub_rule_id, rest, true)?;
+                self.remaining = rest.len();
                 last_sym_start = rule.len();
                 rule.push(llama_grammar_element {
                     type_: llama_cpp_sys_2::LLAMA_GRETYPE_RULE_REF,
@@ -443,16 +551,44 @@ pub enum LlamaGrammarFromStrError {
     LlamaCppNullError,
 }
 
+/// An error that can occur advancing a grammar, see [`LlamaGrammar::accept_token`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrammarAcceptError {
+    /// The grammar does not allow the token in its current state.
+    #[error("the grammar does not allow token {0} here")]
+    Rejected(LlamaToken),
+}
+
+/// An error that can occur creating a grammar from a file, see [`LlamaGrammar::from_file`].
+#[derive(thiserror::Error, Debug)]
+pub enum LlamaGrammarFromFileError {
+    /// A grammar file (or a file it includes) could not be read.
+    #[error("Failed to read grammar file {path:?}: {source}")]
+    Io {
+        /// the file that could not be read
+        path: PathBuf,
+        /// the error that occurred reading it
+        #[source]
+        source: std::io::Error,
+    },
+    /// An `#include` directive is not followed by a quoted path.
+    #[error("Malformed include in {path:?} on line {line}")]
+    MalformedInclude {
+        /// the file containing the directive
+        path: PathBuf,
+        /// the line of the directive, starting at 1
+        line: usize,
+    },
+    /// The merged grammar is invalid.
+    #[error("{0}")]
+    FromStr(#[from] LlamaGrammarFromStrError),
+}
+
 impl FromStr for ParseState {
     type Err = GrammarParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parse_state = ParseState::new();
-        let mut remaining = Some(s);
-        while let Some(str) = remaining {
-            remaining = parse_state.parse_rule(str)?;
-        }
-        Ok(parse_state)
+        ParseState::parse(s).map_err(|(error, _)| error)
     }
 }
 
@@ -461,10 +597,21 @@ impl FromStr for LlamaGrammar {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut parse_state = ParseState::from_str(s)?;
+        let grammar = parse_state.init_grammar()?;
+
+        Ok(Self {
+            parse: parse_state,
+            grammar,
+        })
+    }
+}
 
-        let n_rules = parse_state.rules.len();
-        let root_id = parse_state.get_symbol_id("root");
-        let mut vec = parse_state
+impl ParseState {
+    /// Create a new `llama_grammar` from the parsed rules, starting at "root".
+    fn init_grammar(&mut self) -> Result<NonNull<llama_grammar>, LlamaGrammarFromStrError> {
+        let n_rules = self.rules.len();
+        let root_id = self.get_symbol_id("root");
+        let mut vec = self
             .rules
             .iter_mut()
             .map(|v| v.as_ptr())
@@ -474,11 +621,192 @@ impl FromStr for LlamaGrammar {
         let grammar =
             unsafe { llama_cpp_sys_2::llama_grammar_init(rules, n_rules, root_id as usize) };
 
-        Ok(Self {
-            parse: parse_state,
-            grammar: NonNull::new(grammar).ok_or(LlamaGrammarFromStrError::LlamaCppNullError)?,
-        })
+        NonNull::new(grammar).ok_or(LlamaGrammarFromStrError::LlamaCppNullError)
+    }
+}
+
+impl LlamaGrammar {
+    /// Check that `grammar` is valid GBNF without creating a llama.cpp grammar.
+    ///
+    /// # Errors
+    ///
+    /// - the grammar is malformed, with the line and column of the problem.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::grammar::{GrammarParseError, LlamaGrammar};
+    /// assert!(LlamaGrammar::validate("root ::= \"yes\" | \"no\"").is_ok());
+    ///
+    /// let diagnostic = LlamaGrammar::validate("root ::= answer\nanswer ::= \"\\q\"").unwrap_err();
+    /// assert_eq!((diagnostic.line, diagnostic.column), (2, 13));
+    /// assert!(matches!(diagnostic.error, GrammarParseError::UnknownEscape { escape: 'q' }));
+    ///
+    /// let diagnostic = LlamaGrammar::validate("root ::= \"a\" missing").unwrap_err();
+    /// assert_eq!((diagnostic.line, diagnostic.column), (1, 14));
+    /// ```
+    pub fn validate(grammar: &str) -> Result<(), GrammarDiagnostic> {
+        ParseState::parse(grammar)
+            .map(|_| ())
+            .map_err(|(error, offset)| GrammarDiagnostic::new(grammar, offset, error))
+    }
+
+    /// Load a grammar from a GBNF file, merging in the files it includes.
+    ///
+    /// A line of the form `#include "other.gbnf"` is replaced by the rules of `other.gbnf`,
+    /// relative to the directory of the including file. As the directive is a comment to other GBNF
+    /// parsers, a file with includes is still valid on its own as long as it defines every rule it
+    /// uses. Every file is included at most once, so several files can include the same rules and
+    /// include cycles are harmless. A rule that is defined again replaces the earlier definition,
+    /// which lets a file override rules it includes by defining them after the directive.
+    ///
+    /// # Errors
+    ///
+    /// - a file could not be read.
+    /// - an `#include` is not followed by a quoted path.
+    /// - the merged grammar is invalid.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::grammar::LlamaGrammar;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let dir = std::env::temp_dir().join("llama-cpp-2-grammar-include");
+    /// std::fs::create_dir_all(&dir)?;
+    /// std::fs::write(dir.join("answer.gbnf"), "answer ::= \"yes\" | \"no\"\n")?;
+    /// std::fs::write(
+    ///     dir.join("root.gbnf"),
+    ///     "#include \"answer.gbnf\"\nroot ::= \"Answer: \" answer\n",
+    /// )?;
+    ///
+    /// let grammar = LlamaGrammar::from_file(dir.join("root.gbnf"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LlamaGrammarFromFileError> {
+        let mut grammar = String::new();
+        read_with_includes(path.as_ref(), &mut HashSet::new(), &mut grammar)?;
+        Ok(Self::from_str(&grammar)?)
+    }
+
+    /// Reset the grammar to its initial state so it can be reused for another generation.
+    ///
+    /// # Errors
+    ///
+    /// - llama.cpp failed to create the grammar. This should not happen as it succeeded when this
+    ///   grammar was first created.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::str::FromStr;
+    /// # use llama_cpp_2::grammar::LlamaGrammar;
+    /// let mut grammar = LlamaGrammar::from_str("root ::= \"yes\" | \"no\"")?;
+    /// // ... sample and accept tokens with the grammar ...
+    /// grammar.reset()?;
+    /// # Ok::<(), llama_cpp_2::grammar::LlamaGrammarFromStrError>(())
+    /// ```
+    pub fn reset(&mut self) -> Result<(), LlamaGrammarFromStrError> {
+        let grammar = self.parse.init_grammar()?;
+        let old = std::mem::replace(&mut self.grammar, grammar);
+        unsafe { llama_cpp_sys_2::llama_grammar_free(old.as_ptr()) }
+        Ok(())
+    }
+
+    /// Whether the grammar allows `token` in its current state, i.e. whether
+    /// [`LlamaContext::sample_grammar`] keeps it.
+    ///
+    /// The end of stream token is allowed once the grammar is complete. Other tokens without text,
+    /// such as control tokens, are never allowed.
+    #[must_use]
+    pub fn allows(&self, ctx: &mut LlamaContext, token: LlamaToken) -> bool {
+        let mut candidates =
+            LlamaTokenDataArray::from_iter([LlamaTokenData::new(token, 0.0, 0.0)], false);
+        ctx.sample_grammar(&mut candidates, self);
+        candidates.data[0].logit() != f32::NEG_INFINITY
+    }
+
+    /// Advance the grammar past `token`.
+    ///
+    /// This must be called for every token added to the output, including tokens that were forced
+    /// rather than sampled, to keep the grammar in sync with the generated text. The token is
+    /// checked with [`LlamaGrammar::allows`] first, as llama.cpp aborts the process for tokens the
+    /// grammar does not allow.
+    ///
+    /// # Errors
+    ///
+    /// - [`GrammarAcceptError::Rejected`] if the grammar does not allow `token`. The grammar is
+    ///   unchanged then.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::grammar::{GrammarAcceptError, LlamaGrammar};
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let mut grammar: LlamaGrammar = r#"root ::= "yes" | "no""#.parse()?;
+    /// // the grammar is not complete yet
+    /// let eos = model.token_eos();
+    /// assert_eq!(grammar.accept_token(&mut ctx, eos), Err(GrammarAcceptError::Rejected(eos)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn accept_token(
+        &mut self,
+        ctx: &mut LlamaContext,
+        token: LlamaToken,
+    ) -> Result<(), GrammarAcceptError> {
+        if !self.allows(ctx, token) {
+            return Err(GrammarAcceptError::Rejected(token));
+        }
+        unsafe {
+            llama_cpp_sys_2::llama_grammar_accept_token(
+                ctx.context.as_ptr(),
+                self.grammar.as_ptr(),
+                token.0,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Append the contents of `path` to `grammar`, replacing its `#include` directives by the files
+/// they name unless they are already in `included`.
+fn read_with_includes(
+    path: &Path,
+    included: &mut HashSet<PathBuf>,
+    grammar: &mut String,
+) -> Result<(), LlamaGrammarFromFileError> {
+    let io_error = |source| LlamaGrammarFromFileError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    if !included.insert(path.canonicalize().map_err(io_error)?) {
+        return Ok(());
+    }
+    let contents = std::fs::read_to_string(path).map_err(io_error)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    for (i, line) in contents.lines().enumerate() {
+        let Some(include) = line.trim_start().strip_prefix("#include") else {
+            grammar.push_str(line);
+            grammar.push('\n');
+            continue;
+        };
+        let include = include
+            .trim()
+            .strip_prefix('"')
+            .and_then(|include| include.strip_suffix('"'))
+            .ok_or_else(|| LlamaGrammarFromFileError::MalformedInclude {
+                path: path.to_path_buf(),
+                line: i + 1,
+            })?;
+        read_with_includes(&dir.join(include), included, grammar)?;
     }
+    Ok(())
 }
 
 impl Drop for LlamaGrammar {
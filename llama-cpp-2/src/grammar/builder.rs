@@ -0,0 +1,417 @@
+//! A typed builder for GBNF grammars.
+//!
+//! Rules are declared on a [`GrammarBuilder`] which hands out [`RuleRef`]s. Expressions can only
+//! reference rules through these handles, so a misspelled rule name is a compile error rather than
+//! a grammar that fails to load.
+//!
+//! # Examples
+//!
+//! ```
+//! use llama_cpp_2::grammar::builder::{char_class, choice, literal, one_or_more, seq, GrammarBuilder};
+//!
+//! let mut builder = GrammarBuilder::new();
+//! let digits = builder.rule("digits", one_or_more(char_class(['0'..='9'])));
+//! let answer = builder.rule(
+//!     "answer",
+//!     choice([literal("yes"), literal("no"), seq([literal("maybe "), digits.into()])]),
+//! );
+//! let gbnf = builder.build(answer)?;
+//! assert_eq!(
+//!     gbnf,
+//!     "root ::= answer\ndigits ::= [0-9]+\nanswer ::= \"yes\" | \"no\" | \"maybe \" digits\n"
+//! );
+//! # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+//! ```
+//!
+//! Recursive rules are declared first and defined once the rules they reference exist.
+//!
+//! ```
+//! use llama_cpp_2::grammar::builder::{choice, literal, optional, seq, GrammarBuilder};
+//!
+//! let mut builder = GrammarBuilder::new();
+//! let list = builder.declare("list");
+//! builder.define(list, seq([literal("a"), optional(seq([literal(","), list.into()]))]));
+//! let grammar = builder.build_grammar(list)?;
+//! # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+//! ```
+
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::grammar::{LlamaGrammar, LlamaGrammarFromStrError};
+
+/// A handle to a rule declared on a [`GrammarBuilder`].
+///
+/// A `RuleRef` should only be used with the builder that created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuleRef(usize);
+
+/// An expression in a grammar rule. Create these with the functions in [`crate::grammar::builder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GrammarExpr {
+    /// Matches the string exactly.
+    Literal(String),
+    /// Matches a single char in (or, if `negated`, not in) any of the ranges.
+    CharClass {
+        /// Whether to match chars outside the ranges instead.
+        negated: bool,
+        /// The ranges of chars.
+        ranges: Vec<RangeInclusive<char>>,
+    },
+    /// Matches each expression in order.
+    Seq(Vec<GrammarExpr>),
+    /// Matches any one of the expressions.
+    Choice(Vec<GrammarExpr>),
+    /// Matches the expression between `min` and `max` (unbounded if `None`) times.
+    Repeat {
+        /// The repeated expression.
+        expr: Box<GrammarExpr>,
+        /// The minimum number of repetitions.
+        min: usize,
+        /// The maximum number of repetitions, `None` for no limit. Not less than `min`, see
+        /// [`repeat`].
+        max: Option<usize>,
+    },
+    /// Matches a rule.
+    Rule(RuleRef),
+}
+
+impl From<RuleRef> for GrammarExpr {
+    fn from(rule: RuleRef) -> Self {
+        GrammarExpr::Rule(rule)
+    }
+}
+
+impl From<&str> for GrammarExpr {
+    fn from(literal: &str) -> Self {
+        GrammarExpr::Literal(literal.to_string())
+    }
+}
+
+/// Matches the string exactly.
+#[must_use]
+pub fn literal(literal: impl Into<String>) -> GrammarExpr {
+    GrammarExpr::Literal(literal.into())
+}
+
+/// Matches a single char in any of the ranges. Use `c..=c` for a single char.
+///
+/// ```
+/// # use llama_cpp_2::grammar::builder::{char_class, GrammarBuilder};
+/// let mut builder = GrammarBuilder::new();
+/// let hex = builder.rule("hex", char_class(['0'..='9', 'a'..='f', '-'..='-']));
+/// assert_eq!(builder.build(hex)?, "root ::= hex\nhex ::= [0-9a-f\\x2D]\n");
+/// # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+/// ```
+#[must_use]
+pub fn char_class(ranges: impl IntoIterator<Item = RangeInclusive<char>>) -> GrammarExpr {
+    GrammarExpr::CharClass {
+        negated: false,
+        ranges: ranges.into_iter().collect(),
+    }
+}
+
+/// Matches a single char that is not in any of the ranges.
+#[must_use]
+pub fn not_char_class(ranges: impl IntoIterator<Item = RangeInclusive<char>>) -> GrammarExpr {
+    GrammarExpr::CharClass {
+        negated: true,
+        ranges: ranges.into_iter().collect(),
+    }
+}
+
+/// Matches each expression in order.
+#[must_use]
+pub fn seq(exprs: impl IntoIterator<Item = GrammarExpr>) -> GrammarExpr {
+    GrammarExpr::Seq(exprs.into_iter().collect())
+}
+
+/// Matches any one of the expressions.
+#[must_use]
+pub fn choice(exprs: impl IntoIterator<Item = GrammarExpr>) -> GrammarExpr {
+    GrammarExpr::Choice(exprs.into_iter().collect())
+}
+
+/// Matches `expr` between `min` and `max` (unbounded if `None`) times.
+///
+/// # Panics
+///
+/// - `max` is less than `min`.
+///
+/// ```
+/// # use llama_cpp_2::grammar::builder::{literal, repeat, GrammarBuilder};
+/// let mut builder = GrammarBuilder::new();
+/// let ab = builder.rule("ab", repeat(literal("ab"), 1, Some(3)));
+/// assert_eq!(builder.build(ab)?, "root ::= ab\nab ::= \"ab\" (\"ab\" (\"ab\")?)?\n");
+/// # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+/// ```
+#[must_use]
+pub fn repeat(expr: GrammarExpr, min: usize, max: Option<usize>) -> GrammarExpr {
+    assert!(
+        max.is_none_or(|max| max >= min),
+        "max ({max:?}) should not be less than min ({min})"
+    );
+    GrammarExpr::Repeat {
+        expr: Box::new(expr),
+        min,
+        max,
+    }
+}
+
+/// Matches `expr` zero or one times.
+#[must_use]
+pub fn optional(expr: GrammarExpr) -> GrammarExpr {
+    repeat(expr, 0, Some(1))
+}
+
+/// Matches `expr` any number of times.
+#[must_use]
+pub fn zero_or_more(expr: GrammarExpr) -> GrammarExpr {
+    repeat(expr, 0, None)
+}
+
+/// Matches `expr` at least once.
+#[must_use]
+pub fn one_or_more(expr: GrammarExpr) -> GrammarExpr {
+    repeat(expr, 1, None)
+}
+
+/// An error building a grammar with a [`GrammarBuilder`].
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub enum GrammarBuildError {
+    /// A rule was declared with [`GrammarBuilder::declare`] but never defined.
+    #[error("rule {0:?} was declared but never defined")]
+    UndefinedRule(String),
+    /// llama.cpp failed to create the grammar.
+    #[error(transparent)]
+    Grammar(#[from] LlamaGrammarFromStrError),
+}
+
+/// Builds a GBNF grammar from typed rules. See the [module docs](crate::grammar::builder).
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct GrammarBuilder {
+    rules: Vec<(String, Option<GrammarExpr>)>,
+}
+
+impl GrammarBuilder {
+    /// Create an empty builder.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a rule without defining it yet, e.g. for recursive rules.
+    ///
+    /// The name is only used for readability of the generated grammar. Characters that are not
+    /// allowed in GBNF names are replaced and duplicate names get a numeric suffix.
+    pub fn declare(&mut self, name: &str) -> RuleRef {
+        let mut name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            name.insert_str(0, "rule-");
+        }
+        if name == "root" || self.rules.iter().any(|(n, _)| *n == name) {
+            name = format!("{name}-{}", self.rules.len());
+        }
+        self.rules.push((name, None));
+        RuleRef(self.rules.len() - 1)
+    }
+
+    /// Define (or redefine) a previously declared rule.
+    ///
+    /// # Panics
+    ///
+    /// - `rule` was not created by this builder.
+    pub fn define(&mut self, rule: RuleRef, expr: GrammarExpr) {
+        self.rules
+            .get_mut(rule.0)
+            .expect("rule was not created by this builder")
+            .1 = Some(expr);
+    }
+
+    /// Declare and define a rule.
+    pub fn rule(&mut self, name: &str, expr: GrammarExpr) -> RuleRef {
+        let rule = self.declare(name);
+        self.define(rule, expr);
+        rule
+    }
+
+    /// Render the grammar as GBNF text, starting at `root`.
+    ///
+    /// # Errors
+    ///
+    /// - a declared rule was never defined.
+    ///
+    /// # Panics
+    ///
+    /// - `root` was not created by this builder.
+    pub fn build(&self, root: RuleRef) -> Result<String, GrammarBuildError> {
+        let mut gbnf = format!("root ::= {}\n", self.rules[root.0].0);
+        for (name, expr) in &self.rules {
+            let expr = expr
+                .as_ref()
+                .ok_or_else(|| GrammarBuildError::UndefinedRule(name.clone()))?;
+            gbnf.push_str(name);
+            gbnf.push_str(" ::= ");
+            self.write_expr(&mut gbnf, expr, false);
+            gbnf.push('\n');
+        }
+        Ok(gbnf)
+    }
+
+    /// Render the grammar and load it into a [`LlamaGrammar`].
+    ///
+    /// # Errors
+    ///
+    /// - a declared rule was never defined.
+    /// - llama.cpp failed to create the grammar.
+    ///
+    /// # Panics
+    ///
+    /// - `root` was not created by this builder.
+    pub fn build_grammar(&self, root: RuleRef) -> Result<LlamaGrammar, GrammarBuildError> {
+        Ok(LlamaGrammar::from_str(&self.build(root)?)?)
+    }
+
+    /// Write `expr` to `out`. `nested` expressions are wrapped in parentheses if they are not a
+    /// single symbol.
+    fn write_expr(&self, out: &mut String, expr: &GrammarExpr, nested: bool) {
+        match expr {
+            GrammarExpr::Literal(literal) => {
+                out.push('"');
+                for c in literal.chars() {
+                    write_char(out, c, &['"', '\\']);
+                }
+                out.push('"');
+            }
+            GrammarExpr::CharClass { negated, ranges } => {
+                out.push('[');
+                if *negated {
+                    out.push('^');
+                }
+                for range in ranges {
+                    write_char(out, *range.start(), &['\\', ']', '-', '^']);
+                    if range.start() != range.end() {
+                        out.push('-');
+                        write_char(out, *range.end(), &['\\', ']', '-', '^']);
+                    }
+                }
+                out.push(']');
+            }
+            GrammarExpr::Seq(exprs) | GrammarExpr::Choice(exprs) if exprs.is_empty() => {
+                out.push_str("\"\"");
+            }
+            GrammarExpr::Seq(exprs) | GrammarExpr::Choice(exprs) if exprs.len() == 1 => {
+                self.write_expr(out, &exprs[0], nested);
+            }
+            GrammarExpr::Seq(exprs) => {
+                write_group(out, nested, |out| {
+                    for (i, expr) in exprs.iter().enumerate() {
+                        if i > 0 {
+                            out.push(' ');
+                        }
+                        self.write_expr(out, expr, true);
+                    }
+                });
+            }
+            GrammarExpr::Choice(exprs) => {
+                write_group(out, nested, |out| {
+                    for (i, expr) in exprs.iter().enumerate() {
+                        if i > 0 {
+                            out.push_str(" | ");
+                        }
+                        self.write_expr(out, expr, false);
+                    }
+                });
+            }
+            GrammarExpr::Repeat { expr, min, max } => {
+                write_group(out, nested, |out| self.write_repeat(out, expr, *min, *max));
+            }
+            GrammarExpr::Rule(rule) => out.push_str(&self.rules[rule.0].0),
+        }
+    }
+
+    fn write_repeat(&self, out: &mut String, expr: &GrammarExpr, min: usize, max: Option<usize>) {
+        let required = match max {
+            // `x+` is shorter than `x x*`
+            None if min > 0 => min - 1,
+            _ => min,
+        };
+        for _ in 0..required {
+            self.write_expr(out, expr, true);
+            out.push(' ');
+        }
+        match max {
+            None => {
+                self.write_postfix(out, expr);
+                out.push(if min > 0 { '+' } else { '*' });
+            }
+            Some(max) => {
+                // `x{0,n}` is `(x (x ...)?)?`
+                let optional = max.saturating_sub(min);
+                for i in 0..optional {
+                    if i > 0 {
+                        out.push_str(" (");
+                    } else {
+                        out.push('(');
+                    }
+                    self.write_expr(out, expr, false);
+                }
+                for _ in 0..optional {
+                    out.push_str(")?");
+                }
+            }
+        }
+        if out.ends_with(' ') {
+            out.pop();
+        }
+    }
+
+    /// Write `expr` so a postfix operator applies to all of it.
+    fn write_postfix(&self, out: &mut String, expr: &GrammarExpr) {
+        match expr {
+            GrammarExpr::Literal(_) | GrammarExpr::CharClass { .. } | GrammarExpr::Rule(_) => {
+                self.write_expr(out, expr, true);
+            }
+            _ => {
+                out.push('(');
+                self.write_expr(out, expr, false);
+                out.push(')');
+            }
+        }
+    }
+}
+
+/// Write the output of `write`, wrapped in parentheses if `nested`.
+fn write_group(out: &mut String, nested: bool, write: impl FnOnce(&mut String)) {
+    if nested {
+        out.push('(');
+    }
+    write(out);
+    if nested {
+        out.push(')');
+    }
+}
+
+/// Write `c` escaping it if it is a control char or in `special`.
+fn write_char(out: &mut String, c: char, special: &[char]) {
+    match c {
+        '\n' => out.push_str("\\n"),
+        '\r' => out.push_str("\\r"),
+        '\t' => out.push_str("\\t"),
+        '\\' | '"' | ']' if special.contains(&c) => {
+            out.push('\\');
+            out.push(c);
+        }
+        c if c.is_ascii_control() || special.contains(&c) => {
+            write!(out, "\\x{:02X}", u32::from(c)).expect("writing to a string never fails");
+        }
+        c => out.push(c),
+    }
+}
@@ -0,0 +1,396 @@
+//! Generate JSON grammars from Rust types.
+//!
+//! Types implementing [`LlamaSchema`] describe the JSON they serialize to as a grammar
+//! expression. With the `derive` feature enabled, `#[derive(LlamaSchema)]` implements it for
+//! structs with named fields (as JSON objects with the fields in declaration order) and for enums
+//! with only unit variants (as JSON strings of the variant names).
+//!
+//! # Examples
+//!
+//! ```
+//! use llama_cpp_2::grammar::builder::GrammarExpr;
+//! use llama_cpp_2::grammar::schema::{json_gbnf, LlamaSchema, SchemaBuilder};
+//!
+//! struct Answer {
+//!     confidence: f32,
+//!     sources: Vec<String>,
+//! }
+//!
+//! // this is what `#[derive(LlamaSchema)]` generates
+//! impl LlamaSchema for Answer {
+//!     fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+//!         let fields = [
+//!             ("confidence", builder.rule_for::<f32>()),
+//!             ("sources", builder.rule_for::<Vec<String>>()),
+//!         ];
+//!         builder.object(&fields)
+//!     }
+//! }
+//!
+//! let gbnf = json_gbnf::<Answer>()?;
+//! assert!(gbnf.starts_with("root ::= Answer\n"));
+//! # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+//! ```
+//!
+//! With the `derive` feature:
+//!
+//! ```
+//! # #[cfg(feature = "derive")]
+//! # {
+//! use llama_cpp_2::grammar::schema::{json_grammar, LlamaSchema};
+//!
+//! #[derive(LlamaSchema)]
+//! enum Sentiment {
+//!     Positive,
+//!     Negative,
+//! }
+//!
+//! #[derive(LlamaSchema)]
+//! struct Review {
+//!     sentiment: Sentiment,
+//!     summary: String,
+//!     stars: Option<u8>,
+//! }
+//!
+//! let grammar = json_grammar::<Review>()?;
+//! # }
+//! # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+//! ```
+
+use std::collections::HashMap;
+
+use crate::grammar::builder::{
+    char_class, choice, literal, not_char_class, one_or_more, optional, repeat, seq, zero_or_more,
+    GrammarBuildError, GrammarBuilder, GrammarExpr, RuleRef,
+};
+use crate::grammar::LlamaGrammar;
+
+#[cfg(feature = "derive")]
+pub use llama_cpp_2_derive::LlamaSchema;
+
+/// A type with a known JSON representation that can be described as a grammar.
+pub trait LlamaSchema {
+    /// The grammar expression matching the JSON representation of `Self`.
+    ///
+    /// Use [`SchemaBuilder::rule_for`] to reference other types rather than calling their
+    /// `schema` directly, so each type is only described once and recursive types work.
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr;
+}
+
+/// A [`GrammarBuilder`] that creates at most one rule per type.
+#[derive(Debug, Default)]
+pub struct SchemaBuilder {
+    builder: GrammarBuilder,
+    types: HashMap<&'static str, RuleRef>,
+}
+
+impl SchemaBuilder {
+    /// Create an empty builder.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the rule for `T`, creating it if this is the first time `T` is used.
+    pub fn rule_for<T: LlamaSchema + ?Sized>(&mut self) -> RuleRef {
+        let type_name = std::any::type_name::<T>();
+        if let Some(&rule) = self.types.get(type_name) {
+            return rule;
+        }
+        let rule = self.builder.declare(&short_type_name(type_name));
+        self.types.insert(type_name, rule);
+        let expr = T::schema(self);
+        self.builder.define(rule, expr);
+        rule
+    }
+
+    /// A JSON object with the given fields, in order, separated by optional whitespace.
+    pub fn object(&mut self, fields: &[(&str, RuleRef)]) -> GrammarExpr {
+        let ws = self.ws();
+        let mut exprs = vec![literal("{"), ws.clone()];
+        for (i, (name, rule)) in fields.iter().enumerate() {
+            if i > 0 {
+                exprs.extend([literal(","), ws.clone()]);
+            }
+            exprs.extend([
+                literal(json_string(name)),
+                ws.clone(),
+                literal(":"),
+                ws.clone(),
+                (*rule).into(),
+                ws.clone(),
+            ]);
+        }
+        exprs.push(literal("}"));
+        seq(exprs)
+    }
+
+    /// Optional whitespace between JSON tokens: a space, or a new line indented by up to 20 spaces
+    /// or tabs, like llama.cpp's JSON schema converter. Unbounded whitespace lets models loop on
+    /// new lines forever.
+    pub fn ws(&mut self) -> GrammarExpr {
+        self.rule_for::<Whitespace>().into()
+    }
+
+    /// The underlying [`GrammarBuilder`], e.g. to add rules not tied to a type.
+    pub fn builder(&mut self) -> &mut GrammarBuilder {
+        &mut self.builder
+    }
+
+    /// Render the grammar as GBNF text, starting at `root`.
+    ///
+    /// # Errors
+    ///
+    /// See [`GrammarBuilder::build`].
+    pub fn build(&self, root: RuleRef) -> Result<String, GrammarBuildError> {
+        self.builder.build(root)
+    }
+}
+
+/// The GBNF grammar for the JSON representation of `T`.
+///
+/// # Errors
+///
+/// See [`GrammarBuilder::build`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::grammar::schema::json_gbnf;
+/// // a `char` is a string of exactly one character
+/// let gbnf = json_gbnf::<char>()?;
+/// assert!(gbnf.starts_with("root ::= char\nchar ::= \"\\\"\" StringChar \"\\\"\"\n"));
+/// # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+/// ```
+pub fn json_gbnf<T: LlamaSchema + ?Sized>() -> Result<String, GrammarBuildError> {
+    let mut builder = SchemaBuilder::new();
+    let root = builder.rule_for::<T>();
+    builder.build(root)
+}
+
+/// A [`LlamaGrammar`] constraining output to the JSON representation of `T`.
+///
+/// # Errors
+///
+/// See [`GrammarBuilder::build_grammar`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::grammar::schema::json_grammar;
+/// let grammar = json_grammar::<Vec<Option<bool>>>()?;
+/// # Ok::<(), llama_cpp_2::grammar::builder::GrammarBuildError>(())
+/// ```
+pub fn json_grammar<T: LlamaSchema + ?Sized>() -> Result<LlamaGrammar, GrammarBuildError> {
+    let mut builder = SchemaBuilder::new();
+    let root = builder.rule_for::<T>();
+    builder.builder.build_grammar(root)
+}
+
+/// Quote and escape `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `alloc::vec::Vec<my_crate::Foo>` -> `Vec<Foo>`
+fn short_type_name(type_name: &str) -> String {
+    let mut short = String::with_capacity(type_name.len());
+    let mut segment = String::new();
+    for c in type_name.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            segment.push(c);
+        } else if c == ':' {
+            segment.clear();
+        } else {
+            short.push_str(&segment);
+            segment.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(&segment);
+    short
+}
+
+/// Marker type for the whitespace rule.
+struct Whitespace;
+
+impl LlamaSchema for Whitespace {
+    fn schema(_: &mut SchemaBuilder) -> GrammarExpr {
+        optional(choice([
+            literal(" "),
+            seq([
+                literal("\n"),
+                repeat(char_class([' '..=' ', '\t'..='\t']), 0, Some(20)),
+            ]),
+        ]))
+    }
+}
+
+/// Marker type for a single (possibly escaped) character of a JSON string.
+struct StringChar;
+
+impl LlamaSchema for StringChar {
+    fn schema(_: &mut SchemaBuilder) -> GrammarExpr {
+        let escape = seq([
+            literal("\\"),
+            choice([
+                char_class([
+                    '"'..='"',
+                    '\\'..='\\',
+                    '/'..='/',
+                    'b'..='b',
+                    'f'..='f',
+                    'n'..='n',
+                    'r'..='r',
+                    't'..='t',
+                ]),
+                seq([
+                    literal("u"),
+                    repeat(char_class(['0'..='9', 'a'..='f', 'A'..='F']), 4, Some(4)),
+                ]),
+            ]),
+        ]);
+        choice([
+            not_char_class(['"'..='"', '\\'..='\\', '\0'..='\x1F']),
+            escape,
+        ])
+    }
+}
+
+impl LlamaSchema for str {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        seq([
+            literal("\""),
+            zero_or_more(builder.rule_for::<StringChar>().into()),
+            literal("\""),
+        ])
+    }
+}
+
+impl LlamaSchema for String {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        builder.rule_for::<str>().into()
+    }
+}
+
+impl LlamaSchema for char {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        seq([
+            literal("\""),
+            builder.rule_for::<StringChar>().into(),
+            literal("\""),
+        ])
+    }
+}
+
+impl LlamaSchema for bool {
+    fn schema(_: &mut SchemaBuilder) -> GrammarExpr {
+        choice([literal("true"), literal("false")])
+    }
+}
+
+/// Marker type for the unsigned integer rule.
+struct Unsigned;
+
+impl LlamaSchema for Unsigned {
+    fn schema(_: &mut SchemaBuilder) -> GrammarExpr {
+        choice([
+            literal("0"),
+            seq([
+                char_class(['1'..='9']),
+                zero_or_more(char_class(['0'..='9'])),
+            ]),
+        ])
+    }
+}
+
+/// Marker type for the signed integer rule.
+struct Signed;
+
+impl LlamaSchema for Signed {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        seq([
+            optional(literal("-")),
+            builder.rule_for::<Unsigned>().into(),
+        ])
+    }
+}
+
+/// Marker type for the number rule.
+struct Number;
+
+impl LlamaSchema for Number {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        seq([
+            builder.rule_for::<Signed>().into(),
+            optional(seq([literal("."), one_or_more(char_class(['0'..='9']))])),
+            optional(seq([
+                char_class(['e'..='e', 'E'..='E']),
+                optional(char_class(['-'..='-', '+'..='+'])),
+                one_or_more(char_class(['0'..='9'])),
+            ])),
+        ])
+    }
+}
+
+macro_rules! impl_llama_schema {
+    ($rule:ty => $($t:ty),*) => {
+        $(
+            impl LlamaSchema for $t {
+                fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+                    builder.rule_for::<$rule>().into()
+                }
+            }
+        )*
+    };
+}
+
+impl_llama_schema!(Unsigned => u8, u16, u32, u64, u128, usize);
+impl_llama_schema!(Signed => i8, i16, i32, i64, i128, isize);
+impl_llama_schema!(Number => f32, f64);
+
+impl<T: LlamaSchema> LlamaSchema for Option<T> {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        choice([builder.rule_for::<T>().into(), literal("null")])
+    }
+}
+
+impl<T: LlamaSchema> LlamaSchema for Vec<T> {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        builder.rule_for::<[T]>().into()
+    }
+}
+
+impl<T: LlamaSchema> LlamaSchema for [T] {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        let ws = builder.ws();
+        let item: GrammarExpr = builder.rule_for::<T>().into();
+        seq([
+            literal("["),
+            ws.clone(),
+            optional(seq([
+                item.clone(),
+                zero_or_more(seq([ws.clone(), literal(","), ws.clone(), item])),
+                ws.clone(),
+            ])),
+            literal("]"),
+        ])
+    }
+}
+
+impl<T: LlamaSchema + ?Sized> LlamaSchema for Box<T> {
+    fn schema(builder: &mut SchemaBuilder) -> GrammarExpr {
+        builder.rule_for::<T>().into()
+    }
+}
@@ -51,7 +51,79 @@ fn check_parse_simple() {
                     value: 0,
                 }
             ]],
+            ..ParseState::new()
         },
         parse_state
     );
 }
+
+#[test]
+fn check_undefined_rule() {
+    let error = ParseState::from_str("root ::= greeting \" world\"").unwrap_err();
+    assert!(matches!(
+        error,
+        GrammarParseError::UndefinedRule { name } if name == "greeting"
+    ));
+    let error = ParseState::from_str("greeting ::= \"hello\"").unwrap_err();
+    assert!(matches!(error, GrammarParseError::MissingRoot));
+}
+
+#[test]
+fn check_parse_char_range() {
+    let parse_state = ParseState::from_str("root ::= [a-c-]").unwrap();
+    assert_eq!(
+        parse_state.rules[0],
+        vec![
+            llama_grammar_element {
+                type_: llama_cpp_sys_2::LLAMA_GRETYPE_CHAR,
+                value: 'a' as u32,
+            },
+            llama_grammar_element {
+                type_: llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_RNG_UPPER,
+                value: 'c' as u32,
+            },
+            llama_grammar_element {
+                type_: llama_cpp_sys_2::LLAMA_GRETYPE_CHAR_ALT,
+                value: '-' as u32,
+            },
+            llama_grammar_element {
+                type_: llama_cpp_sys_2::LLAMA_GRETYPE_END,
+                value: 0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn check_builder_output_parses() {
+    use builder::{
+        char_class, choice, literal, not_char_class, one_or_more, optional, seq, zero_or_more,
+        GrammarBuilder,
+    };
+
+    let mut builder = GrammarBuilder::new();
+    let string = builder.rule(
+        "string",
+        seq([
+            literal("\""),
+            zero_or_more(choice([
+                not_char_class(['"'..='"', '\\'..='\\', '\n'..='\n']),
+                seq([
+                    literal("\\"),
+                    char_class(['"'..='"', '\\'..='\\', 'n'..='n']),
+                ]),
+            ])),
+            literal("\""),
+        ]),
+    );
+    let list = builder.declare("list");
+    builder.define(
+        list,
+        seq([string.into(), optional(seq([literal(", "), list.into()]))]),
+    );
+    let number = builder.rule("number", one_or_more(char_class(['0'..='9'])));
+    let root = builder.rule("my root", choice([list.into(), number.into()]));
+    let gbnf = builder.build(root).unwrap();
+    ParseState::from_str(&gbnf).unwrap();
+    LlamaGrammar::from_str(&gbnf).unwrap();
+}
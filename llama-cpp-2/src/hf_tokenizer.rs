@@ -0,0 +1,142 @@
+//! Tokenization with a Hugging Face [`tokenizers::Tokenizer`] instead of the tokenizer in the GGUF
+//! file, for models whose converted tokenizer is known to misbehave.
+//!
+//! Token ids are mapped between the two vocabularies by their text, so the tokens can be decoded
+//! by llama.cpp even if the ids of the two tokenizers differ.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::hf_tokenizer::HfTokenizer;
+//! # use llama_cpp_2::model::LlamaModel;
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//!
+//! let tokenizer = HfTokenizer::from_file("path/to/tokenizer.json", &model)?;
+//! let tokens = tokenizer.encode("Hello, World!", true)?;
+//! assert_eq!(tokenizer.decode(&tokens, false)?, "Hello, World!");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+
+/// An error from [`HfTokenizer`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum HfTokenizerError {
+    /// The `tokenizers` crate failed.
+    #[error("{0}")]
+    Tokenizers(tokenizers::Error),
+    /// A token of the Hugging Face tokenizer has no counterpart in the model's vocabulary.
+    #[error("Hugging Face token id {0} is not in the model's vocabulary")]
+    UnmappedId(u32),
+    /// A token of the model has no counterpart in the Hugging Face tokenizer's vocabulary.
+    #[error("token {0} is not in the Hugging Face tokenizer's vocabulary")]
+    UnmappedToken(LlamaToken),
+}
+
+/// A Hugging Face tokenizer producing tokens of a [`LlamaModel`].
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct HfTokenizer {
+    tokenizer: tokenizers::Tokenizer,
+    to_llama: HashMap<u32, LlamaToken>,
+    from_llama: HashMap<LlamaToken, u32>,
+}
+
+impl HfTokenizer {
+    /// Use `tokenizer` to tokenize text for `model`, mapping the ids of tokens with the same text.
+    #[must_use]
+    pub fn new(tokenizer: tokenizers::Tokenizer, model: &LlamaModel) -> Self {
+        let llama_vocab = model
+            .export_vocab()
+            .into_iter()
+            .map(|entry| (entry.text, entry.token))
+            .collect::<HashMap<_, _>>();
+        let to_llama = tokenizer
+            .get_vocab(true)
+            .into_iter()
+            .filter_map(|(text, id)| llama_vocab.get(&text).map(|&token| (id, token)))
+            .collect::<HashMap<_, _>>();
+        let from_llama = to_llama.iter().map(|(&id, &token)| (token, id)).collect();
+        Self {
+            tokenizer,
+            to_llama,
+            from_llama,
+        }
+    }
+
+    /// Load a `tokenizer.json` file and use it for `model`, see [`Self::new`].
+    ///
+    /// # Errors
+    ///
+    /// If the file can not be read or is not a valid tokenizer.
+    pub fn from_file(path: impl AsRef<Path>, model: &LlamaModel) -> Result<Self, HfTokenizerError> {
+        let tokenizer =
+            tokenizers::Tokenizer::from_file(path).map_err(HfTokenizerError::Tokenizers)?;
+        Ok(Self::new(tokenizer, model))
+    }
+
+    /// The underlying Hugging Face tokenizer.
+    #[must_use]
+    pub fn tokenizer(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Tokenize `text`, adding the special tokens configured in the tokenizer (such as BOS) if
+    /// `add_special_tokens` is set.
+    ///
+    /// # Errors
+    ///
+    /// If the tokenizer fails or produces a token the model does not have.
+    pub fn encode(
+        &self,
+        text: &str,
+        add_special_tokens: bool,
+    ) -> Result<Vec<LlamaToken>, HfTokenizerError> {
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(HfTokenizerError::Tokenizers)?;
+        encoding
+            .get_ids()
+            .iter()
+            .map(|&id| {
+                self.to_llama
+                    .get(&id)
+                    .copied()
+                    .ok_or(HfTokenizerError::UnmappedId(id))
+            })
+            .collect()
+    }
+
+    /// Convert `tokens` back to text, leaving out special tokens if `skip_special_tokens` is set.
+    ///
+    /// # Errors
+    ///
+    /// If a token is not in the tokenizer's vocabulary or the tokenizer fails.
+    pub fn decode(
+        &self,
+        tokens: &[LlamaToken],
+        skip_special_tokens: bool,
+    ) -> Result<String, HfTokenizerError> {
+        let ids = tokens
+            .iter()
+            .map(|&token| {
+                self.from_llama
+                    .get(&token)
+                    .copied()
+                    .ok_or(HfTokenizerError::UnmappedToken(token))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.tokenizer
+            .decode(&ids, skip_special_tokens)
+            .map_err(HfTokenizerError::Tokenizers)
+    }
+}
@@ -12,21 +12,60 @@
 //! # Feature Flags
 //!
 //! - `cublas` enables CUDA gpu support.
+//! - `dynamic-link` links against a shared libllama installed on the system instead of compiling llama.cpp, see the `llama-cpp-sys-2` readme.
+//!   Only the installed `llama.h` is compared byte for byte with the vendored one when building;
+//!   the library loaded at runtime is not checked, so it has to stay the same version.
+//! - `prebuilt` links prebuilt static llama.cpp libraries instead of compiling llama.cpp, see the `llama-cpp-sys-2` readme.
 //! - `sampler` adds the [`context::sample::sampler`] struct for a more rusty way of sampling.
+//! - `derive` adds `#[derive(LlamaSchema)]` to generate JSON grammars for Rust types. See [`grammar::schema`].
+//! - `serde` implements `Serialize` and `Deserialize` for generation output such as [`generation::chunk::GenerationChunk`].
+//! - `tokenizers` adds `hf_tokenizer` to tokenize with a Hugging Face `tokenizers::Tokenizer`.
+//! - `hf-hub` adds `LlamaModel::from_hf` to download models from the Hugging Face Hub.
+//! - `registry` adds `registry` to keep track of model files on disk.
+//! - `tracing-spans` wraps decoding, tokenization and sampling in `tracing` spans (with the batch
+//!   size and sequence ids), so a subscriber can record how long each of them took.
+//! - `metrics` reports tokens, decode latency and KV cache usage through the `metrics` facade, see
+//!   [`metrics`].
+//! - `graphemes` adds [`generation::chunk::GenerationChunks::with_complete_graphemes`] to only
+//!   stream whole grapheme clusters.
+//! - `async` adds [`generation::worker`] to generate on a dedicated thread and stream the tokens
+//!   to async code.
+//! - `jinja` adds [`model::jinja`] to render chat templates llama.cpp does not know with a Jinja
+//!   engine.
+//!
+//! # WebAssembly
+//!
+//! This crate builds for `wasm32-wasip1-threads` and `wasm32-unknown-emscripten`, see the
+//! `llama-cpp-sys-2` readme. The `cublas` and `hf-hub` features are not supported there.
+#[cfg(all(target_family = "wasm", feature = "hf-hub"))]
+compile_error!("the `hf-hub` feature is not supported on WebAssembly");
+
 use std::ffi::NulError;
 use std::fmt::Debug;
 use std::num::NonZeroI32;
 
 use crate::llama_batch::BatchAddError;
+use crate::token::LlamaToken;
 use std::os::raw::c_int;
 use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
+pub mod bench;
 pub mod context;
+pub mod embedding;
+pub mod eval;
+pub mod generation;
 pub mod grammar;
+#[cfg(feature = "tokenizers")]
+pub mod hf_tokenizer;
 pub mod llama_backend;
 pub mod llama_batch;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod model;
+#[cfg(feature = "registry")]
+pub mod registry;
+pub mod speculative;
 pub mod timing;
 pub mod token;
 pub mod token_type;
@@ -64,7 +103,7 @@ pub enum LLamaCppError {
 /// There was an error while getting the chat template from a model.
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
 pub enum ChatTemplateError {
-    /// gguf has no chat template
+    /// gguf has no chat template, with the code returned by llama.cpp.
     #[error("the model has no meta val - returned code {0}")]
     MissingTemplate(i32),
     /// The chat template was not valid utf8.
@@ -78,20 +117,161 @@ pub enum LlamaContextLoadError {
     /// llama.cpp returned null
     #[error("null reference from llama.cpp")]
     NullReturn,
+    /// The model is encoder-only and the context was not created with embeddings enabled.
+    #[error("encoder-only models can only be used with embeddings enabled")]
+    EncoderOnlyModel,
 }
 
 /// Failed to decode a batch.
+///
+/// llama.cpp returns positive codes for warnings, after which the context is unchanged and decoding
+/// can be retried (see [`DecodeError::is_recoverable`]), and negative codes for errors.
+///
+/// # Examples
+///
+/// A server can retry a batch that did not fit with fewer tokens, or after making room in the
+/// cache:
+///
+/// ```no_run
+/// # use llama_cpp_2::context::LlamaContext;
+/// # use llama_cpp_2::llama_batch::LlamaBatch;
+/// # use llama_cpp_2::DecodeError;
+/// # fn decode(ctx: &mut LlamaContext, batch: &mut LlamaBatch) -> Result<(), DecodeError> {
+/// match ctx.decode(batch) {
+///     Err(DecodeError::NoKvCacheSlot { used_cells, .. }) if used_cells > 0 => {
+///         // the cache may just be fragmented, compact it and try again
+///         ctx.kv_cache_defrag();
+///         ctx.kv_cache_update();
+///         ctx.decode(batch)
+///     }
+///     result => result,
+/// }
+/// # }
+/// ```
 #[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[non_exhaustive]
 pub enum DecodeError {
-    /// No kv cache slot was available.
-    #[error("Decode Error 1: NoKvCacheSlot")]
-    NoKvCacheSlot,
+    /// No kv cache slot was available for the batch. Retry with a smaller batch, after
+    /// defragmenting the cache ([`context::LlamaContext::kv_cache_defrag`]) or after evicting
+    /// sequences from it.
+    #[error("Decode Error 1: NoKvCacheSlot for {n_tokens} tokens with {used_cells} of {n_ctx} cells used")]
+    NoKvCacheSlot {
+        /// The number of tokens in the batch.
+        n_tokens: i32,
+        /// The number of cells of the kv cache used by at least one sequence.
+        used_cells: i32,
+        /// The size of the kv cache.
+        n_ctx: u32,
+    },
     /// The number of tokens in the batch was 0.
     #[error("Decode Error -1: n_tokens == 0")]
     NTokensZero,
-    /// An unknown error occurred.
+    /// The batch has more tokens than the `n_batch` of the context. llama.cpp aborts on such
+    /// batches, so this is checked before decoding. Like other invalid batches its code is -1.
+    #[error("Decode Error -1: batch of {n_tokens} tokens exceeds n_batch {n_batch}")]
+    BatchTooLarge {
+        /// The number of tokens in the batch.
+        n_tokens: i32,
+        /// The `n_batch` of the context.
+        n_batch: u32,
+    },
+    /// The batch has more tokens than the `n_ubatch` of a context without causal attention, which
+    /// has to decode the whole batch at once. llama.cpp aborts on such batches, so this is checked
+    /// before decoding. Like other invalid batches its code is -1.
+    #[error("Decode Error -1: batch of {n_tokens} tokens exceeds n_ubatch {n_ubatch} without causal attention")]
+    UbatchTooLarge {
+        /// The number of tokens in the batch.
+        n_tokens: i32,
+        /// The `n_ubatch` of the context.
+        n_ubatch: u32,
+    },
+    /// An unknown warning (positive code) occurred.
     #[error("Decode Error {0}: unknown")]
     Unknown(c_int),
+    /// An unknown error (negative code) occurred, such as failing to compute the graph.
+    #[error("Decode Error {0}: fatal")]
+    Fatal(c_int),
+}
+
+impl DecodeError {
+    /// The code returned by `llama_decode`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::DecodeError;
+    /// assert_eq!(DecodeError::Fatal(-3).code(), -3);
+    /// let error = DecodeError::NoKvCacheSlot { n_tokens: 512, used_cells: 3900, n_ctx: 4096 };
+    /// assert_eq!(error.code(), 1);
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "Decode Error 1: NoKvCacheSlot for 512 tokens with 3900 of 4096 cells used"
+    /// );
+    /// ```
+    #[must_use]
+    pub fn code(&self) -> c_int {
+        match *self {
+            DecodeError::NoKvCacheSlot { .. } => 1,
+            DecodeError::NTokensZero
+            | DecodeError::BatchTooLarge { .. }
+            | DecodeError::UbatchTooLarge { .. } => -1,
+            DecodeError::Unknown(code) | DecodeError::Fatal(code) => code,
+        }
+    }
+
+    /// Is this a warning after which the context is unchanged, so decoding (a smaller batch) can
+    /// be retried?
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::DecodeError;
+    /// let error = DecodeError::NoKvCacheSlot { n_tokens: 512, used_cells: 3900, n_ctx: 4096 };
+    /// assert!(error.is_recoverable());
+    /// assert!(!DecodeError::Fatal(-3).is_recoverable());
+    /// ```
+    #[must_use]
+    pub fn is_recoverable(&self) -> bool {
+        self.code() > 0
+    }
+
+    /// Convert an error code of `llama_decode`, recording the state of `ctx` for a batch of
+    /// `n_tokens`.
+    pub(crate) fn new(code: NonZeroI32, n_tokens: i32, ctx: &context::LlamaContext) -> Self {
+        Self::with_counts(code, n_tokens, ctx.get_kv_cache_used_cells(), ctx.n_ctx())
+    }
+
+    fn with_counts(code: NonZeroI32, n_tokens: i32, used_cells: i32, n_ctx: u32) -> Self {
+        match code.get() {
+            1 => DecodeError::NoKvCacheSlot {
+                n_tokens,
+                used_cells,
+                n_ctx,
+            },
+            -1 => DecodeError::NTokensZero,
+            i if i < 0 => DecodeError::Fatal(i),
+            i => DecodeError::Unknown(i),
+        }
+    }
+}
+
+/// Convert an error code of `llama_decode` without a context. The counts of
+/// [`DecodeError::NoKvCacheSlot`] are unknown and 0, errors returned by
+/// [`context::LlamaContext::decode`] have them.
+///
+/// # Examples
+///
+/// ```
+/// # use std::num::NonZeroI32;
+/// # use llama_cpp_2::DecodeError;
+/// let error = DecodeError::from(NonZeroI32::new(1).unwrap());
+/// assert!(matches!(error, DecodeError::NoKvCacheSlot { n_ctx: 0, .. }));
+/// assert_eq!(DecodeError::from(NonZeroI32::new(-3).unwrap()), DecodeError::Fatal(-3));
+/// ```
+impl From<NonZeroI32> for DecodeError {
+    fn from(code: NonZeroI32) -> Self {
+        Self::with_counts(code, 0, 0, 0)
+    }
 }
 
 /// When embedding related functions fail
@@ -103,20 +283,12 @@ pub enum EmbeddingsError {
     /// Logits weren't enabled for the given token
     #[error("Logits were not enabled for the given token")]
     LogitsNotEnabled,
-    /// The given sequence index exceeds the max sequence id
+    /// The context uses `LLAMA_POOLING_TYPE_NONE`, so there are no sequence embeddings
     #[error("Can't use sequence embeddings with a model supporting only LLAMA_POOLING_TYPE_NONE")]
     NonePoolType,
-}
-
-/// Decode a error from llama.cpp into a [`DecodeError`].
-impl From<NonZeroI32> for DecodeError {
-    fn from(value: NonZeroI32) -> Self {
-        match value.get() {
-            1 => DecodeError::NoKvCacheSlot,
-            -1 => DecodeError::NTokensZero,
-            i => DecodeError::Unknown(i),
-        }
-    }
+    /// The sequence was not part of the last decoded batch
+    #[error("sequence {0} was not part of the last decoded batch")]
+    UnknownSequence(i32),
 }
 
 /// An error that can occur when loading a model.
@@ -131,6 +303,10 @@ pub enum LlamaModelLoadError {
     /// Failed to convert the path to a rust str. This means the path was not valid unicode
     #[error("failed to convert path {0} to str")]
     PathToStrError(PathBuf),
+    /// The progress callback cancelled loading, see
+    /// [`model::params::LlamaModelParams::with_progress_callback`].
+    #[error("loading the model was cancelled")]
+    Cancelled,
 }
 
 /// get the time (in microseconds) according to llama.cpp
@@ -186,14 +362,31 @@ pub fn mlock_supported() -> bool {
 #[non_exhaustive]
 pub enum TokenToStringError {
     /// the token type was unknown
-    #[error("Unknown Token Type")]
-    UnknownTokenType,
+    #[error("Unknown Token Type of token {token}")]
+    UnknownTokenType {
+        /// The token that was converted.
+        token: LlamaToken,
+    },
     /// There was insufficient buffer space to convert the token to a string.
-    #[error("Insufficient Buffer Space {0}")]
-    InsufficientBufferSpace(c_int),
+    #[error(
+        "Insufficient Buffer Space {code} for token {token} with a buffer of {buffer_size} bytes"
+    )]
+    InsufficientBufferSpace {
+        /// The token that was converted.
+        token: LlamaToken,
+        /// The size of the buffer that was too small.
+        buffer_size: usize,
+        /// The code returned by llama.cpp, the negated number of bytes needed.
+        code: c_int,
+    },
     /// The token was not valid utf8.
-    #[error("FromUtf8Error {0}")]
-    FromUtf8Error(#[from] FromUtf8Error),
+    #[error("FromUtf8Error {source} for token {token}")]
+    FromUtf8Error {
+        /// The token that was converted.
+        token: LlamaToken,
+        /// The error converting the bytes of the token.
+        source: FromUtf8Error,
+    },
 }
 
 /// Failed to convert a string to a token sequence.
@@ -221,12 +414,18 @@ pub enum ApplyChatTemplateError {
     /// the buffer was too small.
     #[error("The buffer was too small. Please contact a maintainer and we will update it.")]
     BuffSizeError,
+    /// llama.cpp does not support the template, with the code it returned.
+    #[error("the chat template is not supported - returned code {0}")]
+    UnsupportedTemplate(c_int),
     /// the string contained a null byte and thus could not be converted to a c string.
     #[error("{0}")]
     NulError(#[from] NulError),
     /// the string could not be converted to utf8.
     #[error("{0}")]
     FromUtf8Error(#[from] FromUtf8Error),
+    /// the formatted chat could not be tokenized.
+    #[error("{0}")]
+    StringToTokenError(#[from] StringToTokenError),
 }
 
 /// Get the time in microseconds according to ggml
@@ -2,8 +2,11 @@
 
 use crate::LLamaCppError;
 use llama_cpp_sys_2::ggml_log_level;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, RwLock};
 
 /// Representation of an initialized llama backend
 /// This is required as a parameter for most llama functions as the backend must be initialized
@@ -83,6 +86,137 @@ impl LlamaBackend {
             llama_cpp_sys_2::llama_log_set(Some(void_log), std::ptr::null_mut());
         }
     }
+
+    /// Send llama.cpp's logs to `callback` instead of `stderr`.
+    ///
+    /// llama.cpp logs from whichever thread is loading or evaluating, so `callback` is called from
+    /// those threads. The text is passed on as is, which is usually one line ending in a newline
+    /// but can also be part of a line, e.g. the dots printed while loading a model. A panic in
+    /// `callback` is caught and the message dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_backend::{LlamaBackend, LlamaLogLevel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut backend = LlamaBackend::init()?;
+    /// backend.set_log_callback(|level, text| {
+    ///     if level <= LlamaLogLevel::Warn {
+    ///         eprint!("llama.cpp {level:?}: {text}");
+    ///     }
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_log_callback(
+        &mut self,
+        callback: impl Fn(LlamaLogLevel, &str) + Send + Sync + 'static,
+    ) {
+        *LOG_CALLBACK
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(Arc::new(callback));
+        unsafe {
+            llama_cpp_sys_2::llama_log_set(Some(forward_log), std::ptr::null_mut());
+        }
+    }
+
+    /// Send llama.cpp's logs to [`tracing`] events with the target `llama_cpp` at the level of
+    /// the message, so a subscriber can filter and format them with the rest of the application's
+    /// logs. Partial lines are collected until they are complete and empty lines are dropped.
+    ///
+    /// Use [`LlamaBackend::void_logs`] to silence llama.cpp instead and
+    /// [`LlamaBackend::reset_logs`] to go back to `stderr`.
+    pub fn send_logs_to_tracing(&mut self) {
+        self.set_log_callback(log_to_tracing);
+    }
+
+    /// Send llama.cpp's logs to `stderr` again, undoing [`LlamaBackend::void_logs`],
+    /// [`LlamaBackend::set_log_callback`] and [`LlamaBackend::send_logs_to_tracing`].
+    pub fn reset_logs(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::llama_log_set(None, std::ptr::null_mut());
+        }
+        *LOG_CALLBACK
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = None;
+    }
+}
+
+/// The severity of a llama.cpp log message, from most to least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LlamaLogLevel {
+    /// Something failed, e.g. a model could not be loaded.
+    Error,
+    /// Something unexpected that llama.cpp can continue from.
+    Warn,
+    /// Progress and details, e.g. the metadata of a model being loaded.
+    Info,
+    /// Debugging output.
+    Debug,
+}
+
+impl LlamaLogLevel {
+    /// The level of a raw `ggml_log_level`. Levels this version does not know are treated as
+    /// [`LlamaLogLevel::Info`].
+    fn from_raw(level: ggml_log_level) -> Self {
+        match level {
+            llama_cpp_sys_2::GGML_LOG_LEVEL_ERROR => Self::Error,
+            llama_cpp_sys_2::GGML_LOG_LEVEL_WARN => Self::Warn,
+            llama_cpp_sys_2::GGML_LOG_LEVEL_DEBUG => Self::Debug,
+            _ => Self::Info,
+        }
+    }
+}
+
+type LogCallback = dyn Fn(LlamaLogLevel, &str) + Send + Sync;
+
+/// The callback set with [`LlamaBackend::set_log_callback`]. llama.cpp only keeps a single log
+/// callback, so there is only one of these as well.
+static LOG_CALLBACK: RwLock<Option<Arc<LogCallback>>> = RwLock::new(None);
+
+unsafe extern "C" fn forward_log(level: ggml_log_level, text: *const c_char, _: *mut c_void) {
+    if text.is_null() {
+        return;
+    }
+    let callback = LOG_CALLBACK
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .clone();
+    let Some(callback) = callback else {
+        return;
+    };
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    let level = LlamaLogLevel::from_raw(level);
+    // unwinding into C is undefined behaviour
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| callback(level, &text)));
+}
+
+thread_local! {
+    /// The start of a line logged by this thread, see [`log_to_tracing`].
+    static PARTIAL_LINE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Emit the complete lines of `text` as [`tracing`] events, holding back an incomplete last line
+/// until the rest of it is logged.
+fn log_to_tracing(level: LlamaLogLevel, text: &str) {
+    PARTIAL_LINE.with_borrow_mut(|partial| {
+        partial.push_str(text);
+        let Some(end) = partial.rfind('\n') else {
+            return;
+        };
+        for line in partial[..end]
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+        {
+            match level {
+                LlamaLogLevel::Error => tracing::error!(target: "llama_cpp", "{line}"),
+                LlamaLogLevel::Warn => tracing::warn!(target: "llama_cpp", "{line}"),
+                LlamaLogLevel::Info => tracing::info!(target: "llama_cpp", "{line}"),
+                LlamaLogLevel::Debug => tracing::debug!(target: "llama_cpp", "{line}"),
+            }
+        }
+        partial.drain(..=end);
+    });
 }
 
 /// A rusty wrapper around `numa_strategy`.
@@ -1,38 +1,245 @@
 //! Safe wrapper around `llama_batch`.
+//!
+//! The arrays of a batch are allocated once by [`LlamaBatch::new`] and freed when it is dropped.
+//! In a generation loop allocate a single batch up front and [`LlamaBatch::clear`] it before
+//! adding the tokens of each step, rather than creating a batch per step.
+
+use std::marker::PhantomData;
+use std::{ptr, slice};
 
 use crate::token::LlamaToken;
-use llama_cpp_sys_2::{llama_batch, llama_batch_free, llama_batch_init, llama_pos, llama_seq_id};
+use llama_cpp_sys_2::{
+    llama_batch, llama_batch_free, llama_batch_get_one, llama_batch_init, llama_pos, llama_seq_id,
+};
 
 /// A safe wrapper around `llama_batch`.
+///
+/// # Thread safety
+///
+/// A batch is [`Send`] and [`Sync`], e.g. to fill it on one thread and decode it on another.
+///
+/// ```
+/// fn assert_send_sync<T: Send + Sync>() {}
+/// assert_send_sync::<llama_cpp_2::llama_batch::LlamaBatch>();
+/// assert_send_sync::<llama_cpp_2::llama_batch::LlamaBatchOne>();
+/// ```
 #[derive(Debug)]
 pub struct LlamaBatch {
     /// The number of tokens the batch was allocated with. they are safe to write to - but not necessarily read from as they are not necessarily initialized
     allocated: usize,
+    /// The number of sequence ids allocated per token.
+    n_seq_max: i32,
+    /// The size of the embeddings of a batch of embeddings, 0 for a batch of tokens.
+    n_embd: usize,
     /// The logits that are initialized. Used by [`LlamaContext`] to ensure that only initialized logits are accessed.
     pub(crate) initialized_logits: Vec<i32>,
     /// The llama_cpp batch. always initialize by `llama_cpp_sys_2::llama_batch_init(allocated, <unknown>, <unknown>)`
     pub(crate) llama_batch: llama_batch,
 }
 
+/// A batch of tokens of a single sequence borrowed from a slice, like `llama_batch_get_one`.
+///
+/// Unlike [`LlamaBatch`] it does not allocate, which is all the simple "decode these tokens"
+/// case needs. Create one with [`LlamaBatch::get_one`] and decode it with
+/// [`crate::context::LlamaContext::decode_one`].
+#[derive(Debug)]
+pub struct LlamaBatchOne<'a> {
+    /// The `llama_cpp` batch, pointing into `tokens`.
+    pub(crate) llama_batch: llama_batch,
+    tokens: PhantomData<&'a [LlamaToken]>,
+}
+
+// SAFETY: the arrays of the batch are allocated by `llama_batch_init` for this batch alone and
+// freed in `Drop`, they are not tied to the thread that allocated them.
+unsafe impl Send for LlamaBatch {}
+
+// SAFETY: the arrays are only written through `&mut LlamaBatch`, decoding only reads them.
+unsafe impl Sync for LlamaBatch {}
+
+// SAFETY: the batch only points into the borrowed tokens, which are `Send` and `Sync` as a
+// `&[LlamaToken]`, and it is never written to.
+unsafe impl Send for LlamaBatchOne<'_> {}
+
+// SAFETY: see `Send`
+unsafe impl Sync for LlamaBatchOne<'_> {}
+
 /// Errors that can occur when adding a token to a batch.
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
 pub enum BatchAddError {
-    /// There was not enough space in the batch to add the token.
+    /// There was not enough space in the batch to add the token, and it can not grow any further
+    /// as llama.cpp counts tokens with an `i32`.
     #[error("Insufficient Space of {0}")]
     InsufficientSpace(usize),
+    /// Tokens were added to a batch of embeddings or the other way around.
+    #[error("tokens and embeddings can not be mixed in a batch")]
+    MixedInput,
+    /// The embedding does not have the size the batch was created for.
+    #[error("embedding has {actual} elements but the batch holds embeddings of {expected}")]
+    EmbeddingSize {
+        /// The `n_embd` of the batch.
+        expected: usize,
+        /// The length of the embedding.
+        actual: usize,
+    },
 }
 
 impl LlamaBatch {
     /// Clear the batch. This does not free the memory associated with the batch, but it does reset
     /// the number of tokens to 0.
+    ///
+    /// Clearing is cheap, so reuse one batch for every decode instead of allocating a new one.
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut batch = LlamaBatch::new(4, 1);
+    /// for pos in 0..8 {
+    ///     batch.clear();
+    ///     batch.add(LlamaToken(pos), pos, &[0], true)?;
+    ///     // ctx.decode(&mut batch)?;
+    ///     assert_eq!(batch.n_tokens(), 1);
+    /// }
+    /// assert_eq!(batch.capacity(), 4);
+    /// # Ok::<(), llama_cpp_2::llama_batch::BatchAddError>(())
+    /// ```
     pub fn clear(&mut self) {
         self.llama_batch.n_tokens = 0;
         self.initialized_logits.clear();
     }
 
+    /// Make room for at least `additional` more tokens, reallocating the arrays of the batch if
+    /// needed. The tokens already in the batch are kept.
+    ///
+    /// [`LlamaBatch::add`] and [`LlamaBatch::add_sequence`] grow the batch on their own, reserving
+    /// up front just avoids reallocating several times.
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut batch = LlamaBatch::new(1, 1);
+    /// batch.add_sequence(&[LlamaToken(1), LlamaToken(2), LlamaToken(3)], 0, false)?;
+    /// assert_eq!(batch.n_tokens(), 3);
+    /// assert!(batch.capacity() >= 3);
+    ///
+    /// batch.reserve(100)?;
+    /// assert!(batch.capacity() >= 103);
+    /// # Ok::<(), llama_cpp_2::llama_batch::BatchAddError>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// If the batch would hold more than `i32::MAX` tokens.
+    ///
+    /// # Panics
+    ///
+    /// - [`self.llama_batch.n_tokens`] does not fit into a usize
+    pub fn reserve(&mut self, additional: usize) -> Result<(), BatchAddError> {
+        let n_tokens = usize::try_from(self.n_tokens()).expect("cannot fit n_tokens into a usize");
+        let required = n_tokens.saturating_add(additional);
+        if required <= self.allocated {
+            return Ok(());
+        }
+        let max = usize::try_from(i32::MAX).unwrap_or(usize::MAX);
+        if required > max {
+            return Err(BatchAddError::InsufficientSpace(self.allocated));
+        }
+        let allocated = required.max(self.allocated.saturating_mul(2)).min(max);
+        let allocated_i32 = i32::try_from(allocated).expect("allocated is at most i32::MAX");
+
+        let old = self.llama_batch;
+        let n_embd = i32::try_from(self.n_embd).expect("cannot fit n_embd into a i32");
+        let mut new = unsafe { llama_batch_init(allocated_i32, n_embd, self.n_seq_max) };
+        let n_seq_max = usize::try_from(self.n_seq_max).unwrap_or(0);
+        unsafe {
+            if self.n_embd == 0 {
+                ptr::copy_nonoverlapping(old.token, new.token, n_tokens);
+            } else {
+                ptr::copy_nonoverlapping(old.embd, new.embd, n_tokens * self.n_embd);
+            }
+            ptr::copy_nonoverlapping(old.pos, new.pos, n_tokens);
+            ptr::copy_nonoverlapping(old.n_seq_id, new.n_seq_id, n_tokens);
+            ptr::copy_nonoverlapping(old.logits, new.logits, n_tokens);
+            for i in 0..n_tokens {
+                ptr::copy_nonoverlapping(*old.seq_id.add(i), *new.seq_id.add(i), n_seq_max);
+            }
+        }
+        new.n_tokens = old.n_tokens;
+        self.llama_batch = new;
+        self.allocated = allocated;
+        unsafe { llama_batch_free(old) };
+        Ok(())
+    }
+
+    /// The distinct sequence ids of the tokens in the batch, sorted.
+    pub(crate) fn seq_ids(&self) -> Vec<llama_seq_id> {
+        let n_tokens = usize::try_from(self.n_tokens()).unwrap_or(0);
+        let mut seq_ids = Vec::new();
+        for i in 0..n_tokens {
+            unsafe {
+                let n_seq_id = usize::try_from(*self.llama_batch.n_seq_id.add(i)).unwrap_or(0);
+                let token_seq_ids = *self.llama_batch.seq_id.add(i);
+                seq_ids.extend_from_slice(slice::from_raw_parts(token_seq_ids, n_seq_id));
+            }
+        }
+        seq_ids.sort_unstable();
+        seq_ids.dedup();
+        seq_ids
+    }
+
+    /// The position and sequence ids of the `i`th token.
+    pub(crate) fn token_pos_seq_ids(&self, i: usize) -> (llama_pos, &[llama_seq_id]) {
+        assert!(
+            i < usize::try_from(self.n_tokens()).unwrap_or(0),
+            "token {i} is not in the batch"
+        );
+        unsafe {
+            let n_seq_id = usize::try_from(*self.llama_batch.n_seq_id.add(i)).unwrap_or(0);
+            let seq_ids = slice::from_raw_parts(*self.llama_batch.seq_id.add(i), n_seq_id);
+            (*self.llama_batch.pos.add(i), seq_ids)
+        }
+    }
+
+    /// An empty batch of the same kind, with room for as many tokens.
+    pub(crate) fn empty_like(&self) -> Self {
+        if self.n_embd == 0 {
+            Self::new(self.allocated, self.n_seq_max)
+        } else {
+            Self::new_embeddings(self.allocated, self.n_embd, self.n_seq_max)
+        }
+    }
+
+    /// Clear `dest`, which must be of the same kind, and copy the tokens whose sequence ids
+    /// satisfy `keep` to it.
+    pub(crate) fn copy_retained(
+        &self,
+        dest: &mut LlamaBatch,
+        keep: impl Fn(&[llama_seq_id]) -> bool,
+    ) -> Result<(), BatchAddError> {
+        dest.clear();
+        for i in 0..usize::try_from(self.n_tokens()).unwrap_or(0) {
+            let (pos, seq_ids) = self.token_pos_seq_ids(i);
+            if !keep(seq_ids) {
+                continue;
+            }
+            let logits = unsafe { *self.llama_batch.logits.add(i) } != 0;
+            if self.n_embd == 0 {
+                let token = unsafe { *self.llama_batch.token.add(i) };
+                dest.add(LlamaToken(token), pos, seq_ids, logits)?;
+            } else {
+                let embedding = unsafe {
+                    slice::from_raw_parts(self.llama_batch.embd.add(i * self.n_embd), self.n_embd)
+                };
+                dest.add_embedding(embedding, pos, seq_ids, logits)?;
+            }
+        }
+        Ok(())
+    }
+
     /// add a token to the batch for sequences `seq_ids` at position `pos`. If `logits` is true, the
     /// token will be initialized and can be read from after the next decode.
     ///
+    /// The batch grows if it is full, see [`LlamaBatch::reserve`].
+    ///
     /// # Panics
     ///
     /// - [`self.llama_batch.n_tokens`] does not fit into a usize
@@ -40,7 +247,8 @@ impl LlamaBatch {
     ///
     /// # Errors
     ///
-    /// returns a error if there is insufficient space in the buffer
+    /// returns a error if the batch can not grow any further, or
+    /// [`BatchAddError::MixedInput`] for a batch of embeddings
     pub fn add(
         &mut self,
         LlamaToken(id): LlamaToken,
@@ -48,16 +256,84 @@ impl LlamaBatch {
         seq_ids: &[i32],
         logits: bool,
     ) -> Result<(), BatchAddError> {
-        if self.allocated
-            < usize::try_from(self.n_tokens() + 1).expect("cannot fit n_tokens into a usize")
-        {
-            return Err(BatchAddError::InsufficientSpace(self.allocated));
+        if self.n_embd != 0 {
+            return Err(BatchAddError::MixedInput);
         }
-        let offset = self.llama_batch.n_tokens;
-        let offset_usize = usize::try_from(offset).expect("cannot fit n_tokens into a usize");
+        self.reserve(1)?;
+        let offset_usize =
+            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
         unsafe {
             // batch.token   [batch.n_tokens] = id;
             self.llama_batch.token.add(offset_usize).write(id);
+        }
+        self.push(pos, seq_ids, logits);
+        Ok(())
+    }
+
+    /// Add an embedding vector as the input for sequences `seq_ids` at position `pos`, e.g. a soft
+    /// prompt or the output of a multimodal projector. If `logits` is true, its output will be
+    /// initialized and can be read from after the next decode.
+    ///
+    /// The batch must be created with [`LlamaBatch::new_embeddings`], and the embedding must have
+    /// its `n_embd` elements. The batch grows if it is full, see [`LlamaBatch::reserve`].
+    ///
+    /// # Errors
+    ///
+    /// - [`BatchAddError::MixedInput`] if this is a batch of tokens
+    /// - [`BatchAddError::EmbeddingSize`] if `embedding` does not have `n_embd` elements
+    /// - [`BatchAddError::InsufficientSpace`] if the batch can not grow any further
+    ///
+    /// # Panics
+    ///
+    /// - [`self.llama_batch.n_tokens`] does not fit into a usize
+    /// - [`seq_ids.len()`] does not fit into a [`llama_seq_id`]
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::llama_batch::{BatchAddError, LlamaBatch};
+    /// let mut batch = LlamaBatch::new_embeddings(8, 4, 1);
+    /// batch.add_embedding(&[0.1, 0.2, 0.3, 0.4], 0, &[0], true)?;
+    /// assert_eq!(batch.n_tokens(), 1);
+    ///
+    /// assert_eq!(
+    ///     batch.add_embedding(&[0.1, 0.2], 1, &[0], true),
+    ///     Err(BatchAddError::EmbeddingSize { expected: 4, actual: 2 })
+    /// );
+    /// # Ok::<(), BatchAddError>(())
+    /// ```
+    pub fn add_embedding(
+        &mut self,
+        embedding: &[f32],
+        pos: llama_pos,
+        seq_ids: &[i32],
+        logits: bool,
+    ) -> Result<(), BatchAddError> {
+        if self.n_embd == 0 {
+            return Err(BatchAddError::MixedInput);
+        }
+        if embedding.len() != self.n_embd {
+            return Err(BatchAddError::EmbeddingSize {
+                expected: self.n_embd,
+                actual: embedding.len(),
+            });
+        }
+        self.reserve(1)?;
+        let offset_usize =
+            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
+        unsafe {
+            let embd = self.llama_batch.embd.add(offset_usize * self.n_embd);
+            ptr::copy_nonoverlapping(embedding.as_ptr(), embd, self.n_embd);
+        }
+        self.push(pos, seq_ids, logits);
+        Ok(())
+    }
+
+    /// Set the position, sequences and logits of the input written at `n_tokens` and count it.
+    fn push(&mut self, pos: llama_pos, seq_ids: &[i32], logits: bool) {
+        let offset = self.llama_batch.n_tokens;
+        let offset_usize = usize::try_from(offset).expect("cannot fit n_tokens into a usize");
+        unsafe {
             // batch.pos     [batch.n_tokens] = pos,
             self.llama_batch.pos.add(offset_usize).write(pos);
             // batch.n_seq_id[batch.n_tokens] = seq_ids.size();
@@ -87,8 +363,6 @@ impl LlamaBatch {
 
         // batch.n_tokens++;
         self.llama_batch.n_tokens += 1;
-
-        Ok(())
     }
 
     /// Add a sequence of tokens to the batch for the given sequence id. If `logits_all` is true, the
@@ -96,9 +370,11 @@ impl LlamaBatch {
     ///
     /// Either way the last token in the sequence will have its logits set to `true`.
     ///
+    /// The batch grows if the tokens do not fit, see [`LlamaBatch::reserve`].
+    ///
     /// # Errors
     ///
-    /// Returns an error if there is insufficient space in the buffer
+    /// Returns an error if the batch can not grow any further
     ///
     /// # Panics
     ///
@@ -110,13 +386,8 @@ impl LlamaBatch {
         seq_id: i32,
         logits_all: bool,
     ) -> Result<(), BatchAddError> {
-        let n_tokens_0 =
-            usize::try_from(self.llama_batch.n_tokens).expect("cannot fit n_tokens into a usize");
         let n_tokens = tokens.len();
-
-        if self.allocated < n_tokens_0 + n_tokens {
-            return Err(BatchAddError::InsufficientSpace(self.allocated));
-        }
+        self.reserve(n_tokens)?;
 
         let last_index = llama_pos::try_from(n_tokens.saturating_sub(1))
             .expect("cannot fit n_tokens into a llama_pos");
@@ -127,11 +398,14 @@ impl LlamaBatch {
         Ok(())
     }
 
-    /// Create a new `LlamaBatch` that can contain up to `n_tokens` tokens.
+    /// Create a new `LlamaBatch` with room for `n_tokens` tokens.
+    ///
+    /// The batch grows when more tokens are added, but [`crate::context::LlamaContext::decode`]
+    /// rejects batches with more than `n_batch` tokens, so `n_batch` is usually the right size.
     ///
     /// # Arguments
     ///
-    /// - `n_tokens`: the maximum number of tokens that can be added to the batch
+    /// - `n_tokens`: the number of tokens to allocate the batch for
     /// - `n_seq_max`: the maximum number of sequences that can be added to the batch (generally 1 unless you know what you are doing)
     ///
     /// # Panics
@@ -144,16 +418,98 @@ impl LlamaBatch {
 
         LlamaBatch {
             allocated: n_tokens,
+            n_seq_max,
+            n_embd: 0,
             initialized_logits: vec![],
             llama_batch: batch,
         }
     }
 
+    /// Create a new `LlamaBatch` of embedding vectors of `n_embd` elements with room for
+    /// `n_tokens` of them, see [`LlamaBatch::add_embedding`]. `n_embd` has to match
+    /// [`crate::model::LlamaModel::n_embd`] of the model it is decoded with.
+    ///
+    /// # Panics
+    ///
+    /// - `n_tokens` or `n_embd` is greater than `i32::MAX`
+    /// - `n_embd` is 0
+    #[must_use]
+    pub fn new_embeddings(n_tokens: usize, n_embd: usize, n_seq_max: i32) -> Self {
+        assert!(n_embd > 0, "n_embd must be greater than 0");
+        let n_tokens_i32 = i32::try_from(n_tokens).expect("cannot fit n_tokens into a i32");
+        let n_embd_i32 = i32::try_from(n_embd).expect("cannot fit n_embd into a i32");
+        let batch = unsafe { llama_batch_init(n_tokens_i32, n_embd_i32, n_seq_max) };
+
+        LlamaBatch {
+            allocated: n_tokens,
+            n_seq_max,
+            n_embd,
+            initialized_logits: vec![],
+            llama_batch: batch,
+        }
+    }
+
+    /// Borrow `tokens` as a batch for sequence `seq_id`, with the first token at position `pos_0`
+    /// and the following ones at consecutive positions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than `i32::MAX` tokens.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::llama_batch::LlamaBatch;
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let tokens = model.str_to_token("Hello", AddBos::Always)?;
+    /// ctx.decode_one(&LlamaBatch::get_one(&tokens, 0, 0))?;
+    /// // the logits of the last token are at index 0
+    /// let next = ctx.sample_token_greedy_ith(0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn get_one(
+        tokens: &[LlamaToken],
+        pos_0: llama_pos,
+        seq_id: llama_seq_id,
+    ) -> LlamaBatchOne<'_> {
+        let n_tokens = i32::try_from(tokens.len()).expect("cannot fit n_tokens into a i32");
+        // llama.cpp only reads the tokens, the pointer is only mutable for historical reasons
+        let tokens_ptr = tokens
+            .as_ptr()
+            .cast::<llama_cpp_sys_2::llama_token>()
+            .cast_mut();
+        let llama_batch = unsafe { llama_batch_get_one(tokens_ptr, n_tokens, pos_0, seq_id) };
+        LlamaBatchOne {
+            llama_batch,
+            tokens: PhantomData,
+        }
+    }
+
     /// Returns the number of tokens in the batch.
     #[must_use]
     pub fn n_tokens(&self) -> i32 {
         self.llama_batch.n_tokens
     }
+
+    /// Returns the size of the embeddings of a batch created with [`LlamaBatch::new_embeddings`],
+    /// or `None` for a batch of tokens.
+    #[must_use]
+    pub fn n_embd(&self) -> Option<usize> {
+        (self.n_embd != 0).then_some(self.n_embd)
+    }
+
+    /// Returns the number of tokens the batch has room for before it grows.
+    #[must_use]
+    pub fn capacity(&self) -> usize {
+        self.allocated
+    }
 }
 
 impl Drop for LlamaBatch {
@@ -0,0 +1,81 @@
+//! Counters, gauges and histograms reported through the [`metrics`](https://docs.rs/metrics)
+//! facade, e.g. to be scraped by Prometheus.
+//!
+//! Nothing is recorded until the application installs a recorder, such as the one of
+//! `metrics-exporter-prometheus`. The metrics have no labels, so the gauges describe the context
+//! that decoded last. [`describe`] registers the unit and a description of every metric with the
+//! recorder.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // install a recorder first, e.g. `metrics_exporter_prometheus::PrometheusBuilder::new().install()?`
+//! llama_cpp_2::metrics::describe();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use ::metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+
+/// The number of tokens yielded by [`crate::generation::Generator`]s.
+pub const TOKENS_GENERATED: &str = "llama_cpp_tokens_generated_total";
+/// The number of prompt tokens decoded by [`crate::generation::Generator`]s.
+pub const PROMPT_TOKENS: &str = "llama_cpp_prompt_tokens_total";
+/// The number of tokens decoded by [`crate::context::LlamaContext::decode`] and
+/// [`crate::context::LlamaContext::decode_one`].
+pub const DECODED_TOKENS: &str = "llama_cpp_decoded_tokens_total";
+/// How long each successful decode took.
+pub const DECODE_DURATION: &str = "llama_cpp_decode_duration_seconds";
+/// The number of KV cache cells in use after the last decode.
+pub const KV_CACHE_USED_CELLS: &str = "llama_cpp_kv_cache_used_cells";
+/// The fraction of the KV cache in use after the last decode.
+pub const KV_CACHE_USAGE: &str = "llama_cpp_kv_cache_usage_ratio";
+/// The number of tokens whose KV cache was restored from a session file instead of decoding them,
+/// see [`crate::context::LlamaContext::load_session_file`].
+pub const SESSION_TOKENS_RESTORED: &str = "llama_cpp_session_tokens_restored_total";
+
+/// Describe every metric of this crate to the installed recorder.
+pub fn describe() {
+    describe_counter!(TOKENS_GENERATED, Unit::Count, "Tokens generated");
+    describe_counter!(PROMPT_TOKENS, Unit::Count, "Prompt tokens decoded");
+    describe_counter!(DECODED_TOKENS, Unit::Count, "Tokens decoded");
+    describe_histogram!(DECODE_DURATION, Unit::Seconds, "Duration of a decode");
+    describe_gauge!(KV_CACHE_USED_CELLS, Unit::Count, "KV cache cells in use");
+    describe_gauge!(KV_CACHE_USAGE, "Fraction of the KV cache in use");
+    describe_counter!(
+        SESSION_TOKENS_RESTORED,
+        Unit::Count,
+        "Tokens restored from session files"
+    );
+}
+
+/// Record a successful decode of `n_tokens` tokens.
+#[allow(clippy::cast_precision_loss, clippy::cast_sign_loss)]
+pub(crate) fn decoded(n_tokens: i32, duration: Duration, used_cells: i32, n_ctx: u32) {
+    counter!(DECODED_TOKENS).increment(n_tokens.max(0) as u64);
+    histogram!(DECODE_DURATION).record(duration);
+    gauge!(KV_CACHE_USED_CELLS).set(f64::from(used_cells));
+    if n_ctx > 0 {
+        gauge!(KV_CACHE_USAGE).set(f64::from(used_cells) / f64::from(n_ctx));
+    }
+}
+
+/// Record the decoded prompt of a generation.
+pub(crate) fn prompt_decoded(n_tokens: usize) {
+    counter!(PROMPT_TOKENS).increment(n_tokens as u64);
+}
+
+/// Record a generated token.
+pub(crate) fn token_generated() {
+    counter!(TOKENS_GENERATED).increment(1);
+}
+
+/// Record a session file restoring the KV cache of `n_tokens` tokens.
+pub(crate) fn session_restored(n_tokens: usize) {
+    counter!(SESSION_TOKENS_RESTORED).increment(n_tokens as u64);
+}
@@ -3,11 +3,15 @@ use std::ffi::CString;
 use std::os::raw::c_int;
 use std::path::Path;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
-use crate::context::params::LlamaContextParams;
+use crate::context::params::{LlamaContextParams, RopeScalingType};
+use crate::context::profile::GraphProfiler;
 use crate::context::LlamaContext;
 use crate::llama_backend::LlamaBackend;
-use crate::model::params::LlamaModelParams;
+use crate::model::params::{LlamaModelParams, LoadProgress};
+use crate::token::mask::TokenMask;
 use crate::token::LlamaToken;
 use crate::token_type::LlamaTokenType;
 use crate::{
@@ -15,14 +19,32 @@ use crate::{
     NewLlamaChatMessageError, StringToTokenError, TokenToStringError,
 };
 
+pub mod chat_template;
+#[cfg(feature = "hf-hub")]
+pub mod hf;
+pub mod integrity;
+#[cfg(feature = "jinja")]
+pub mod jinja;
+pub mod lora;
 pub mod params;
+pub mod quantize;
+pub mod tensor;
+pub mod vocab;
 
 /// A safe wrapper around `llama_model`.
-#[derive(Debug)]
-#[repr(transparent)]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaModel {
     pub(crate) model: NonNull<llama_cpp_sys_2::llama_model>,
+    /// The end of generation tokens, looked up on first use.
+    eog_tokens: OnceLock<TokenMask>,
+}
+
+impl std::fmt::Debug for LlamaModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LlamaModel")
+            .field("model", &self.model)
+            .finish_non_exhaustive()
+    }
 }
 
 /// A Safe wrapper around `llama_chat_message`
@@ -64,8 +86,58 @@ impl LlamaModel {
     /// platforms due to llama.cpp returning a `c_int` (i32 on most platforms) which is almost certainly positive.
     #[must_use]
     pub fn n_ctx_train(&self) -> u32 {
+        self.try_n_ctx_train()
+            .expect("n_ctx_train fits into an u32")
+    }
+
+    /// get the number of tokens the model was trained on, without panicking on unexpected values.
+    ///
+    /// # Errors
+    ///
+    /// If llama.cpp returns a negative number.
+    pub fn try_n_ctx_train(&self) -> Result<u32, std::num::TryFromIntError> {
         let n_ctx_train = unsafe { llama_cpp_sys_2::llama_n_ctx_train(self.model.as_ptr()) };
-        u32::try_from(n_ctx_train).expect("n_ctx_train fits into an u32")
+        u32::try_from(n_ctx_train)
+    }
+
+    /// The `RoPE` scaling type the model was trained with, which contexts use when their
+    /// [`RopeScalingType`] is [`RopeScalingType::Unspecified`].
+    ///
+    /// This is read from the `<arch>.rope.scaling.type` key of the model's metadata and defaults to
+    /// [`RopeScalingType::Linear`] if the key is missing, like llama.cpp does. Types unknown to
+    /// this library are returned as [`RopeScalingType::Unspecified`].
+    #[must_use]
+    pub fn rope_scaling_type_train(&self) -> RopeScalingType {
+        let Some(arch) = self.meta_val_str("general.architecture") else {
+            return RopeScalingType::Linear;
+        };
+        match self
+            .meta_val_str(&format!("{arch}.rope.scaling.type"))
+            .as_deref()
+        {
+            Some("none") => RopeScalingType::None,
+            None | Some("linear") => RopeScalingType::Linear,
+            Some("yarn") => RopeScalingType::Yarn,
+            Some(_) => RopeScalingType::Unspecified,
+        }
+    }
+
+    /// The `RoPE` frequency base the model was trained with.
+    ///
+    /// This is read from the `<arch>.rope.freq_base` key of the model's metadata and defaults to
+    /// 10000 if the key is missing, like llama.cpp does.
+    #[must_use]
+    pub fn rope_freq_base_train(&self) -> f32 {
+        self.meta_val_str("general.architecture")
+            .and_then(|arch| self.meta_val_str(&format!("{arch}.rope.freq_base")))
+            .and_then(|freq_base| freq_base.parse().ok())
+            .unwrap_or(10_000.0)
+    }
+
+    /// The `RoPE` frequency scaling factor the model was trained with.
+    #[must_use]
+    pub fn rope_freq_scale_train(&self) -> f32 {
+        unsafe { llama_cpp_sys_2::llama_rope_freq_scale_train(self.model.as_ptr()) }
     }
 
     /// Get all tokens in the model.
@@ -91,6 +163,160 @@ impl LlamaModel {
         LlamaToken(token)
     }
 
+    /// Whether the tokenizer of the model expects a beginning of stream token in front of the
+    /// prompt, `None` if the model does not say.
+    #[must_use]
+    pub fn add_bos_token(&self) -> Option<bool> {
+        match unsafe { llama_cpp_sys_2::llama_add_bos_token(self.model.as_ptr()) } {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Get the classification token (`[CLS]`) that starts the input of BERT-style encoders, if the
+    /// model has one. For word piece models without an explicit one this is the beginning of
+    /// stream token, which llama.cpp uses as `[CLS]`.
+    #[must_use]
+    pub fn token_cls(&self) -> Option<LlamaToken> {
+        self.special_token_from_meta(&["tokenizer.ggml.cls_token_id"])
+            .or_else(|| (self.vocab_type() == VocabType::WPM).then(|| self.token_bos()))
+    }
+
+    /// Get the separator token (`[SEP]`) that ends the segments of the input of BERT-style
+    /// encoders, if the model has one. For word piece models without an explicit one this is the
+    /// end of stream token, which llama.cpp uses as `[SEP]`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// // `[CLS] query [SEP] document [SEP]` for a cross-encoder
+    /// let (cls, sep) = model.token_cls().zip(model.token_sep()).expect("a BERT-style model");
+    /// let mut tokens = vec![cls];
+    /// tokens.extend(model.str_to_token("what is a llama?", AddBos::Never)?);
+    /// tokens.push(sep);
+    /// tokens.extend(model.str_to_token("The llama is a camelid.", AddBos::Never)?);
+    /// tokens.push(sep);
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn token_sep(&self) -> Option<LlamaToken> {
+        // llama.cpp (and its conversion scripts) spell the key "seperator"
+        self.special_token_from_meta(&[
+            "tokenizer.ggml.seperator_token_id",
+            "tokenizer.ggml.separator_token_id",
+        ])
+        .or_else(|| (self.vocab_type() == VocabType::WPM).then(|| self.token_eos()))
+    }
+
+    /// Get the padding token, if the model has one.
+    #[must_use]
+    pub fn token_pad(&self) -> Option<LlamaToken> {
+        self.special_token_from_meta(&["tokenizer.ggml.padding_token_id"])
+    }
+
+    /// The first of the metadata `keys` that holds a token of the vocabulary.
+    fn special_token_from_meta(&self, keys: &[&str]) -> Option<LlamaToken> {
+        keys.iter()
+            .filter_map(|key| self.meta_val_str(key)?.parse::<i32>().ok())
+            .find(|id| (0..self.n_vocab()).contains(id))
+            .map(LlamaToken)
+    }
+
+    /// Get the end of turn token, if the model has one.
+    ///
+    /// This is the `tokenizer.ggml.eot_token_id` of the model or else the first special token
+    /// with a known end of turn text (such as `<|eot_id|>`, `<|im_end|>` or `<end_of_turn>`).
+    /// The vendored llama.cpp returns the id of `CodeLlama`'s end of turn token for every model,
+    /// so `llama_token_eot` is not used.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// // e.g. `<|eot_id|>` for llama 3 instruct models
+    /// if let Some(eot) = model.token_eot() {
+    ///     println!("turns end with {}", model.token_to_str(eot)?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn token_eot(&self) -> Option<LlamaToken> {
+        self.special_token_from_meta(&[EOT_TOKEN_ID_KEY])
+            .or_else(|| {
+                END_OF_TURN_TEXTS
+                    .iter()
+                    .find_map(|text| self.special_token_by_text(text))
+            })
+    }
+
+    /// Get every token that ends generation in token id order: the end of stream token, the end
+    /// of turn token and any other special token with a known end of generation text, such as
+    /// `<|endoftext|>` for models whose end of stream token is something else.
+    ///
+    /// Chat models often end their turn with a token other than [`LlamaModel::token_eos`], so
+    /// generation should stop on all of these.
+    ///
+    /// The vendored llama.cpp does not know which tokens end generation. Apart from the end of
+    /// stream token and the `tokenizer.ggml.eot_token_id` metadata, they are found with a
+    /// heuristic: the control and user defined tokens whose text is one of a fixed list of end of
+    /// turn and end of text markers, such as `<|eot_id|>`, `<|im_end|>`, `<end_of_turn>` and
+    /// `<|endoftext|>`. Models ending their turns with another marker need it as a stop sequence.
+    ///
+    /// The tokens are looked up once per model, see [`LlamaModel::eog_token_mask`].
+    #[must_use]
+    pub fn eog_tokens(&self) -> Vec<LlamaToken> {
+        self.eog_token_mask().iter().collect()
+    }
+
+    /// [`LlamaModel::eog_tokens`] as a mask, looked up on the first call and cached afterwards.
+    #[must_use]
+    pub fn eog_token_mask(&self) -> &TokenMask {
+        self.eog_tokens.get_or_init(|| {
+            let texts = END_OF_TURN_TEXTS.iter().chain(&END_OF_TEXT_TEXTS);
+            let special = texts.filter_map(|text| self.special_token_by_text(text));
+            std::iter::once(self.token_eos())
+                .chain(self.token_eot())
+                .chain(special)
+                .collect()
+        })
+    }
+
+    /// Does `token` end generation? This is the case for all of [`LlamaModel::eog_tokens`].
+    #[must_use]
+    pub fn is_eog_token(&self, token: LlamaToken) -> bool {
+        self.eog_token_mask().contains(token)
+    }
+
+    /// The special token whose text is exactly `text`, if the vocabulary has one.
+    fn special_token_by_text(&self, text: &str) -> Option<LlamaToken> {
+        match self.str_to_token(text, AddBos::Never).ok()?.as_slice() {
+            &[token] if self.is_special_token_with_text(token, |t| t == text) => Some(token),
+            _ => None,
+        }
+    }
+
+    /// Is `token` a control or user defined token whose text matches?
+    fn is_special_token_with_text(
+        &self,
+        token: LlamaToken,
+        matches: impl Fn(&str) -> bool,
+    ) -> bool {
+        matches!(
+            self.try_token_type(token),
+            Ok(LlamaTokenType::Control | LlamaTokenType::UserDefined)
+        ) && matches(&self.token_text(token))
+    }
+
     /// Get the newline token.
     #[must_use]
     pub fn token_nl(&self) -> LlamaToken {
@@ -107,6 +333,34 @@ impl LlamaModel {
         self.token_to_str_with_size(token, 32)
     }
 
+    /// Convert single token to a string, replacing invalid UTF-8 with `U+FFFD` instead of failing.
+    ///
+    /// Useful for logging and debugging, where a single unusual token should not stop the output.
+    /// Tokens that do not fit the default buffer are converted with
+    /// [`LlamaModel::token_to_piece_raw`].
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// # let token = LlamaToken::new(0);
+    /// eprintln!("sampled {token}: {:?}", model.token_to_str_lossy(token));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn token_to_str_lossy(&self, token: LlamaToken) -> String {
+        String::from_utf8_lossy(&self.token_to_bytes_lossy(token)).into_owned()
+    }
+
+    /// [`LlamaModel::token_to_bytes`], falling back to [`LlamaModel::token_to_piece_raw`].
+    fn token_to_bytes_lossy(&self, token: LlamaToken) -> Vec<u8> {
+        self.token_to_bytes(token)
+            .unwrap_or_else(|_| self.token_to_piece_raw(token))
+    }
+
     /// Convert single token to bytes.
     ///
     /// # Errors
@@ -116,17 +370,86 @@ impl LlamaModel {
         self.token_to_bytes_with_size(token, 32)
     }
 
+    /// Convert a token to exactly the bytes llama.cpp produces for it.
+    ///
+    /// Unlike [`LlamaModel::token_to_bytes`] this returns the byte of byte tokens (e.g. the
+    /// `<0x0A>`-style tokens of SPM vocabularies) and `▅` (U+2585) for unknown tokens, does not
+    /// special-case the newline token and grows the buffer as needed, so byte-level consumers get
+    /// the raw data. Control tokens such as BOS and EOS, and unused tokens, have no text in
+    /// llama.cpp and give empty output.
+    ///
+    /// # Panics
+    ///
+    /// If the piece is larger than [`c_int::MAX`] bytes.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let bytes = (0..model.n_vocab())
+    ///     .map(|id| model.token_to_piece_raw(LlamaToken::new(id)))
+    ///     .collect::<Vec<_>>();
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn token_to_piece_raw(&self, token: LlamaToken) -> Vec<u8> {
+        let mut buf = vec![0_u8; 32];
+        loop {
+            let len = c_int::try_from(buf.len()).expect("length fits into c_int");
+            let size = unsafe {
+                llama_cpp_sys_2::llama_token_to_piece(
+                    self.model.as_ptr(),
+                    token.0,
+                    buf.as_mut_ptr().cast::<std::os::raw::c_char>(),
+                    len,
+                )
+            };
+            if let Ok(size) = usize::try_from(size) {
+                buf.truncate(size);
+                return buf;
+            }
+            // a negative size is the number of bytes needed
+            let needed = size.unsigned_abs() as usize;
+            buf.resize(needed.max(buf.len() * 2), 0);
+        }
+    }
+
     /// Convert a vector of tokens to a single string.
     ///
+    /// The bytes of all tokens are joined before converting them to UTF-8, so characters split
+    /// across tokens are handled correctly.
+    ///
     /// # Errors
     ///
-    /// See [`TokenToStringError`] for more information.
+    /// See [`TokenToStringError`] for more information. If the joined bytes are not valid UTF-8
+    /// the error holds the token where the invalid bytes start.
     pub fn tokens_to_str(&self, tokens: &[LlamaToken]) -> Result<String, TokenToStringError> {
-        let mut builder = String::with_capacity(tokens.len() * 4);
-        for str in tokens.iter().copied().map(|t| self.token_to_str(t)) {
-            builder += &str?;
+        let mut bytes = Vec::with_capacity(tokens.len() * 4);
+        // the end of the bytes of each token, to find the token at fault if the bytes are invalid
+        let mut ends = Vec::with_capacity(tokens.len());
+        for &token in tokens {
+            self.token_to_bytes_into(token, &mut bytes)?;
+            ends.push(bytes.len());
         }
-        Ok(builder)
+        String::from_utf8(bytes).map_err(|source| {
+            let valid_up_to = source.utf8_error().valid_up_to();
+            let token = tokens[ends.partition_point(|&end| end <= valid_up_to)];
+            TokenToStringError::FromUtf8Error { token, source }
+        })
+    }
+
+    /// Convert a vector of tokens to a single string, replacing invalid UTF-8 with `U+FFFD`
+    /// instead of failing. See [`LlamaModel::token_to_str_lossy`].
+    #[must_use]
+    pub fn tokens_to_str_lossy(&self, tokens: &[LlamaToken]) -> String {
+        let bytes = tokens
+            .iter()
+            .flat_map(|&token| self.token_to_bytes_lossy(token))
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(&bytes).into_owned()
     }
 
     /// Convert a string to a Vector of tokens.
@@ -139,7 +462,6 @@ impl LlamaModel {
     ///
     /// - if there is more than [`usize::MAX`] [`LlamaToken`]s in [`str`].
     ///
-    ///
     /// ```no_run
     /// use llama_cpp_2::model::LlamaModel;
     ///
@@ -151,6 +473,10 @@ impl LlamaModel {
     /// let tokens = model.str_to_token("Hello, World!", AddBos::Always)?;
     /// # Ok(())
     /// # }
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(level = "debug", skip_all, fields(n_bytes = str.len(), ?add_bos))
+    )]
     pub fn str_to_token(
         &self,
         str: &str,
@@ -206,15 +532,199 @@ impl LlamaModel {
         Ok(buffer.into_iter().map(LlamaToken).collect())
     }
 
+    /// Convert a string to tokens like [`Self::str_to_token`] and end them with the separator
+    /// token ([`Self::token_sep`]) if the model has one.
+    ///
+    /// BERT-style encoders expect their input as `[CLS] text [SEP]`. For their word piece
+    /// vocabularies ([`VocabType::WPM`]) llama.cpp only prepends `[CLS]` (the beginning of stream
+    /// token), so tokenize with [`AddBos::Always`] and this to embed a text with such a model.
+    ///
+    /// # Errors
+    ///
+    /// See [`Self::str_to_token`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/bge-small.gguf", &Default::default())?;
+    /// let tokens = model.str_to_token_with_sep("Hello, World!", AddBos::Always)?;
+    /// assert_eq!(tokens.first().copied(), model.token_cls());
+    /// assert_eq!(tokens.last().copied(), model.token_sep());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn str_to_token_with_sep(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let mut tokens = self.str_to_token(str, add_bos)?;
+        tokens.extend(self.token_sep());
+        Ok(tokens)
+    }
+
+    /// Convert a string to tokens like [`Self::str_to_token`], counting the tokens first to
+    /// allocate exactly as many as needed.
+    ///
+    /// [`Self::str_to_token`] allocates room for one token per two bytes of `str` and tokenizes a
+    /// second time if that is too little. For multi-megabyte documents this reserves far more
+    /// memory than the tokens take. This always tokenizes twice, once to count the tokens without
+    /// a buffer and once into a buffer of exactly that size, trading time for a predictable
+    /// allocation.
+    ///
+    /// # Errors
+    ///
+    /// - if [`str`] contains a null byte.
+    /// - if [`str`] is longer than [`c_int::MAX`] bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let book = std::fs::read_to_string("path/to/book.txt")?;
+    /// let tokens = model.str_to_token_exact(&book, AddBos::Always)?;
+    /// assert_eq!(tokens.capacity(), tokens.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn str_to_token_exact(
+        &self,
+        str: &str,
+        add_bos: AddBos,
+    ) -> Result<Vec<LlamaToken>, StringToTokenError> {
+        let add_bos = match add_bos {
+            AddBos::Always => true,
+            AddBos::Never => false,
+        };
+        let c_string = CString::new(str)?;
+        let len = c_int::try_from(c_string.as_bytes().len())?;
+
+        // with no room for any token llama.cpp returns the negated number of tokens
+        let needed = unsafe {
+            llama_cpp_sys_2::llama_tokenize(
+                self.model.as_ptr(),
+                c_string.as_ptr(),
+                len,
+                std::ptr::null_mut(),
+                0,
+                add_bos,
+                true,
+            )
+        };
+        let n_tokens = needed.unsigned_abs() as usize;
+        let mut buffer = Vec::with_capacity(n_tokens);
+        if n_tokens > 0 {
+            let size = unsafe {
+                llama_cpp_sys_2::llama_tokenize(
+                    self.model.as_ptr(),
+                    c_string.as_ptr(),
+                    len,
+                    buffer.as_mut_ptr(),
+                    -needed,
+                    add_bos,
+                    true,
+                )
+            };
+            debug_assert_eq!(size, -needed, "tokenizing twice gives the same tokens");
+            // Safety: llama-cpp has initialized `n_tokens` elements, the capacity of the buffer
+            unsafe { buffer.set_len(n_tokens) }
+        }
+        // collected in place, keeping the exact capacity
+        Ok(buffer.into_iter().map(LlamaToken).collect())
+    }
+
+    /// Tokenize many documents with [`Self::str_to_token`] on all available cores, e.g. to build
+    /// an embedding index. The tokens are returned in the order of `docs`.
+    ///
+    /// Tokenizing only reads the vocabulary, so the threads share the model. Each thread takes the
+    /// next document when it is done with the last one, which balances documents of different
+    /// lengths.
+    ///
+    /// # Errors
+    ///
+    /// - if any document contains a null byte.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let docs = ["The first document.", "The second one.", "And a third."];
+    /// let tokens = model.str_to_tokens_par(&docs, AddBos::Always)?;
+    /// assert_eq!(tokens.len(), docs.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn str_to_tokens_par<S: AsRef<str> + Sync>(
+        &self,
+        docs: &[S],
+        add_bos: AddBos,
+    ) -> Result<Vec<Vec<LlamaToken>>, StringToTokenError> {
+        let n_threads = std::thread::available_parallelism()
+            .map_or(1, std::num::NonZeroUsize::get)
+            .min(docs.len());
+        if n_threads <= 1 {
+            return docs
+                .iter()
+                .map(|doc| self.str_to_token(doc.as_ref(), add_bos))
+                .collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let tokenize = || {
+            let mut tokenized = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(doc) = docs.get(i) else {
+                    return tokenized;
+                };
+                tokenized.push((i, self.str_to_token(doc.as_ref(), add_bos)));
+            }
+        };
+        let mut tokens = vec![Vec::new(); docs.len()];
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..n_threads).map(|_| scope.spawn(tokenize)).collect();
+            for handle in handles {
+                let tokenized = handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                for (i, doc_tokens) in tokenized {
+                    tokens[i] = doc_tokens?;
+                }
+            }
+            Ok(tokens)
+        })
+    }
+
     /// Get the type of a token.
     ///
     /// # Panics
     ///
     /// If the token type is not known to this library.
     #[must_use]
-    pub fn token_type(&self, LlamaToken(id): LlamaToken) -> LlamaTokenType {
+    pub fn token_type(&self, token: LlamaToken) -> LlamaTokenType {
+        self.try_token_type(token).expect("token type is valid")
+    }
+
+    /// Get the type of a token, without panicking on types unknown to this library.
+    ///
+    /// # Errors
+    ///
+    /// If the token type is not known to this library.
+    pub fn try_token_type(
+        &self,
+        LlamaToken(id): LlamaToken,
+    ) -> Result<LlamaTokenType, crate::token_type::LlamaTokenTypeFromIntError> {
         let token_type = unsafe { llama_cpp_sys_2::llama_token_get_type(self.model.as_ptr(), id) };
-        LlamaTokenType::try_from(token_type).expect("token type is valid")
+        LlamaTokenType::try_from(token_type)
     }
 
     /// Convert a token to a string with a specified buffer size.
@@ -238,7 +748,8 @@ impl LlamaModel {
         buffer_size: usize,
     ) -> Result<String, TokenToStringError> {
         let bytes = self.token_to_bytes_with_size(token, buffer_size)?;
-        Ok(String::from_utf8(bytes)?)
+        String::from_utf8(bytes)
+            .map_err(|source| TokenToStringError::FromUtf8Error { token, source })
     }
 
     /// Convert a token to bytes with a specified buffer size.
@@ -250,55 +761,128 @@ impl LlamaModel {
     ///
     /// - if the token type is unknown
     /// - the resultant token is larger than `buffer_size`.
-    ///
-    /// # Panics
-    ///
-    /// - if `buffer_size` does not fit into a [`c_int`].
-    /// - if the returned size from llama-cpp does not fit into a [`usize`]. (this should never happen)
     pub fn token_to_bytes_with_size(
         &self,
         token: LlamaToken,
         buffer_size: usize,
     ) -> Result<Vec<u8>, TokenToStringError> {
-        if token == self.token_nl() {
-            return Ok(String::from("\n").into_bytes());
+        if let Some(bytes) = self.fixed_token_bytes(token) {
+            return Ok(bytes.to_vec());
         }
+        let mut bytes = vec![0; buffer_size];
+        let len = self.token_to_bytes_into_slice(token, &mut bytes)?;
+        bytes.truncate(len);
+        Ok(bytes)
+    }
 
-        match self.token_type(token) {
-            LlamaTokenType::Normal | LlamaTokenType::UserDefined => {}
-            LlamaTokenType::Control => {
-                if token == self.token_bos() || token == self.token_eos() {
-                    return Ok(Vec::new());
-                }
-            }
-            LlamaTokenType::Unknown
-            | LlamaTokenType::Undefined
-            | LlamaTokenType::Byte
-            | LlamaTokenType::Unused => {
-                return Ok(Vec::new());
+    /// Append the bytes of a token (as returned by [`LlamaModel::token_to_bytes`]) to `buf`,
+    /// returning how many were appended.
+    ///
+    /// This runs once per generated token in streaming loops. Reusing `buf` avoids allocating for
+    /// every token, and the buffer is grown if a token does not fit.
+    ///
+    /// # Errors
+    ///
+    /// - if the token type is unknown. Nothing is appended then.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{AddBos, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut bytes = Vec::with_capacity(1024);
+    /// for token in model.str_to_token("Hello, World!", AddBos::Never)? {
+    ///     model.token_to_bytes_into(token, &mut bytes)?;
+    /// }
+    /// assert_eq!(String::from_utf8(bytes)?, "Hello, World!");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn token_to_bytes_into(
+        &self,
+        token: LlamaToken,
+        buf: &mut Vec<u8>,
+    ) -> Result<usize, TokenToStringError> {
+        let start = buf.len();
+        buf.resize(start + 32, 0);
+        let len = match self.token_to_bytes_into_slice(token, &mut buf[start..]) {
+            Err(TokenToStringError::InsufficientBufferSpace { code, .. }) => {
+                buf.resize(start + code.unsigned_abs() as usize, 0);
+                self.token_to_bytes_into_slice(token, &mut buf[start..])
             }
+            len => len,
+        };
+        buf.truncate(start + *len.as_ref().unwrap_or(&0));
+        len
+    }
+
+    /// Write the bytes of a token (as returned by [`LlamaModel::token_to_bytes`]) to the start of
+    /// `buf` without allocating, returning how many were written.
+    ///
+    /// # Errors
+    ///
+    /// - if the token type is unknown
+    /// - the bytes do not fit into `buf`. The error holds the number of bytes needed.
+    pub fn token_to_bytes_into_slice(
+        &self,
+        token: LlamaToken,
+        buf: &mut [u8],
+    ) -> Result<usize, TokenToStringError> {
+        let buffer_size = buf.len();
+        let insufficient = |needed: usize| TokenToStringError::InsufficientBufferSpace {
+            token,
+            buffer_size,
+            code: c_int::try_from(needed).map_or(c_int::MIN, |needed| -needed),
+        };
+        if let Some(bytes) = self.fixed_token_bytes(token) {
+            let dest = buf
+                .get_mut(..bytes.len())
+                .ok_or_else(|| insufficient(bytes.len()))?;
+            dest.copy_from_slice(bytes);
+            return Ok(bytes.len());
         }
 
-        let string = CString::new(vec![b'*'; buffer_size]).expect("no null");
-        let len = string.as_bytes().len();
-        let len = c_int::try_from(len).expect("length fits into c_int");
-        let buf = string.into_raw();
+        let len = c_int::try_from(buffer_size).unwrap_or(c_int::MAX);
         let size = unsafe {
-            llama_cpp_sys_2::llama_token_to_piece(self.model.as_ptr(), token.0, buf, len)
+            llama_cpp_sys_2::llama_token_to_piece(
+                self.model.as_ptr(),
+                token.0,
+                buf.as_mut_ptr().cast::<std::os::raw::c_char>(),
+                len,
+            )
         };
 
         match size {
-            0 => Err(TokenToStringError::UnknownTokenType),
-            i if i.is_negative() => Err(TokenToStringError::InsufficientBufferSpace(i)),
-            size => {
-                let string = unsafe { CString::from_raw(buf) };
-                let mut bytes = string.into_bytes();
-                let len = usize::try_from(size).expect("size is positive and fits into usize");
-                bytes.truncate(len);
-                Ok(bytes)
+            0 => Err(TokenToStringError::UnknownTokenType { token }),
+            i if i.is_negative() => Err(TokenToStringError::InsufficientBufferSpace {
+                token,
+                buffer_size,
+                code: i,
+            }),
+            size => Ok(size.unsigned_abs() as usize),
+        }
+    }
+
+    /// The bytes of tokens that [`LlamaModel::token_to_bytes`] does not ask llama.cpp for: the
+    /// newline token, and nothing for BOS, EOS, byte, unknown and unused tokens.
+    fn fixed_token_bytes(&self, token: LlamaToken) -> Option<&'static [u8]> {
+        if token == self.token_nl() {
+            return Some(b"\n");
+        }
+        match self.token_type(token) {
+            LlamaTokenType::Normal | LlamaTokenType::UserDefined => None,
+            LlamaTokenType::Control => {
+                (token == self.token_bos() || token == self.token_eos()).then_some(&[])
             }
+            LlamaTokenType::Unknown
+            | LlamaTokenType::Undefined
+            | LlamaTokenType::Byte
+            | LlamaTokenType::Unused => Some(&[]),
         }
     }
+
     /// The number of tokens the model was trained on.
     ///
     /// This returns a `c_int` for maximum compatibility. Most of the time it can be cast to an i32
@@ -315,8 +899,18 @@ impl LlamaModel {
     /// If llama-cpp emits a vocab type that is not known to this library.
     #[must_use]
     pub fn vocab_type(&self) -> VocabType {
+        self.try_vocab_type().expect("invalid vocab type")
+    }
+
+    /// The type of vocab the model was trained on, without panicking on types unknown to this
+    /// library.
+    ///
+    /// # Errors
+    ///
+    /// If llama-cpp emits a vocab type that is not known to this library.
+    pub fn try_vocab_type(&self) -> Result<VocabType, LlamaTokenTypeFromIntError> {
         let vocab_type = unsafe { llama_cpp_sys_2::llama_vocab_type(self.model.as_ptr()) };
-        VocabType::try_from(vocab_type).expect("invalid vocab type")
+        VocabType::try_from(vocab_type)
     }
 
     /// This returns a `c_int` for maximum compatibility. Most of the time it can be cast to an i32
@@ -326,6 +920,151 @@ impl LlamaModel {
         unsafe { llama_cpp_sys_2::llama_n_embd(self.model.as_ptr()) }
     }
 
+    /// The number of layers of the model.
+    #[must_use]
+    pub fn n_layer(&self) -> i32 {
+        unsafe { llama_cpp_sys_2::llama_n_layer(self.model.as_ptr()) }
+    }
+
+    /// Returns `false` for encoder-only models (such as BERT-style embedding models like bge, e5
+    /// or nomic-embed) which use non-causal attention. These models can only be used to compute
+    /// embeddings and not to generate text.
+    ///
+    /// This is read from the `<arch>.attention.causal` key of the model's metadata and defaults to
+    /// `true` if the key is missing.
+    #[must_use]
+    pub fn is_causal(&self) -> bool {
+        let Some(arch) = self.meta_val_str("general.architecture") else {
+            return true;
+        };
+        self.meta_val_str(&format!("{arch}.attention.causal"))
+            .as_deref()
+            != Some("false")
+    }
+
+    /// Returns `true` for recurrent models (such as Mamba), whose "KV" cache holds a single
+    /// state per sequence rather than one cell per token. See
+    /// [`LlamaContext::clear_kv_cache_seq`] and [`LlamaContext::copy_kv_cache_seq`] for how this
+    /// changes the cache operations.
+    ///
+    /// The vendored llama.cpp does not expose this, so it is derived from the
+    /// `general.architecture` metadata the same way llama.cpp sets up its cache: only the `mamba`
+    /// architecture is recurrent.
+    #[must_use]
+    pub fn is_recurrent(&self) -> bool {
+        self.meta_val_str("general.architecture").as_deref() == Some("mamba")
+    }
+
+    /// Get a metadata value as a string, `None` if the key does not exist or is not valid utf8.
+    ///
+    /// Values of other types are formatted by llama.cpp.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let arch = model.meta_val_str("general.architecture");
+    /// assert_eq!(arch.as_deref(), Some("llama"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn meta_val_str(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).ok()?;
+        read_meta_str(|buf, len| unsafe {
+            llama_cpp_sys_2::llama_model_meta_val_str(self.model.as_ptr(), key.as_ptr(), buf, len)
+        })
+    }
+
+    /// The number of metadata entries of the model.
+    #[must_use]
+    pub fn meta_count(&self) -> i32 {
+        unsafe { llama_cpp_sys_2::llama_model_meta_count(self.model.as_ptr()) }
+    }
+
+    /// Get the key of the `i`th metadata entry, `None` if `i` is out of range or the key is not
+    /// valid utf8.
+    #[must_use]
+    pub fn meta_key_by_index(&self, i: i32) -> Option<String> {
+        read_meta_str(|buf, len| unsafe {
+            llama_cpp_sys_2::llama_model_meta_key_by_index(self.model.as_ptr(), i, buf, len)
+        })
+    }
+
+    /// Get the value of the `i`th metadata entry as a string (see [`Self::meta_val_str`]), `None`
+    /// if `i` is out of range or the value is not valid utf8.
+    #[must_use]
+    pub fn meta_val_str_by_index(&self, i: i32) -> Option<String> {
+        read_meta_str(|buf, len| unsafe {
+            llama_cpp_sys_2::llama_model_meta_val_str_by_index(self.model.as_ptr(), i, buf, len)
+        })
+    }
+
+    /// Get every metadata entry of the model as a key and a string value, in the order of the
+    /// model file. Entries with keys or values that are not valid utf8 are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// for (key, value) in model.metadata() {
+    ///     println!("{key} = {value}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        (0..self.meta_count())
+            .filter_map(|i| Some((self.meta_key_by_index(i)?, self.meta_val_str_by_index(i)?)))
+            .collect()
+    }
+
+    /// A short description of the model by llama.cpp: its architecture, size and type, e.g.
+    /// `llama 7B Q4_K - Medium`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// println!(
+    ///     "{}: {:.2}B parameters, {:.2} GiB",
+    ///     model.desc(),
+    ///     model.n_params() as f64 / 1e9,
+    ///     model.size() as f64 / f64::from(1 << 30),
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn desc(&self) -> String {
+        read_meta_str(|buf, len| unsafe {
+            llama_cpp_sys_2::llama_model_desc(self.model.as_ptr(), buf, len)
+        })
+        .unwrap_or_default()
+    }
+
+    /// The total size of the tensors of the model in bytes.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_size(self.model.as_ptr()) }
+    }
+
+    /// The total number of parameters of the model.
+    #[must_use]
+    pub fn n_params(&self) -> u64 {
+        unsafe { llama_cpp_sys_2::llama_model_n_params(self.model.as_ptr()) }
+    }
+
     /// Get chat template from model.
     ///
     /// # Errors
@@ -375,13 +1114,26 @@ impl LlamaModel {
             .ok_or(LlamaModelLoadError::PathToStrError(path.to_path_buf()))?;
 
         let cstr = CString::new(path)?;
+        let mut model_params = params.params;
+        let mut progress = LoadProgress::new(params);
+        if let Some(progress) = &mut progress {
+            progress.install(&mut model_params);
+        }
         let llama_model =
-            unsafe { llama_cpp_sys_2::llama_load_model_from_file(cstr.as_ptr(), params.params) };
+            unsafe { llama_cpp_sys_2::llama_load_model_from_file(cstr.as_ptr(), model_params) };
 
-        let model = NonNull::new(llama_model).ok_or(LlamaModelLoadError::NullResult)?;
+        let error = if progress.is_some_and(LoadProgress::cancelled) {
+            LlamaModelLoadError::Cancelled
+        } else {
+            LlamaModelLoadError::NullResult
+        };
+        let model = NonNull::new(llama_model).ok_or(error)?;
 
         tracing::debug!(?path, "Loaded model");
-        Ok(LlamaModel { model })
+        Ok(LlamaModel {
+            model,
+            eog_tokens: OnceLock::new(),
+        })
     }
 
     /// Create a new context from this model.
@@ -389,6 +1141,9 @@ impl LlamaModel {
     /// # Errors
     ///
     /// There is many ways this can fail. See [`LlamaContextLoadError`] for more information.
+    ///
+    /// Encoder-only models (see [`LlamaModel::is_causal`]) can not generate text, so creating a
+    /// context for them without [`LlamaContextParams::with_embeddings`] is an error.
     // we intentionally do not derive Copy on `LlamaContextParams` to allow llama.cpp to change the type to be non-trivially copyable.
     #[allow(clippy::needless_pass_by_value)]
     pub fn new_context(
@@ -396,13 +1151,19 @@ impl LlamaModel {
         _: &LlamaBackend,
         params: LlamaContextParams,
     ) -> Result<LlamaContext, LlamaContextLoadError> {
-        let context_params = params.context_params;
+        if !params.embeddings() && !self.is_causal() {
+            return Err(LlamaContextLoadError::EncoderOnlyModel);
+        }
+        let mut context_params = params.context_params;
+        let graph_profiler = params
+            .graph_profiling()
+            .then(|| GraphProfiler::install(&mut context_params));
         let context = unsafe {
             llama_cpp_sys_2::llama_new_context_with_model(self.model.as_ptr(), context_params)
         };
         let context = NonNull::new(context).ok_or(LlamaContextLoadError::NullReturn)?;
 
-        Ok(LlamaContext::new(self, context, params.embeddings()))
+        Ok(LlamaContext::new(self, context, &params, graph_profiler))
     }
 
     /// Apply the models chat template to some messages.
@@ -449,6 +1210,9 @@ impl LlamaModel {
                 buff.as_mut_ptr().cast::<std::os::raw::c_char>(),
                 buff.len() as i32,
             );
+            if res < 0 {
+                return Err(ApplyChatTemplateError::UnsupportedTemplate(res));
+            }
             // A buffer twice the size should be sufficient for all models, if this is not the case for a new model, we can increase it
             // The error message informs the user to contact a maintainer
             if res > buff.len() as i32 {
@@ -458,6 +1222,83 @@ impl LlamaModel {
         }?;
         Ok(formatted_chat)
     }
+
+    /// Apply the models chat template to some messages (see [`LlamaModel::apply_chat_template`])
+    /// and tokenize the result, ready to be decoded.
+    ///
+    /// Special tokens in the formatted chat (such as `<|im_start|>`) are parsed into their tokens
+    /// rather than tokenized as text. A beginning of stream token is added only if the tokenizer
+    /// expects one and the template did not already render it, so the prompt never starts with two.
+    ///
+    /// # Errors
+    ///
+    /// See [`ApplyChatTemplateError`] for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let chat = vec![
+    ///     LlamaChatMessage::new("system".to_string(), "You are a helpful assistant.".to_string())?,
+    ///     LlamaChatMessage::new("user".to_string(), "Hello!".to_string())?,
+    /// ];
+    /// let tokens = model.apply_chat_template_and_tokenize(None, chat, true)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn apply_chat_template_and_tokenize(
+        &self,
+        tmpl: Option<String>,
+        chat: Vec<LlamaChatMessage>,
+        add_ass: bool,
+    ) -> Result<Vec<LlamaToken>, ApplyChatTemplateError> {
+        let formatted_chat = self.apply_chat_template(tmpl, chat, add_ass)?;
+        // llama.cpp adds a bos token to sentencepiece prompts unless the model says otherwise.
+        let add_bos = self
+            .add_bos_token()
+            .unwrap_or(self.vocab_type() == VocabType::SPM);
+        let bos = self.token_text(self.token_bos());
+        let add_bos = if add_bos && (bos.is_empty() || !formatted_chat.starts_with(&bos)) {
+            AddBos::Always
+        } else {
+            AddBos::Never
+        };
+        Ok(self.str_to_token(&formatted_chat, add_bos)?)
+    }
+}
+
+/// The metadata key of the end of turn token.
+const EOT_TOKEN_ID_KEY: &str = "tokenizer.ggml.eot_token_id";
+
+/// The texts of the special tokens chat models end their turn with, most specific first.
+const END_OF_TURN_TEXTS: [&str; 6] = [
+    "<|eot_id|>",
+    "<|im_end|>",
+    "<end_of_turn>",
+    "<|END_OF_TURN_TOKEN|>",
+    "<|end|>",
+    "\u{2581}<EOT>",
+];
+
+/// The texts of special tokens ending a document, which end generation too.
+const END_OF_TEXT_TEXTS: [&str; 2] = ["<|endoftext|>", "<|end_of_text|>"];
+
+/// Read a string from one of the `llama_model_meta_*` functions or `llama_model_desc`, which write
+/// into `buf` and return the length of the whole string or a negative value on failure, growing the
+/// buffer as needed.
+fn read_meta_str(mut read: impl FnMut(*mut std::os::raw::c_char, usize) -> i32) -> Option<String> {
+    let mut buf = vec![0_u8; 128];
+    loop {
+        let len = usize::try_from(read(buf.as_mut_ptr().cast(), buf.len())).ok()?;
+        if len < buf.len() {
+            buf.truncate(len);
+            return String::from_utf8(buf).ok();
+        }
+        buf.resize(len + 1, 0);
+    }
 }
 
 impl Drop for LlamaModel {
@@ -474,6 +1315,8 @@ pub enum VocabType {
     BPE = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_BPE as _,
     /// Sentence Piece Tokenizer
     SPM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_SPM as _,
+    /// Word Piece Tokenizer, used by BERT-style encoder models
+    WPM = llama_cpp_sys_2::LLAMA_VOCAB_TYPE_WPM as _,
 }
 
 /// There was an error converting a `llama_vocab_type` to a `VocabType`.
@@ -491,6 +1334,7 @@ impl TryFrom<llama_cpp_sys_2::llama_vocab_type> for VocabType {
         match value {
             llama_cpp_sys_2::LLAMA_VOCAB_TYPE_BPE => Ok(VocabType::BPE),
             llama_cpp_sys_2::LLAMA_VOCAB_TYPE_SPM => Ok(VocabType::SPM),
+            llama_cpp_sys_2::LLAMA_VOCAB_TYPE_WPM => Ok(VocabType::WPM),
             unknown => Err(LlamaTokenTypeFromIntError::UnknownValue(unknown)),
         }
     }
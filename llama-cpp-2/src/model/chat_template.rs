@@ -0,0 +1,191 @@
+//! Guessing the chat template of models whose GGUF has a missing or mangled template.
+//!
+//! [`LlamaModel::apply_chat_template`] silently falls back to `chatml` if a model has no
+//! template, and fails if the template is not recognized by llama.cpp. For such models
+//! [`LlamaModel::detect_chat_template`] guesses the name of a template llama.cpp knows, which
+//! can then be passed to [`LlamaModel::apply_chat_template`] explicitly.
+
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+
+/// The names of the templates [`LlamaModel::apply_chat_template`] accepts.
+pub const KNOWN_CHAT_TEMPLATES: [&str; 6] =
+    ["chatml", "llama2", "zephyr", "monarch", "gemma", "orion"];
+
+/// Substrings of a template identifying a known template, checked in the same order as llama.cpp.
+const TEMPLATE_MARKERS: [(&str, &str); 6] = [
+    ("<|im_start|>", "chatml"),
+    ("[INST]", "llama2"),
+    ("<|user|>", "zephyr"),
+    ("bos_token + message['role']", "monarch"),
+    ("<start_of_turn>", "gemma"),
+    ("'\\n\\nAssistant: ' + eos_token", "orion"),
+];
+
+/// Special tokens only models trained on a template have, ordered by how specific they are.
+const TOKEN_MARKERS: [(&str, &str); 4] = [
+    ("<|im_start|>", "chatml"),
+    ("<start_of_turn>", "gemma"),
+    ("<|user|>", "zephyr"),
+    ("[INST]", "llama2"),
+];
+
+/// Architectures which only have a single chat template.
+const ARCHITECTURE_MARKERS: [(&str, &str); 3] =
+    [("gemma", "gemma"), ("orion", "orion"), ("qwen2", "chatml")];
+
+/// The template llama.cpp uses for models without a template.
+const FALLBACK_TEMPLATE: &str = "chatml";
+
+/// What a [`DetectedChatTemplate`] is based on, from least to most reliable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChatTemplateConfidence {
+    /// Nothing pointed to a template, this is the template llama.cpp uses for models without one.
+    Fallback,
+    /// The architecture of the model is only used with a single template.
+    Architecture,
+    /// The vocabulary has the special tokens of the template.
+    Tokens,
+    /// The template of the model is (or contains the markers of) a known template.
+    Template,
+}
+
+/// A chat template guessed by [`LlamaModel::detect_chat_template`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::module_name_repetitions)]
+pub struct DetectedChatTemplate {
+    /// The name of the template, one of [`KNOWN_CHAT_TEMPLATES`].
+    pub name: &'static str,
+    /// What the guess is based on.
+    pub confidence: ChatTemplateConfidence,
+}
+
+impl DetectedChatTemplate {
+    /// Detect a known template from the text of a template the same way llama.cpp does, `None` if
+    /// it matches none of [`KNOWN_CHAT_TEMPLATES`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::chat_template::{ChatTemplateConfidence, DetectedChatTemplate};
+    /// let template = "{% for message in messages %}{{'<|im_start|>' + message['role'] }}";
+    /// let detected = DetectedChatTemplate::from_template(template).unwrap();
+    /// assert_eq!(detected.name, "chatml");
+    /// assert_eq!(detected.confidence, ChatTemplateConfidence::Template);
+    ///
+    /// assert_eq!(DetectedChatTemplate::from_template(" gemma\n").unwrap().name, "gemma");
+    /// assert_eq!(DetectedChatTemplate::from_template("{{ messages }}"), None);
+    /// ```
+    #[must_use]
+    pub fn from_template(template: &str) -> Option<Self> {
+        let name = KNOWN_CHAT_TEMPLATES
+            .into_iter()
+            .find(|&name| name == template.trim())
+            .or_else(|| {
+                TEMPLATE_MARKERS
+                    .into_iter()
+                    .find(|(marker, _)| template.contains(marker))
+                    .map(|(_, name)| name)
+            })?;
+        Some(Self {
+            name,
+            confidence: ChatTemplateConfidence::Template,
+        })
+    }
+
+    /// Detect a known template from the texts of the tokens of a vocabulary, `None` if it has
+    /// none of their special tokens.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::chat_template::{ChatTemplateConfidence, DetectedChatTemplate};
+    /// let vocab = ["<s>", "</s>", "<start_of_turn>", "<end_of_turn>", "hello"];
+    /// let detected = DetectedChatTemplate::from_tokens(vocab).unwrap();
+    /// assert_eq!(detected.name, "gemma");
+    /// assert_eq!(detected.confidence, ChatTemplateConfidence::Tokens);
+    ///
+    /// assert_eq!(DetectedChatTemplate::from_tokens(["<s>", "</s>"]), None);
+    /// ```
+    #[must_use]
+    pub fn from_tokens(tokens: impl IntoIterator<Item = impl AsRef<str>>) -> Option<Self> {
+        let position = tokens
+            .into_iter()
+            .filter_map(|token| {
+                TOKEN_MARKERS
+                    .iter()
+                    .position(|(marker, _)| *marker == token.as_ref())
+            })
+            .min()?;
+        Some(Self {
+            name: TOKEN_MARKERS[position].1,
+            confidence: ChatTemplateConfidence::Tokens,
+        })
+    }
+
+    /// Detect a known template from the `general.architecture` of a model, `None` if the
+    /// architecture is used with different templates.
+    #[must_use]
+    pub fn from_architecture(architecture: &str) -> Option<Self> {
+        let (_, name) = ARCHITECTURE_MARKERS
+            .into_iter()
+            .find(|(arch, _)| *arch == architecture)?;
+        Some(Self {
+            name,
+            confidence: ChatTemplateConfidence::Architecture,
+        })
+    }
+
+    /// The template llama.cpp uses for models without a template.
+    #[must_use]
+    pub fn fallback() -> Self {
+        Self {
+            name: FALLBACK_TEMPLATE,
+            confidence: ChatTemplateConfidence::Fallback,
+        }
+    }
+}
+
+impl LlamaModel {
+    /// Guess the chat template of the model, for models whose template is missing, mangled or
+    /// not known to llama.cpp.
+    ///
+    /// The template of the model is checked first, then the special tokens of its vocabulary,
+    /// then its architecture. If none of them point to a known template the result is
+    /// [`DetectedChatTemplate::fallback`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::chat_template::ChatTemplateConfidence;
+    /// # use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let detected = model.detect_chat_template();
+    /// if detected.confidence < ChatTemplateConfidence::Tokens {
+    ///     eprintln!("guessing the `{}` chat template", detected.name);
+    /// }
+    /// let chat = vec![LlamaChatMessage::new("user".to_string(), "Hello!".to_string())?];
+    /// let prompt = model.apply_chat_template(Some(detected.name.to_string()), chat, true)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn detect_chat_template(&self) -> DetectedChatTemplate {
+        self.meta_val_str("tokenizer.chat_template")
+            .as_deref()
+            .and_then(DetectedChatTemplate::from_template)
+            .or_else(|| {
+                DetectedChatTemplate::from_tokens(
+                    (0..self.n_vocab()).map(|i| self.token_text(LlamaToken::new(i))),
+                )
+            })
+            .or_else(|| {
+                self.meta_val_str("general.architecture")
+                    .as_deref()
+                    .and_then(DetectedChatTemplate::from_architecture)
+            })
+            .unwrap_or_else(DetectedChatTemplate::fallback)
+    }
+}
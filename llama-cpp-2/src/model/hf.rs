@@ -0,0 +1,102 @@
+//! Loading models straight from the Hugging Face Hub, like the `-hf` option of llama.cpp's tools.
+//!
+//! Files are downloaded with [`hf_hub`] into the shared Hugging Face cache (`~/.cache/huggingface`
+//! or `$HF_HOME`), so a model that was downloaded before, by this crate or by Python tools, is
+//! loaded without going online. Interrupted downloads start over, as `hf-hub` does not resume them.
+
+use hf_hub::api::sync::{Api, ApiBuilder, ApiError};
+
+use crate::llama_backend::LlamaBackend;
+use crate::model::params::LlamaModelParams;
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+/// Failed to download or load a model from the Hugging Face Hub.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum LlamaModelHfError {
+    /// Downloading the file failed.
+    #[error("{0}")]
+    Api(#[from] ApiError),
+    /// Loading the downloaded file failed.
+    #[error("{0}")]
+    Load(#[from] LlamaModelLoadError),
+}
+
+impl LlamaModel {
+    /// Download `file` from the model repository `repo` (e.g. `TheBloke/Llama-2-7B-Chat-GGUF`)
+    /// unless it is cached already, showing a progress bar on the terminal, and load it.
+    ///
+    /// Uses the access token saved by `huggingface-cli login`, if any. See
+    /// [`LlamaModel::from_hf_with_api`] to use another cache directory, token or no progress bar.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelHfError`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let backend = LlamaBackend::init()?;
+    /// let model = LlamaModel::from_hf(
+    ///     &backend,
+    ///     "TheBloke/Llama-2-7B-Chat-GGUF",
+    ///     "llama-2-7b-chat.Q4_K_M.gguf",
+    ///     &Default::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_hf(
+        backend: &LlamaBackend,
+        repo: &str,
+        file: &str,
+        params: &LlamaModelParams,
+    ) -> Result<Self, LlamaModelHfError> {
+        let api = ApiBuilder::new().with_progress(true).build()?;
+        Self::from_hf_with_api(backend, &api, repo, file, params)
+    }
+
+    /// Like [`LlamaModel::from_hf`], but download with a configured `api`.
+    ///
+    /// # Errors
+    ///
+    /// See [`LlamaModelHfError`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::llama_backend::LlamaBackend;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// use hf_hub::api::sync::ApiBuilder;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = LlamaBackend::init()?;
+    /// let api = ApiBuilder::new()
+    ///     .with_cache_dir("models".into())
+    ///     .with_progress(false)
+    ///     .build()?;
+    /// let model = LlamaModel::from_hf_with_api(
+    ///     &backend,
+    ///     &api,
+    ///     "TheBloke/Llama-2-7B-Chat-GGUF",
+    ///     "llama-2-7b-chat.Q4_K_M.gguf",
+    ///     &Default::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_hf_with_api(
+        backend: &LlamaBackend,
+        api: &Api,
+        repo: &str,
+        file: &str,
+        params: &LlamaModelParams,
+    ) -> Result<Self, LlamaModelHfError> {
+        let path = api.model(repo.to_string()).get(file)?;
+        Ok(Self::load_from_file(backend, path, params)?)
+    }
+}
@@ -0,0 +1,530 @@
+//! Checking model files before loading them, e.g. to validate downloads.
+//!
+//! [`verify`] only reads the GGUF header (and optionally the data of unquantized tensors), so it is
+//! much cheaper than loading the model and reports problems as a [`ModelVerifyError`] instead of
+//! failing somewhere inside llama.cpp. The header is parsed in Rust, as ggml aborts the process on
+//! some malformed headers. GGUF files do not contain checksums, [`verify_sha256`] checks a file
+//! against a published SHA-256 hash (e.g. the one Hugging Face lists for each file) instead.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::model::integrity::{verify, ModelVerifyError};
+//!
+//! match verify("path/to/model.gguf", false) {
+//!     Ok(()) => println!("looks good"),
+//!     Err(ModelVerifyError::Truncated { tensor, expected, actual }) => {
+//!         println!("download incomplete: {tensor} ends at {expected} but the file has {actual} bytes");
+//!     }
+//!     Err(err) => println!("invalid model: {err}"),
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// The magic bytes every GGUF file starts with.
+const GGUF_MAGIC: [u8; 4] = *b"GGUF";
+
+/// A problem found by [`verify`] or [`verify_sha256`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ModelVerifyError {
+    /// The file could not be read.
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The path is not valid unicode.
+    #[error("failed to convert path {0} to str")]
+    PathToStr(PathBuf),
+    /// The path contains a null byte.
+    #[error("null byte in path {0}")]
+    NulError(#[from] std::ffi::NulError),
+    /// The file does not start with the GGUF magic bytes.
+    #[error("not a GGUF file (magic {0:?})")]
+    BadMagic([u8; 4]),
+    /// The header of the file is malformed or truncated.
+    #[error("the GGUF header is invalid")]
+    InvalidHeader,
+    /// The data of a tensor extends past the end of the file.
+    #[error("tensor {tensor} ends at byte {expected} but the file only has {actual} bytes")]
+    Truncated {
+        /// The name of the first tensor that does not fit.
+        tensor: String,
+        /// The size the file needs to have to contain the tensor.
+        expected: u64,
+        /// The actual size of the file.
+        actual: u64,
+    },
+    /// An unquantized tensor contains NaN or infinite values.
+    #[error("tensor {tensor} contains a non-finite value at element {index}")]
+    NonFinite {
+        /// The name of the tensor.
+        tensor: String,
+        /// The index of the first non-finite element.
+        index: u64,
+    },
+    /// The SHA-256 hash of the file is not the expected one.
+    #[error("expected SHA-256 {expected} but the file has {actual}")]
+    ChecksumMismatch {
+        /// The expected hash, as given to [`verify_sha256`].
+        expected: String,
+        /// The hash of the file in lowercase hex.
+        actual: String,
+    },
+}
+
+/// Check that the file at `path` is a complete GGUF file.
+///
+/// This checks the magic bytes, parses the header and checks that the data of every tensor is
+/// within the file. If `check_tensors` is set, the data of `f32` and `f16` tensors is also read
+/// and checked for NaN and infinite values, which reads most of the file for unquantized models.
+///
+/// For models split into several files each file has to be verified on its own.
+///
+/// # Errors
+///
+/// The first problem found, see [`ModelVerifyError`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::model::integrity::{verify, ModelVerifyError};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let path = std::env::temp_dir().join("llama-cpp-2-verify-doctest.gguf");
+/// std::fs::write(&path, "<html>404 Not Found</html>")?;
+/// assert!(matches!(verify(&path, false), Err(ModelVerifyError::BadMagic(_))));
+///
+/// std::fs::write(&path, b"GGUF\x03\x00")?;
+/// assert!(matches!(verify(&path, false), Err(ModelVerifyError::InvalidHeader)));
+/// # std::fs::remove_file(&path)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify(path: impl AsRef<Path>, check_tensors: bool) -> Result<(), ModelVerifyError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let tensors = read_tensor_locations(&mut file, file_len)?;
+    if let Some(tensor) = tensors.iter().find(|tensor| tensor.end() > file_len) {
+        return Err(ModelVerifyError::Truncated {
+            tensor: tensor.name.clone(),
+            expected: tensor.end(),
+            actual: file_len,
+        });
+    }
+    if check_tensors {
+        for tensor in &tensors {
+            check_finite(&mut file, tensor)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that the SHA-256 hash of the file at `path` is `expected`, given in hex (either case).
+///
+/// This reads the whole file. Use it together with [`verify`], which finds truncated or malformed
+/// files without a known hash.
+///
+/// # Errors
+///
+/// [`ModelVerifyError::ChecksumMismatch`] if the hashes differ, or [`ModelVerifyError::Io`] if the
+/// file can not be read.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::model::integrity::{verify_sha256, ModelVerifyError};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let path = std::env::temp_dir().join("llama-cpp-2-verify-sha256-doctest.gguf");
+/// std::fs::write(&path, "abc")?;
+/// verify_sha256(
+///     &path,
+///     "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD",
+/// )?;
+/// assert!(matches!(
+///     verify_sha256(&path, "0000"),
+///     Err(ModelVerifyError::ChecksumMismatch { .. })
+/// ));
+/// # std::fs::remove_file(&path)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn verify_sha256(path: impl AsRef<Path>, expected: &str) -> Result<(), ModelVerifyError> {
+    let (_, actual) = hash_file(path.as_ref())?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(ModelVerifyError::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// The size and hex SHA-256 hash of the file at `path`.
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            write!(hex, "{byte:02x}").expect("writing to a string does not fail");
+            hex
+        });
+    Ok((size, sha256))
+}
+
+/// The parsed header of a GGUF file, with a ggml context holding the tensor metadata.
+pub(crate) struct GgufHeader {
+    gguf: *mut llama_cpp_sys_2::gguf_context,
+    ggml: *mut llama_cpp_sys_2::ggml_context,
+}
+
+impl GgufHeader {
+    #[cfg(feature = "registry")]
+    pub(crate) fn read(path: &CStr) -> Option<Self> {
+        Self::init(path, true)
+    }
+
+    /// Read the header and the data of all tensors, which is only sensible for small files.
+    pub(crate) fn read_with_data(path: &CStr) -> Option<Self> {
+        Self::init(path, false)
+    }
+
+    fn init(path: &CStr, no_alloc: bool) -> Option<Self> {
+        // ggml aborts on some malformed headers, and reads the whole data section with the data
+        let mut file = File::open(path.to_str().ok()?).ok()?;
+        let file_len = file.metadata().ok()?.len();
+        let tensors = read_tensor_locations(&mut file, file_len).ok()?;
+        if !no_alloc && tensors.iter().any(|tensor| tensor.end() > file_len) {
+            return None;
+        }
+        drop(file);
+
+        let mut ggml = std::ptr::null_mut();
+        let params = llama_cpp_sys_2::gguf_init_params {
+            no_alloc,
+            ctx: std::ptr::addr_of_mut!(ggml),
+        };
+        let gguf = unsafe { llama_cpp_sys_2::gguf_init_from_file(path.as_ptr(), params) };
+        (!gguf.is_null()).then_some(Self { gguf, ggml })
+    }
+
+    /// The metadata entries with string values.
+    #[cfg(feature = "registry")]
+    pub(crate) fn string_metadata(&self) -> Vec<(String, String)> {
+        let n_kv = unsafe { llama_cpp_sys_2::gguf_get_n_kv(self.gguf) };
+        (0..n_kv)
+            .filter(|&i| unsafe {
+                llama_cpp_sys_2::gguf_get_kv_type(self.gguf, i) == llama_cpp_sys_2::GGUF_TYPE_STRING
+            })
+            .map(|i| unsafe {
+                let key = CStr::from_ptr(llama_cpp_sys_2::gguf_get_key(self.gguf, i));
+                let value = CStr::from_ptr(llama_cpp_sys_2::gguf_get_val_str(self.gguf, i));
+                (
+                    key.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    /// The names of the tensors, in file order.
+    pub(crate) fn tensor_names(&self) -> Vec<String> {
+        let n_tensors = unsafe { llama_cpp_sys_2::gguf_get_n_tensors(self.gguf) };
+        (0..n_tensors)
+            .map(|i| unsafe {
+                let name = CStr::from_ptr(llama_cpp_sys_2::gguf_get_tensor_name(self.gguf, i));
+                name.to_string_lossy().into_owned()
+            })
+            .collect()
+    }
+
+    /// The data of the `f32` tensor called `name`. Only available if the header was read with
+    /// [`GgufHeader::read_with_data`].
+    pub(crate) fn tensor_f32(&self, name: &CStr) -> Option<&[f32]> {
+        if self.ggml.is_null() {
+            return None;
+        }
+        let tensor = unsafe { llama_cpp_sys_2::ggml_get_tensor(self.ggml, name.as_ptr()) };
+        let tensor = unsafe { tensor.as_ref() }?;
+        if tensor.type_ != llama_cpp_sys_2::GGML_TYPE_F32 || tensor.data.is_null() {
+            return None;
+        }
+        let len = usize::try_from(unsafe { llama_cpp_sys_2::ggml_nelements(tensor) }).ok()?;
+        Some(unsafe { std::slice::from_raw_parts(tensor.data.cast::<f32>(), len) })
+    }
+}
+
+impl Drop for GgufHeader {
+    fn drop(&mut self) {
+        unsafe {
+            llama_cpp_sys_2::gguf_free(self.gguf);
+            if !self.ggml.is_null() {
+                llama_cpp_sys_2::ggml_free(self.ggml);
+            }
+        }
+    }
+}
+
+/// Where the data of a tensor is in the file.
+struct TensorLocation {
+    name: String,
+    offset: u64,
+    size: u64,
+    ggml_type: llama_cpp_sys_2::ggml_type,
+}
+
+impl TensorLocation {
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+/// Parse the GGUF header of `file` and locate the data of its tensors, rejecting everything
+/// `gguf_init_from_file` would abort on or compute garbage from.
+fn read_tensor_locations(
+    file: &mut File,
+    file_len: u64,
+) -> Result<Vec<TensorLocation>, ModelVerifyError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = HeaderReader {
+        reader: BufReader::new(file),
+        pos: 0,
+        file_len,
+    };
+    let mut magic = [0; 4];
+    header.reader.read_exact(&mut magic)?;
+    header.pos = 4;
+    if magic != GGUF_MAGIC {
+        return Err(ModelVerifyError::BadMagic(magic));
+    }
+    // version 1 used 32 bit counts and is not supported by ggml
+    if header.u32()? < 2 {
+        return Err(ModelVerifyError::InvalidHeader);
+    }
+    let n_tensors = header.u64()?;
+    let n_kv = header.u64()?;
+
+    let mut alignment = None;
+    for _ in 0..n_kv {
+        let key = header.string()?;
+        let value_type = header.u32()?;
+        if key == b"general.alignment" && alignment.is_none() {
+            // ggml reads it as a u32 and divides by it
+            if value_type != llama_cpp_sys_2::GGUF_TYPE_UINT32 {
+                return Err(ModelVerifyError::InvalidHeader);
+            }
+            match header.u32()? {
+                0 => return Err(ModelVerifyError::InvalidHeader),
+                value => alignment = Some(u64::from(value)),
+            }
+            continue;
+        }
+        header.skip_value(value_type)?;
+    }
+    let alignment = alignment.unwrap_or(u64::from(llama_cpp_sys_2::GGUF_DEFAULT_ALIGNMENT));
+
+    let mut tensors = Vec::new();
+    for _ in 0..n_tensors {
+        let name = String::from_utf8_lossy(&header.string()?).into_owned();
+        let n_dims = header.u32()?;
+        if n_dims > llama_cpp_sys_2::GGML_MAX_DIMS {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        let mut n_elements: i64 = 1;
+        for _ in 0..n_dims {
+            let n = i64::try_from(header.u64()?).map_err(|_| ModelVerifyError::InvalidHeader)?;
+            n_elements = n_elements
+                .checked_mul(n)
+                .filter(|_| n > 0)
+                .ok_or(ModelVerifyError::InvalidHeader)?;
+        }
+        let ggml_type = header.u32()?;
+        let offset = header.u64()?;
+        if ggml_type >= llama_cpp_sys_2::GGML_TYPE_COUNT {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        // removed types have a block size of 0
+        let block_size = i64::from(unsafe { llama_cpp_sys_2::ggml_blck_size(ggml_type) });
+        if block_size <= 0 || n_elements % block_size != 0 {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        let size = unsafe { llama_cpp_sys_2::ggml_row_size(ggml_type, n_elements) } as u64;
+        tensors.push(TensorLocation {
+            name,
+            offset,
+            size,
+            ggml_type,
+        });
+    }
+
+    let data_offset = header.pos.next_multiple_of(alignment);
+    let data_size = tensors
+        .iter()
+        .try_fold(0_u64, |total, tensor| {
+            total.checked_add(tensor.size.checked_next_multiple_of(alignment)?)
+        })
+        .ok_or(ModelVerifyError::InvalidHeader)?;
+    for tensor in &mut tensors {
+        if tensor
+            .offset
+            .checked_add(tensor.size)
+            .is_none_or(|end| end > data_size)
+        {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        tensor.offset += data_offset;
+    }
+    Ok(tensors)
+}
+
+/// Reads the header of a GGUF file, failing with [`ModelVerifyError::InvalidHeader`] if it ends
+/// early.
+struct HeaderReader<'a> {
+    reader: BufReader<&'a mut File>,
+    pos: u64,
+    file_len: u64,
+}
+
+impl HeaderReader<'_> {
+    fn bytes(&mut self, n: u64) -> Result<Vec<u8>, ModelVerifyError> {
+        if n > self.file_len.saturating_sub(self.pos) {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        let mut bytes = vec![0; usize::try_from(n).map_err(|_| ModelVerifyError::InvalidHeader)?];
+        self.reader.read_exact(&mut bytes).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                ModelVerifyError::InvalidHeader
+            } else {
+                err.into()
+            }
+        })?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: u64) -> Result<(), ModelVerifyError> {
+        if n > self.file_len.saturating_sub(self.pos) {
+            return Err(ModelVerifyError::InvalidHeader);
+        }
+        let offset = i64::try_from(n).map_err(|_| ModelVerifyError::InvalidHeader)?;
+        self.reader.seek_relative(offset)?;
+        self.pos += n;
+        Ok(())
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelVerifyError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().expect("read 4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, ModelVerifyError> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("read 8 bytes")))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, ModelVerifyError> {
+        let len = self.u64()?;
+        self.bytes(len)
+    }
+
+    /// Skip a metadata value of `value_type`. Arrays of arrays and unknown types are invalid.
+    fn skip_value(&mut self, value_type: u32) -> Result<(), ModelVerifyError> {
+        match value_type {
+            llama_cpp_sys_2::GGUF_TYPE_STRING => {
+                let len = self.u64()?;
+                self.skip(len)
+            }
+            llama_cpp_sys_2::GGUF_TYPE_ARRAY => {
+                let element_type = self.u32()?;
+                let n = self.u64()?;
+                if element_type == llama_cpp_sys_2::GGUF_TYPE_STRING {
+                    (0..n).try_for_each(|_| self.skip_value(llama_cpp_sys_2::GGUF_TYPE_STRING))
+                } else {
+                    let size =
+                        gguf_type_size(element_type).ok_or(ModelVerifyError::InvalidHeader)?;
+                    self.skip(n.checked_mul(size).ok_or(ModelVerifyError::InvalidHeader)?)
+                }
+            }
+            _ => self.skip(gguf_type_size(value_type).ok_or(ModelVerifyError::InvalidHeader)?),
+        }
+    }
+}
+
+/// The size of a fixed size `gguf_type`.
+fn gguf_type_size(value_type: u32) -> Option<u64> {
+    match value_type {
+        // u8, i8, bool
+        0 | 1 | 7 => Some(1),
+        // u16, i16
+        2 | 3 => Some(2),
+        // u32, i32, f32
+        4..=6 => Some(4),
+        // u64, i64, f64
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+/// Check that an `f32` or `f16` tensor only contains finite values. Other types are skipped.
+fn check_finite(file: &mut File, tensor: &TensorLocation) -> Result<(), ModelVerifyError> {
+    let is_finite: fn(&[u8]) -> bool = match tensor.ggml_type {
+        llama_cpp_sys_2::GGML_TYPE_F32 => {
+            |bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).is_finite()
+        }
+        // all exponent bits set is infinity or NaN
+        llama_cpp_sys_2::GGML_TYPE_F16 => |bytes| bytes[1] & 0x7C != 0x7C,
+        _ => return Ok(()),
+    };
+    let element_size = if tensor.ggml_type == llama_cpp_sys_2::GGML_TYPE_F32 {
+        4
+    } else {
+        2
+    };
+    file.seek(SeekFrom::Start(tensor.offset))?;
+    let mut reader = file.take(tensor.size);
+    let mut buf = vec![0; 1 << 20];
+    let mut index = 0;
+    loop {
+        let n = read_full(&mut reader, &mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        for element in buf[..n].chunks_exact(element_size) {
+            if !is_finite(element) {
+                return Err(ModelVerifyError::NonFinite {
+                    tensor: tensor.name.clone(),
+                    index,
+                });
+            }
+            index += 1;
+        }
+    }
+}
+
+/// Fill `buf` as far as possible, so chunks only end early at the end of the data.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
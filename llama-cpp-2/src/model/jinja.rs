@@ -0,0 +1,176 @@
+//! Rendering chat templates with a Jinja engine, for models whose template llama.cpp does not know.
+//!
+//! [`LlamaModel::apply_chat_template`] only recognizes a fixed list of templates. The templates in
+//! GGUF files are Jinja templates as used by Hugging Face `transformers`, so
+//! [`LlamaModel::render_chat_template`] renders them with [`minijinja`] instead, with the
+//! `messages`, `bos_token`, `eos_token` and `add_generation_prompt` variables and the
+//! `raise_exception` function templates expect. Python string methods such as `strip()` are
+//! supported through `minijinja-contrib`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use llama_cpp_2::model::{LlamaChatMessage, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let chat = vec![
+//!     LlamaChatMessage::new("system".to_string(), "You are a helpful assistant.".to_string())?,
+//!     LlamaChatMessage::new("user".to_string(), "Hello!".to_string())?,
+//! ];
+//! // llama.cpp if it knows the template, the Jinja engine otherwise
+//! let prompt = model.apply_chat_template_with_fallback(None, &chat, true)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use minijinja::{context, Environment, Error, ErrorKind, Value};
+
+use crate::model::{LlamaChatMessage, LlamaModel};
+use crate::token::LlamaToken;
+use crate::ApplyChatTemplateError;
+
+/// Failed to render a chat template.
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum RenderChatTemplateError {
+    /// The model has no chat template and none was given.
+    #[error("the model has no chat template")]
+    MissingTemplate,
+    /// The template is not valid or raised an exception, e.g. because the roles do not alternate.
+    #[error("{0}")]
+    Template(#[from] Error),
+    /// A message is not valid UTF-8.
+    #[error("{0}")]
+    Utf8(#[from] std::str::Utf8Error),
+    /// llama.cpp failed to apply a template it knows.
+    #[error("{0}")]
+    Apply(#[from] ApplyChatTemplateError),
+}
+
+/// Render the Jinja chat `template` for `chat`.
+///
+/// The template is rendered like `transformers` does, with `trim_blocks` and `lstrip_blocks`
+/// enabled. `bos_token` and `eos_token` are the texts of the model's special tokens and
+/// `add_generation_prompt` tells the template to end with the start of an assistant message.
+///
+/// # Errors
+///
+/// - the template is not valid or raised an exception.
+/// - a message is not valid UTF-8.
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::model::jinja::render_chat_template;
+/// # use llama_cpp_2::model::LlamaChatMessage;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let template = "{% for message in messages %}<|{{ message.role }}|>{{ message.content }}{{ eos_token }}{% endfor %}\
+///                 {% if add_generation_prompt %}<|assistant|>{% endif %}";
+/// let chat = vec![LlamaChatMessage::new("user".to_string(), "Hello!".to_string())?];
+/// let prompt = render_chat_template(template, &chat, "<s>", "</s>", true)?;
+/// assert_eq!(prompt, "<|user|>Hello!</s><|assistant|>");
+/// # Ok(())
+/// # }
+/// ```
+pub fn render_chat_template(
+    template: &str,
+    chat: &[LlamaChatMessage],
+    bos_token: &str,
+    eos_token: &str,
+    add_generation_prompt: bool,
+) -> Result<String, RenderChatTemplateError> {
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+    env.add_function(
+        "raise_exception",
+        |message: String| -> Result<Value, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, message))
+        },
+    );
+
+    let messages = chat
+        .iter()
+        .map(|message| {
+            Ok(context! {
+                role => message.role.to_str()?,
+                content => message.content.to_str()?,
+            })
+        })
+        .collect::<Result<Vec<_>, std::str::Utf8Error>>()?;
+    let rendered = env.template_from_str(template)?.render(context! {
+        messages => messages,
+        bos_token => bos_token,
+        eos_token => eos_token,
+        add_generation_prompt => add_generation_prompt,
+    })?;
+    Ok(rendered)
+}
+
+impl LlamaModel {
+    /// Render a Jinja chat template for `chat` with the model's special tokens, see
+    /// [`render_chat_template`]. `template` of `None` renders the model's own template, the
+    /// `tokenizer.chat_template` metadata.
+    ///
+    /// # Errors
+    ///
+    /// - the model has no template and none was given.
+    /// - see [`render_chat_template`].
+    pub fn render_chat_template(
+        &self,
+        template: Option<&str>,
+        chat: &[LlamaChatMessage],
+        add_generation_prompt: bool,
+    ) -> Result<String, RenderChatTemplateError> {
+        let model_template;
+        let template = if let Some(template) = template {
+            template
+        } else {
+            model_template = self
+                .meta_val_str("tokenizer.chat_template")
+                .ok_or(RenderChatTemplateError::MissingTemplate)?;
+            &model_template
+        };
+        render_chat_template(
+            template,
+            chat,
+            &self.special_token_text(self.token_bos()),
+            &self.special_token_text(self.token_eos()),
+            add_generation_prompt,
+        )
+    }
+
+    /// [`LlamaModel::apply_chat_template`], rendering the template with
+    /// [`LlamaModel::render_chat_template`] if llama.cpp does not support it.
+    ///
+    /// `tmpl` is a template name or Jinja source as for [`LlamaModel::apply_chat_template`].
+    /// Only Jinja sources can be rendered if llama.cpp does not know them.
+    ///
+    /// # Errors
+    ///
+    /// - llama.cpp failed to apply a template for another reason than not supporting it.
+    /// - see [`LlamaModel::render_chat_template`].
+    pub fn apply_chat_template_with_fallback(
+        &self,
+        tmpl: Option<&str>,
+        chat: &[LlamaChatMessage],
+        add_ass: bool,
+    ) -> Result<String, RenderChatTemplateError> {
+        let result = self.apply_chat_template(tmpl.map(str::to_string), chat.to_vec(), add_ass);
+        if let Err(ApplyChatTemplateError::UnsupportedTemplate(_)) = result {
+            self.render_chat_template(tmpl, chat, add_ass)
+        } else {
+            Ok(result?)
+        }
+    }
+
+    /// The text of a special token, empty if the model does not have it.
+    fn special_token_text(&self, token: LlamaToken) -> String {
+        if token.0 < 0 {
+            return String::new();
+        }
+        self.token_text(token)
+    }
+}
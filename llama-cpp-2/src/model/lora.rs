@@ -0,0 +1,298 @@
+//! Applying `LoRA` adapters to a model.
+//!
+//! This version of llama.cpp merges adapters into the weights of the model. Merging is additive, so
+//! several adapters can be stacked, and an adapter can be re-scaled by merging it again with the
+//! difference of the scales. [`LoraAdapters`] keeps track of the merged scales to do this for you.
+//!
+//! Adapters are merged into the weights as they are. For quantized models this loses some quality
+//! and every re-scale adds rounding error, so prefer f16 or f32 models when switching scales often.
+//!
+//! Changing the adapters of a model with contexts, e.g. to switch adapters between the requests of a
+//! server, is possible with the `unsafe` [`LlamaContext::set_lora`] and friends.
+
+use std::ffi::CString;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use crate::context::LlamaContext;
+use crate::model::LlamaModel;
+
+/// Failed to apply a `LoRA` adapter.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum LoraError {
+    /// The path is not valid unicode.
+    #[error("failed to convert path {0} to str")]
+    PathToStr(PathBuf),
+    /// The path contains a null byte.
+    #[error("null byte in path {0}")]
+    NulError(#[from] std::ffi::NulError),
+    /// llama.cpp failed to apply the adapter, e.g. because it does not match the model.
+    #[error("failed to apply the LoRA adapter (code {0})")]
+    ApplyFailed(i32),
+}
+
+impl LlamaModel {
+    /// Merge the `LoRA` adapter at `path` into the weights, scaled by `scale`.
+    ///
+    /// Applying an adapter again adds to the weights again, see [`LoraAdapters`] to change the
+    /// scale of an adapter instead.
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`]. The model may be partially modified if applying fails.
+    pub fn apply_lora_from_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        scale: f32,
+        n_threads: NonZeroU32,
+    ) -> Result<(), LoraError> {
+        // SAFETY: the model is borrowed mutably, so no context is using it.
+        unsafe { apply_lora(self, path.as_ref(), scale, n_threads) }
+    }
+}
+
+/// Merge the adapter at `path` into the weights of `model`.
+///
+/// # Safety
+///
+/// No context of `model` may be evaluating while the weights change.
+unsafe fn apply_lora(
+    model: &LlamaModel,
+    path: &Path,
+    scale: f32,
+    n_threads: NonZeroU32,
+) -> Result<(), LoraError> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| LoraError::PathToStr(path.to_path_buf()))?;
+    let path = CString::new(path)?;
+    let n_threads = i32::try_from(n_threads.get()).unwrap_or(i32::MAX);
+    let result = llama_cpp_sys_2::llama_model_apply_lora_from_file(
+        model.model.as_ptr(),
+        path.as_ptr(),
+        scale,
+        std::ptr::null(),
+        n_threads,
+    );
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(LoraError::ApplyFailed(result))
+    }
+}
+
+/// The `LoRA` adapters merged into a model and their scales.
+///
+/// Use one instance per model, and only change the model's adapters through it.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use std::num::NonZeroU32;
+/// # use llama_cpp_2::model::LlamaModel;
+/// use llama_cpp_2::model::lora::LoraAdapters;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+/// let mut model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+/// let mut adapters = LoraAdapters::new(NonZeroU32::new(8).unwrap());
+/// adapters.set(&mut model, "style.gguf", 1.0)?;
+/// adapters.set(&mut model, "domain.gguf", 0.5)?;
+/// // tone down the style without reloading the model
+/// adapters.set(&mut model, "style.gguf", 0.3)?;
+/// assert_eq!(adapters.scale("style.gguf"), 0.3);
+/// // and remove it entirely
+/// adapters.remove(&mut model, "style.gguf")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoraAdapters {
+    n_threads: NonZeroU32,
+    scales: Vec<(PathBuf, f32)>,
+}
+
+impl LoraAdapters {
+    /// Track the adapters of a model without any, applying adapters with `n_threads` threads.
+    #[must_use]
+    pub fn new(n_threads: NonZeroU32) -> Self {
+        Self {
+            n_threads,
+            scales: Vec::new(),
+        }
+    }
+
+    /// The scale the adapter at `path` is currently applied with, 0 if it is not applied.
+    #[must_use]
+    pub fn scale(&self, path: impl AsRef<Path>) -> f32 {
+        let path = path.as_ref();
+        self.scales
+            .iter()
+            .find(|(applied, _)| applied == path)
+            .map_or(0.0, |&(_, scale)| scale)
+    }
+
+    /// The applied adapters and their scales, in the order they were first applied.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, f32)> {
+        self.scales
+            .iter()
+            .map(|(path, scale)| (path.as_path(), *scale))
+    }
+
+    /// Apply the adapter at `path` to `model` with `scale`, replacing its current scale. Other
+    /// adapters keep theirs.
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`]. The tracked scale is unchanged if applying fails.
+    pub fn set(
+        &mut self,
+        model: &mut LlamaModel,
+        path: impl AsRef<Path>,
+        scale: f32,
+    ) -> Result<(), LoraError> {
+        // SAFETY: the model is borrowed mutably, so no context is using it.
+        unsafe { self.set_shared(model, path.as_ref(), scale) }
+    }
+
+    /// Remove the adapter at `path` from `model` by applying it with the negated scale.
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`].
+    pub fn remove(
+        &mut self,
+        model: &mut LlamaModel,
+        path: impl AsRef<Path>,
+    ) -> Result<(), LoraError> {
+        self.set(model, path, 0.0)
+    }
+
+    /// Remove all adapters from `model`.
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`]. Adapters removed before the failure stay removed.
+    pub fn clear(&mut self, model: &mut LlamaModel) -> Result<(), LoraError> {
+        // SAFETY: the model is borrowed mutably, so no context is using it.
+        unsafe { self.clear_shared(model) }
+    }
+
+    /// [`LoraAdapters::set`] for a model that may have contexts.
+    ///
+    /// # Safety
+    ///
+    /// No context of `model` may be evaluating while the weights change.
+    unsafe fn set_shared(
+        &mut self,
+        model: &LlamaModel,
+        path: &Path,
+        scale: f32,
+    ) -> Result<(), LoraError> {
+        let delta = scale - self.scale(path);
+        if delta != 0.0 {
+            apply_lora(model, path, delta, self.n_threads)?;
+        }
+        match self.scales.iter_mut().find(|(applied, _)| applied == path) {
+            Some((_, applied_scale)) => *applied_scale = scale,
+            None => self.scales.push((path.to_path_buf(), scale)),
+        }
+        self.scales.retain(|&(_, scale)| scale != 0.0);
+        Ok(())
+    }
+
+    /// [`LoraAdapters::clear`] for a model that may have contexts.
+    ///
+    /// # Safety
+    ///
+    /// No context of `model` may be evaluating while the weights change.
+    unsafe fn clear_shared(&mut self, model: &LlamaModel) -> Result<(), LoraError> {
+        while let Some((path, _)) = self.scales.first().cloned() {
+            self.set_shared(model, &path, 0.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl LlamaContext<'_> {
+    /// Switch the adapter at `path` of this context's model to `scale` without rebuilding the
+    /// context, e.g. between the requests of a server. A scale of 0 removes the adapter.
+    ///
+    /// The KV cache is cleared, as it was computed with the old weights.
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`]. The KV cache is cleared even if applying fails.
+    ///
+    /// # Safety
+    ///
+    /// The weights are shared by all contexts of the model, so no other context of it may be
+    /// evaluating (e.g. on another thread) while they change, and other contexts have to clear
+    /// their KV cache too.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::num::NonZeroU32;
+    /// # use llama_cpp_2::context::params::LlamaContextParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// use llama_cpp_2::model::lora::LoraAdapters;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut ctx = model.new_context(&backend, LlamaContextParams::default())?;
+    /// let mut adapters = LoraAdapters::new(NonZeroU32::new(8).unwrap());
+    /// for (adapter, prompt) in [("french.gguf", "Bonjour"), ("german.gguf", "Hallo")] {
+    ///     // SAFETY: this is the only context of the model.
+    ///     unsafe {
+    ///         ctx.clear_lora(&mut adapters)?;
+    ///         ctx.set_lora(&mut adapters, adapter, 1.0)?;
+    ///     }
+    ///     // ... answer `prompt` with `ctx`
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub unsafe fn set_lora(
+        &mut self,
+        adapters: &mut LoraAdapters,
+        path: impl AsRef<Path>,
+        scale: f32,
+    ) -> Result<(), LoraError> {
+        self.clear_kv_cache();
+        adapters.set_shared(self.model, path.as_ref(), scale)
+    }
+
+    /// Remove the adapter at `path` from this context's model, see [`LlamaContext::set_lora`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`].
+    ///
+    /// # Safety
+    ///
+    /// See [`LlamaContext::set_lora`].
+    pub unsafe fn remove_lora(
+        &mut self,
+        adapters: &mut LoraAdapters,
+        path: impl AsRef<Path>,
+    ) -> Result<(), LoraError> {
+        self.set_lora(adapters, path, 0.0)
+    }
+
+    /// Remove all adapters from this context's model, see [`LlamaContext::set_lora`].
+    ///
+    /// # Errors
+    ///
+    /// See [`LoraError`]. Adapters removed before the failure stay removed.
+    ///
+    /// # Safety
+    ///
+    /// See [`LlamaContext::set_lora`].
+    pub unsafe fn clear_lora(&mut self, adapters: &mut LoraAdapters) -> Result<(), LoraError> {
+        self.clear_kv_cache();
+        adapters.clear_shared(self.model)
+    }
+}
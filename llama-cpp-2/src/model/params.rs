@@ -1,29 +1,116 @@
 //! A safe wrapper around `llama_model_params`.
 
 use crate::model::params::kv_overrides::KvOverrides;
-use std::ffi::{c_char, CStr};
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr};
 use std::fmt::{Debug, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
 use std::ptr::null;
 
 pub mod kv_overrides;
 
+/// A rusty wrapper around `llama_split_mode`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LlamaSplitMode {
+    /// Use a single GPU.
+    None,
+    /// Split layers and the KV cache across GPUs.
+    Layer,
+    /// Split rows across GPUs.
+    Row,
+}
+
+/// Create a `LlamaSplitMode` from a `llama_split_mode` - returns `LlamaSplitMode::Layer` (the
+/// llama.cpp default) if the value is not recognized.
+impl From<llama_cpp_sys_2::llama_split_mode> for LlamaSplitMode {
+    fn from(value: llama_cpp_sys_2::llama_split_mode) -> Self {
+        match value {
+            llama_cpp_sys_2::LLAMA_SPLIT_MODE_NONE => Self::None,
+            llama_cpp_sys_2::LLAMA_SPLIT_MODE_ROW => Self::Row,
+            _ => Self::Layer,
+        }
+    }
+}
+
+/// Create a `llama_split_mode` from a `LlamaSplitMode`.
+impl From<LlamaSplitMode> for llama_cpp_sys_2::llama_split_mode {
+    fn from(value: LlamaSplitMode) -> Self {
+        match value {
+            LlamaSplitMode::None => llama_cpp_sys_2::LLAMA_SPLIT_MODE_NONE,
+            LlamaSplitMode::Layer => llama_cpp_sys_2::LLAMA_SPLIT_MODE_LAYER,
+            LlamaSplitMode::Row => llama_cpp_sys_2::LLAMA_SPLIT_MODE_ROW,
+        }
+    }
+}
+
 /// A safe wrapper around `llama_model_params`.
+///
+/// With the `serde` feature the values that have `with_*` methods and the key-value overrides
+/// can be (de)serialized, with missing fields keeping their default values. Override keys have to
+/// be ASCII and at most 127 bytes long.
+///
+/// # Examples
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # fn main() -> Result<(), serde_json::Error> {
+/// use llama_cpp_2::model::params::kv_overrides::ParamOverrideValue;
+/// use llama_cpp_2::model::params::{LlamaModelParams, LlamaSplitMode};
+///
+/// let params: LlamaModelParams = serde_json::from_str(
+///     r#"{
+///         "n_gpu_layers": 33,
+///         "split_mode": "row",
+///         "use_mmap": false,
+///         "kv_overrides": { "tokenizer.ggml.add_bos_token": false, "llama.context_length": 8192 }
+///     }"#,
+/// )?;
+/// assert_eq!(params.n_gpu_layers(), 33);
+/// assert_eq!(params.split_mode(), LlamaSplitMode::Row);
+/// assert!(!params.use_mmap());
+///
+/// let overrides = params.kv_overrides().into_iter().collect::<Vec<_>>();
+/// assert_eq!(overrides[0].0.to_bytes(), b"llama.context_length");
+/// assert_eq!(overrides[0].1, ParamOverrideValue::Int(8192));
+/// assert_eq!(overrides[1].1, ParamOverrideValue::Bool(false));
+///
+/// let json = serde_json::to_string(&params)?;
+/// let round_tripped: LlamaModelParams = serde_json::from_str(&json)?;
+/// assert_eq!(serde_json::to_string(&round_tripped)?, json);
+///
+/// // keys are stored as C strings of ASCII characters
+/// let non_ascii = serde_json::from_str::<LlamaModelParams>(r#"{ "kv_overrides": { "é": 1 } }"#);
+/// assert!(non_ascii.is_err());
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "serde"))]
+/// # fn main() {}
+/// ```
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaModelParams {
     pub(crate) params: llama_cpp_sys_2::llama_model_params,
     kv_overrides: Vec<llama_cpp_sys_2::llama_model_kv_override>,
+    /// Set with [`LlamaModelParams::with_progress_callback`].
+    progress_callback: Option<RefCell<ProgressCallback>>,
 }
 
+type ProgressCallback = Box<dyn FnMut(f32) -> bool>;
+
 impl Debug for LlamaModelParams {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("LlamaModelParams")
             .field("n_gpu_layers", &self.params.n_gpu_layers)
+            .field("split_mode", &self.params.split_mode)
             .field("main_gpu", &self.params.main_gpu)
             .field("vocab_only", &self.params.vocab_only)
             .field("use_mmap", &self.params.use_mmap)
             .field("use_mlock", &self.params.use_mlock)
             .field("kv_overrides", &"vec of kv_overrides")
+            .field("progress_callback", &self.progress_callback.is_some())
             .finish()
     }
 }
@@ -66,6 +153,27 @@ impl LlamaModelParams {
     ///
     /// assert_eq!(k.to_bytes(), b"key", "expected key to be 'key', was {:?}", k);
     /// ```
+    ///
+    /// Overrides are kept in the order they were appended:
+    ///
+    /// ```rust
+    /// # use std::ffi::CString;
+    /// use std::pin::pin;
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// # use llama_cpp_2::model::params::kv_overrides::ParamOverrideValue;
+    /// let mut params = pin!(LlamaModelParams::default());
+    /// let first = CString::new("first").expect("CString::new failed");
+    /// let second = CString::new("second").expect("CString::new failed");
+    /// params.as_mut().append_kv_override(&first, ParamOverrideValue::Int(1));
+    /// params.as_mut().append_kv_override(&second, ParamOverrideValue::Bool(true));
+    ///
+    /// let kv_overrides = params.kv_overrides().into_iter().collect::<Vec<_>>();
+    /// assert_eq!(kv_overrides.len(), 2);
+    /// assert_eq!(kv_overrides[0].0.to_bytes(), b"first");
+    /// assert_eq!(kv_overrides[0].1, ParamOverrideValue::Int(1));
+    /// assert_eq!(kv_overrides[1].0.to_bytes(), b"second");
+    /// assert_eq!(kv_overrides[1].1, ParamOverrideValue::Bool(true));
+    /// ```
     #[allow(clippy::missing_panics_doc)] // panics are just to enforce internal invariants, not user errors
     pub fn append_kv_override(
         mut self: Pin<&mut Self>,
@@ -74,7 +182,7 @@ impl LlamaModelParams {
     ) {
         let kv_override = self
             .kv_overrides
-            .get_mut(0)
+            .last_mut()
             .expect("kv_overrides did not have a next allocated");
 
         assert_eq!(kv_override.key[0], 0, "last kv_override was not empty");
@@ -102,8 +210,6 @@ impl LlamaModelParams {
 
         // set the pointer to the (potentially) new vector
         self.params.kv_overrides = self.kv_overrides.as_ptr();
-
-        eprintln!("saved ptr: {:?}", self.params.kv_overrides);
     }
 }
 
@@ -114,6 +220,12 @@ impl LlamaModelParams {
         self.params.n_gpu_layers
     }
 
+    /// How the model is split across GPUs.
+    #[must_use]
+    pub fn split_mode(&self) -> LlamaSplitMode {
+        LlamaSplitMode::from(self.params.split_mode)
+    }
+
     /// The GPU that is used for scratch and small tensors
     #[must_use]
     pub fn main_gpu(&self) -> i32 {
@@ -154,6 +266,19 @@ impl LlamaModelParams {
         self
     }
 
+    /// sets how the model is split across GPUs.
+    /// ```
+    /// # use llama_cpp_2::model::params::{LlamaModelParams, LlamaSplitMode};
+    /// let params = LlamaModelParams::default();
+    /// let params = params.with_split_mode(LlamaSplitMode::None);
+    /// assert_eq!(params.split_mode(), LlamaSplitMode::None);
+    /// ```
+    #[must_use]
+    pub fn with_split_mode(mut self, split_mode: LlamaSplitMode) -> Self {
+        self.params.split_mode = split_mode.into();
+        self
+    }
+
     /// sets the main GPU
     #[must_use]
     pub fn with_main_gpu(mut self, main_gpu: i32) -> Self {
@@ -168,22 +293,125 @@ impl LlamaModelParams {
         self
     }
 
+    /// sets `use_mmap`
+    #[must_use]
+    pub fn with_use_mmap(mut self, use_mmap: bool) -> Self {
+        self.params.use_mmap = use_mmap;
+        self
+    }
+
     /// sets `use_mlock`
     #[must_use]
     pub fn with_use_mlock(mut self, use_mlock: bool) -> Self {
         self.params.use_mlock = use_mlock;
         self
     }
+
+    /// Call `callback` with the progress of loading the model, from 0.0 to 1.0. Returning `false`
+    /// cancels loading, and [`LlamaModel::load_from_file`] fails with
+    /// [`LlamaModelLoadError::Cancelled`]. A panic in the callback cancels loading as well and is
+    /// resumed once llama.cpp has cleaned up.
+    ///
+    /// The callback is not (de)serialized with the `serde` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::sync::atomic::{AtomicBool, Ordering};
+    /// # use std::sync::Arc;
+    /// # use llama_cpp_2::model::params::LlamaModelParams;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::LlamaModelLoadError;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// let cancel = Arc::new(AtomicBool::new(false));
+    /// // e.g. set by a cancel button
+    /// let cancelled = Arc::clone(&cancel);
+    /// let params = LlamaModelParams::default().with_progress_callback(move |progress| {
+    ///     eprint!("\rloading {:.0}%", progress * 100.0);
+    ///     !cancelled.load(Ordering::Relaxed)
+    /// });
+    /// match LlamaModel::load_from_file(&backend, "path/to/model", &params) {
+    ///     Ok(model) => { /* ... */ }
+    ///     Err(LlamaModelLoadError::Cancelled) => eprintln!("\nloading cancelled"),
+    ///     Err(err) => return Err(err.into()),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`LlamaModel::load_from_file`]: crate::model::LlamaModel::load_from_file
+    /// [`LlamaModelLoadError::Cancelled`]: crate::LlamaModelLoadError::Cancelled
+    #[must_use]
+    pub fn with_progress_callback(mut self, callback: impl FnMut(f32) -> bool + 'static) -> Self {
+        self.progress_callback = Some(RefCell::new(Box::new(callback)));
+        self
+    }
+}
+
+/// The state of loading a model with a progress callback, see
+/// [`LlamaModelParams::with_progress_callback`].
+pub(crate) struct LoadProgress<'a> {
+    callback: &'a RefCell<ProgressCallback>,
+    cancelled: bool,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<'a> LoadProgress<'a> {
+    /// The state for `params`, `None` if they have no progress callback.
+    pub(crate) fn new(params: &'a LlamaModelParams) -> Option<Self> {
+        params.progress_callback.as_ref().map(|callback| Self {
+            callback,
+            cancelled: false,
+            panic: None,
+        })
+    }
+
+    /// Point the progress callback of `params` to this state, which must not move until the
+    /// model is loaded.
+    pub(crate) fn install(&mut self, params: &mut llama_cpp_sys_2::llama_model_params) {
+        params.progress_callback = Some(progress_callback);
+        params.progress_callback_user_data = std::ptr::from_mut(self).cast();
+    }
+
+    /// Whether the callback cancelled loading. Resumes a panic of the callback.
+    pub(crate) fn cancelled(self) -> bool {
+        if let Some(panic) = self.panic {
+            std::panic::resume_unwind(panic);
+        }
+        self.cancelled
+    }
+}
+
+/// Called by llama.cpp while loading, returning `false` to cancel.
+unsafe extern "C" fn progress_callback(progress: f32, user_data: *mut c_void) -> bool {
+    let state = &mut *user_data.cast::<LoadProgress>();
+    if state.cancelled || state.panic.is_some() {
+        return false;
+    }
+    // unwinding into llama.cpp would abort, so the panic is resumed after loading
+    match catch_unwind(AssertUnwindSafe(|| (state.callback.borrow_mut())(progress))) {
+        Ok(true) => true,
+        Ok(false) => {
+            state.cancelled = true;
+            false
+        }
+        Err(panic) => {
+            state.panic = Some(panic);
+            false
+        }
+    }
 }
 
 /// Default parameters for `LlamaModel`. (as defined in llama.cpp by `llama_model_default_params`)
 /// ```
-/// # use llama_cpp_2::model::params::LlamaModelParams;
+/// # use llama_cpp_2::model::params::{LlamaModelParams, LlamaSplitMode};
 /// let params = LlamaModelParams::default();
 /// #[cfg(not(target_os = "macos"))]
 /// assert_eq!(params.n_gpu_layers(), 0, "n_gpu_layers should be 0");
 /// #[cfg(target_os = "macos")]
 /// assert_eq!(params.n_gpu_layers(), 999, "n_gpu_layers should be 999");
+/// assert_eq!(params.split_mode(), LlamaSplitMode::Layer, "split_mode should be Layer");
 /// assert_eq!(params.main_gpu(), 0, "main_gpu should be 0");
 /// assert_eq!(params.vocab_only(), false, "vocab_only should be false");
 /// assert_eq!(params.use_mmap(), true, "use_mmap should be true");
@@ -201,6 +429,88 @@ impl Default for LlamaModelParams {
                     int_value: 0,
                 },
             }],
+            progress_callback: None,
+        }
+    }
+}
+
+/// The (de)serialized form of [`LlamaModelParams`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SerializedModelParams {
+    n_gpu_layers: u32,
+    split_mode: LlamaSplitMode,
+    main_gpu: i32,
+    vocab_only: bool,
+    use_mmap: bool,
+    use_mlock: bool,
+    kv_overrides: std::collections::BTreeMap<String, kv_overrides::ParamOverrideValue>,
+}
+
+#[cfg(feature = "serde")]
+impl Default for SerializedModelParams {
+    fn default() -> Self {
+        Self::from(&LlamaModelParams::default())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<&LlamaModelParams> for SerializedModelParams {
+    fn from(params: &LlamaModelParams) -> Self {
+        Self {
+            n_gpu_layers: u32::try_from(params.n_gpu_layers()).unwrap_or(0),
+            split_mode: params.split_mode(),
+            main_gpu: params.main_gpu(),
+            vocab_only: params.vocab_only(),
+            use_mmap: params.use_mmap(),
+            use_mlock: params.use_mlock(),
+            kv_overrides: params
+                .kv_overrides()
+                .into_iter()
+                .map(|(key, value)| (key.to_string_lossy().into_owned(), value))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for LlamaModelParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedModelParams::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for LlamaModelParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let serialized = SerializedModelParams::deserialize(deserializer)?;
+        let mut params = Self::default()
+            .with_n_gpu_layers(serialized.n_gpu_layers)
+            .with_split_mode(serialized.split_mode)
+            .with_main_gpu(serialized.main_gpu)
+            .with_vocab_only(serialized.vocab_only)
+            .with_use_mmap(serialized.use_mmap)
+            .with_use_mlock(serialized.use_mlock);
+        for (key, value) in serialized.kv_overrides {
+            // `append_kv_override` stores the key as `c_char`, which is signed on most platforms
+            if !key.is_ascii() {
+                return Err(D::Error::custom(format!(
+                    "kv override key {key:?} is not ASCII"
+                )));
+            }
+            let key = std::ffi::CString::new(key).map_err(D::Error::custom)?;
+            // the key is copied into a fixed size buffer, including the nul terminator
+            if key.as_bytes_with_nul().len() > 128 {
+                return Err(D::Error::custom(format!(
+                    "kv override key {key:?} is longer than 127 bytes"
+                )));
+            }
+            // the overrides live on the heap, so moving the params afterwards is fine
+            Pin::new(&mut params).append_kv_override(&key, value);
         }
+        Ok(params)
     }
 }
@@ -5,6 +5,8 @@ use std::ffi::{CStr, CString};
 use std::fmt::Debug;
 
 /// An override value for a model parameter.
+///
+/// With the `serde` feature this is (de)serialized as a plain boolean, float or integer.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ParamOverrideValue {
     /// A string value
@@ -41,6 +43,52 @@ impl ParamOverrideValue {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for ParamOverrideValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            ParamOverrideValue::Bool(value) => serializer.serialize_bool(value),
+            ParamOverrideValue::Float(value) => serializer.serialize_f64(value),
+            ParamOverrideValue::Int(value) => serializer.serialize_i64(value),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ParamOverrideValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = ParamOverrideValue;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a boolean, float or integer")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, value: bool) -> Result<Self::Value, E> {
+                Ok(ParamOverrideValue::Bool(value))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, value: f64) -> Result<Self::Value, E> {
+                Ok(ParamOverrideValue::Float(value))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(ParamOverrideValue::Int(value))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                i64::try_from(value)
+                    .map(ParamOverrideValue::Int)
+                    .map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
 impl From<&llama_cpp_sys_2::llama_model_kv_override> for ParamOverrideValue {
     fn from(
         llama_cpp_sys_2::llama_model_kv_override {
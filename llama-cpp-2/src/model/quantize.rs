@@ -0,0 +1,324 @@
+//! Quantizing model files, e.g. to ship one F16 GGUF and quantize it for the user's hardware.
+//!
+//! Importance matrices are not supported: the vendored llama.cpp takes them as a C++ map, so the
+//! low-bit types that need one can not be quantized to, see [`LlamaFtype::requires_imatrix`].
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::model::quantize::{quantize, LlamaFtype, QuantizeParams};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! let params = QuantizeParams::new(LlamaFtype::MostlyQ4KM);
+//! quantize(&backend, "model-f16.gguf", "model-q4_k_m.gguf", &params)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::CString;
+use std::fmt::{Debug, Formatter};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+use crate::llama_backend::LlamaBackend;
+
+/// The type a model is quantized to, a rusty wrapper around `llama_ftype`.
+///
+/// The names follow the `quantize` tool of llama.cpp, e.g. [`LlamaFtype::MostlyQ4KM`] is
+/// `Q4_K_M`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LlamaFtype {
+    /// All tensors in `F32`.
+    AllF32,
+    /// `F16`.
+    MostlyF16,
+    /// `Q4_0`.
+    MostlyQ4_0,
+    /// `Q4_1`.
+    MostlyQ4_1,
+    /// `Q8_0`.
+    MostlyQ8_0,
+    /// `Q5_0`.
+    MostlyQ5_0,
+    /// `Q5_1`.
+    MostlyQ5_1,
+    /// `Q2_K`.
+    MostlyQ2K,
+    /// `Q2_K_S`.
+    MostlyQ2KS,
+    /// `Q3_K_S`.
+    MostlyQ3KS,
+    /// `Q3_K_M`.
+    MostlyQ3KM,
+    /// `Q3_K_L`.
+    MostlyQ3KL,
+    /// `Q4_K_S`.
+    MostlyQ4KS,
+    /// `Q4_K_M`.
+    MostlyQ4KM,
+    /// `Q5_K_S`.
+    MostlyQ5KS,
+    /// `Q5_K_M`.
+    MostlyQ5KM,
+    /// `Q6_K`.
+    MostlyQ6K,
+    /// `IQ1_S`.
+    MostlyIq1S,
+    /// `IQ2_XXS`.
+    MostlyIq2Xxs,
+    /// `IQ2_XS`.
+    MostlyIq2Xs,
+    /// `IQ2_S`.
+    MostlyIq2S,
+    /// `IQ2_M`.
+    MostlyIq2M,
+    /// `IQ3_XXS`.
+    MostlyIq3Xxs,
+    /// `IQ3_XS`.
+    MostlyIq3Xs,
+    /// `IQ3_S`.
+    MostlyIq3S,
+    /// `IQ3_M`.
+    MostlyIq3M,
+    /// `IQ4_NL`.
+    MostlyIq4Nl,
+    /// `IQ4_XS`.
+    MostlyIq4Xs,
+}
+
+impl LlamaFtype {
+    /// Whether quantizing to this type needs an importance matrix, which this version of the
+    /// bindings can not pass to llama.cpp.
+    ///
+    /// These are the types llama.cpp refuses to quantize to without one: the `IQ1` and `IQ2`
+    /// types and `Q2_K_S`.
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::quantize::LlamaFtype;
+    /// assert!(LlamaFtype::MostlyIq2Xxs.requires_imatrix());
+    /// assert!(LlamaFtype::MostlyQ2KS.requires_imatrix());
+    /// assert!(!LlamaFtype::MostlyQ2K.requires_imatrix());
+    /// assert!(!LlamaFtype::MostlyQ4KM.requires_imatrix());
+    /// ```
+    #[must_use]
+    pub fn requires_imatrix(self) -> bool {
+        matches!(
+            self,
+            Self::MostlyIq1S
+                | Self::MostlyIq2Xxs
+                | Self::MostlyIq2Xs
+                | Self::MostlyIq2S
+                | Self::MostlyIq2M
+                | Self::MostlyQ2KS
+        )
+    }
+}
+
+impl From<LlamaFtype> for llama_cpp_sys_2::llama_ftype {
+    fn from(value: LlamaFtype) -> Self {
+        use llama_cpp_sys_2 as sys;
+        match value {
+            LlamaFtype::AllF32 => sys::LLAMA_FTYPE_ALL_F32,
+            LlamaFtype::MostlyF16 => sys::LLAMA_FTYPE_MOSTLY_F16,
+            LlamaFtype::MostlyQ4_0 => sys::LLAMA_FTYPE_MOSTLY_Q4_0,
+            LlamaFtype::MostlyQ4_1 => sys::LLAMA_FTYPE_MOSTLY_Q4_1,
+            LlamaFtype::MostlyQ8_0 => sys::LLAMA_FTYPE_MOSTLY_Q8_0,
+            LlamaFtype::MostlyQ5_0 => sys::LLAMA_FTYPE_MOSTLY_Q5_0,
+            LlamaFtype::MostlyQ5_1 => sys::LLAMA_FTYPE_MOSTLY_Q5_1,
+            LlamaFtype::MostlyQ2K => sys::LLAMA_FTYPE_MOSTLY_Q2_K,
+            LlamaFtype::MostlyQ2KS => sys::LLAMA_FTYPE_MOSTLY_Q2_K_S,
+            LlamaFtype::MostlyQ3KS => sys::LLAMA_FTYPE_MOSTLY_Q3_K_S,
+            LlamaFtype::MostlyQ3KM => sys::LLAMA_FTYPE_MOSTLY_Q3_K_M,
+            LlamaFtype::MostlyQ3KL => sys::LLAMA_FTYPE_MOSTLY_Q3_K_L,
+            LlamaFtype::MostlyQ4KS => sys::LLAMA_FTYPE_MOSTLY_Q4_K_S,
+            LlamaFtype::MostlyQ4KM => sys::LLAMA_FTYPE_MOSTLY_Q4_K_M,
+            LlamaFtype::MostlyQ5KS => sys::LLAMA_FTYPE_MOSTLY_Q5_K_S,
+            LlamaFtype::MostlyQ5KM => sys::LLAMA_FTYPE_MOSTLY_Q5_K_M,
+            LlamaFtype::MostlyQ6K => sys::LLAMA_FTYPE_MOSTLY_Q6_K,
+            LlamaFtype::MostlyIq1S => sys::LLAMA_FTYPE_MOSTLY_IQ1_S,
+            LlamaFtype::MostlyIq2Xxs => sys::LLAMA_FTYPE_MOSTLY_IQ2_XXS,
+            LlamaFtype::MostlyIq2Xs => sys::LLAMA_FTYPE_MOSTLY_IQ2_XS,
+            LlamaFtype::MostlyIq2S => sys::LLAMA_FTYPE_MOSTLY_IQ2_S,
+            LlamaFtype::MostlyIq2M => sys::LLAMA_FTYPE_MOSTLY_IQ2_M,
+            LlamaFtype::MostlyIq3Xxs => sys::LLAMA_FTYPE_MOSTLY_IQ3_XXS,
+            LlamaFtype::MostlyIq3Xs => sys::LLAMA_FTYPE_MOSTLY_IQ3_XS,
+            LlamaFtype::MostlyIq3S => sys::LLAMA_FTYPE_MOSTLY_IQ3_S,
+            LlamaFtype::MostlyIq3M => sys::LLAMA_FTYPE_MOSTLY_IQ3_M,
+            LlamaFtype::MostlyIq4Nl => sys::LLAMA_FTYPE_MOSTLY_IQ4_NL,
+            LlamaFtype::MostlyIq4Xs => sys::LLAMA_FTYPE_MOSTLY_IQ4_XS,
+        }
+    }
+}
+
+/// Failed to quantize a model.
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum QuantizeError {
+    /// The path is not valid unicode.
+    #[error("failed to convert path {0} to str")]
+    PathToStr(PathBuf),
+    /// The path contains a null byte.
+    #[error("null byte in path {0}")]
+    NulError(#[from] std::ffi::NulError),
+    /// The type needs an importance matrix, see [`LlamaFtype::requires_imatrix`].
+    #[error("quantizing to {0:?} requires an importance matrix")]
+    RequiresImatrix(LlamaFtype),
+    /// llama.cpp failed to quantize the model, e.g. because the input could not be read or the
+    /// output could not be written. The details are logged by llama.cpp.
+    #[error("failed to quantize the model")]
+    Failed,
+}
+
+/// A safe wrapper around `llama_model_quantize_params`.
+///
+/// The vendored llama.cpp takes the importance matrix as a pointer to a C++ `std::unordered_map`,
+/// which can not be built from Rust, so there is no way to set one and types that need it are
+/// rejected, see [`LlamaFtype::requires_imatrix`]. The output and token embedding tensors use the
+/// types llama.cpp picks for the [`LlamaFtype`].
+#[derive(Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct QuantizeParams {
+    ftype: LlamaFtype,
+    params: llama_cpp_sys_2::llama_model_quantize_params,
+}
+
+impl Debug for QuantizeParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuantizeParams")
+            .field("ftype", &self.ftype)
+            .field("n_threads", &self.params.nthread)
+            .field("allow_requantize", &self.params.allow_requantize)
+            .field(
+                "quantize_output_tensor",
+                &self.params.quantize_output_tensor,
+            )
+            .field("only_copy", &self.params.only_copy)
+            .field("pure", &self.params.pure)
+            .finish()
+    }
+}
+
+// SAFETY: the imatrix pointer is always null, the rest is plain data.
+unsafe impl Send for QuantizeParams {}
+unsafe impl Sync for QuantizeParams {}
+
+impl QuantizeParams {
+    /// Quantize to `ftype` with llama.cpp's defaults for everything else.
+    #[must_use]
+    pub fn new(ftype: LlamaFtype) -> Self {
+        let mut params = unsafe { llama_cpp_sys_2::llama_model_quantize_default_params() };
+        params.ftype = ftype.into();
+        params.imatrix = std::ptr::null_mut();
+        Self { ftype, params }
+    }
+
+    /// The type the model is quantized to.
+    #[must_use]
+    pub fn ftype(&self) -> LlamaFtype {
+        self.ftype
+    }
+
+    /// Set the number of threads, [`None`] to use all hardware threads.
+    #[must_use]
+    pub fn with_n_threads(mut self, n_threads: Option<NonZeroU32>) -> Self {
+        self.params.nthread = n_threads.map_or(0, |n| i32::try_from(n.get()).unwrap_or(i32::MAX));
+        self
+    }
+
+    /// The number of threads, [`None`] for all hardware threads.
+    #[must_use]
+    pub fn n_threads(&self) -> Option<NonZeroU32> {
+        u32::try_from(self.params.nthread)
+            .ok()
+            .and_then(NonZeroU32::new)
+    }
+
+    /// Allow quantizing tensors that are already quantized, which loses more quality than
+    /// quantizing from F16 or F32.
+    #[must_use]
+    pub fn with_allow_requantize(mut self, allow_requantize: bool) -> Self {
+        self.params.allow_requantize = allow_requantize;
+        self
+    }
+
+    /// Quantize the output tensor too, on by default.
+    #[must_use]
+    pub fn with_quantize_output_tensor(mut self, quantize_output_tensor: bool) -> Self {
+        self.params.quantize_output_tensor = quantize_output_tensor;
+        self
+    }
+
+    /// Only copy the tensors, ignoring the type, e.g. to rewrite the metadata of a file.
+    #[must_use]
+    pub fn with_only_copy(mut self, only_copy: bool) -> Self {
+        self.params.only_copy = only_copy;
+        self
+    }
+
+    /// Quantize every tensor to the type instead of using higher precision types for the tensors
+    /// that are most sensitive to quantization, as the `_S`, `_M` and `_L` variants do.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use std::num::NonZeroU32;
+    /// use llama_cpp_2::model::quantize::{LlamaFtype, QuantizeParams};
+    /// let params = QuantizeParams::new(LlamaFtype::MostlyQ5KS)
+    ///     .with_n_threads(NonZeroU32::new(4))
+    ///     .with_pure(true);
+    /// assert_eq!(params.ftype(), LlamaFtype::MostlyQ5KS);
+    /// assert_eq!(params.n_threads(), NonZeroU32::new(4));
+    /// assert!(params.pure());
+    /// ```
+    #[must_use]
+    pub fn with_pure(mut self, pure: bool) -> Self {
+        self.params.pure = pure;
+        self
+    }
+
+    /// Whether every tensor is quantized to the type.
+    #[must_use]
+    pub fn pure(&self) -> bool {
+        self.params.pure
+    }
+}
+
+/// Quantize the GGUF model at `src` according to `params` and write it to `dst`.
+///
+/// This reads and writes the whole model and can take minutes for large models.
+///
+/// # Errors
+///
+/// See [`QuantizeError`].
+pub fn quantize(
+    _: &LlamaBackend,
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    params: &QuantizeParams,
+) -> Result<(), QuantizeError> {
+    if params.ftype.requires_imatrix() && !params.params.only_copy {
+        return Err(QuantizeError::RequiresImatrix(params.ftype));
+    }
+    let path_to_cstring = |path: &Path| -> Result<CString, QuantizeError> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| QuantizeError::PathToStr(path.to_path_buf()))?;
+        Ok(CString::new(path)?)
+    };
+    let src = path_to_cstring(src.as_ref())?;
+    let dst = path_to_cstring(dst.as_ref())?;
+    let result = unsafe {
+        llama_cpp_sys_2::llama_model_quantize(
+            src.as_ptr(),
+            dst.as_ptr(),
+            std::ptr::from_ref(&params.params),
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(QuantizeError::Failed)
+    }
+}
@@ -0,0 +1,156 @@
+//! Information about the weight tensors of a loaded model, e.g. to show how much memory each layer
+//! uses and on which device it is.
+//!
+//! With the `serde` feature [`LlamaTensorInfo`] can be serialized directly.
+
+use std::ffi::{CStr, CString};
+use std::path::Path;
+
+use crate::model::LlamaModel;
+use crate::LlamaModelLoadError;
+
+/// A weight tensor of a loaded model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::module_name_repetitions)]
+pub struct LlamaTensorInfo {
+    /// The name of the tensor in the GGUF file, e.g. `blk.0.attn_q.weight`.
+    pub name: String,
+    /// The number of elements in each dimension, innermost first.
+    pub shape: Vec<i64>,
+    /// The name of the ggml type of the elements, e.g. `q4_K` or `f32`.
+    pub ggml_type: String,
+    /// The size of the tensor's data in bytes.
+    pub n_bytes: usize,
+    /// The name of the backend buffer holding the data (such as `CPU` or `CUDA0`), `None` if it
+    /// has not been allocated in one.
+    pub buffer: Option<String>,
+}
+
+impl LlamaTensorInfo {
+    /// The number of dimensions of the tensor.
+    #[must_use]
+    pub fn n_dims(&self) -> usize {
+        self.shape.len()
+    }
+
+    /// The number of elements of the tensor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::model::tensor::LlamaTensorInfo;
+    /// let info = LlamaTensorInfo {
+    ///     name: "blk.0.attn_q.weight".to_string(),
+    ///     shape: vec![4096, 4096],
+    ///     ggml_type: "q4_0".to_string(),
+    ///     n_bytes: 9_437_184,
+    ///     buffer: Some("CPU".to_string()),
+    /// };
+    /// assert_eq!(info.n_dims(), 2);
+    /// assert_eq!(info.n_elements(), 16_777_216);
+    /// ```
+    #[must_use]
+    pub fn n_elements(&self) -> i64 {
+        self.shape.iter().product()
+    }
+}
+
+impl LlamaModel {
+    /// Get the tensor called `name`, `None` if the model has no such tensor.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let embeddings = model.tensor("token_embd.weight").expect("model has token embeddings");
+    /// assert_eq!(embeddings.shape[1], i64::from(model.n_vocab()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn tensor(&self, name: &str) -> Option<LlamaTensorInfo> {
+        let c_name = CString::new(name).ok()?;
+        let tensor = unsafe {
+            llama_cpp_sys_2::llama_get_model_tensor(self.model.as_ptr(), c_name.as_ptr())
+        };
+        let tensor = unsafe { tensor.as_ref() }?;
+        let n_dims = unsafe { llama_cpp_sys_2::ggml_n_dims(tensor) };
+        let n_dims = usize::try_from(n_dims).unwrap_or(0);
+        let ggml_type = unsafe { CStr::from_ptr(llama_cpp_sys_2::ggml_type_name(tensor.type_)) };
+        let buffer = (!tensor.buffer.is_null()).then(|| {
+            let buffer =
+                unsafe { CStr::from_ptr(llama_cpp_sys_2::ggml_backend_buffer_name(tensor.buffer)) };
+            buffer.to_string_lossy().into_owned()
+        });
+        Some(LlamaTensorInfo {
+            name: name.to_string(),
+            shape: tensor.ne[..n_dims].to_vec(),
+            ggml_type: ggml_type.to_string_lossy().into_owned(),
+            n_bytes: unsafe { llama_cpp_sys_2::ggml_nbytes(tensor) },
+            buffer,
+        })
+    }
+
+    /// Get every tensor of the model, in the order of the GGUF file at `path`.
+    ///
+    /// llama.cpp does not keep the list of tensor names after loading, so they are read from the
+    /// header of the file the model was loaded from. For models split into several files only the
+    /// tensors of the given file are returned.
+    ///
+    /// # Errors
+    ///
+    /// If the path is not valid unicode, contains a null byte or is not a GGUF file.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::collections::BTreeMap;
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// let path = "path/to/model";
+    /// let model = LlamaModel::load_from_file(&backend, path, &Default::default())?;
+    /// let mut per_layer = BTreeMap::<String, usize>::new();
+    /// for tensor in model.tensors(path)? {
+    ///     let layer = tensor.name.split('.').take(2).collect::<Vec<_>>().join(".");
+    ///     *per_layer.entry(layer).or_default() += tensor.n_bytes;
+    /// }
+    /// for (layer, n_bytes) in per_layer {
+    ///     println!("{layer}: {:.1} MiB", n_bytes as f64 / 1024.0 / 1024.0);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn tensors(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<LlamaTensorInfo>, LlamaModelLoadError> {
+        let path = path.as_ref();
+        let path = path
+            .to_str()
+            .ok_or(LlamaModelLoadError::PathToStrError(path.to_path_buf()))?;
+        let path = CString::new(path)?;
+        let params = llama_cpp_sys_2::gguf_init_params {
+            no_alloc: true,
+            ctx: std::ptr::null_mut(),
+        };
+        let gguf = unsafe { llama_cpp_sys_2::gguf_init_from_file(path.as_ptr(), params) };
+        if gguf.is_null() {
+            return Err(LlamaModelLoadError::NullResult);
+        }
+        let n_tensors = unsafe { llama_cpp_sys_2::gguf_get_n_tensors(gguf) };
+        let names = (0..n_tensors)
+            .map(|i| {
+                let name =
+                    unsafe { CStr::from_ptr(llama_cpp_sys_2::gguf_get_tensor_name(gguf, i)) };
+                name.to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>();
+        unsafe { llama_cpp_sys_2::gguf_free(gguf) };
+        Ok(names.iter().filter_map(|name| self.tensor(name)).collect())
+    }
+}
@@ -0,0 +1,69 @@
+//! Exporting the vocabulary of a model, e.g. to analyze or diff tokenizers.
+//!
+//! With the `serde` feature [`VocabEntry`] can be serialized directly.
+
+use std::ffi::CStr;
+
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+use crate::token_type::LlamaTokenType;
+
+/// A token of the vocabulary of a model.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::module_name_repetitions)]
+pub struct VocabEntry {
+    /// The token.
+    pub token: LlamaToken,
+    /// The text of the token as stored in the vocabulary (e.g. `▁the` or `<0x0A>` for
+    /// sentencepiece models), see [`LlamaModel::token_to_piece_raw`] for the bytes it decodes to.
+    pub text: String,
+    /// The score of the token, used by sentencepiece tokenizers to choose between merges.
+    pub score: f32,
+    /// The type of the token, `None` if it is not known to this library.
+    pub token_type: Option<LlamaTokenType>,
+}
+
+impl LlamaModel {
+    /// Get every token of the vocabulary with its text, score and type.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let vocab = model.export_vocab();
+    /// assert_eq!(vocab.len(), model.n_vocab() as usize);
+    /// # #[cfg(feature = "serde")]
+    /// std::fs::write("vocab.json", serde_json::to_string_pretty(&vocab)?)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn export_vocab(&self) -> Vec<VocabEntry> {
+        (0..self.n_vocab())
+            .map(LlamaToken::new)
+            .map(|token| VocabEntry {
+                token,
+                text: self.token_text(token),
+                score: unsafe {
+                    llama_cpp_sys_2::llama_token_get_score(self.model.as_ptr(), token.0)
+                },
+                token_type: self.try_token_type(token).ok(),
+            })
+            .collect()
+    }
+
+    /// The text of `token` as stored in the vocabulary.
+    pub(super) fn token_text(&self, token: LlamaToken) -> String {
+        let text = unsafe { llama_cpp_sys_2::llama_token_get_text(self.model.as_ptr(), token.0) };
+        if text.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(text) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
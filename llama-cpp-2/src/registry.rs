@@ -0,0 +1,285 @@
+//! A small on-disk registry of model files, so applications can list, check and clean up the models
+//! they downloaded.
+//!
+//! The registry is a directory with an `index.json` describing each registered file: its path,
+//! size, SHA-256 hash, the string metadata from its GGUF header and when it was added and last
+//! used. Model files can live inside the registry directory (see [`ModelRegistry::dir`]) or anywhere
+//! else, e.g. in the Hugging Face cache.
+//!
+//! # Examples
+//!
+//! ```
+//! use llama_cpp_2::registry::ModelRegistry;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = std::env::temp_dir().join("llama-cpp-2-registry-doctest");
+//! # let _ = std::fs::remove_dir_all(&dir);
+//! let mut registry = ModelRegistry::open(&dir)?;
+//! let path = registry.dir().join("tiny.gguf");
+//! std::fs::write(&path, b"not really a model")?;
+//!
+//! let entry = registry.register("tiny", &path)?;
+//! assert_eq!(entry.size, 18);
+//! assert!(registry.verify("tiny")?);
+//!
+//! // remove models that were not used in the last 30 days
+//! let month_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(30 * 24 * 3600);
+//! let removed = registry.gc(|entry| entry.last_used() > month_ago)?;
+//! assert!(removed.is_empty());
+//!
+//! // the index is saved after every change
+//! let registry = ModelRegistry::open(&dir)?;
+//! assert_eq!(registry.entries().map(|entry| &entry.name).collect::<Vec<_>>(), ["tiny"]);
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::model::integrity::{hash_file, GgufHeader};
+
+/// The name of the index file in the registry directory.
+const INDEX_FILE: &str = "index.json";
+
+/// An error from [`ModelRegistry`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum RegistryError {
+    /// Reading or writing a file failed.
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The index file is not valid.
+    #[error("invalid registry index: {0}")]
+    Index(#[from] serde_json::Error),
+    /// No model is registered under the name.
+    #[error("no model named {0} is registered")]
+    UnknownModel(String),
+}
+
+/// A model file known to a [`ModelRegistry`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ModelEntry {
+    /// The name the model was registered under.
+    pub name: String,
+    /// The path of the model file.
+    pub path: PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The SHA-256 hash of the file as lowercase hex, the same as the blob names in the Hugging
+    /// Face cache.
+    pub sha256: String,
+    /// The metadata of the GGUF header with string values, such as `general.architecture` and
+    /// `general.name`. Empty if the file is not a GGUF file.
+    pub metadata: BTreeMap<String, String>,
+    /// When the model was registered, in seconds since the unix epoch.
+    pub added_at: u64,
+    /// When the model was last used (see [`ModelRegistry::touch`]), in seconds since the unix
+    /// epoch.
+    pub last_used_at: u64,
+}
+
+impl ModelEntry {
+    /// When the model was registered.
+    #[must_use]
+    pub fn added(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.added_at)
+    }
+
+    /// When the model was last used.
+    #[must_use]
+    pub fn last_used(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_used_at)
+    }
+}
+
+/// A directory tracking model files, see the [module docs](crate::registry).
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ModelRegistry {
+    dir: PathBuf,
+    entries: BTreeMap<String, ModelEntry>,
+}
+
+impl ModelRegistry {
+    /// Open the registry in `dir`, creating the directory if needed.
+    ///
+    /// # Errors
+    ///
+    /// If the directory can not be created or the index can not be read.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, RegistryError> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let entries = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(index) => serde_json::from_slice(&index)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self { dir, entries })
+    }
+
+    /// The registry directory, a good place to download models to.
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The registered models, ordered by name.
+    pub fn entries(&self) -> impl Iterator<Item = &ModelEntry> {
+        self.entries.values()
+    }
+
+    /// Get the model registered as `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&ModelEntry> {
+        self.entries.get(name)
+    }
+
+    /// Register the file at `path` as `name`, replacing any model registered under that name.
+    ///
+    /// This reads the whole file to hash it.
+    ///
+    /// # Errors
+    ///
+    /// If the file can not be read or the index can not be saved.
+    pub fn register(
+        &mut self,
+        name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<&ModelEntry, RegistryError> {
+        let path = path.as_ref();
+        let (size, sha256) = hash_file(path)?;
+        let now = now();
+        let entry = ModelEntry {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            size,
+            sha256,
+            metadata: read_metadata(path),
+            added_at: now,
+            last_used_at: now,
+        };
+        self.entries.insert(name.to_string(), entry);
+        self.save()?;
+        Ok(&self.entries[name])
+    }
+
+    /// Record that the model registered as `name` was used now, e.g. when loading it.
+    ///
+    /// # Errors
+    ///
+    /// If no such model is registered or the index can not be saved.
+    pub fn touch(&mut self, name: &str) -> Result<(), RegistryError> {
+        let entry = self
+            .entries
+            .get_mut(name)
+            .ok_or_else(|| RegistryError::UnknownModel(name.to_string()))?;
+        entry.last_used_at = now();
+        self.save()
+    }
+
+    /// Check that the file of the model registered as `name` still has the size and hash it was
+    /// registered with. A missing file is reported as `false`.
+    ///
+    /// # Errors
+    ///
+    /// If no such model is registered or the file can not be read.
+    pub fn verify(&self, name: &str) -> Result<bool, RegistryError> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownModel(name.to_string()))?;
+        match std::fs::metadata(&entry.path) {
+            Ok(metadata) if metadata.len() != entry.size => return Ok(false),
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        let (_, sha256) = hash_file(&entry.path)?;
+        Ok(sha256 == entry.sha256)
+    }
+
+    /// Unregister the model registered as `name`, deleting its file if `delete_file` is set.
+    ///
+    /// # Errors
+    ///
+    /// If the file can not be deleted or the index can not be saved.
+    pub fn remove(
+        &mut self,
+        name: &str,
+        delete_file: bool,
+    ) -> Result<Option<ModelEntry>, RegistryError> {
+        let Some(entry) = self.entries.remove(name) else {
+            return Ok(None);
+        };
+        if delete_file {
+            remove_file(&entry.path)?;
+        }
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    /// Delete the files of all models for which `keep` returns `false` and unregister them, along
+    /// with models whose files no longer exist. Returns the removed entries.
+    ///
+    /// # Errors
+    ///
+    /// If a file can not be deleted or the index can not be saved. Models removed before the
+    /// failure stay removed.
+    pub fn gc(
+        &mut self,
+        mut keep: impl FnMut(&ModelEntry) -> bool,
+    ) -> Result<Vec<ModelEntry>, RegistryError> {
+        let (kept, mut stale): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|(_, entry)| entry.path.exists() && keep(entry));
+        self.entries = kept;
+        let mut removed = Vec::with_capacity(stale.len());
+        while let Some((name, entry)) = stale.pop_first() {
+            if let Err(err) = remove_file(&entry.path) {
+                stale.insert(name, entry);
+                self.entries.append(&mut stale);
+                self.save()?;
+                return Err(err.into());
+            }
+            removed.push(entry);
+        }
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Write the index, replacing the old one atomically.
+    fn save(&self) -> Result<(), RegistryError> {
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.entries)?)?;
+        std::fs::rename(tmp, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+}
+
+/// The string metadata of the GGUF file at `path`, empty if it is not one.
+fn read_metadata(path: &Path) -> BTreeMap<String, String> {
+    let header = path
+        .to_str()
+        .and_then(|path| CString::new(path).ok())
+        .and_then(|path| GgufHeader::read(&path));
+    header.map_or_else(BTreeMap::new, |header| {
+        header.string_metadata().into_iter().collect()
+    })
+}
+
+/// Delete the file at `path`, which is fine if it is already gone.
+fn remove_file(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
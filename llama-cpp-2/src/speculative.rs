@@ -0,0 +1,811 @@
+//! Verification of drafted tokens for speculative decoding.
+//!
+//! In speculative decoding a cheap draft model proposes a few tokens which the target model then
+//! evaluates in a single batch. [`SpeculativeVerifier::verify`] decides how many of the drafted
+//! tokens to keep and which token to continue with, according to an [`AcceptanceRule`].
+//!
+//! The verifier also keeps [`SpeculativeStats`] of the drafted and accepted tokens, to tune the
+//! draft length and to check whether the draft model is worth it.
+//!
+//! All distributions are the softmax of the candidates' logits, so apply temperature and any other
+//! logit processing to the candidates before verifying.
+//!
+//! [`SpeculativeGenerator`] runs the whole loop with a target and a draft context: it drafts
+//! [`SpeculativeParams::with_n_draft`] tokens with the draft model, evaluates them in one batch of
+//! the target model, verifies them and rolls both KV caches back to the accepted tokens.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::speculative::{SpeculativeGenerator, SpeculativeParams};
+//! # use llama_cpp_2::context::params::LlamaContextParams;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! let target = LlamaModel::load_from_file(&backend, "path/to/llama-70b.gguf", &Default::default())?;
+//! let draft = LlamaModel::load_from_file(&backend, "path/to/llama-1b.gguf", &Default::default())?;
+//! let mut target_ctx = target.new_context(&backend, LlamaContextParams::default())?;
+//! let mut draft_ctx = draft.new_context(&backend, LlamaContextParams::default())?;
+//!
+//! let params = SpeculativeParams::default().with_n_draft(8).with_max_tokens(Some(256));
+//! let mut generator = SpeculativeGenerator::new(&mut target_ctx, &mut draft_ctx, params)?;
+//! generator.start(&target.str_to_token("The capital of France is", AddBos::Always)?)?;
+//! for token in &mut generator {
+//!     print!("{}", target.token_to_str_lossy(token?));
+//! }
+//! println!("\n{:.2} tokens per step", generator.stats().tokens_per_step());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::context::LlamaContext;
+use crate::generation::FinishReason;
+use crate::llama_batch::{BatchAddError, LlamaBatch};
+use crate::token::data::LlamaTokenData;
+use crate::token::data_array::LlamaTokenDataArray;
+use crate::token::LlamaToken;
+use crate::DecodeError;
+
+/// How drafted tokens are checked against the target model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcceptanceRule {
+    /// Accept a drafted token if it is the target model's most likely token. Matches greedy
+    /// decoding of the target model exactly.
+    Greedy,
+    /// Accept a drafted token `x` with probability `min(1, p(x) / q(x))`, where `p` is the target
+    /// and `q` the draft distribution, and otherwise sample from `max(0, p - q)`. The output is
+    /// distributed exactly as if sampled from the target model
+    /// ([Leviathan et al.](https://arxiv.org/abs/2211.17192)).
+    Stochastic,
+    /// Accept a drafted token if the target model finds it typical enough:
+    /// `p(x) > min(posterior_threshold, posterior_alpha * exp(-H(p)))`. Accepts more tokens than
+    /// [`Self::Stochastic`] at high temperatures while not needing the draft distributions
+    /// ([Medusa](https://arxiv.org/abs/2401.10774)). The token after the accepted ones is the target
+    /// model's most likely token.
+    Typical {
+        /// The probability above which a token is always accepted (e.g. 0.09).
+        posterior_threshold: f32,
+        /// The scale of the entropy-dependent threshold (e.g. 0.3, roughly its square root).
+        posterior_alpha: f32,
+    },
+}
+
+/// The result of [`SpeculativeVerifier::verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verification {
+    /// How many of the drafted tokens were accepted, from the start.
+    pub accepted: usize,
+    /// The token following the accepted ones, sampled from the target model. This is either the
+    /// correction for the first rejected token or a bonus token if all of them were accepted.
+    pub next: LlamaToken,
+}
+
+/// Statistics of the drafts checked by a [`SpeculativeVerifier`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::module_name_repetitions)]
+pub struct SpeculativeStats {
+    /// How many drafts were verified, i.e. how many batches the target model evaluated.
+    pub steps: usize,
+    /// How many tokens were drafted.
+    pub drafted: usize,
+    /// How many of the drafted tokens were accepted.
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    /// The number of tokens generated, the accepted tokens plus the token following them at each
+    /// step.
+    #[must_use]
+    pub fn generated(&self) -> usize {
+        self.accepted + self.steps
+    }
+
+    /// The fraction of drafted tokens that were accepted, 0 if nothing was drafted.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            return 0.0;
+        }
+        self.accepted as f64 / self.drafted as f64
+    }
+
+    /// The average number of tokens generated per evaluation of the target model, 0 if nothing
+    /// was verified. Without speculative decoding this is 1.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn tokens_per_step(&self) -> f64 {
+        if self.steps == 0 {
+            return 0.0;
+        }
+        self.generated() as f64 / self.steps as f64
+    }
+
+    /// The expected speedup over decoding with the target model alone, given how long the draft
+    /// model takes to generate a token relative to the target model (e.g. 0.1 if it is ten
+    /// times faster). Values below 1 mean the draft model slows generation down.
+    ///
+    /// This assumes evaluating a batch of drafted tokens takes the target model as long as a
+    /// single token, which holds while generation is memory bound.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::speculative::SpeculativeStats;
+    /// let stats = SpeculativeStats { steps: 10, drafted: 40, accepted: 30 };
+    /// assert_eq!(stats.acceptance_rate(), 0.75);
+    /// assert_eq!(stats.tokens_per_step(), 4.0);
+    /// // drafting 4 tokens with a 10 times faster model costs 0.4 target evaluations per step
+    /// assert!((stats.estimated_speedup(0.1) - 4.0 / 1.4).abs() < 1e-9);
+    /// ```
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn estimated_speedup(&self, draft_cost: f64) -> f64 {
+        if self.steps == 0 {
+            return 0.0;
+        }
+        let draft_per_step = self.drafted as f64 / self.steps as f64;
+        self.tokens_per_step() / (1.0 + draft_cost * draft_per_step)
+    }
+}
+
+/// Checks drafted tokens against the target model's distributions.
+#[derive(Debug, Clone)]
+pub struct SpeculativeVerifier {
+    rule: AcceptanceRule,
+    rng: u64,
+    stats: SpeculativeStats,
+}
+
+impl SpeculativeVerifier {
+    /// Create a verifier using `rule`, seeding its random number generator with `seed`.
+    #[must_use]
+    pub fn new(rule: AcceptanceRule, seed: u64) -> Self {
+        Self {
+            rule,
+            rng: seed,
+            stats: SpeculativeStats::default(),
+        }
+    }
+
+    /// Reseed the random number generator, e.g. between requests so each of them is
+    /// reproducible.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = seed;
+    }
+
+    /// The rule used to accept drafted tokens.
+    #[must_use]
+    pub fn rule(&self) -> AcceptanceRule {
+        self.rule
+    }
+
+    /// The statistics of all drafts verified since creation or the last
+    /// [`SpeculativeVerifier::take_stats`].
+    #[must_use]
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    /// Return the statistics and start counting from zero, e.g. at the end of each request.
+    pub fn take_stats(&mut self) -> SpeculativeStats {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// Decide how many `draft` tokens to keep.
+    ///
+    /// * `draft` - the tokens proposed by the draft model.
+    /// * `draft_candidates` - the draft model's candidates each drafted token was chosen from. Only
+    ///   used by [`AcceptanceRule::Stochastic`], may be empty otherwise.
+    /// * `target_candidates` - the target model's candidates at each drafted position, plus one
+    ///   more for the position after the last drafted token.
+    ///
+    /// # Panics
+    ///
+    /// - if `target_candidates` does not have one more element than `draft`.
+    /// - if the rule is [`AcceptanceRule::Stochastic`] and `draft_candidates` does not have as many
+    ///   elements as `draft`.
+    /// - if any of the target candidates are empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::speculative::{AcceptanceRule, SpeculativeVerifier};
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let candidates = |logits: [f32; 3]| {
+    ///     LlamaTokenDataArray::from_iter((0..).zip(logits).map(|(i, logit)| LlamaTokenData::new(LlamaToken(i), logit, 0.0)), false)
+    /// };
+    /// // the target model agrees with the first drafted token but not with the second
+    /// let draft = [LlamaToken(1), LlamaToken(2)];
+    /// let target = [candidates([0.0, 5.0, 0.0]), candidates([5.0, 0.0, 0.0]), candidates([0.0, 0.0, 5.0])];
+    ///
+    /// let mut verifier = SpeculativeVerifier::new(AcceptanceRule::Greedy, 0);
+    /// let verification = verifier.verify(&draft, &[], &target);
+    /// assert_eq!(verification.accepted, 1);
+    /// assert_eq!(verification.next, LlamaToken(0));
+    /// assert_eq!(verifier.stats().acceptance_rate(), 0.5);
+    /// ```
+    pub fn verify(
+        &mut self,
+        draft: &[LlamaToken],
+        draft_candidates: &[LlamaTokenDataArray],
+        target_candidates: &[LlamaTokenDataArray],
+    ) -> Verification {
+        let verification = self.verify_draft(draft, draft_candidates, target_candidates);
+        self.stats.steps += 1;
+        self.stats.drafted += draft.len();
+        self.stats.accepted += verification.accepted;
+        verification
+    }
+
+    fn verify_draft(
+        &mut self,
+        draft: &[LlamaToken],
+        draft_candidates: &[LlamaTokenDataArray],
+        target_candidates: &[LlamaTokenDataArray],
+    ) -> Verification {
+        assert_eq!(
+            target_candidates.len(),
+            draft.len() + 1,
+            "expected target candidates for every drafted token and one more"
+        );
+        if self.rule == AcceptanceRule::Stochastic {
+            assert_eq!(
+                draft_candidates.len(),
+                draft.len(),
+                "expected draft candidates for every drafted token"
+            );
+        }
+
+        for (i, &token) in draft.iter().enumerate() {
+            if let Some(next) = self.reject(token, draft_candidates.get(i), &target_candidates[i]) {
+                return Verification { accepted: i, next };
+            }
+        }
+
+        let p = softmax(&target_candidates[draft.len()]);
+        let next = match self.rule {
+            AcceptanceRule::Stochastic => self.sample(&p).unwrap_or_else(|| argmax(&p)),
+            AcceptanceRule::Greedy | AcceptanceRule::Typical { .. } => argmax(&p),
+        };
+        Verification {
+            accepted: draft.len(),
+            next,
+        }
+    }
+
+    /// Check a single drafted token, returning the token to use instead if it is rejected.
+    fn reject(
+        &mut self,
+        token: LlamaToken,
+        draft_candidates: Option<&LlamaTokenDataArray>,
+        target_candidates: &LlamaTokenDataArray,
+    ) -> Option<LlamaToken> {
+        let p = softmax(target_candidates);
+        let p_x = p.get(&token).copied().unwrap_or(0.0);
+        match self.rule {
+            AcceptanceRule::Greedy => Some(argmax(&p)).filter(|&best| best != token),
+            AcceptanceRule::Stochastic => {
+                let q = softmax(draft_candidates.expect("checked in verify"));
+                let q_x = q.get(&token).copied().unwrap_or(0.0);
+                if q_x <= p_x || self.uniform() * q_x < p_x {
+                    return None;
+                }
+                let residual = p
+                    .iter()
+                    .map(|(&t, &p_t)| (t, (p_t - q.get(&t).copied().unwrap_or(0.0)).max(0.0)))
+                    .collect();
+                Some(self.sample(&residual).unwrap_or_else(|| argmax(&p)))
+            }
+            AcceptanceRule::Typical {
+                posterior_threshold,
+                posterior_alpha,
+            } => {
+                let entropy: f32 = p
+                    .values()
+                    .filter(|&&p_t| p_t > 0.0)
+                    .map(|&p_t| -p_t * p_t.ln())
+                    .sum();
+                let threshold = posterior_threshold.min(posterior_alpha * (-entropy).exp());
+                (p_x <= threshold).then(|| argmax(&p))
+            }
+        }
+    }
+
+    /// Sample a token in proportion to the (not necessarily normalized) `weights`. `None` if they
+    /// are all zero.
+    fn sample(&mut self, weights: &HashMap<LlamaToken, f32>) -> Option<LlamaToken> {
+        let mut weights = weights
+            .iter()
+            .filter(|(_, &w)| w > 0.0)
+            .map(|(&t, &w)| (t, w))
+            .collect::<Vec<_>>();
+        // iteration order of a `HashMap` is random, sort to make sampling reproducible
+        weights.sort_by_key(|&(t, _)| t);
+        let total: f32 = weights.iter().map(|&(_, w)| w).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut target = self.uniform() * total;
+        for &(token, weight) in &weights {
+            if target < weight {
+                return Some(token);
+            }
+            target -= weight;
+        }
+        weights.last().map(|&(token, _)| token)
+    }
+
+    /// A uniformly distributed number in `[0, 1)` from a splitmix64 generator.
+    #[allow(clippy::cast_precision_loss)]
+    fn uniform(&mut self) -> f32 {
+        self.rng = self.rng.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1_u32 << 24) as f32
+    }
+}
+
+/// The probabilities of the candidates, computed from their logits.
+fn softmax(candidates: &LlamaTokenDataArray) -> HashMap<LlamaToken, f32> {
+    let max = candidates
+        .data
+        .iter()
+        .map(LlamaTokenData::logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exp = candidates
+        .data
+        .iter()
+        .map(|data| (data.id(), (data.logit() - max).exp()))
+        .collect::<Vec<_>>();
+    let sum: f32 = exp.iter().map(|&(_, e)| e).sum();
+    exp.into_iter().map(|(token, e)| (token, e / sum)).collect()
+}
+
+/// The most likely token, preferring the lowest id on ties.
+///
+/// # Panics
+///
+/// If `p` is empty.
+fn argmax(p: &HashMap<LlamaToken, f32>) -> LlamaToken {
+    p.iter()
+        .max_by(|(t_a, p_a), (t_b, p_b)| p_a.total_cmp(p_b).then(t_b.cmp(t_a)))
+        .map(|(&token, _)| token)
+        .expect("candidates should not be empty")
+}
+
+/// Settings of a [`SpeculativeGenerator`].
+///
+/// # Examples
+///
+/// ```
+/// # use llama_cpp_2::speculative::{AcceptanceRule, SpeculativeParams};
+/// let params = SpeculativeParams::default()
+///     .with_n_draft(8)
+///     .with_rule(AcceptanceRule::Stochastic)
+///     .with_temperature(0.8);
+/// assert_eq!(params.n_draft(), 8);
+/// assert_eq!(params.rule(), AcceptanceRule::Stochastic);
+/// assert_eq!(params.max_tokens(), None);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct SpeculativeParams {
+    n_draft: usize,
+    rule: AcceptanceRule,
+    temperature: f32,
+    seed: u64,
+    max_tokens: Option<usize>,
+}
+
+impl Default for SpeculativeParams {
+    fn default() -> Self {
+        Self {
+            n_draft: 5,
+            rule: AcceptanceRule::Greedy,
+            temperature: 0.0,
+            seed: 0,
+            max_tokens: None,
+        }
+    }
+}
+
+impl SpeculativeParams {
+    /// Set how many tokens the draft model proposes per step, 5 by default. Fewer are drafted
+    /// near the end of the context or of `max_tokens`, and 0 decodes with the target model alone.
+    #[must_use]
+    pub fn with_n_draft(mut self, n_draft: usize) -> Self {
+        self.n_draft = n_draft;
+        self
+    }
+
+    /// Set the rule drafted tokens are verified with, [`AcceptanceRule::Greedy`] by default.
+    ///
+    /// With [`AcceptanceRule::Stochastic`] the draft model samples its tokens, otherwise it
+    /// drafts its most likely tokens.
+    #[must_use]
+    pub fn with_rule(mut self, rule: AcceptanceRule) -> Self {
+        self.rule = rule;
+        self
+    }
+
+    /// Set the temperature both models' logits are divided by before drafting and verifying. 0
+    /// (the default) leaves the logits unchanged.
+    #[must_use]
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Set the seed of the verifier's random number generator, see [`SpeculativeVerifier::new`].
+    /// The draft model samples with the random number generator of its context.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Stop after `max_tokens` tokens, [`None`] (the default) for no limit.
+    #[must_use]
+    pub fn with_max_tokens(mut self, max_tokens: Option<usize>) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    /// The number of tokens drafted per step.
+    #[must_use]
+    pub fn n_draft(&self) -> usize {
+        self.n_draft
+    }
+
+    /// The rule drafted tokens are verified with.
+    #[must_use]
+    pub fn rule(&self) -> AcceptanceRule {
+        self.rule
+    }
+
+    /// The temperature applied to the logits, 0 if they are left unchanged.
+    #[must_use]
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    /// The seed of the verifier's random number generator.
+    #[must_use]
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The maximum number of generated tokens, if limited.
+    #[must_use]
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_tokens
+    }
+}
+
+/// An error of a [`SpeculativeGenerator`].
+#[derive(Debug, thiserror::Error)]
+#[allow(clippy::module_name_repetitions)]
+pub enum SpeculativeError {
+    /// The models have different vocabularies, so drafted tokens mean nothing to the target model.
+    #[error("the target model has {target} tokens, but the draft model has {draft}")]
+    VocabMismatch {
+        /// The vocabulary size of the target model.
+        target: i32,
+        /// The vocabulary size of the draft model.
+        draft: i32,
+    },
+    /// A model is recurrent, so its state can not be rolled back to the accepted tokens.
+    #[error("recurrent models can not be used for speculative decoding")]
+    RecurrentModel,
+    /// The prompt is empty.
+    #[error("the prompt is empty")]
+    EmptyPrompt,
+    /// Decoding failed.
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+    /// The batch could not hold the tokens.
+    #[error("{0}")]
+    BatchAdd(#[from] BatchAddError),
+}
+
+/// Generates tokens of a target model with tokens drafted by a smaller model, see the
+/// [module docs](crate::speculative).
+///
+/// Both contexts decode on sequence 0. [`SpeculativeGenerator::start`] clears their KV caches and
+/// iteration yields the generated tokens. It ends after an end of generation token (which is not
+/// yielded), after `max_tokens` tokens, when either context is full or after the first error.
+#[allow(clippy::module_name_repetitions)]
+pub struct SpeculativeGenerator<'a, 'target, 'draft> {
+    target: &'a mut LlamaContext<'target>,
+    draft: &'a mut LlamaContext<'draft>,
+    params: SpeculativeParams,
+    verifier: SpeculativeVerifier,
+    batch: LlamaBatch,
+    /// The prompt and the generated tokens.
+    tokens: Vec<LlamaToken>,
+    /// The number of tokens in the KV cache of the target context, always `tokens.len() - 1`.
+    target_past: usize,
+    /// The number of tokens in the KV cache of the draft context.
+    draft_past: usize,
+    /// Generated tokens that were not yielded yet.
+    ready: VecDeque<LlamaToken>,
+    n_generated: usize,
+    finish_reason: Option<FinishReason>,
+    finished: bool,
+}
+
+impl std::fmt::Debug for SpeculativeGenerator<'_, '_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpeculativeGenerator")
+            .field("params", &self.params)
+            .field("verifier", &self.verifier)
+            .field("n_tokens", &self.tokens.len())
+            .field("n_generated", &self.n_generated)
+            .field("finish_reason", &self.finish_reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, 'target, 'draft> SpeculativeGenerator<'a, 'target, 'draft> {
+    /// Generate with the model of `target` and draft tokens with the model of `draft`.
+    ///
+    /// # Errors
+    ///
+    /// - the models have different vocabulary sizes.
+    /// - either model is recurrent.
+    pub fn new(
+        target: &'a mut LlamaContext<'target>,
+        draft: &'a mut LlamaContext<'draft>,
+        params: SpeculativeParams,
+    ) -> Result<Self, SpeculativeError> {
+        let (n_target, n_draft) = (target.model.n_vocab(), draft.model.n_vocab());
+        if n_target != n_draft {
+            return Err(SpeculativeError::VocabMismatch {
+                target: n_target,
+                draft: n_draft,
+            });
+        }
+        if target.model.is_recurrent() || draft.model.is_recurrent() {
+            return Err(SpeculativeError::RecurrentModel);
+        }
+        Ok(Self {
+            target,
+            draft,
+            verifier: SpeculativeVerifier::new(params.rule, params.seed),
+            params,
+            batch: LlamaBatch::new(512, 1),
+            tokens: Vec::new(),
+            target_past: 0,
+            draft_past: 0,
+            ready: VecDeque::new(),
+            n_generated: 0,
+            finish_reason: None,
+            finished: true,
+        })
+    }
+
+    /// Clear both KV caches and decode all but the last token of `prompt` with both models.
+    /// Iteration then generates the continuation of `prompt`. The statistics are kept, see
+    /// [`SpeculativeGenerator::take_stats`].
+    ///
+    /// # Errors
+    ///
+    /// - the prompt is empty.
+    /// - decoding failed, e.g. because the prompt does not fit into a context.
+    pub fn start(&mut self, prompt: &[LlamaToken]) -> Result<(), SpeculativeError> {
+        let Some((_, prefix)) = prompt.split_last() else {
+            return Err(SpeculativeError::EmptyPrompt);
+        };
+        self.target.clear_kv_cache();
+        self.draft.clear_kv_cache();
+        self.tokens = prompt.to_vec();
+        self.ready.clear();
+        self.n_generated = 0;
+        self.finish_reason = None;
+        self.finished = true;
+        decode_all(self.target, &mut self.batch, prefix, 0)?;
+        decode_all(self.draft, &mut self.batch, prefix, 0)?;
+        self.target_past = prefix.len();
+        self.draft_past = prefix.len();
+        self.finished = false;
+        Ok(())
+    }
+
+    /// The number of tokens generated since [`SpeculativeGenerator::start`], including those not
+    /// yielded yet.
+    #[must_use]
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// Why the generation ended, `None` while it is running or after an error.
+    #[must_use]
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// The statistics of the drafts verified so far.
+    #[must_use]
+    pub fn stats(&self) -> SpeculativeStats {
+        self.verifier.stats()
+    }
+
+    /// Return the statistics and start counting from zero.
+    pub fn take_stats(&mut self) -> SpeculativeStats {
+        self.verifier.take_stats()
+    }
+
+    /// Draft tokens, verify them with the target model and queue the generated tokens.
+    fn step(&mut self) -> Result<(), SpeculativeError> {
+        let n_tokens = self.tokens.len();
+        let target_room = (self.target.n_ctx() as usize).saturating_sub(n_tokens);
+        // the draft context holds the last token and all but the last drafted one
+        let draft_room = (self.draft.n_ctx() as usize + 1).saturating_sub(n_tokens);
+        if self.n_generated >= self.params.max_tokens.unwrap_or(usize::MAX) {
+            self.finish(FinishReason::MaxTokens);
+            return Ok(());
+        }
+        if self.target_past >= self.target.n_ctx() as usize {
+            self.finish(FinishReason::ContextFull);
+            return Ok(());
+        }
+        let remaining = self
+            .params
+            .max_tokens
+            .map_or(usize::MAX, |max| max.saturating_sub(self.n_generated));
+        let n_draft = self
+            .params
+            .n_draft
+            .min(target_room)
+            .min(draft_room)
+            .min(remaining.saturating_sub(1))
+            .min(self.target.n_batch().saturating_sub(1) as usize);
+
+        let (draft, draft_candidates) = self.draft(n_draft)?;
+
+        self.batch.clear();
+        let last = self.tokens[n_tokens - 1];
+        for (pos, &token) in (self.target_past..).zip(std::iter::once(&last).chain(&draft)) {
+            self.batch.add(token, pos_i32(pos), &[0], true)?;
+        }
+        self.target.decode(&mut self.batch)?;
+        let target_candidates = (0..=pos_i32(draft.len()))
+            .map(|i| candidates(self.target, i, self.params.temperature))
+            .collect::<Vec<_>>();
+
+        let verification = self
+            .verifier
+            .verify(&draft, &draft_candidates, &target_candidates);
+        let accepted = &draft[..verification.accepted];
+
+        // keep the last token and the accepted ones in the KV caches
+        self.target_past = n_tokens + accepted.len();
+        self.draft_past = self.draft_past.min(self.target_past);
+        trim(self.target, self.target_past);
+        trim(self.draft, self.draft_past);
+
+        for &token in accepted.iter().chain([&verification.next]) {
+            self.tokens.push(token);
+            self.n_generated += 1;
+            if token == self.target.model.token_eos() {
+                self.finish(FinishReason::Eos);
+                return Ok(());
+            }
+            if self.target.model.is_eog_token(token) {
+                self.finish(FinishReason::EogToken);
+                return Ok(());
+            }
+            self.ready.push_back(token);
+        }
+        Ok(())
+    }
+
+    /// Let the draft model propose up to `n_draft` tokens, stopping early at an end of generation
+    /// token. Returns the tokens and the candidates they were chosen from.
+    fn draft(
+        &mut self,
+        n_draft: usize,
+    ) -> Result<(Vec<LlamaToken>, Vec<LlamaTokenDataArray>), SpeculativeError> {
+        let mut draft = Vec::with_capacity(n_draft);
+        let mut draft_candidates = Vec::with_capacity(n_draft);
+        while draft.len() < n_draft {
+            // the tokens the draft context has not seen, at least the last one
+            let n_pending = self.tokens.len() + draft.len() - self.draft_past;
+            let pending = self.tokens.iter().chain(&draft).skip(self.draft_past);
+            self.batch.clear();
+            for (i, &token) in pending.enumerate() {
+                let pos = pos_i32(self.draft_past + i);
+                self.batch.add(token, pos, &[0], i + 1 == n_pending)?;
+            }
+            self.draft.decode(&mut self.batch)?;
+            self.draft_past += n_pending;
+
+            let candidates =
+                candidates(self.draft, pos_i32(n_pending - 1), self.params.temperature);
+            let token = if self.params.rule == AcceptanceRule::Stochastic {
+                candidates.clone().sample_token(self.draft)
+            } else {
+                argmax(&softmax(&candidates))
+            };
+            draft.push(token);
+            draft_candidates.push(candidates);
+            if self.draft.model.is_eog_token(token) {
+                break;
+            }
+        }
+        Ok((draft, draft_candidates))
+    }
+
+    fn finish(&mut self, reason: FinishReason) {
+        self.finish_reason = Some(reason);
+        self.finished = true;
+    }
+}
+
+impl Iterator for SpeculativeGenerator<'_, '_, '_> {
+    type Item = Result<LlamaToken, SpeculativeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Some(Ok(token));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.step() {
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// Decode `tokens` from position `start` in batches of up to [`LlamaContext::n_batch`] tokens,
+/// without logits.
+fn decode_all(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    tokens: &[LlamaToken],
+    start: usize,
+) -> Result<(), SpeculativeError> {
+    let n_batch = (ctx.n_batch() as usize).max(1);
+    for (i, chunk) in tokens.chunks(n_batch).enumerate() {
+        batch.clear();
+        for (pos, &token) in (start + i * n_batch..).zip(chunk) {
+            batch.add(token, pos_i32(pos), &[0], false)?;
+        }
+        ctx.decode(batch)?;
+    }
+    Ok(())
+}
+
+/// The candidates of the `i`th token of the last batch of `ctx`, with the logits divided by
+/// `temperature` if it is positive.
+fn candidates(ctx: &mut LlamaContext, i: i32, temperature: f32) -> LlamaTokenDataArray {
+    let mut candidates = ctx.token_data_array_ith(i);
+    if temperature > 0.0 {
+        for data in &mut candidates.data {
+            data.set_logit(data.logit() / temperature);
+        }
+    }
+    candidates
+}
+
+/// Remove everything after the first `n_keep` positions of sequence 0.
+fn trim(ctx: &mut LlamaContext, n_keep: usize) {
+    ctx.shift_kv_cache_seq(0, pos_i32(n_keep), i32::MAX)
+        .expect("the KV cache of a transformer model can be trimmed");
+}
+
+fn pos_i32(pos: usize) -> i32 {
+    i32::try_from(pos).expect("position should fit into an i32")
+}
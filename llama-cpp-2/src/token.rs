@@ -5,10 +5,13 @@ use std::fmt::Display;
 
 pub mod data;
 pub mod data_array;
+pub mod decoder;
+pub mod mask;
 
 /// A safe wrapper for `llama_token`.
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaToken(pub llama_cpp_sys_2::llama_token);
 
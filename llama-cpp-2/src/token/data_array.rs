@@ -1,12 +1,19 @@
 //! an rusty equivalent of `llama_token_data`.
 use crate::context::LlamaContext;
 use crate::token::data::LlamaTokenData;
+use crate::token::mask::TokenMask;
 use crate::token::LlamaToken;
 use llama_cpp_sys_2::llama_token;
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::ptr;
 
 /// a safe wrapper around `llama_token_data_array`.
+///
+/// Restricting the candidates to a set of tokens ([`LlamaTokenDataArray::sample_ban`],
+/// [`LlamaTokenDataArray::sample_allow`] and [`LlamaTokenDataArray::sample_logit_bias`]) only
+/// touches the given tokens when the candidates are the full vocabulary in token id order, as
+/// returned by [`LlamaContext::candidates_ith`], and checks every candidate otherwise.
 #[derive(Debug, Clone, PartialEq)]
 #[allow(clippy::module_name_repetitions)]
 pub struct LlamaTokenDataArray {
@@ -52,6 +59,32 @@ impl LlamaTokenDataArray {
     {
         Self::new(data.into_iter().collect(), sorted)
     }
+
+    /// Replace the candidates with one per logit in token id order, with a probability of 0.
+    ///
+    /// The existing buffer is overwritten in place, so refilling an array every token only
+    /// allocates when the vocabulary grows. `llama_token_data` interleaves ids and probabilities
+    /// with the logits, so the logits still have to be copied once.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut array = LlamaTokenDataArray::new(Vec::with_capacity(3), true);
+    /// array.fill_from_logits(&[0.5, 1.5, -0.5]);
+    /// assert_eq!(array.data.len(), 3);
+    /// assert_eq!(array.data[1].id(), LlamaToken(1));
+    /// assert_eq!(array.data[1].logit(), 1.5);
+    /// assert_eq!(array.sorted, false);
+    /// ```
+    pub fn fill_from_logits(&mut self, logits: &[f32]) {
+        self.data.clear();
+        self.data.extend(
+            (0_i32..)
+                .zip(logits)
+                .map(|(id, &logit)| LlamaTokenData::new(LlamaToken(id), logit, 0.0)),
+        );
+        self.sorted = false;
+    }
 }
 
 impl LlamaTokenDataArray {
@@ -157,6 +190,177 @@ impl LlamaTokenDataArray {
         }
     }
 
+    /// Exclude every token in `banned` from sampling by setting its logit to negative infinity.
+    /// The candidates are no longer sorted afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let banned = TokenMask::from_iter([LlamaToken::new(1), LlamaToken::new(3)]);
+    ///
+    /// let mut candidates = LlamaTokenDataArray::from_iter((0..4).map(|i| LlamaTokenData::new(LlamaToken::new(i), 1.0, 0.0)), false);
+    /// candidates.sample_ban(&banned);
+    ///
+    /// let logits = candidates.data.iter().map(|data| data.logit()).collect::<Vec<_>>();
+    /// assert_eq!(logits, vec![1.0, f32::NEG_INFINITY, 1.0, f32::NEG_INFINITY]);
+    ///
+    /// candidates.sample_softmax(None);
+    /// assert_eq!(candidates.data[2].p(), 0.0);
+    ///
+    /// // banning the most likely token invalidates the order by logit
+    /// candidates.sample_ban(&TokenMask::from_iter([candidates.data[0].id()]));
+    /// assert!(!candidates.sorted);
+    /// ```
+    pub fn sample_ban(&mut self, banned: &TokenMask) {
+        if self.is_in_id_order(banned.iter()) {
+            for index in banned.iter().filter_map(index_of) {
+                self.data[index].set_logit(f32::NEG_INFINITY);
+            }
+        } else {
+            for data in &mut self.data {
+                if banned.contains(data.id()) {
+                    data.set_logit(f32::NEG_INFINITY);
+                }
+            }
+        }
+        self.sorted = false;
+    }
+
+    /// Remove every token that is not in `allowed` from the candidates.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let allowed = TokenMask::from_iter([LlamaToken::new(1), LlamaToken::new(3)]);
+    ///
+    /// let mut candidates = LlamaTokenDataArray::from_iter((0..4).map(|i| LlamaTokenData::new(LlamaToken::new(i), 1.0, 0.0)), false);
+    /// candidates.sample_allow(&allowed);
+    ///
+    /// let tokens = candidates.data.iter().map(|data| data.id()).collect::<Vec<_>>();
+    /// assert_eq!(tokens, vec![LlamaToken::new(1), LlamaToken::new(3)]);
+    /// ```
+    pub fn sample_allow(&mut self, allowed: &TokenMask) {
+        if self.is_in_id_order(allowed.iter()) {
+            self.data = allowed
+                .iter()
+                .filter_map(index_of)
+                .map(|index| self.data[index])
+                .collect();
+            self.sorted = false;
+        } else {
+            self.data.retain(|data| allowed.contains(data.id()));
+        }
+    }
+
+    /// Add `bias` to the logit of each token in `logit_bias`. A bias of negative infinity bans the
+    /// token. The candidates are no longer sorted afterwards, unless `logit_bias` is empty.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use std::collections::BTreeMap;
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let logit_bias = BTreeMap::from([(LlamaToken::new(0), 2.0), (LlamaToken::new(2), -1.0)]);
+    ///
+    /// let mut candidates = LlamaTokenDataArray::from_iter((0..3).map(|i| LlamaTokenData::new(LlamaToken::new(i), 1.0, 0.0)), true);
+    /// candidates.sample_logit_bias(&logit_bias);
+    ///
+    /// let logits = candidates.data.iter().map(|data| data.logit()).collect::<Vec<_>>();
+    /// assert_eq!(logits, vec![3.0, 1.0, 0.0]);
+    /// assert!(!candidates.sorted);
+    /// ```
+    pub fn sample_logit_bias(&mut self, logit_bias: &BTreeMap<LlamaToken, f32>) {
+        if self.is_in_id_order(logit_bias.keys().copied()) {
+            for (&token, bias) in logit_bias {
+                if let Some(data) = index_of(token).and_then(|index| self.data.get_mut(index)) {
+                    data.set_logit(data.logit() + bias);
+                }
+            }
+        } else {
+            for data in &mut self.data {
+                if let Some(bias) = logit_bias.get(&data.id()) {
+                    data.set_logit(data.logit() + bias);
+                }
+            }
+        }
+        if !logit_bias.is_empty() {
+            self.sorted = false;
+        }
+    }
+
+    /// Is each of `tokens` at the index of its id, so it can be looked up without a search?
+    fn is_in_id_order(&self, mut tokens: impl Iterator<Item = LlamaToken>) -> bool {
+        tokens.all(|token| {
+            index_of(token)
+                .and_then(|index| self.data.get(index))
+                .is_some_and(|data| data.id() == token)
+        })
+    }
+
+    /// Top-n-sigma sampling as described in [Top-nσ](https://arxiv.org/abs/2411.07641): keep only
+    /// the tokens whose logit is at most `n` standard deviations below the highest logit. Does
+    /// nothing if `n` is 0 or less.
+    ///
+    /// The other tokens are banned (their logit is set to negative infinity), so this works on raw
+    /// logits and is not affected by the temperature applied afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let logits = [0.0, 1.0, 2.0, 3.0, 10.0, 9.5];
+    /// let mut candidates = LlamaTokenDataArray::from_iter(
+    ///     (0..).zip(logits).map(|(i, logit)| LlamaTokenData::new(LlamaToken::new(i), logit, 0.0)),
+    ///     false,
+    /// );
+    /// candidates.sample_top_n_sigma(1.0);
+    ///
+    /// let kept = candidates
+    ///     .data
+    ///     .iter()
+    ///     .filter(|data| data.logit().is_finite())
+    ///     .map(|data| data.id())
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(kept, vec![LlamaToken::new(4), LlamaToken::new(5)]);
+    /// ```
+    #[allow(clippy::cast_precision_loss)]
+    pub fn sample_top_n_sigma(&mut self, n: f32) {
+        if n <= 0.0 || self.data.len() <= 1 {
+            return;
+        }
+        let logits = || {
+            self.data
+                .iter()
+                .map(LlamaTokenData::logit)
+                .filter(|logit| *logit != f32::NEG_INFINITY)
+        };
+        let count = logits().count();
+        if count == 0 {
+            return;
+        }
+        let max = logits().fold(f32::NEG_INFINITY, f32::max);
+        let mean = logits().sum::<f32>() / count as f32;
+        let variance = logits().map(|logit| (logit - mean).powi(2)).sum::<f32>() / count as f32;
+        let threshold = max - n * variance.sqrt();
+        for data in &mut self.data {
+            if data.logit() < threshold {
+                data.set_logit(f32::NEG_INFINITY);
+            }
+        }
+    }
+
     /// Sorts candidate tokens by their logits in descending order and calculate probabilities based on logits.
     ///
     /// # Example
@@ -229,6 +433,10 @@ impl LlamaTokenDataArray {
     }
 
     /// Randomly selects a token from the candidates based on their probabilities.
+    #[cfg_attr(
+        feature = "tracing-spans",
+        tracing::instrument(level = "debug", skip_all, fields(n_candidates = self.data.len()))
+    )]
     pub fn sample_token(&mut self, ctx: &mut LlamaContext) -> LlamaToken {
         let llama_token = unsafe {
             self.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
@@ -239,7 +447,42 @@ impl LlamaTokenDataArray {
     }
 
     /// Top-K sampling described in academic paper [The Curious Case of Neural Text Degeneration](https://arxiv.org/abs/1904.09751)
+    ///
+    /// Keeps the `k` (at least `min_keep`) tokens with the highest logits, sorted in descending
+    /// order. `k` of 0 or less keeps all tokens. Unsorted candidates are first narrowed down with a
+    /// selection in linear time, so only the kept tokens are sorted instead of the whole vocabulary.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use llama_cpp_2::token::data::LlamaTokenData;
+    /// # use llama_cpp_2::token::data_array::LlamaTokenDataArray;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut candidates = LlamaTokenDataArray::from_iter(
+    ///     [0.3, 0.9, 0.1, 0.7, 0.5].into_iter().enumerate().map(|(i, logit)| {
+    ///         LlamaTokenData::new(LlamaToken::new(i32::try_from(i).unwrap()), logit, 0.0)
+    ///     }),
+    ///     false,
+    /// );
+    /// candidates.sample_top_k(None, 3, 1);
+    ///
+    /// assert!(candidates.sorted);
+    /// let tokens = candidates.data.iter().map(|data| data.id()).collect::<Vec<_>>();
+    /// assert_eq!(tokens, vec![LlamaToken::new(1), LlamaToken::new(3), LlamaToken::new(4)]);
+    /// ```
     pub fn sample_top_k(&mut self, ctx: Option<&mut LlamaContext>, k: i32, min_keep: usize) {
+        let k = usize::try_from(k)
+            .ok()
+            .filter(|&k| k > 0)
+            .unwrap_or(self.data.len())
+            .max(min_keep);
+        if !self.sorted && k > 0 && k < self.data.len() {
+            // llama.cpp partially sorts all candidates, select the top k first so it only sorts those
+            self.data
+                .select_nth_unstable_by(k - 1, |a, b| b.logit().total_cmp(&a.logit()));
+            self.data.truncate(k);
+        }
+        let k = i32::try_from(k).unwrap_or(i32::MAX);
         let ctx = ctx.map_or(ptr::null_mut(), |ctx| ctx.context.as_ptr());
         unsafe {
             self.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
@@ -345,6 +588,37 @@ impl LlamaTokenDataArray {
         }
     }
 
+    /// Mirostat 1.0 algorithm described in the [paper](https://arxiv.org/abs/2007.14966). Uses tokens instead of words.
+    ///
+    /// # Parameters
+    ///
+    /// * `tau`, `eta` and `mu` as in [`LlamaTokenDataArray::sample_token_mirostat_v2`].
+    /// * `m` The number of tokens considered in the estimation of `s_hat`. The paper uses 100.
+    pub fn sample_token_mirostat_v1(
+        &mut self,
+        ctx: &mut LlamaContext,
+        tau: f32,
+        eta: f32,
+        m: i32,
+        mu: &mut f32,
+    ) -> LlamaToken {
+        let mu_ptr = ptr::from_mut(mu);
+        let token = unsafe {
+            self.modify_as_c_llama_token_data_array(|c_llama_token_data_array| {
+                llama_cpp_sys_2::llama_sample_token_mirostat(
+                    ctx.context.as_ptr(),
+                    c_llama_token_data_array,
+                    tau,
+                    eta,
+                    m,
+                    mu_ptr,
+                )
+            })
+        };
+        *mu = unsafe { *mu_ptr };
+        LlamaToken(token)
+    }
+
     ///  Mirostat 2.0 algorithm described in the [paper](https://arxiv.org/abs/2007.14966). Uses tokens instead of words.
     ///
     /// # Parameters
@@ -375,3 +649,8 @@ impl LlamaTokenDataArray {
         LlamaToken(token)
     }
 }
+
+/// The index of `token` in candidates that are in token id order.
+fn index_of(token: LlamaToken) -> Option<usize> {
+    usize::try_from(token.0).ok()
+}
@@ -0,0 +1,128 @@
+//! Turning a stream of tokens into text without splitting UTF-8 characters.
+//!
+//! Byte-level vocabularies often split a character (emoji, CJK) over several tokens, so the bytes
+//! of a single token are not always valid UTF-8. [`TokenDecoder`] holds back the start of an
+//! incomplete character until the tokens that complete it arrive.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use llama_cpp_2::token::decoder::TokenDecoder;
+//! # use llama_cpp_2::model::{AddBos, LlamaModel};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+//! # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+//! let mut decoder = TokenDecoder::new();
+//! for token in model.str_to_token("こんにちは 👋", AddBos::Never)? {
+//!     print!("{}", decoder.decode(&model, token)?);
+//! }
+//! print!("{}", decoder.finish());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::model::LlamaModel;
+use crate::token::LlamaToken;
+use crate::TokenToStringError;
+
+/// Decodes tokens one at a time into complete UTF-8 text, see the
+/// [module docs](crate::token::decoder).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenDecoder {
+    pending: Vec<u8>,
+}
+
+impl TokenDecoder {
+    /// A decoder with nothing held back.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The text of `token` and any held back bytes it completes. The start of a character that
+    /// is not complete yet is held back for the next token.
+    ///
+    /// # Errors
+    ///
+    /// - if the token type is unknown. Nothing is decoded then.
+    pub fn decode(
+        &mut self,
+        model: &LlamaModel,
+        token: LlamaToken,
+    ) -> Result<String, TokenToStringError> {
+        model.token_to_bytes_into(token, &mut self.pending)?;
+        Ok(self.take_complete())
+    }
+
+    /// [`TokenDecoder::decode`] for the bytes of a token. Invalid UTF-8 that can not become valid
+    /// with more bytes is replaced by `U+FFFD`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::decoder::TokenDecoder;
+    /// let mut decoder = TokenDecoder::new();
+    /// let waving_hand = "👋".as_bytes();
+    /// assert_eq!(decoder.push(b"Hi "), "Hi ");
+    /// assert_eq!(decoder.push(&waving_hand[..1]), "");
+    /// assert_eq!(decoder.push(&waving_hand[1..3]), "");
+    /// assert!(decoder.has_pending());
+    /// assert_eq!(decoder.push(&waving_hand[3..]), "👋");
+    /// assert_eq!(decoder.finish(), "");
+    /// ```
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        self.take_complete()
+    }
+
+    /// Whether bytes of an incomplete character are held back.
+    #[must_use]
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The held back bytes, with `U+FFFD` for the incomplete character, e.g. once generation
+    /// ends. The decoder is empty afterwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::decoder::TokenDecoder;
+    /// let mut decoder = TokenDecoder::new();
+    /// assert_eq!(decoder.push(&"é".as_bytes()[..1]), "");
+    /// assert_eq!(decoder.finish(), "\u{FFFD}");
+    /// assert!(!decoder.has_pending());
+    /// ```
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+
+    /// Remove and return the pending bytes up to the start of an incomplete last character.
+    fn take_complete(&mut self) -> String {
+        let complete = self.pending.len() - incomplete_suffix_len(&self.pending);
+        let text = String::from_utf8_lossy(&self.pending[..complete]).into_owned();
+        self.pending.drain(..complete);
+        text
+    }
+}
+
+/// The number of bytes at the end of `bytes` that start a UTF-8 character but do not complete it.
+pub(crate) fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        // skip continuation bytes until the start of the last character
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            continue;
+        }
+        let len = match byte {
+            0b1111_0000.. => 4,
+            0b1110_0000.. => 3,
+            0b1100_0000.. => 2,
+            _ => 1,
+        };
+        return if len > back { back } else { 0 };
+    }
+    0
+}
@@ -0,0 +1,239 @@
+//! A compact set of tokens for masking candidates during sampling.
+
+use crate::model::{AddBos, LlamaModel};
+use crate::token::LlamaToken;
+use crate::StringToTokenError;
+
+/// A set of tokens stored as a bitset indexed by token id.
+///
+/// Build it once (e.g. before a generation) and apply it every step with
+/// [`crate::token::data_array::LlamaTokenDataArray::sample_ban`] or
+/// [`crate::token::data_array::LlamaTokenDataArray::sample_allow`], which only touch the tokens in
+/// the mask rather than scanning the whole vocabulary.
+///
+/// ```
+/// # use llama_cpp_2::token::mask::TokenMask;
+/// # use llama_cpp_2::token::LlamaToken;
+/// let mut mask = TokenMask::from_iter([LlamaToken(3), LlamaToken(70)]);
+/// assert!(mask.insert(LlamaToken(5)));
+/// assert!(!mask.insert(LlamaToken(3)));
+/// assert!(mask.contains(LlamaToken(70)));
+/// assert!(!mask.contains(LlamaToken(4)));
+/// assert_eq!(mask.iter().collect::<Vec<_>>(), vec![LlamaToken(3), LlamaToken(5), LlamaToken(70)]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct TokenMask {
+    /// Never ends with a zero word, so equal sets compare equal.
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl TokenMask {
+    /// Create an empty mask.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a mask of every token in the vocabulary of `model` for which `predicate` returns
+    /// true. The predicate is given the token and its text (see [`LlamaModel::token_to_bytes`]).
+    /// Tokens whose text can not be retrieved are skipped.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// // only allow numbers
+    /// let digits = TokenMask::from_vocab(&model, |_, text| {
+    ///     !text.is_empty() && text.iter().all(u8::is_ascii_digit)
+    /// });
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_vocab(
+        model: &LlamaModel,
+        mut predicate: impl FnMut(LlamaToken, &[u8]) -> bool,
+    ) -> Self {
+        (0..model.n_vocab())
+            .map(LlamaToken)
+            .filter(|&token| {
+                model
+                    .token_to_bytes_with_size(token, 256)
+                    .is_ok_and(|text| predicate(token, &text))
+            })
+            .collect()
+    }
+
+    /// Create a mask of the tokens that can appear in JSON output, plus the end of generation
+    /// tokens.
+    ///
+    /// JSON strings can hold any character except unescaped control characters, so a token is
+    /// included if its text is not empty and has no control characters other than the whitespace
+    /// `\t`, `\n` and `\r`, and no bytes that never occur in UTF-8 (see
+    /// [`LlamaModel::token_to_piece_raw`]). Tokens holding part of a multi-byte character, such as
+    /// byte tokens, are included. This removes special tokens and stray control
+    /// characters, a cheap safeguard when a full grammar is overkill. It does not enforce the JSON
+    /// syntax, use a [`crate::grammar::LlamaGrammar`] for that.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// // build it once and apply it with `sample_allow` every step
+    /// let json = TokenMask::json(&model);
+    /// assert!(json.contains(model.token_eos()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn json(model: &LlamaModel) -> Self {
+        // the raw pieces, as byte tokens are needed for characters missing from SPM vocabularies
+        let mut mask = (0..model.n_vocab())
+            .map(LlamaToken)
+            .filter(|&token| {
+                let text = model.token_to_piece_raw(token);
+                !text.is_empty()
+                    && text.iter().all(|&byte| match byte {
+                        b'\t' | b'\n' | b'\r' => true,
+                        // 0xc0 and 0xc1 would start overlong encodings, 0xf5.. code points above
+                        // U+10FFFF
+                        0..=0x1f | 0xc0 | 0xc1 | 0xf5..=0xff => false,
+                        _ => true,
+                    })
+            })
+            .collect::<Self>();
+        mask.extend(model.eog_token_mask().iter());
+        mask
+    }
+
+    /// Add a token to the mask, returning `true` if it was not already present.
+    ///
+    /// # Panics
+    ///
+    /// If the token id is negative.
+    pub fn insert(&mut self, token: LlamaToken) -> bool {
+        let (word, bit) = Self::position(token);
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        let inserted = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        self.len += usize::from(inserted);
+        inserted
+    }
+
+    /// Remove a token from the mask, returning `true` if it was present.
+    ///
+    /// # Panics
+    ///
+    /// If the token id is negative.
+    ///
+    /// ```
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # use llama_cpp_2::token::LlamaToken;
+    /// let mut mask = TokenMask::from_iter([LlamaToken(3), LlamaToken(700)]);
+    /// assert!(mask.remove(LlamaToken(700)));
+    /// assert!(!mask.remove(LlamaToken(700)));
+    /// assert_eq!(mask, TokenMask::from_iter([LlamaToken(3)]));
+    /// ```
+    pub fn remove(&mut self, token: LlamaToken) -> bool {
+        let (word, bit) = Self::position(token);
+        let Some(bits) = self.bits.get_mut(word) else {
+            return false;
+        };
+        let removed = *bits & bit != 0;
+        *bits &= !bit;
+        self.len -= usize::from(removed);
+        while self.bits.last() == Some(&0) {
+            self.bits.pop();
+        }
+        removed
+    }
+
+    /// Is `token` in the mask? Negative token ids are never in the mask.
+    #[must_use]
+    pub fn contains(&self, LlamaToken(id): LlamaToken) -> bool {
+        let Ok(id) = usize::try_from(id) else {
+            return false;
+        };
+        self.bits
+            .get(id / 64)
+            .is_some_and(|bits| bits & (1 << (id % 64)) != 0)
+    }
+
+    /// Add every token that `text` tokenizes to (without a BOS token).
+    ///
+    /// Note that the same text can tokenize differently depending on what precedes it (e.g. with
+    /// or without a leading space), so add each variant that should be covered.
+    ///
+    /// # Errors
+    ///
+    /// See [`StringToTokenError`] for more information.
+    ///
+    /// ```no_run
+    /// # use llama_cpp_2::model::LlamaModel;
+    /// # use llama_cpp_2::token::mask::TokenMask;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let backend = llama_cpp_2::llama_backend::LlamaBackend::init()?;
+    /// # let model = LlamaModel::load_from_file(&backend, "path/to/model", &Default::default())?;
+    /// let mut banned = TokenMask::new();
+    /// banned.insert_str(&model, "As an AI")?;
+    /// banned.insert_str(&model, " As an AI")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_str(&mut self, model: &LlamaModel, text: &str) -> Result<(), StringToTokenError> {
+        self.extend(model.str_to_token(text, AddBos::Never)?);
+        Ok(())
+    }
+
+    /// The number of tokens in the mask.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is the mask empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the tokens in the mask in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = LlamaToken> + '_ {
+        (0_i32..)
+            .step_by(64)
+            .zip(&self.bits)
+            .flat_map(|(base, &bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| LlamaToken(base + bit))
+            })
+    }
+
+    fn position(LlamaToken(id): LlamaToken) -> (usize, u64) {
+        let id = usize::try_from(id).expect("token id should not be negative");
+        (id / 64, 1 << (id % 64))
+    }
+}
+
+impl FromIterator<LlamaToken> for TokenMask {
+    fn from_iter<T: IntoIterator<Item = LlamaToken>>(iter: T) -> Self {
+        let mut mask = Self::new();
+        mask.extend(iter);
+        mask
+    }
+}
+
+impl Extend<LlamaToken> for TokenMask {
+    fn extend<T: IntoIterator<Item = LlamaToken>>(&mut self, iter: T) {
+        for token in iter {
+            self.insert(token);
+        }
+    }
+}
@@ -7,6 +7,46 @@ fn main() {
 
     let cublas_enabled = env::var("CARGO_FEATURE_CUBLAS").is_ok();
 
+    // `cfg!` in a build script describes the host, these describe the target we compile for
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
+    let is_wasm = target_arch == "wasm32";
+
+    let prebuilt_enabled = env::var("CARGO_FEATURE_PREBUILT").is_ok();
+    if prebuilt_enabled && env::var("CARGO_FEATURE_DYNAMIC_LINK").is_ok() {
+        panic!("the prebuilt and dynamic-link features can not be enabled together");
+    }
+
+    if prebuilt_enabled {
+        let dir = prebuilt_dir();
+        link_prebuilt(&dir, cublas_enabled, &target_os);
+        let include_dir = dir.join("include");
+        check_header_version(&include_dir.join("llama.h"));
+        generate_bindings(&include_dir.join("llama.h"));
+        println!("cargo:INCLUDE={}", include_dir.to_str().unwrap());
+        return;
+    }
+
+    if env::var("CARGO_FEATURE_DYNAMIC_LINK").is_ok() {
+        if cublas_enabled {
+            println!(
+                "cargo:warning=the cublas feature has no effect when linking a system libllama"
+            );
+        }
+        let include_dir = link_system_llama();
+        generate_bindings(&include_dir.join("llama.h"));
+        println!("cargo:INCLUDE={}", include_dir.to_str().unwrap());
+        return;
+    }
+
+    if is_wasm {
+        assert!(!cublas_enabled, "CUBLAS is not supported on WebAssembly");
+        assert!(
+            target_os == "wasi" || target_os == "emscripten",
+            "llama.cpp needs a C standard library, build for wasm32-wasip1-threads or wasm32-unknown-emscripten instead"
+        );
+    }
+
     let mut ggml_cuda = if cublas_enabled {
         Some(cc::Build::new())
     } else {
@@ -25,20 +65,9 @@ fn main() {
 
     // https://github.com/ggerganov/llama.cpp/blob/a836c8f534ab789b02da149fbdaf7735500bff74/Makefile#L364-L368
     if let Some(ggml_cuda) = &mut ggml_cuda {
-        for lib in [
-            "cuda", "cublas", "culibos", "cudart", "cublasLt", "pthread", "dl", "rt",
-        ] {
-            println!("cargo:rustc-link-lib={}", lib);
-        }
-        if !ggml_cuda.get_compiler().is_like_msvc() {
-            for lib in ["culibos", "pthread", "dl", "rt"] {
-                println!("cargo:rustc-link-lib={}", lib);
-            }
-        }
+        link_cuda(ggml_cuda.get_compiler().is_like_msvc());
 
-        println!("cargo:rustc-link-search=native=/usr/local/cuda/lib64");
-
-        if cfg!(target_arch = "aarch64") {
+        if target_arch == "aarch64" {
             ggml_cuda
                 .flag_if_supported("-mfp16-format=ieee")
                 .flag_if_supported("-mno-unaligned-access");
@@ -71,7 +100,17 @@ fn main() {
     for build in [&mut ggml, &mut llama_cpp] {
         let compiler = build.get_compiler();
 
-        if cfg!(target_arch = "i686") || cfg!(target_arch = "x86_64") {
+        if is_wasm {
+            let features = get_supported_target_features();
+            // ggml has SIMD kernels behind `__wasm_simd128__`
+            if features.contains("simd128") {
+                build.flag("-msimd128");
+            }
+            // ggml uses threads, which needs shared memory on WebAssembly
+            if target_os == "emscripten" || features.contains("atomics") {
+                build.flag("-pthread");
+            }
+        } else if target_arch == "x86" || target_arch == "x86_64" {
             let features = x86::Features::get_target();
             if compiler.is_like_clang() || compiler.is_like_gnu() {
                 build.flag("-pthread");
@@ -102,10 +141,8 @@ fn main() {
                     _ => {}
                 }
             }
-        } else if cfg!(target_arch = "aarch64")
-            && (compiler.is_like_clang() || compiler.is_like_gnu())
-        {
-            if cfg!(target_os = "macos") {
+        } else if target_arch == "aarch64" && (compiler.is_like_clang() || compiler.is_like_gnu()) {
+            if target_os == "macos" {
                 build.flag("-mcpu=apple-m1");
             } else if env::var("HOST") == env::var("TARGET") {
                 build.flag("-mcpu=native");
@@ -115,13 +152,10 @@ fn main() {
     }
 
     // https://github.com/ggerganov/llama.cpp/blob/191221178f51b6e81122c5bda0fd79620e547d07/Makefile#L133-L141
-    if cfg!(target_os = "macos") {
+    if target_os == "macos" {
         assert!(!cublas_enabled, "CUBLAS is not supported on macOS");
 
-        println!("cargo:rustc-link-lib=framework=Metal");
-        println!("cargo:rustc-link-lib=framework=Foundation");
-        println!("cargo:rustc-link-lib=framework=MetalPerformanceShaders");
-        println!("cargo:rustc-link-lib=framework=MetalKit");
+        link_apple_frameworks();
 
         llama_cpp.define("_DARWIN_C_SOURCE", None);
 
@@ -130,17 +164,16 @@ fn main() {
         llama_cpp.define("GGML_USE_ACCELERATE", None);
         llama_cpp.define("ACCELERATE_NEW_LAPACK", None);
         llama_cpp.define("ACCELERATE_LAPACK_ILP64", None);
-        println!("cargo:rustc-link-lib=framework=Accelerate");
 
         metal_hack(&mut ggml);
         ggml.include("./llama.cpp/ggml-metal.h");
     }
 
-    if cfg!(target_os = "dragonfly") {
+    if target_os == "dragonfly" {
         llama_cpp.define("__BSD_VISIBLE", None);
     }
 
-    if cfg!(target_os = "linux") {
+    if target_os == "linux" {
         ggml.define("_GNU_SOURCE", None);
     }
 
@@ -183,12 +216,17 @@ fn main() {
     llama_cpp.compile("llama");
     println!("compiled llama");
 
-    let header = "llama.cpp/llama.h";
+    generate_bindings(Path::new("llama.cpp/llama.h"));
+    let llama_cpp_dir = PathBuf::from("llama.cpp").canonicalize().unwrap();
+    println!("cargo:INCLUDE={}", llama_cpp_dir.to_str().unwrap());
+}
 
-    println!("cargo:rerun-if-changed={header}");
+/// Generate `bindings.rs` in `OUT_DIR` from the llama.cpp `header`.
+fn generate_bindings(header: &Path) {
+    println!("cargo:rerun-if-changed={}", header.display());
 
     let bindings = bindgen::builder()
-        .header(header)
+        .header(header.to_str().expect("header path is not valid unicode"))
         .derive_partialeq(true)
         .no_debug("llama_grammar_element")
         .prepend_enum_name(false)
@@ -200,11 +238,178 @@ fn main() {
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("failed to write bindings to file");
-    let llama_cpp_dir = PathBuf::from("llama.cpp").canonicalize().unwrap();
-    println!("cargo:INCLUDE={}", llama_cpp_dir.to_str().unwrap());
     println!("cargo:OUT_DIR={}", out_path.to_str().unwrap());
 }
 
+/// Link against a shared libllama installed on the system instead of compiling the vendored
+/// sources, returning the directory containing its `llama.h`.
+///
+/// The library is searched in `LLAMA_LIB_DIR` and the header in `LLAMA_INCLUDE_DIR`, both
+/// defaulting to the `lib` and `include` directories of `LLAMA_PREFIX` if it is set, and to the
+/// linker's and compiler's default search paths otherwise.
+///
+/// The safe wrapper is written against the vendored llama.cpp, so the installed header has to be
+/// the same version. This version of llama.cpp has no way to query the version of the library at
+/// runtime, so the header is compared when building. Set `LLAMA_SKIP_VERSION_CHECK` to use a
+/// different version at your own risk.
+fn link_system_llama() -> PathBuf {
+    for var in ["LLAMA_PREFIX", "LLAMA_LIB_DIR", "LLAMA_INCLUDE_DIR"] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+    let prefix = env::var_os("LLAMA_PREFIX").map(PathBuf::from);
+    let lib_dir = env::var_os("LLAMA_LIB_DIR")
+        .map(PathBuf::from)
+        .or_else(|| prefix.as_ref().map(|prefix| prefix.join("lib")));
+    let include_dir = env::var_os("LLAMA_INCLUDE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| prefix.as_ref().map(|prefix| prefix.join("include")))
+        .or_else(|| {
+            ["/usr/local/include", "/usr/include"]
+                .into_iter()
+                .map(PathBuf::from)
+                .find(|dir| dir.join("llama.h").exists())
+        })
+        .expect("llama.h not found, set LLAMA_INCLUDE_DIR or LLAMA_PREFIX");
+
+    if let Some(lib_dir) = lib_dir {
+        println!("cargo:rustc-link-search=native={}", lib_dir.display());
+    }
+    println!("cargo:rustc-link-lib=dylib=llama");
+
+    check_header_version(&include_dir.join("llama.h"));
+    include_dir
+}
+
+/// Panic if `header` differs from the vendored `llama.h`, unless `LLAMA_SKIP_VERSION_CHECK` is
+/// set or the vendored sources are missing.
+fn check_header_version(header: &Path) {
+    println!("cargo:rerun-if-env-changed=LLAMA_SKIP_VERSION_CHECK");
+    let vendored = Path::new("llama.cpp/llama.h");
+    if env::var_os("LLAMA_SKIP_VERSION_CHECK").is_some() || !vendored.exists() {
+        return;
+    }
+    let installed = std::fs::read_to_string(header)
+        .unwrap_or_else(|e| panic!("failed to read {}: {e}", header.display()));
+    let expected = std::fs::read_to_string(vendored).expect("failed to read llama.h");
+    assert!(
+        installed == expected,
+        "{} is from a different llama.cpp version than llama-cpp-sys-2 was written for, \
+        use the version of the llama.cpp submodule or set LLAMA_SKIP_VERSION_CHECK",
+        header.display()
+    );
+}
+
+/// The directory with the prebuilt llama.cpp, from `LLAMA_PREBUILT_DIR` or downloaded and
+/// extracted from the `.tar.gz` archive at `LLAMA_PREBUILT_URL` into `OUT_DIR`.
+///
+/// Downloading uses the `curl` and `tar` commands, so no TLS stack has to be built for the build
+/// script. The archive is only extracted if its SHA-256 checksum matches `LLAMA_PREBUILT_SHA256`,
+/// as the libraries are linked as they are.
+fn prebuilt_dir() -> PathBuf {
+    println!("cargo:rerun-if-env-changed=LLAMA_PREBUILT_DIR");
+    println!("cargo:rerun-if-env-changed=LLAMA_PREBUILT_URL");
+    println!("cargo:rerun-if-env-changed=LLAMA_PREBUILT_SHA256");
+    if let Some(dir) = env::var_os("LLAMA_PREBUILT_DIR") {
+        return PathBuf::from(dir);
+    }
+    let url = env::var("LLAMA_PREBUILT_URL")
+        .expect("the prebuilt feature needs LLAMA_PREBUILT_DIR or LLAMA_PREBUILT_URL to be set");
+    let expected_sha256 = env::var("LLAMA_PREBUILT_SHA256")
+        .expect("LLAMA_PREBUILT_URL needs LLAMA_PREBUILT_SHA256 to be set as well")
+        .trim()
+        .to_ascii_lowercase();
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let dir = out_dir.join("llama-prebuilt");
+    let archive = out_dir.join("llama-prebuilt.tar.gz");
+    let run = |command: &mut std::process::Command| {
+        let status = command
+            .status()
+            .unwrap_or_else(|e| panic!("failed to run {command:?}: {e}"));
+        assert!(status.success(), "{command:?} failed with {status}");
+    };
+    run(std::process::Command::new("curl")
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(&archive)
+        .arg(&url));
+    let sha256 = sha256_hex(&archive);
+    assert!(
+        sha256 == expected_sha256,
+        "the archive downloaded from {url} has the SHA-256 checksum {sha256}, \
+        LLAMA_PREBUILT_SHA256 is {expected_sha256}"
+    );
+    std::fs::create_dir_all(&dir).expect("failed to create the prebuilt directory");
+    run(std::process::Command::new("tar")
+        .arg("-xzf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&dir));
+    dir
+}
+
+/// The SHA-256 checksum of the file at `path` as lowercase hex.
+fn sha256_hex(path: &Path) -> String {
+    use sha2::{Digest, Sha256};
+
+    let bytes =
+        std::fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {e}", path.display()));
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Link the static `llama` and `ggml` libraries in `dir/lib` along with what they depend on.
+fn link_prebuilt(dir: &Path, cublas_enabled: bool, target_os: &str) {
+    println!(
+        "cargo:rustc-link-search=native={}",
+        dir.join("lib").display()
+    );
+    println!("cargo:rustc-link-lib=static=llama");
+    println!("cargo:rustc-link-lib=static=ggml");
+    if cublas_enabled {
+        println!("cargo:rustc-link-lib=static=ggml-cuda");
+        link_cuda(target_os == "windows");
+    }
+    match target_os {
+        "macos" | "ios" => {
+            link_apple_frameworks();
+            println!("cargo:rustc-link-lib=c++");
+        }
+        "windows" => {}
+        _ => println!("cargo:rustc-link-lib=stdc++"),
+    }
+}
+
+// https://github.com/ggerganov/llama.cpp/blob/a836c8f534ab789b02da149fbdaf7735500bff74/Makefile#L364-L368
+fn link_cuda(is_msvc: bool) {
+    for lib in [
+        "cuda", "cublas", "culibos", "cudart", "cublasLt", "pthread", "dl", "rt",
+    ] {
+        println!("cargo:rustc-link-lib={}", lib);
+    }
+    if !is_msvc {
+        for lib in ["culibos", "pthread", "dl", "rt"] {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    }
+
+    println!("cargo:rustc-link-search=native=/usr/local/cuda/lib64");
+}
+
+fn link_apple_frameworks() {
+    println!("cargo:rustc-link-lib=framework=Metal");
+    println!("cargo:rustc-link-lib=framework=Foundation");
+    println!("cargo:rustc-link-lib=framework=MetalPerformanceShaders");
+    println!("cargo:rustc-link-lib=framework=MetalKit");
+    println!("cargo:rustc-link-lib=framework=Accelerate");
+}
+
 // courtesy of https://github.com/rustformers/llm
 fn metal_hack(build: &mut cc::Build) {
     const GGML_METAL_METAL_PATH: &str = "llama.cpp/ggml-metal.metal";